@@ -0,0 +1,32 @@
+//! Embeds the configuration `JANITOR_EMBEDDED_CONFIG` points at into the
+//! janitor when built with the `embedded-config` feature, see
+//! `Config::load`.
+
+use std::{env, fs, path::PathBuf};
+
+const EMBEDDED_CONFIG: &str = "JANITOR_EMBEDDED_CONFIG";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBEDDED_CONFIG").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed={EMBEDDED_CONFIG}");
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+    let config = match env::var(EMBEDDED_CONFIG) {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            fs::read_to_string(&path)
+                .unwrap_or_else(|error| panic!("failed to read {EMBEDDED_CONFIG} {path}: {error}"))
+        }
+        Err(_) => {
+            println!(
+                "cargo:warning={EMBEDDED_CONFIG} is not set, embedding an empty configuration"
+            );
+            String::new()
+        }
+    };
+
+    fs::write(out.join("embedded-config.toml"), config).expect("failed to embed the configuration");
+}
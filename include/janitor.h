@@ -0,0 +1,66 @@
+/*
+ * C interface to the planning logic of nix-janitor.
+ *
+ * Build the shared library with:
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * Dates are naive timestamps: the seconds since 1970-01-01 00:00:00 in the
+ * local time nix-env prints the dates of the generations in.
+ *
+ * Functions returning a pointer return NULL on failure, janitor_last_error()
+ * then describes what went wrong.
+ */
+
+#ifndef JANITOR_H
+#define JANITOR_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct JanitorGeneration {
+    uint32_t id;
+    int64_t date;
+    bool current;
+} JanitorGeneration;
+
+typedef struct JanitorGenerations JanitorGenerations;
+
+/* Owned by the library, valid until the next call on the same thread. */
+const char *janitor_last_error(void);
+
+/* Parses the output of `nix-env --list-generations`. */
+JanitorGenerations *janitor_parse_listing(const char *listing);
+
+/*
+ * The generations the janitor would delete from the profile at `profile` at
+ * the time `now`, using the defaults of the profile kind unless
+ * `keep_seconds` or `keep_at_least` are non-negative. Fails if `keep_seconds`
+ * exceeds about a century.
+ */
+JanitorGenerations *janitor_plan(const JanitorGenerations *generations,
+                                 const char *profile,
+                                 int64_t keep_seconds,
+                                 ssize_t keep_at_least,
+                                 int64_t now);
+
+size_t janitor_generations_len(const JanitorGenerations *generations);
+
+/* Ordered by id, returns false if `index` is out of bounds. */
+bool janitor_generations_get(const JanitorGenerations *generations,
+                             size_t index,
+                             JanitorGeneration *out);
+
+void janitor_generations_free(JanitorGenerations *generations);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* JANITOR_H */
@@ -0,0 +1,95 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use crate::error::{JanitorError, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{executor::Executor, filesystem, generation_set::GenerationSet, nix_env};
+
+/// The way generations of a profile are listed.
+///
+/// Deletion always goes through `nix-env`, so that nix itself keeps track of
+/// the profile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Backend {
+    /// Run `nix-env --list-generations`.
+    #[default]
+    NixEnv,
+
+    /// Read the generation links of the profile, see [filesystem].
+    Filesystem,
+}
+
+impl Backend {
+    /// All available backends.
+    pub const ALL: [Self; 2] = [Self::NixEnv, Self::Filesystem];
+
+    /// Lists all generations of the profile at `profile` using this backend.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use janitor::{Backend, TokioExecutor};
+    ///
+    /// # async fn example() -> eyre::Result<()> {
+    /// let generations = Backend::Filesystem
+    ///     .list_generations(&TokioExecutor, "/nix/var/nix/profiles/system")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list_generations<E, P>(&self, executor: &E, profile: P) -> Result<GenerationSet>
+    where
+        E: Executor + ?Sized,
+        P: AsRef<Path>,
+    {
+        match self {
+            Self::NixEnv => nix_env::list_generations(executor, profile).await,
+            Self::Filesystem => filesystem::list_generations(profile),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::NixEnv => "nix-env",
+            Self::Filesystem => "filesystem",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Backend {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nix-env" => Ok(Self::NixEnv),
+            "filesystem" => Ok(Self::Filesystem),
+            other => Err(parse_error!("unknown backend: {other}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_roundtrips() -> Result<()> {
+        for backend in Backend::ALL {
+            assert_eq!(backend.to_string().parse::<Backend>()?, backend);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_backend() {
+        assert!("nix-profile".parse::<Backend>().is_err());
+    }
+}
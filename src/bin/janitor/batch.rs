@@ -0,0 +1,415 @@
+//! `janitor batch`: cleans up the profiles named by jobs read from stdin,
+//! for tools orchestrating the janitor without building a command line per
+//! profile.
+//!
+//! The protocol is line based: every line of stdin is a JSON object naming
+//! a profile and optionally its retention, every job is answered with a JSON
+//! object on a line of stdout once it has been processed. Blank lines are
+//! skipped. A job failing does not stop the batch, the janitor exits with an
+//! error after the last one though.
+
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use eyre::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use janitor::{
+    duration::{self, parse_duration, parse_keep_since, KeepSince},
+    Job, KeepSinceDerivation, Profile, RetentionOverrides, RunReport,
+};
+
+use crate::{
+    commands::Context, log_report, record_success, redact, save_listing_cache, MANAGED_BY_SYSTEM,
+};
+
+/// A job read from a line of stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    /// The profile to clean up.
+    pub profile: PathBuf,
+
+    /// Keep generations that have been active within this duration.
+    pub keep: Option<String>,
+
+    /// Keep generations that have been active within this many days.
+    pub keep_days: Option<i64>,
+
+    /// Keep generations that have been active within this duration or on or
+    /// after this date.
+    pub keep_since: Option<String>,
+
+    /// Keep at least this many of the most recent generations.
+    pub keep_at_least: Option<usize>,
+}
+
+impl Request {
+    /// Parses the job on a `line`.
+    pub fn parse(line: &str) -> Result<Self> {
+        serde_json::from_str(line).wrap_err("invalid job")
+    }
+
+    /// The retention settings of the job.
+    ///
+    /// # Errors
+    ///
+    /// Fails if more than one of `keep`, `keep_days` and `keep_since` is
+    /// given, or one of them is invalid.
+    pub fn retention(&self) -> Result<RetentionOverrides> {
+        let keep_since = match (&self.keep, self.keep_days, &self.keep_since) {
+            (None, None, None) => None,
+            (Some(keep), None, None) => Some(KeepSince::Within(parse_duration(keep)?)),
+            (None, Some(days), None) if days < 0 => bail!("keep_days must not be negative"),
+            (None, Some(days), None) => Some(KeepSince::Within(duration::days(days)?)),
+            (None, None, Some(since)) => Some(parse_keep_since(since)?),
+            _ => bail!("keep, keep_days and keep_since are the same setting, give only one"),
+        };
+
+        Ok(RetentionOverrides {
+            keep: match keep_since {
+                Some(KeepSince::Within(keep)) => Some(keep),
+                _ => None,
+            },
+            keep_since: match keep_since {
+                Some(KeepSince::Date(date)) => Some(date),
+                _ => None,
+            },
+            keep_at_least: self.keep_at_least,
+            ..Default::default()
+        })
+    }
+}
+
+/// The answer to a job, written to a line of stdout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Outcome {
+    /// The line of stdin the job has been read from, counted from 1.
+    pub line: usize,
+
+    /// The profile of the job, unless it could not be read.
+    pub profile: Option<PathBuf>,
+
+    /// Whether the job succeeded.
+    pub ok: bool,
+
+    /// Whether nothing has been deleted, as `--dry-run` has been given.
+    pub dry_run: bool,
+
+    /// The ids of the generations deleted, or that would be deleted in a
+    /// dry run.
+    pub deleted: Vec<u32>,
+
+    /// How the cutoff date of the job has been derived, unless it failed
+    /// or has been skipped before.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_since: Option<String>,
+
+    /// Why nothing has been done to the profile, if it has been skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+
+    /// Why the job failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a job did to its profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Cleaned {
+    /// The ids of the generations deleted, or that would be deleted.
+    deleted: Vec<u32>,
+
+    /// How the cutoff date of the job has been derived.
+    keep_since: Option<KeepSinceDerivation>,
+
+    /// Why the profile has been skipped.
+    skipped: Option<&'static str>,
+}
+
+/// `janitor batch`: processes the jobs on stdin, then collects garbage and
+/// performs the further steps of the run, unless `dry_run`.
+///
+/// # Errors
+///
+/// Fails if stdin can not be read or stdout not be written, or if any of
+/// the jobs failed.
+pub fn run(context: &Context, dry_run: bool) -> Result<()> {
+    let cache = context.listing_cache();
+
+    // The steps of the run after the deletions happen once after all jobs.
+    let mut options = context.settings.options.clone();
+    options.gc = false;
+    options.verify_store = false;
+    options.repair_store = false;
+    options.stale_pins = None;
+    options.free_at_least = None;
+    options.per_user_budget = None;
+
+    let mut report = RunReport::default();
+    let (jobs, failed) = process(
+        io::stdin().lock(),
+        io::stdout().lock(),
+        dry_run,
+        |request| {
+            let profile = Profile::new(&request.profile);
+            if profile.managed_by_system() {
+                return Ok(Cleaned {
+                    skipped: Some(MANAGED_BY_SYSTEM),
+                    ..Cleaned::default()
+                });
+            }
+            let job = context
+                .settings
+                .job_with(&profile, request.retention()?, context.now)?;
+
+            if dry_run {
+                let planned = context
+                    .driver
+                    .plan(vec![job], options.clone(), cache.as_ref())?;
+                return Ok(Cleaned {
+                    deleted: planned
+                        .iter()
+                        .flat_map(|job| job.state().to_delete.iter().map(|g| g.id))
+                        .collect(),
+                    keep_since: planned.iter().find_map(Job::keep_since_derivation),
+                    skipped: None,
+                });
+            }
+
+            let cleaned = context
+                .driver
+                .run(vec![job], options.clone(), cache.as_ref())?;
+            let deleted = cleaned
+                .profiles()
+                .iter()
+                .flat_map(|profile| profile.deleted.iter().map(|g| g.id))
+                .collect();
+            let keep_since = cleaned.profiles().iter().find_map(|p| p.keep_since);
+            for profile in cleaned.profiles() {
+                report.record(profile.clone());
+            }
+
+            Ok(Cleaned {
+                deleted,
+                keep_since,
+                skipped: None,
+            })
+        },
+    )?;
+
+    let finished = (!dry_run).then(|| {
+        context
+            .driver
+            .run(Vec::new(), context.settings.options.clone(), cache.as_ref())
+    });
+    save_listing_cache(&context.settings, cache);
+
+    if let Some(finished) = finished.transpose()? {
+        if let Some(gc) = finished.gc() {
+            report.record_gc(*gc);
+        }
+        if let Some(verification) = finished.verification() {
+            report.record_verification(verification.clone());
+        }
+        log_report(&report, context.settings.explain_skip);
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {jobs} jobs failed");
+    }
+    if !dry_run {
+        record_success(&context.settings, context.run_id, &report);
+    }
+
+    Ok(())
+}
+
+/// Reads the jobs from `input`, processes each with `clean`, and writes the
+/// outcomes to `output`.
+///
+/// Returns the number of jobs and of the ones that failed.
+fn process<R, W, F>(input: R, mut output: W, dry_run: bool, mut clean: F) -> Result<(usize, usize)>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&Request) -> Result<Cleaned>,
+{
+    let (mut jobs, mut failed) = (0, 0);
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line.wrap_err("Failed to read the jobs")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        jobs += 1;
+
+        let request = Request::parse(&line);
+        let profile = request.as_ref().ok().map(|request| request.profile.clone());
+        let result = request.and_then(|request| {
+            let span = tracing::info_span!("batch", line = index + 1);
+            span.in_scope(|| clean(&request))
+        });
+
+        let (cleaned, error) = match result {
+            Ok(cleaned) => (cleaned, None),
+            Err(error) => {
+                let error = format!("{error:#}");
+                tracing::warn!(line = index + 1, error, "job failed");
+                failed += 1;
+
+                (Cleaned::default(), Some(error))
+            }
+        };
+        let outcome = Outcome {
+            line: index + 1,
+            profile,
+            ok: error.is_none(),
+            dry_run,
+            deleted: cleaned.deleted,
+            keep_since: cleaned.keep_since.map(|derivation| derivation.to_string()),
+            skipped: cleaned.skipped.map(ToString::to_string),
+            error,
+        };
+
+        let json = serde_json::to_string(&outcome).wrap_err("Failed to encode the outcome")?;
+        writeln!(output, "{}", redact::text(&json)).wrap_err("Failed to write the outcome")?;
+        output.flush().wrap_err("Failed to write the outcome")?;
+    }
+
+    Ok((jobs, failed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::{Duration, NaiveDate};
+    use eyre::eyre;
+    use janitor::{ProfileKind, RetentionPolicy};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::profile(r#"{"profile": "/p/a"}"#, None, None, None)]
+    #[case::days(
+        r#"{"profile": "/p/a", "keep_days": 3}"#,
+        Some(Duration::days(3)),
+        None,
+        None
+    )]
+    #[case::keep(
+        r#"{"profile": "/p/a", "keep": "36h"}"#,
+        Some(Duration::hours(36)),
+        None,
+        None
+    )]
+    #[case::since_duration(
+        r#"{"profile": "/p/a", "keep_since": "2w", "keep_at_least": 2}"#,
+        Some(Duration::weeks(2)),
+        None,
+        Some(2)
+    )]
+    #[case::since_date(
+        r#"{"profile": "/p/a", "keep_since": "2024-05-01"}"#,
+        None,
+        Some(NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()),
+        None
+    )]
+    fn requests(
+        #[case] line: &str,
+        #[case] keep: Option<Duration>,
+        #[case] keep_since: Option<chrono::NaiveDateTime>,
+        #[case] keep_at_least: Option<usize>,
+    ) -> Result<()> {
+        let request = Request::parse(line)?;
+
+        assert_eq!(request.profile, PathBuf::from("/p/a"));
+        assert_eq!(
+            request.retention()?,
+            RetentionOverrides {
+                keep,
+                keep_since,
+                keep_at_least,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::negative_days(r#"{"profile": "/p/a", "keep_days": -1}"#)]
+    #[case::negative_keep(r#"{"profile": "/p/a", "keep": "-1d"}"#)]
+    #[case::both(r#"{"profile": "/p/a", "keep_days": 1, "keep": "1d"}"#)]
+    #[case::invalid_since(r#"{"profile": "/p/a", "keep_since": "yesterday"}"#)]
+    fn invalid_retention(#[case] line: &str) {
+        assert!(Request::parse(line).unwrap().retention().is_err());
+    }
+
+    #[rstest]
+    #[case::not_json("/p/a")]
+    #[case::missing_profile(r#"{"keep_days": 1}"#)]
+    #[case::unknown_field(r#"{"profile": "/p/a", "days": 1}"#)]
+    fn invalid_requests(#[case] line: &str) {
+        assert!(Request::parse(line).is_err());
+    }
+
+    #[test]
+    fn answers_every_job() -> Result<()> {
+        let input = concat!(
+            "{\"profile\": \"/p/a\", \"keep_days\": 3}\n",
+            "\n",
+            "{\"profile\": \"/p/missing\"}\n",
+            "not json\n",
+            "{\"profile\": \"/etc/profiles/per-user/alice\"}\n",
+        );
+        let mut output = Vec::new();
+        let now = NaiveDate::from_ymd_opt(2024, 5, 15)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let policy = RetentionPolicy::default_for(ProfileKind::User);
+
+        let counts = process(
+            input.as_bytes(),
+            &mut output,
+            false,
+            |request| match request.profile.to_str() {
+                Some("/p/a") => Ok(Cleaned {
+                    deleted: vec![1, 2],
+                    keep_since: Some(policy.derive_keep_since(now)),
+                    skipped: None,
+                }),
+                Some("/etc/profiles/per-user/alice") => Ok(Cleaned {
+                    skipped: Some(MANAGED_BY_SYSTEM),
+                    ..Cleaned::default()
+                }),
+                _ => Err(eyre!("profile does not exist")),
+            },
+        )?;
+
+        assert_eq!(counts, (4, 2));
+        let lines: Vec<_> = String::from_utf8(output)?
+            .lines()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            lines[..2],
+            [
+                r#"{"line":1,"profile":"/p/a","ok":true,"dry_run":false,"deleted":[1,2],"keep_since":"2024-05-08 00:00:00 UTC = now 2024-05-15 00:00:00 UTC - keep 7d"}"#,
+                r#"{"line":3,"profile":"/p/missing","ok":false,"dry_run":false,"deleted":[],"error":"profile does not exist"}"#,
+            ]
+        );
+        assert!(lines[2].starts_with(
+            r#"{"line":4,"profile":null,"ok":false,"dry_run":false,"deleted":[],"error":"invalid job: "#
+        ));
+        assert_eq!(
+            lines[3],
+            r#"{"line":5,"profile":"/etc/profiles/per-user/alice","ok":true,"dry_run":false,"deleted":[],"skipped":"managed by the system generation through users.users.<name>.packages"}"#
+        );
+
+        Ok(())
+    }
+}
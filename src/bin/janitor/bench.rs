@@ -0,0 +1,361 @@
+use std::{
+    collections::BTreeSet,
+    env, fmt, fs, io,
+    path::PathBuf,
+    process::Output,
+    time::{Duration, Instant},
+};
+
+use futures::executor::block_on;
+
+use janitor::{
+    nix_env,
+    nix_store::{self, GcReport},
+    Backend, Blocking, BlockingExecutor, CommandLine, Profile, StdExecutor,
+};
+
+/// The number of deleted paths in the synthetic `nix-store --gc` output the
+/// parsing of it is measured on.
+const TRANSCRIPT_LINES: usize = 1_000_000;
+
+/// Replays a `nix-store --gc` transcript written to files, whatever command
+/// it is asked to run, so that its output is read from a child process like
+/// the one of the real garbage collector.
+#[derive(Debug)]
+struct Replay {
+    stdout: PathBuf,
+    stderr: PathBuf,
+}
+
+impl Replay {
+    /// Writes the transcript of a garbage collection deleting `paths` store
+    /// paths to the temporary directory.
+    fn write(paths: usize) -> io::Result<Self> {
+        let dir = env::temp_dir();
+        let replay = Self {
+            stdout: dir.join(format!("janitor-bench-gc-stdout-{}", std::process::id())),
+            stderr: dir.join(format!("janitor-bench-gc-stderr-{}", std::process::id())),
+        };
+
+        let (stdout, stderr) = gc_transcript(paths);
+        fs::write(&replay.stdout, stdout)?;
+        fs::write(&replay.stderr, stderr)?;
+
+        Ok(replay)
+    }
+
+    fn command(&self) -> CommandLine {
+        CommandLine::new("sh")
+            .args(["-c", "cat \"$1\" >&2 && cat \"$2\"", "sh"])
+            .arg(&self.stderr)
+            .arg(&self.stdout)
+    }
+}
+
+impl BlockingExecutor for Replay {
+    fn output(&self, _command: CommandLine) -> io::Result<Output> {
+        StdExecutor.output(self.command())
+    }
+
+    fn output_streaming(
+        &self,
+        _command: CommandLine,
+        on_stderr: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        StdExecutor.output_streaming(self.command(), on_stderr)
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.stdout);
+        let _ = fs::remove_file(&self.stderr);
+    }
+}
+
+/// The outcome of a single benchmarked operation.
+#[derive(Debug)]
+pub struct Measurement {
+    /// What has been measured, e.g. `list`.
+    pub operation: &'static str,
+
+    /// The backend or tool used.
+    pub via: String,
+
+    /// The fastest time over all iterations, or why the operation failed.
+    pub outcome: Result<Duration, String>,
+}
+
+/// The results of `janitor bench`.
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    /// All measurements, in the order they have been taken.
+    pub measurements: Vec<Measurement>,
+
+    /// The fastest listing backend agreeing with `nix-env`, if any.
+    pub preferred: Option<Backend>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for Measurement {
+            operation,
+            via,
+            outcome,
+        } in &self.measurements
+        {
+            match outcome {
+                Ok(duration) => writeln!(f, "{operation:<8} {via:<24} {duration:>12.3?}")?,
+                Err(error) => writeln!(f, "{operation:<8} {via:<24} failed: {error}")?,
+            }
+        }
+
+        match self.preferred {
+            Some(backend) => writeln!(f, "preferred backend: {backend}"),
+            None => writeln!(f, "preferred backend: none, keeping the current choice"),
+        }
+    }
+}
+
+/// The generation ids of every benchmarked profile, as listed by a backend.
+type Listing = Vec<BTreeSet<u32>>;
+
+/// Measures listing the `profiles` with every [Backend], deleting
+/// generations (as a dry run), garbage collection (only printing the dead
+/// paths) and parsing the output of a garbage collection deleting
+/// [TRANSCRIPT_LINES] paths, read from a child process either collected
+/// first or streamed, taking the fastest of `iterations` runs each.
+///
+/// Nothing is modified by the benchmark.
+pub fn run(profiles: &[Profile], iterations: usize) -> BenchReport {
+    let executor = Blocking(StdExecutor);
+    let iterations = iterations.max(1);
+
+    let mut report = BenchReport::default();
+    let mut listings = Vec::new();
+
+    for backend in Backend::ALL {
+        let outcome = measure(iterations, || {
+            profiles
+                .iter()
+                .map(|profile| {
+                    let generations = block_on(backend.list_generations(&executor, profile))?;
+                    Ok(generations.into())
+                })
+                .collect::<eyre::Result<Listing>>()
+        });
+
+        if let Ok((duration, listing)) = &outcome {
+            listings.push((backend, *duration, listing.clone()));
+        }
+        report.measurements.push(Measurement {
+            operation: "list",
+            via: backend.to_string(),
+            outcome: outcome.map(|(duration, _)| duration),
+        });
+    }
+
+    let outcome = measure(iterations, || {
+        for profile in profiles {
+            let generations = block_on(nix_env::list_generations(&executor, profile))?;
+            let old = generations.iter().filter(|g| !g.current).copied().collect();
+
+            block_on(nix_env::delete_generations_dry_run(
+                &executor, profile, &old,
+            ))?;
+        }
+
+        Ok(())
+    });
+    report.measurements.push(Measurement {
+        operation: "delete",
+        via: "nix-env (dry run)".to_string(),
+        outcome: outcome.map(|(duration, _)| duration),
+    });
+
+    let outcome = measure(1, || Ok(block_on(nix_store::dead_paths(&executor))?));
+    report.measurements.push(Measurement {
+        operation: "gc",
+        via: "nix-store (print dead)".to_string(),
+        outcome: outcome.map(|(duration, _)| duration),
+    });
+
+    let replay = Replay::write(TRANSCRIPT_LINES);
+    for (reading, streamed) in [("buffered", false), ("streamed", true)] {
+        let outcome = match &replay {
+            Ok(replay) => measure(iterations, || match streamed {
+                true => Ok(nix_store::blocking::collect_garbage(replay)?),
+                false => {
+                    let output = replay.output(CommandLine::new("nix-store"))?;
+                    Ok(GcReport::scan(&output.stdout[..], &output.stderr[..])?)
+                }
+            }),
+            Err(error) => Err(format!("failed to write the transcript: {error}")),
+        };
+        report.measurements.push(Measurement {
+            operation: "parse",
+            via: format!("gc output, {}M, {reading}", TRANSCRIPT_LINES / 1_000_000),
+            outcome: outcome.map(|(duration, _)| duration),
+        });
+    }
+
+    report.preferred = preferred(&listings);
+
+    report
+}
+
+/// Synthesizes the stdout and stderr of `nix-store --gc` deleting `paths`
+/// store paths.
+fn gc_transcript(paths: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut stderr = b"finding garbage collector roots...\ndeleting garbage...\n".to_vec();
+    for n in 0..paths {
+        stderr.extend_from_slice(
+            format!("deleting '/nix/store/{n:0>32}-synthetic-path-{n}'\n").as_bytes(),
+        );
+    }
+    stderr.extend_from_slice(
+        b"deleting unused links...\nnote: currently hard linking saves 0.00 MiB\n",
+    );
+
+    let stdout = format!("{paths} store paths deleted, 1024.00 MiB freed\n").into_bytes();
+
+    (stdout, stderr)
+}
+
+fn measure<T, F>(iterations: usize, mut operation: F) -> Result<(Duration, T), String>
+where
+    F: FnMut() -> eyre::Result<T>,
+{
+    let mut fastest = None;
+
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let result = operation().map_err(|error| error.to_string())?;
+        let elapsed = start.elapsed();
+
+        fastest = match fastest {
+            Some((duration, _)) if duration <= elapsed => fastest,
+            _ => Some((elapsed, result)),
+        };
+    }
+
+    fastest.ok_or_else(|| "not run".to_string())
+}
+
+/// Picks the fastest backend whose listing agrees with the one of `nix-env`,
+/// which is the reference every other backend has to match.
+fn preferred(listings: &[(Backend, Duration, Listing)]) -> Option<Backend> {
+    let (_, _, reference) = listings
+        .iter()
+        .find(|(backend, _, _)| *backend == Backend::NixEnv)?;
+
+    listings
+        .iter()
+        .filter(|(backend, _, listing)| {
+            let agrees = listing == reference;
+            if !agrees {
+                tracing::warn!(%backend, "listing differs from nix-env, not considered");
+            }
+
+            agrees
+        })
+        .min_by_key(|(_, duration, _)| *duration)
+        .map(|(backend, _, _)| *backend)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn listing(ids: &[u32]) -> Listing {
+        vec![ids.iter().copied().collect()]
+    }
+
+    #[test]
+    fn prefers_fastest_agreeing() {
+        let listings = [
+            (Backend::NixEnv, Duration::from_millis(20), listing(&[1, 2])),
+            (
+                Backend::Filesystem,
+                Duration::from_millis(1),
+                listing(&[1, 2]),
+            ),
+        ];
+
+        assert_eq!(preferred(&listings), Some(Backend::Filesystem));
+    }
+
+    #[test]
+    fn ignores_disagreeing() {
+        let listings = [
+            (Backend::NixEnv, Duration::from_millis(20), listing(&[1, 2])),
+            (Backend::Filesystem, Duration::from_millis(1), listing(&[2])),
+        ];
+
+        assert_eq!(preferred(&listings), Some(Backend::NixEnv));
+    }
+
+    #[test]
+    fn nothing_without_reference() {
+        let listings = [(Backend::Filesystem, Duration::from_millis(1), listing(&[2]))];
+
+        assert_eq!(preferred(&listings), None);
+    }
+
+    #[test]
+    fn measure_keeps_fastest() {
+        let mut calls = 0;
+
+        let (_, value) = measure(3, || {
+            calls += 1;
+            Ok(calls)
+        })
+        .unwrap();
+
+        assert_eq!(calls, 3);
+        assert!((1..=3).contains(&value));
+    }
+
+    #[test]
+    fn parses_gc_transcript() -> eyre::Result<()> {
+        let (stdout, stderr) = gc_transcript(1000);
+
+        assert_eq!(
+            GcReport::scan(&stdout[..], &stderr[..])?.paths_deleted,
+            1000
+        );
+        assert_eq!(GcReport::scan(&b""[..], &stderr[..])?.paths_deleted, 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn streams_replayed_transcript() -> eyre::Result<()> {
+        let replay = Replay::write(1000)?;
+
+        let report = nix_store::blocking::collect_garbage(&replay)?;
+
+        assert_eq!(report.paths_deleted, 1000);
+        assert_eq!(report.bytes_freed, 1024 * 1024 * 1024);
+        assert_eq!(report.unrecognized, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn renders_failures() {
+        let report = BenchReport {
+            measurements: vec![Measurement {
+                operation: "gc",
+                via: "nix-store".to_string(),
+                outcome: Err("not found".to_string()),
+            }],
+            preferred: None,
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "gc       nix-store                failed: not found\n\
+             preferred backend: none, keeping the current choice\n"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Compares the system generations the retention policy keeps with the boot
+//! entries of the bootloader, which NixOS limits on its own with
+//! `boot.loader.*.configurationLimit`.
+//!
+//! Keeping fewer generations than there are boot entries leaves entries of
+//! deleted generations in the boot menu until the next switch, and booting
+//! them fails once their store paths are collected. Keeping more only keeps
+//! generations that can not be booted.
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+/// Where the boot partition is mounted.
+pub const BOOT: &str = "/boot";
+
+/// How the retention of the system profile relates to the bootloader.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootLimit {
+    /// The `configurationLimit` of the bootloader, counted from the boot
+    /// entries if not given.
+    pub configured: Option<usize>,
+
+    /// Keep at least as many system generations as the bootloader lists.
+    pub align: bool,
+}
+
+impl BootLimit {
+    /// The number of generations the bootloader lists, the configured limit
+    /// or the number of entries found in `boot`, if any.
+    pub fn limit(&self, boot: &Path) -> Option<usize> {
+        self.configured
+            .or_else(|| entries(boot).map(|entries| entries.len()))
+    }
+}
+
+/// How the number of kept system generations differs from the boot entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Fewer generations are kept than the bootloader lists.
+    FewerKept,
+
+    /// More generations are kept than the bootloader lists.
+    MoreKept,
+}
+
+/// Compares the number of `kept` system generations with the `limit` of the
+/// bootloader.
+///
+/// Keeping more is fine as long as the profile does not have more than
+/// `limit` generations at all.
+pub fn compare(kept: usize, listed: usize, limit: usize) -> Option<Mismatch> {
+    if kept < limit.min(listed) {
+        Some(Mismatch::FewerKept)
+    } else if kept > limit {
+        Some(Mismatch::MoreKept)
+    } else {
+        None
+    }
+}
+
+/// Returns the ids of the system generations with an entry in the bootloader
+/// installed in `boot`, from the entries of systemd-boot or the menu of
+/// GRUB, or `None` if neither is found.
+///
+/// Specialisations share the entry of their generation.
+pub fn entries(boot: &Path) -> Option<BTreeSet<u32>> {
+    if let Ok(dir) = fs::read_dir(boot.join("loader/entries")) {
+        let entries = dir
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let generation = name
+                    .strip_prefix("nixos-generation-")?
+                    .strip_suffix(".conf")?;
+                let (id, _) = generation.split_once('-').unwrap_or((generation, ""));
+
+                id.parse().ok()
+            })
+            .collect();
+
+        return Some(entries);
+    }
+
+    let menu = fs::read_to_string(boot.join("grub/grub.cfg")).ok()?;
+    let entries = menu
+        .lines()
+        .filter_map(|line| {
+            let (_, configuration) = line.trim().split_once("NixOS - Configuration ")?;
+            let end = configuration
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(configuration.len());
+
+            configuration[..end].parse().ok()
+        })
+        .collect();
+
+    Some(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, io, path::PathBuf};
+
+    use super::*;
+
+    use eyre::Result;
+    use rstest::rstest;
+
+    fn temp_dir(name: &str) -> io::Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("janitor-boot-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
+    #[rstest]
+    #[case::matching(10, 20, 10, None)]
+    #[case::fewer(5, 20, 10, Some(Mismatch::FewerKept))]
+    #[case::more(15, 20, 10, Some(Mismatch::MoreKept))]
+    #[case::young_profile(3, 3, 10, None)]
+    fn compares(
+        #[case] kept: usize,
+        #[case] listed: usize,
+        #[case] limit: usize,
+        #[case] expected: Option<Mismatch>,
+    ) {
+        assert_eq!(compare(kept, listed, limit), expected);
+    }
+
+    #[test]
+    fn systemd_boot_entries() -> Result<()> {
+        let boot = temp_dir("systemd")?;
+        let entries_dir = boot.join("loader/entries");
+        fs::create_dir_all(&entries_dir)?;
+        for name in [
+            "nixos-generation-41.conf",
+            "nixos-generation-42.conf",
+            "nixos-generation-42-specialisation-gaming.conf",
+            "memtest86.conf",
+        ] {
+            fs::write(entries_dir.join(name), "")?;
+        }
+
+        let entries = entries(&boot);
+        fs::remove_dir_all(&boot)?;
+
+        assert_eq!(entries, Some(BTreeSet::from([41, 42])));
+
+        Ok(())
+    }
+
+    #[test]
+    fn grub_entries() -> Result<()> {
+        let boot = temp_dir("grub")?;
+        fs::create_dir_all(boot.join("grub"))?;
+        fs::write(
+            boot.join("grub/grub.cfg"),
+            "menuentry \"NixOS - Default\" {\n}\n\
+             submenu \"NixOS - All configurations\" {\n\
+             menuentry \"NixOS - Configuration 42 (2023-06-03 - 23.05)\" {\n}\n\
+             menuentry \"NixOS - Configuration 41 (2023-06-01 - 23.05)\" {\n}\n\
+             }\n",
+        )?;
+
+        let limit = BootLimit::default().limit(&boot);
+        let entries = entries(&boot);
+        fs::remove_dir_all(&boot)?;
+
+        assert_eq!(entries, Some(BTreeSet::from([41, 42])));
+        assert_eq!(limit, Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_bootloader() -> Result<()> {
+        let boot = temp_dir("none")?;
+
+        let limit = BootLimit::default().limit(&boot);
+        let configured = BootLimit {
+            configured: Some(10),
+            align: false,
+        }
+        .limit(&boot);
+        fs::remove_dir_all(&boot)?;
+
+        assert_eq!(limit, None);
+        assert_eq!(configured, Some(10));
+
+        Ok(())
+    }
+}
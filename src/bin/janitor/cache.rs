@@ -0,0 +1,384 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use eyre::{Context, Result};
+use janitor::{duration::format_duration, Backend, Generation, GenerationSet};
+use serde::{Deserialize, Serialize};
+
+const SYSTEM_CACHE: &str = "/var/cache/nix-janitor/listings.toml";
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How many days listings are kept without being used by default, see
+/// [ListingCache::prune].
+pub const DEFAULT_KEEP_DAYS: i64 = 30;
+
+/// When a profile has been changed last.
+///
+/// Creating a generation replaces the profile link, while deleting one
+/// removes its generation link from the directory containing the profile, so
+/// the modification times of both are taken into account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Modified {
+    link: (i64, i64),
+    dir: (i64, i64),
+}
+
+impl Modified {
+    /// Reads the modification times of the profile at `profile`.
+    pub fn of(profile: &Path) -> io::Result<Self> {
+        let link = fs::symlink_metadata(profile)?;
+        let dir = match profile.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => fs::metadata(dir)?,
+            _ => fs::metadata(".")?,
+        };
+
+        Ok(Self {
+            link: (link.mtime(), link.mtime_nsec()),
+            dir: (dir.mtime(), dir.mtime_nsec()),
+        })
+    }
+}
+
+/// A cached listing of a single profile.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    profile: PathBuf,
+    backend: Backend,
+    modified: Modified,
+
+    /// The generations in the format of `nix-env --list-generations`.
+    generations: Vec<String>,
+
+    /// When the listing has been used last, `None` in caches written before
+    /// this has been tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    used: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct File {
+    #[serde(default)]
+    profiles: Vec<Entry>,
+}
+
+/// Generation listings of earlier runs, keyed by the profile they belong to.
+///
+/// A listing is only reused as long as the profile has not been modified
+/// since, see [Modified], and has been produced by the same [Backend].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ListingCache {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl ListingCache {
+    /// Loads the cache from `path`, returning an empty cache if the file does
+    /// not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        tracing::debug!(?path, "reading listing cache");
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(error) => {
+                return Err(error)
+                    .wrap_err_with(|| format!("Failed to read cache {}", path.display()))
+            }
+        };
+
+        Self::parse(&content).wrap_err_with(|| format!("Invalid cache {}", path.display()))
+    }
+
+    /// Loads the cache from `path`, starting with an empty cache if it can not
+    /// be read.
+    pub fn load_or_default(path: &Path) -> Self {
+        Self::load(path).unwrap_or_else(|error| {
+            tracing::warn!(%error, "ignoring unreadable listing cache");
+            Self::default()
+        })
+    }
+
+    /// Writes the cache to `path`, only logging failures, as the cache is
+    /// merely an optimization.
+    pub fn save_or_warn(&self, path: &Path) {
+        if let Err(error) = self.save(path) {
+            tracing::warn!(%error, "failed to save the listing cache");
+        }
+    }
+
+    /// Writes the cache to `path`, creating its directory if necessary.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        tracing::debug!(?path, "writing listing cache");
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        let file = File {
+            profiles: self.entries.values().cloned().collect(),
+        };
+
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, toml::to_string(&file)?)
+            .wrap_err_with(|| format!("Failed to write cache {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .wrap_err_with(|| format!("Failed to write cache {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// The generations of `profile` listed by `backend`, if they have been
+    /// cached while the profile was in the same state as described by
+    /// `modified`.
+    pub fn get(
+        &mut self,
+        profile: &Path,
+        backend: Backend,
+        modified: Modified,
+    ) -> Option<GenerationSet> {
+        let entry = self.entries.get_mut(profile)?;
+        if entry.backend != backend || entry.modified != modified {
+            return None;
+        }
+        entry.used = Some(Utc::now());
+
+        entry
+            .generations
+            .iter()
+            .map(Generation::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .map(GenerationSet::from)
+    }
+
+    /// Remembers the `generations` of `profile`, listed by `backend` while
+    /// the profile was in the state described by `modified`.
+    pub fn insert(
+        &mut self,
+        profile: &Path,
+        backend: Backend,
+        modified: Modified,
+        generations: &GenerationSet,
+    ) {
+        let entry = Entry {
+            profile: profile.to_path_buf(),
+            backend,
+            modified,
+            generations: generations.iter().map(format_generation).collect(),
+            used: Some(Utc::now()),
+        };
+
+        self.entries.insert(profile.to_path_buf(), entry);
+    }
+
+    /// Forgets the listings that have not been used within `keep` before
+    /// `now`, and those of profiles that no longer exist, so that the cache
+    /// does not keep growing with profiles that are not cleaned up anymore.
+    ///
+    /// Listings never used since this has been tracked count as used `now`.
+    pub fn prune(&mut self, now: DateTime<Utc>, keep: Duration) {
+        let before = self.entries.len();
+
+        self.entries.retain(|profile, entry| {
+            let used = *entry.used.get_or_insert(now);
+
+            used >= now - keep && fs::symlink_metadata(profile).is_ok()
+        });
+
+        let pruned = before - self.entries.len();
+        if pruned > 0 {
+            tracing::info!(pruned, keep = %format_duration(keep), "pruned stale listings from the cache");
+        }
+    }
+
+    /// Forgets the listing of `profile`.
+    pub fn invalidate(&mut self, profile: &Path) {
+        self.entries.remove(profile);
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: File = toml::from_str(content)?;
+
+        Ok(Self {
+            entries: file
+                .profiles
+                .into_iter()
+                .map(|entry| (entry.profile.clone(), entry))
+                .collect(),
+        })
+    }
+}
+
+fn format_generation(generation: &Generation) -> String {
+    let current = if generation.current { " (current)" } else { "" };
+
+    format!(
+        "{} {}{current}",
+        generation.id,
+        generation.date.format(DATE_FORMAT)
+    )
+}
+
+/// The default location of the listing cache.
+///
+/// When running in a root shell, this is
+/// `/var/cache/nix-janitor/listings.toml`, otherwise
+/// `$XDG_CACHE_HOME/nix-janitor/listings.toml`, falling back to `~/.cache`.
+pub fn default_path() -> Option<PathBuf> {
+    if janitor::system_by_default() {
+        return Some(PathBuf::from(SYSTEM_CACHE));
+    }
+
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("nix-janitor").join("listings.toml"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+
+    fn generations() -> GenerationSet {
+        let date = |s| NaiveDateTime::parse_from_str(s, DATE_FORMAT).unwrap();
+
+        vec![
+            Generation {
+                id: 1,
+                date: date("2023-06-01 08:10:47"),
+                current: false,
+            },
+            Generation {
+                id: 2,
+                date: date("2023-06-02 09:00:00"),
+                current: true,
+            },
+        ]
+        .into()
+    }
+
+    fn modified(link: i64) -> Modified {
+        Modified {
+            link: (link, 0),
+            dir: (1, 0),
+        }
+    }
+
+    #[test]
+    fn hit() {
+        let profile = Path::new("/profile");
+        let mut cache = ListingCache::default();
+        cache.insert(profile, Backend::NixEnv, modified(1), &generations());
+
+        assert_eq!(
+            cache.get(profile, Backend::NixEnv, modified(1)),
+            Some(generations())
+        );
+    }
+
+    #[test]
+    fn miss_when_modified() {
+        let profile = Path::new("/profile");
+        let mut cache = ListingCache::default();
+        cache.insert(profile, Backend::NixEnv, modified(1), &generations());
+
+        assert_eq!(cache.get(profile, Backend::NixEnv, modified(2)), None);
+    }
+
+    #[test]
+    fn miss_for_other_backend() {
+        let profile = Path::new("/profile");
+        let mut cache = ListingCache::default();
+        cache.insert(profile, Backend::NixEnv, modified(1), &generations());
+
+        assert_eq!(cache.get(profile, Backend::Filesystem, modified(1)), None);
+    }
+
+    #[test]
+    fn invalidate() {
+        let profile = Path::new("/profile");
+        let mut cache = ListingCache::default();
+        cache.insert(profile, Backend::NixEnv, modified(1), &generations());
+        cache.invalidate(profile);
+
+        assert_eq!(cache.get(profile, Backend::NixEnv, modified(1)), None);
+    }
+
+    #[test]
+    fn prune() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-cache-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        for profile in ["recent", "stale", "untracked"] {
+            std::os::unix::fs::symlink("profile-1-link", dir.join(profile))?;
+        }
+
+        let now = Utc::now();
+        let mut cache = ListingCache::default();
+        for profile in ["recent", "stale", "untracked", "missing"] {
+            cache.insert(
+                &dir.join(profile),
+                Backend::NixEnv,
+                modified(1),
+                &generations(),
+            );
+        }
+        cache.entries.get_mut(&dir.join("stale")).unwrap().used = Some(now - Duration::days(31));
+        cache.entries.get_mut(&dir.join("untracked")).unwrap().used = None;
+
+        cache.prune(now, Duration::days(DEFAULT_KEEP_DAYS));
+        fs::remove_dir_all(&dir)?;
+
+        let kept: Vec<_> = cache.entries.keys().cloned().collect();
+        assert_eq!(kept, [dir.join("recent"), dir.join("untracked")]);
+        assert_eq!(cache.entries[&dir.join("untracked")].used, Some(now));
+
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-cache-{}", std::process::id()));
+        let path = dir.join("listings.toml");
+
+        let mut cache = ListingCache::default();
+        cache.insert(
+            Path::new("/profile"),
+            Backend::Filesystem,
+            modified(1),
+            &generations(),
+        );
+        cache.save(&path)?;
+        let loaded = ListingCache::load(&path);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(loaded?, cache);
+
+        Ok(())
+    }
+
+    #[test]
+    fn modified_of_profile() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-modified-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        std::os::unix::fs::symlink("profile-1-link", dir.join("profile"))?;
+
+        let modified = Modified::of(&dir.join("profile"));
+        let missing = Modified::of(&dir.join("missing"));
+        fs::remove_dir_all(&dir)?;
+
+        assert!(modified.is_ok());
+        assert!(missing.is_err());
+
+        Ok(())
+    }
+}
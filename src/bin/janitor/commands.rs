@@ -0,0 +1,216 @@
+//! The commands working on the profiles: listing them, planning and
+//! performing their cleanup, and collecting garbage.
+
+use std::{path::Path, sync::Mutex};
+
+use chrono::NaiveDateTime;
+use eyre::Result;
+use janitor::{
+    state::{Discovered, Planned},
+    Job, RunReport,
+};
+
+#[cfg(feature = "tokio")]
+use crate::runtime::RuntimeOptions;
+use crate::{
+    cache::ListingCache,
+    check_failures, log_report, log_resources, pipeline,
+    plan::{read_plan, write_plan, Listing, NixPlan, Plan},
+    record_success, redact,
+    run_id::RunId,
+    save_listing_cache, PartialRun, RunOptions, Settings,
+};
+
+/// How the profiles are processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Driver {
+    /// One after another without an async runtime.
+    Blocking,
+
+    /// Concurrently on an async runtime.
+    #[cfg(feature = "tokio")]
+    Tokio(RuntimeOptions),
+}
+
+impl Driver {
+    /// Lists the profiles of the `jobs` and plans their cleanup.
+    pub fn plan(
+        self,
+        jobs: Vec<Job<Discovered>>,
+        options: RunOptions,
+        cache: Option<&Mutex<ListingCache>>,
+    ) -> Result<Vec<Job<Planned>>> {
+        match self {
+            Self::Blocking => pipeline::plan_blocking(jobs, options, cache),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(runtime) => pipeline::plan_tokio(jobs, options, cache, runtime),
+        }
+    }
+
+    /// Cleans up the profiles of the `jobs`.
+    pub fn run(
+        self,
+        jobs: Vec<Job<Discovered>>,
+        options: RunOptions,
+        cache: Option<&Mutex<ListingCache>>,
+    ) -> Result<RunReport> {
+        match self {
+            Self::Blocking => pipeline::run_blocking(jobs, options, cache),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(runtime) => pipeline::run_tokio(jobs, options, cache, runtime),
+        }
+    }
+
+    /// Deletes the generations planned by the `jobs`, see
+    /// [pipeline::apply_blocking].
+    pub fn apply(
+        self,
+        jobs: Vec<Job<Planned>>,
+        options: RunOptions,
+        cache: Option<&Mutex<ListingCache>>,
+    ) -> Result<RunReport> {
+        match self {
+            Self::Blocking => pipeline::apply_blocking(jobs, options, cache),
+            #[cfg(feature = "tokio")]
+            Self::Tokio(runtime) => pipeline::apply_tokio(jobs, options, cache, runtime),
+        }
+    }
+}
+
+/// Everything the commands need.
+#[derive(Debug)]
+pub struct Context {
+    /// The resolved settings.
+    pub settings: Settings,
+
+    /// How the profiles are processed.
+    pub driver: Driver,
+
+    /// The current time the retention policies are resolved against.
+    pub now: NaiveDateTime,
+
+    /// The id of the run.
+    pub run_id: RunId,
+
+    /// Whether the report is sorted and the resource usage left out, for
+    /// reproducible output.
+    pub deterministic: bool,
+
+    /// Whether the plan is printed as a Nix attribute set, see `--emit-nix`.
+    pub emit_nix: bool,
+}
+
+impl Context {
+    pub fn listing_cache(&self) -> Option<Mutex<ListingCache>> {
+        self.settings
+            .cache_path
+            .as_deref()
+            .map(|path| Mutex::new(ListingCache::load_or_default(path)))
+    }
+
+    fn planned(&self) -> Result<Vec<Job<Planned>>> {
+        let jobs = self.settings.jobs(self.now)?;
+        let cache = self.listing_cache();
+
+        let planned = self
+            .driver
+            .plan(jobs, self.settings.options.clone(), cache.as_ref());
+        save_listing_cache(&self.settings, cache);
+
+        planned
+    }
+}
+
+/// `janitor list`: prints the generations of every profile.
+pub fn list(context: &Context) -> Result<()> {
+    let planned = context.planned()?;
+
+    print!("{}", redact::text(&Listing(&planned).to_string()));
+    Ok(())
+}
+
+/// `janitor plan`: prints which generations a cleanup would delete and keep,
+/// as a Nix attribute set with `--emit-nix`, and writes the plan to `out`
+/// for `janitor apply`, if given.
+pub fn plan(context: &Context, out: Option<&Path>) -> Result<()> {
+    let planned = context.planned()?;
+
+    print!("{}", redact::text(&render(context, &planned)));
+
+    if let Some(out) = out {
+        write_plan(out, &planned)?;
+        tracing::info!(path = ?out, "wrote plan");
+    }
+    Ok(())
+}
+
+/// Renders the `planned` jobs, as a Nix attribute set with `--emit-nix`.
+fn render(context: &Context, planned: &[Job<Planned>]) -> String {
+    match context.emit_nix {
+        true => NixPlan(planned).to_string(),
+        false => Plan(planned).to_string(),
+    }
+}
+
+/// `janitor apply`: deletes the generations planned in the plan file at
+/// `path` and performs the further steps of the run, recording its success,
+/// or only prints the plan if `dry_run`.
+pub fn apply(context: &Context, path: &Path, dry_run: bool) -> Result<()> {
+    let planned = read_plan(path)?;
+    if dry_run {
+        print!("{}", redact::text(&render(context, &planned)));
+        return Ok(());
+    }
+
+    tracing::info!(?path, profiles = planned.len(), "applying plan");
+    let cache = context.listing_cache();
+
+    let report = context
+        .driver
+        .apply(planned, context.settings.options.clone(), cache.as_ref());
+    save_listing_cache(&context.settings, cache);
+
+    finish(context, report?)
+}
+
+/// `janitor clean`: deletes generations, unless `delete_generations` is
+/// off, and performs the further steps of the run, recording its success.
+pub fn clean(context: &Context, delete_generations: bool) -> Result<()> {
+    tracing::debug!(backend = %context.settings.options.backend, "listing generations");
+    let jobs = match delete_generations {
+        true => context.settings.jobs(context.now)?,
+        false => Vec::new(),
+    };
+    let cache = context.listing_cache();
+
+    let report = context
+        .driver
+        .run(jobs, context.settings.options.clone(), cache.as_ref());
+    save_listing_cache(&context.settings, cache);
+
+    finish(context, report?)
+}
+
+/// Logs the `report` of a run and records its success, failing if any
+/// profile failed or the run has been cut short.
+fn finish(context: &Context, mut report: RunReport) -> Result<()> {
+    match context.deterministic {
+        true => report.sort(),
+        false => log_resources(&report),
+    }
+    log_report(&report, context.settings.explain_skip);
+    check_failures(&report)?;
+    record_success(&context.settings, context.run_id, &report);
+
+    match report.is_partial() {
+        true => Err(PartialRun(report.cut_short().to_vec()).into()),
+        false => Ok(()),
+    }
+}
+
+/// `janitor gc`: collects garbage without deleting any generation.
+pub fn gc(mut context: Context) -> Result<()> {
+    context.settings.options.gc = true;
+
+    clean(&context, false)
+}
@@ -0,0 +1,158 @@
+//! Entry points accepting the options of the classic nix tools, so that the
+//! janitor can replace them in existing scripts.
+//!
+//! When the janitor is invoked through a link named like one of the tools,
+//! e.g. `nix-collect-garbage`, the matching `janitor compat` command is run.
+
+use std::{ffi::OsString, path::Path};
+
+use chrono::Duration;
+use clap::{Args, Subcommand};
+
+use crate::{
+    interface::{parse_duration, parse_size},
+    Settings,
+};
+
+const NIX_COLLECT_GARBAGE: &str = "nix-collect-garbage";
+
+/// The tools the janitor can stand in for.
+#[derive(Debug, Clone, Subcommand, PartialEq, Eq)]
+pub enum Compat {
+    /// Delete old generations of all profiles and collect garbage, like
+    /// `nix-collect-garbage`.
+    ///
+    /// Unlike the original, the retention policies still apply: generations
+    /// that have been active recently enough or are among the most recent
+    /// ones kept for each profile kind are not deleted.
+    #[command(name = NIX_COLLECT_GARBAGE)]
+    NixCollectGarbage(CollectGarbage),
+}
+
+/// The options of `nix-collect-garbage`.
+#[derive(Debug, Clone, Default, Args, PartialEq, Eq)]
+pub struct CollectGarbage {
+    /// Delete all generations of all profiles but the current one.
+    #[arg(short = 'd', long)]
+    pub delete_old: bool,
+
+    /// Delete the generations of all profiles that have not been active
+    /// within this period, e.g. `30d`.
+    #[arg(
+        long,
+        value_name = "PERIOD",
+        value_parser = parse_duration,
+        conflicts_with = "delete_old"
+    )]
+    pub delete_older_than: Option<Duration>,
+
+    /// Stop collecting garbage after freeing this many bytes.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_freed: Option<u64>,
+}
+
+impl CollectGarbage {
+    /// Whether generations are deleted before collecting garbage.
+    pub fn deletes_generations(&self) -> bool {
+        self.delete_old || self.delete_older_than.is_some()
+    }
+
+    /// Applies the options to the `settings` resolved from the command line
+    /// and the configuration.
+    pub fn apply(&self, mut settings: Settings) -> Settings {
+        if self.delete_old {
+            settings.overrides.keep = Some(Duration::zero());
+            settings.overrides.keep_since = None;
+        }
+        if let Some(period) = self.delete_older_than {
+            settings.overrides.keep = Some(period);
+            settings.overrides.keep_since = None;
+        }
+
+        settings.options.gc = true;
+        settings.options.max_freed = self.max_freed;
+
+        settings
+    }
+}
+
+/// Inserts the `compat` command into the command line `args` if the janitor
+/// has been invoked through a link named like one of the supported tools.
+pub fn args<I>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args: Vec<_> = args.into_iter().collect();
+
+    let invoked_as = args
+        .first()
+        .and_then(|program| Path::new(program).file_name())
+        .map(ToOwned::to_owned);
+
+    if invoked_as.as_deref() == Some(NIX_COLLECT_GARBAGE.as_ref()) {
+        args.splice(1..1, ["compat".into(), NIX_COLLECT_GARBAGE.into()]);
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use clap::Parser;
+    use rstest::rstest;
+
+    use crate::interface::{Command, NJParser};
+
+    fn parse(args: &[&str]) -> Option<CollectGarbage> {
+        let args = super::args(args.iter().map(OsString::from));
+
+        match NJParser::try_parse_from(args).ok()?.command {
+            Some(Command::Compat {
+                tool: Compat::NixCollectGarbage(options),
+            }) => Some(options),
+            _ => None,
+        }
+    }
+
+    #[rstest]
+    #[case::plain(&["janitor", "compat", "nix-collect-garbage"], Some(CollectGarbage::default()))]
+    #[case::delete_old(
+        &["janitor", "compat", "nix-collect-garbage", "-d"],
+        Some(CollectGarbage { delete_old: true, ..CollectGarbage::default() })
+    )]
+    #[case::linked(
+        &["/run/current-system/sw/bin/nix-collect-garbage", "--delete-older-than", "30d"],
+        Some(CollectGarbage { delete_older_than: Some(Duration::days(30)), ..CollectGarbage::default() })
+    )]
+    #[case::max_freed(
+        &["nix-collect-garbage", "--max-freed", "1G"],
+        Some(CollectGarbage { max_freed: Some(1024 * 1024 * 1024), ..CollectGarbage::default() })
+    )]
+    #[case::conflicting(&["nix-collect-garbage", "-d", "--delete-older-than", "30d"], None)]
+    #[case::not_linked(&["janitor", "-d"], None)]
+    fn options(#[case] args: &[&str], #[case] expected: Option<CollectGarbage>) {
+        assert_eq!(parse(args), expected);
+    }
+
+    #[rstest]
+    #[case::gc_only(CollectGarbage::default(), None, false)]
+    #[case::delete_old(CollectGarbage { delete_old: true, ..CollectGarbage::default() }, Some(Duration::zero()), true)]
+    #[case::older_than(
+        CollectGarbage { delete_older_than: Some(Duration::days(30)), ..CollectGarbage::default() },
+        Some(Duration::days(30)),
+        true
+    )]
+    fn apply(
+        #[case] options: CollectGarbage,
+        #[case] keep: Option<Duration>,
+        #[case] deletes: bool,
+    ) {
+        let settings = options.apply(Settings::default());
+
+        assert_eq!(settings.overrides.keep, keep);
+        assert!(settings.options.gc);
+        assert_eq!(options.deletes_generations(), deletes);
+    }
+}
@@ -0,0 +1,965 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fmt, fs,
+    num::{NonZeroU64, NonZeroUsize},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::Duration;
+use eyre::{bail, Context, Result};
+use janitor::{
+    duration::{days, parse_duration},
+    schedule::Schedule,
+    size::{parse_size, SizeEstimation},
+    Exceptions, GenerationOrder, Profile, ProfileKind, RetentionOverrides, Thinning,
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::gc_lock;
+#[cfg(feature = "tokio")]
+use crate::runtime::Flavor;
+
+const SYSTEM_CONFIG: &str = "/etc/nix-janitor/config.toml";
+
+/// The configuration embedded at build time, see `build.rs`.
+#[cfg(feature = "embedded-config")]
+const EMBEDDED_CONFIG: &str = include_str!(concat!(env!("OUT_DIR"), "/embedded-config.toml"));
+
+/// The most generations a single range of `never_delete` or `always_delete`
+/// may span.
+const MAX_RANGE: u32 = 100_000;
+
+/// Settings read from the janitor configuration file.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Whether the system profile should be cleaned up.
+    pub system: Option<bool>,
+
+    /// Patterns of the profiles to clean up instead of the default ones, e.g.
+    /// `["/nix/var/nix/profiles/per-user/*/profile"]`.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    /// Patterns of the profiles never to clean up, e.g.
+    /// `["/home/*/.local/state/nix/profiles/home-manager*"]`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Whether to clean up the profiles of all users instead of only the
+    /// invoking one, unless `profiles` are given, see `--all-users`.
+    pub all_users: Option<bool>,
+
+    /// Whether to run the garbage collector after deleting generations.
+    pub gc: Option<bool>,
+
+    /// Whether to skip the garbage collection if the system collects garbage
+    /// on its own.
+    pub defer_to_system_gc: Option<bool>,
+
+    /// Append every store path deleted by the garbage collector to this
+    /// file.
+    pub gc_log: Option<PathBuf>,
+
+    /// Only log every this many store paths deleted by the garbage
+    /// collector.
+    pub gc_log_every: Option<NonZeroU64>,
+
+    /// Append every cleanup to this SQLite database, see `--report-db`.
+    #[cfg(feature = "report-db")]
+    pub report_db: Option<PathBuf>,
+
+    /// The `configurationLimit` of the bootloader, compared with the
+    /// retention of the system profile instead of counting the boot entries.
+    pub boot_limit: Option<usize>,
+
+    /// Whether to keep at least as many system generations as the
+    /// bootloader lists.
+    pub align_boot_limit: Option<bool>,
+
+    /// Whether to delete generations from all profiles or from none, see
+    /// `--atomic`.
+    pub atomic: Option<bool>,
+
+    /// Whether to abort the run when cleaning up a profile fails, see
+    /// `--fail-fast`.
+    pub fail_fast: Option<bool>,
+
+    /// Skip the remaining steps of a run taking longer than this, e.g.
+    /// `"30m"`, see `--max-runtime`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_runtime: Option<Duration>,
+
+    /// How many profiles to process at once, see `--jobs`.
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Whether to explain why nothing has been deleted from a profile for
+    /// every such profile, see `--explain-skip`.
+    pub explain_skip: Option<bool>,
+
+    /// Whether to fail on conditions that are otherwise only warned about,
+    /// see `--strict`.
+    pub strict: Option<bool>,
+
+    /// Whether to assume "yes" for all confirmations, see `--yes`.
+    pub assume_yes: Option<bool>,
+
+    /// Whether to keep generations that look like rollback targets.
+    pub protect_rollbacks: Option<bool>,
+
+    /// Keep generations that have been active within this duration, e.g.
+    /// `"36h"`. Takes precedence over `keep_days`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub keep: Option<Duration>,
+
+    /// Keep generations that have been active within this many days.
+    #[serde(default, deserialize_with = "deserialize_days")]
+    pub keep_days: Option<i64>,
+
+    /// Keep at least this many of the most recent generations.
+    pub keep_at_least: Option<usize>,
+
+    /// Keep at most this many of the most recent generations, even if they
+    /// have been active within `keep`.
+    pub keep_at_most: Option<usize>,
+
+    /// Which generations are the most recent ones, by `"id"` or by
+    /// `"date"`.
+    pub order_by: Option<GenerationOrder>,
+
+    /// Keep the last generation of each of this many most recent days with
+    /// generations. Together with `keep_weekly` and `keep_monthly` this
+    /// keeps a sparse history beyond the other retention settings.
+    pub keep_daily: Option<usize>,
+
+    /// Keep the last generation of each of this many most recent weeks.
+    pub keep_weekly: Option<usize>,
+
+    /// Keep the last generation of each of this many most recent months.
+    pub keep_monthly: Option<usize>,
+
+    /// Retention settings for the system profiles of single nixos-containers,
+    /// by container name, e.g. `[containers.web]`.
+    #[serde(default)]
+    pub containers: BTreeMap<String, RetentionConfig>,
+
+    /// Retention settings for the profiles of a kind or a single profile,
+    /// e.g. `[overrides.home-manager]` or
+    /// `[overrides."/home/alice/.local/state/nix/profiles/profile"]`.
+    #[serde(default)]
+    pub overrides: BTreeMap<ProfileSelector, RetentionConfig>,
+
+    /// How the async runtime schedules its tasks, `"multi-thread"` or
+    /// `"current-thread"`.
+    #[cfg(feature = "tokio")]
+    pub runtime: Option<Flavor>,
+
+    /// The number of worker threads of a multi-thread runtime.
+    #[cfg(feature = "tokio")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// When `janitor serve` cleans up on its own, e.g.
+    /// `"Sat..Sun 03:00..05:00"`.
+    #[serde(default, deserialize_with = "deserialize_schedule")]
+    pub schedule: Option<Schedule>,
+
+    /// Start scheduled cleanups up to this long after their window opens,
+    /// instead of anywhere within the window.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub jitter: Option<Duration>,
+
+    /// Forget cached listings of profiles that have not been cleaned up
+    /// within this duration, e.g. `"30d"`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub keep_cache: Option<Duration>,
+
+    /// How the sizes of generations are determined, `"exact"` from their
+    /// closures or `"fast"` with `du` on their store paths alone.
+    pub size_estimation: Option<SizeEstimation>,
+
+    /// How many sizes of closures are cached at most, the ones used least
+    /// recently are forgotten first.
+    pub size_cache_limit: Option<usize>,
+
+    /// Tighten the retention of the profiles of each user whose retained
+    /// generations take up more than this, e.g. `"20GiB"`, see
+    /// `--per-user-budget`.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub per_user_budget: Option<u64>,
+
+    /// Never tighten the retention of a profile below this many of its most
+    /// recent generations.
+    pub per_user_floor: Option<usize>,
+
+    /// Skip the garbage collection while running on a battery charged below
+    /// this many percent.
+    pub min_battery: Option<u8>,
+
+    /// Skip the garbage collection unless this shell command succeeds, e.g.
+    /// to check for a metered connection.
+    pub power_hook: Option<String>,
+
+    /// The directory on shared storage limiting how many machines collect
+    /// garbage at once, see `--gc-lock`.
+    #[serde(default, deserialize_with = "deserialize_lock_location")]
+    pub gc_lock: Option<PathBuf>,
+
+    /// How many machines may collect garbage at once with `gc_lock`.
+    pub gc_lock_slots: Option<NonZeroUsize>,
+}
+
+/// Retention settings for some profiles, taking precedence over the general
+/// ones.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Keep generations that have been active within this duration.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub keep: Option<Duration>,
+
+    /// Keep generations that have been active within this many days.
+    #[serde(default, deserialize_with = "deserialize_days")]
+    pub keep_days: Option<i64>,
+
+    /// Keep at least this many of the most recent generations.
+    pub keep_at_least: Option<usize>,
+
+    /// Keep at most this many of the most recent generations, even if they
+    /// have been active within `keep`.
+    pub keep_at_most: Option<usize>,
+
+    /// Which generations are the most recent ones, by `"id"` or by
+    /// `"date"`.
+    pub order_by: Option<GenerationOrder>,
+
+    /// Keep the last generation of each of this many most recent days with
+    /// generations. Together with `keep_weekly` and `keep_monthly` this
+    /// keeps a sparse history beyond the other retention settings.
+    pub keep_daily: Option<usize>,
+
+    /// Keep the last generation of each of this many most recent weeks.
+    pub keep_weekly: Option<usize>,
+
+    /// Keep the last generation of each of this many most recent months.
+    pub keep_monthly: Option<usize>,
+
+    /// The ids of generations never to delete, e.g. `[661, 670]`, or ranges
+    /// of them like `"600-640"`.
+    #[serde(default, deserialize_with = "deserialize_ids")]
+    pub never_delete: BTreeSet<u32>,
+
+    /// The ids of generations to delete even if the retention keeps them,
+    /// given like `never_delete`. Generations kept for their own safety,
+    /// like the current one, are never deleted.
+    #[serde(default, deserialize_with = "deserialize_ids")]
+    pub always_delete: BTreeSet<u32>,
+}
+
+/// Selects the profiles per-profile settings apply to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ProfileSelector {
+    /// All profiles of a kind, given by its name like `system`.
+    Kind(ProfileKind),
+
+    /// A single profile, given by its absolute path.
+    Path(PathBuf),
+}
+
+impl ProfileSelector {
+    /// Whether the settings for `self` apply to the `profile`.
+    pub fn matches(&self, profile: &Profile) -> bool {
+        match self {
+            Self::Kind(kind) => profile.kind() == *kind,
+            Self::Path(path) => profile.as_ref() == path.as_path(),
+        }
+    }
+}
+
+impl fmt::Display for ProfileSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kind(kind) => write!(f, "{kind}"),
+            Self::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ProfileSelector {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.starts_with('/') {
+            true => Ok(Self::Path(PathBuf::from(s))),
+            false => Ok(Self::Kind(s.parse()?)),
+        }
+    }
+}
+
+impl TryFrom<String> for ProfileSelector {
+    type Error = eyre::Report;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl RetentionConfig {
+    /// The retention settings given for the profiles.
+    pub fn retention(&self) -> RetentionOverrides {
+        RetentionOverrides {
+            keep: self.keep.or(self.keep_days.map(Duration::days)),
+            keep_since: None,
+            keep_at_least: self.keep_at_least,
+            keep_at_most: self.keep_at_most,
+            order_by: self.order_by,
+            thinning: thinning(self.keep_daily, self.keep_weekly, self.keep_monthly),
+        }
+    }
+
+    /// The generations of the profiles kept or deleted no matter what their
+    /// retention says.
+    pub fn exceptions(&self) -> Exceptions {
+        Exceptions {
+            pinned: self.never_delete.clone(),
+            always_delete: self.always_delete.clone(),
+        }
+    }
+
+    fn has_exceptions(&self) -> bool {
+        !self.never_delete.is_empty() || !self.always_delete.is_empty()
+    }
+}
+
+impl Config {
+    /// Loads the configuration.
+    ///
+    /// If `path` is given, that file has to exist and is the only one read.
+    /// Otherwise `/etc/nix-janitor/config.toml` is read, then the user
+    /// config (`$XDG_CONFIG_HOME/nix-janitor/config.toml`), with the
+    /// settings of the user taking precedence. Files that do not exist are
+    /// skipped, if none exists, the [fallback](Config::fallback) is
+    /// returned.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        if let Some(path) = path {
+            return Self::read(path);
+        }
+
+        let paths: Vec<_> = default_paths()
+            .into_iter()
+            .rev()
+            .filter(|p| p.exists())
+            .collect();
+        if paths.is_empty() {
+            return Self::fallback();
+        }
+
+        paths.iter().try_fold(Self::default(), |config, path| {
+            Ok(config.layer(Self::read(path)?))
+        })
+    }
+
+    /// The configuration used if no configuration file exists: the one
+    /// embedded at build time with the `embedded-config` feature, from the
+    /// file `JANITOR_EMBEDDED_CONFIG` points at, or the default one.
+    pub fn fallback() -> Result<Self> {
+        #[cfg(feature = "embedded-config")]
+        {
+            tracing::debug!("using the embedded config");
+            Self::parse(EMBEDDED_CONFIG).wrap_err("Invalid embedded config")
+        }
+
+        #[cfg(not(feature = "embedded-config"))]
+        Ok(Self::default())
+    }
+
+    /// The configuration with the settings of `over` taking precedence over
+    /// the ones of `self`.
+    ///
+    /// Lists of profiles and of excluded profiles replace each other, the
+    /// settings of containers and single profiles are merged, the ones of
+    /// `over` replacing the ones of `self` for the same container or
+    /// profile.
+    pub fn layer(mut self, over: Self) -> Self {
+        self.containers.extend(over.containers);
+        self.overrides.extend(over.overrides);
+
+        // The periods of thinning are a single setting, the ones of `over`
+        // win if it gives any of them.
+        let thinning = match (over.keep_daily, over.keep_weekly, over.keep_monthly) {
+            (None, None, None) => (self.keep_daily, self.keep_weekly, self.keep_monthly),
+            over => over,
+        };
+
+        Self {
+            system: over.system.or(self.system),
+            profiles: match over.profiles.is_empty() {
+                true => self.profiles,
+                false => over.profiles,
+            },
+            exclude: match over.exclude.is_empty() {
+                true => self.exclude,
+                false => over.exclude,
+            },
+            all_users: over.all_users.or(self.all_users),
+            gc: over.gc.or(self.gc),
+            defer_to_system_gc: over.defer_to_system_gc.or(self.defer_to_system_gc),
+            gc_log: over.gc_log.or(self.gc_log),
+            gc_log_every: over.gc_log_every.or(self.gc_log_every),
+            #[cfg(feature = "report-db")]
+            report_db: over.report_db.or(self.report_db),
+            boot_limit: over.boot_limit.or(self.boot_limit),
+            align_boot_limit: over.align_boot_limit.or(self.align_boot_limit),
+            atomic: over.atomic.or(self.atomic),
+            fail_fast: over.fail_fast.or(self.fail_fast),
+            max_runtime: over.max_runtime.or(self.max_runtime),
+            jobs: over.jobs.or(self.jobs),
+            explain_skip: over.explain_skip.or(self.explain_skip),
+            strict: over.strict.or(self.strict),
+            assume_yes: over.assume_yes.or(self.assume_yes),
+            protect_rollbacks: over.protect_rollbacks.or(self.protect_rollbacks),
+            // A duration and a number of days are the same setting, the
+            // one of `over` wins in either form.
+            keep: match over.keep_days {
+                Some(_) => over.keep,
+                None => over.keep.or(self.keep),
+            },
+            keep_days: over.keep_days.or(self.keep_days),
+            keep_at_least: over.keep_at_least.or(self.keep_at_least),
+            keep_at_most: over.keep_at_most.or(self.keep_at_most),
+            order_by: over.order_by.or(self.order_by),
+            keep_daily: thinning.0,
+            keep_weekly: thinning.1,
+            keep_monthly: thinning.2,
+            containers: self.containers,
+            overrides: self.overrides,
+            #[cfg(feature = "tokio")]
+            runtime: over.runtime.or(self.runtime),
+            #[cfg(feature = "tokio")]
+            worker_threads: over.worker_threads.or(self.worker_threads),
+            schedule: over.schedule.or(self.schedule),
+            jitter: over.jitter.or(self.jitter),
+            keep_cache: over.keep_cache.or(self.keep_cache),
+            size_estimation: over.size_estimation.or(self.size_estimation),
+            size_cache_limit: over.size_cache_limit.or(self.size_cache_limit),
+            per_user_budget: over.per_user_budget.or(self.per_user_budget),
+            per_user_floor: over.per_user_floor.or(self.per_user_floor),
+            min_battery: over.min_battery.or(self.min_battery),
+            power_hook: over.power_hook.or(self.power_hook),
+            gc_lock: over.gc_lock.or(self.gc_lock),
+            gc_lock_slots: over.gc_lock_slots.or(self.gc_lock_slots),
+        }
+    }
+
+    /// The file of the configuration taking precedence in [Config::load], or
+    /// if none of the default locations exists yet, the user config.
+    pub fn location(path: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = path {
+            return Some(path.to_path_buf());
+        }
+
+        let paths = default_paths();
+        paths.iter().find(|p| p.exists()).or(paths.first()).cloned()
+    }
+
+    /// The retention settings given in the configuration file.
+    pub fn retention(&self) -> RetentionOverrides {
+        RetentionOverrides {
+            keep: self.keep.or(self.keep_days.map(Duration::days)),
+            keep_since: None,
+            keep_at_least: self.keep_at_least,
+            keep_at_most: self.keep_at_most,
+            order_by: self.order_by,
+            thinning: thinning(self.keep_daily, self.keep_weekly, self.keep_monthly),
+        }
+    }
+
+    /// The retention settings given for single containers, by name.
+    pub fn container_retention(&self) -> BTreeMap<String, RetentionOverrides> {
+        self.containers
+            .iter()
+            .map(|(name, container)| (name.clone(), container.retention()))
+            .collect()
+    }
+
+    /// The retention settings given for the profiles of a kind or single
+    /// profiles.
+    pub fn profile_retention(&self) -> BTreeMap<ProfileSelector, RetentionOverrides> {
+        self.overrides
+            .iter()
+            .map(|(selector, overrides)| (selector.clone(), overrides.retention()))
+            .collect()
+    }
+
+    /// The generations kept or deleted no matter what the retention says,
+    /// given for single containers, by name.
+    pub fn container_exceptions(&self) -> BTreeMap<String, Exceptions> {
+        self.containers
+            .iter()
+            .filter(|(_, container)| container.has_exceptions())
+            .map(|(name, container)| (name.clone(), container.exceptions()))
+            .collect()
+    }
+
+    /// The generations kept or deleted no matter what the retention says,
+    /// given for the profiles of a kind or single profiles.
+    pub fn profile_exceptions(&self) -> BTreeMap<ProfileSelector, Exceptions> {
+        self.overrides
+            .iter()
+            .filter(|(_, overrides)| overrides.has_exceptions())
+            .map(|(selector, overrides)| (selector.clone(), overrides.exceptions()))
+            .collect()
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        tracing::debug!(?path, "reading config");
+
+        let content = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config {}", path.display()))?;
+
+        Self::parse(&content).wrap_err_with(|| format!("Invalid config {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = String::deserialize(deserializer)?;
+
+    parse_duration(&input)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_days<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = i64::deserialize(deserializer)?;
+
+    days(input)
+        .map(|_| Some(input))
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = String::deserialize(deserializer)?;
+
+    parse_size(&input)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_lock_location<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = String::deserialize(deserializer)?;
+
+    gc_lock::parse_location(&input)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_schedule<'de, D>(deserializer: D) -> Result<Option<Schedule>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = String::deserialize(deserializer)?;
+
+    input.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// The thinning given by the number of days, weeks and months to keep the
+/// last generation of, `None` if none of them is given. Periods not given
+/// keep nothing.
+pub fn thinning(
+    keep_daily: Option<usize>,
+    keep_weekly: Option<usize>,
+    keep_monthly: Option<usize>,
+) -> Option<Thinning> {
+    (keep_daily.is_some() || keep_weekly.is_some() || keep_monthly.is_some()).then(|| Thinning {
+        keep_daily: keep_daily.unwrap_or(0),
+        keep_weekly: keep_weekly.unwrap_or(0),
+        keep_monthly: keep_monthly.unwrap_or(0),
+    })
+}
+
+/// A generation id or a range of them, `"FIRST-LAST"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Ids {
+    Id(u32),
+    Range(String),
+}
+
+fn deserialize_ids<'de, D>(deserializer: D) -> Result<BTreeSet<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut ids = BTreeSet::new();
+    for entry in Vec::<Ids>::deserialize(deserializer)? {
+        match entry {
+            Ids::Id(id) => {
+                ids.insert(id);
+            }
+            Ids::Range(range) => {
+                ids.extend(parse_id_range(&range).map_err(serde::de::Error::custom)?);
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Parses the range of generation ids `FIRST-LAST`, both included.
+fn parse_id_range(input: &str) -> Result<RangeInclusive<u32>> {
+    let Some((first, last)) = input.split_once('-') else {
+        bail!("invalid generation range {input:?}, expected FIRST-LAST");
+    };
+    let parse = |id: &str| {
+        id.trim()
+            .parse::<u32>()
+            .wrap_err_with(|| format!("invalid generation range {input:?}"))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+
+    if first > last {
+        bail!("generation range {input:?} is empty");
+    }
+    if last - first >= MAX_RANGE {
+        bail!("generation range {input:?} spans more than {MAX_RANGE} generations");
+    }
+
+    Ok(first..=last)
+}
+
+/// Shows the generation `ids` with consecutive ones as ranges, like they are
+/// given in the configuration.
+pub fn format_ids(ids: &BTreeSet<u32>) -> String {
+    let mut ranges: Vec<RangeInclusive<u32>> = Vec::new();
+    for &id in ids {
+        match ranges.last_mut() {
+            Some(range) if range.end().checked_add(1) == Some(id) => {
+                *range = *range.start()..=id;
+            }
+            _ => ranges.push(id..=id),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|range| match range.start() == range.end() {
+            true => range.start().to_string(),
+            false => format!("{}-{}", range.start(), range.end()),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn default_paths() -> Vec<PathBuf> {
+    let user_config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_config
+        .map(|dir| dir.join("nix-janitor").join("config.toml"))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(SYSTEM_CONFIG)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::empty("", Config::default())]
+    #[case::system("system = true", Config { system: Some(true), ..Default::default() })]
+    #[case::no_system("system = false", Config { system: Some(false), ..Default::default() })]
+    #[case::gc("gc = true", Config { gc: Some(true), ..Default::default() })]
+    #[case::gc_log(
+        "gc_log = \"/var/log/janitor-gc.log\"\ngc_log_every = 100",
+        Config {
+            gc_log: Some("/var/log/janitor-gc.log".into()),
+            gc_log_every: NonZeroU64::new(100),
+            ..Default::default()
+        }
+    )]
+    #[case::profiles(
+        "profiles = [\"/nix/var/nix/profiles/per-user/*/profile\"]",
+        Config {
+            profiles: vec!["/nix/var/nix/profiles/per-user/*/profile".to_string()],
+            ..Default::default()
+        }
+    )]
+    #[case::exclude(
+        "exclude = [\"/home/*/.local/state/nix/profiles/home-manager*\"]",
+        Config {
+            exclude: vec!["/home/*/.local/state/nix/profiles/home-manager*".to_string()],
+            ..Default::default()
+        }
+    )]
+    #[case::all_users(
+        "all_users = true",
+        Config { all_users: Some(true), ..Default::default() }
+    )]
+    #[case::order_by(
+        "order_by = \"date\"",
+        Config { order_by: Some(GenerationOrder::Date), ..Default::default() }
+    )]
+    #[case::explain_skip(
+        "explain_skip = true",
+        Config { explain_skip: Some(true), ..Default::default() }
+    )]
+    #[case::protect_rollbacks(
+        "protect_rollbacks = true",
+        Config { protect_rollbacks: Some(true), ..Default::default() }
+    )]
+    #[case::retention(
+        "keep_days = 3\nkeep_at_least = 2",
+        Config { keep_days: Some(3), keep_at_least: Some(2), ..Default::default() }
+    )]
+    #[case::keep(
+        "keep = \"2d12h\"",
+        Config { keep: Some(Duration::hours(60)), ..Default::default() }
+    )]
+    #[case::schedule(
+        "schedule = \"Sat..Sun 03:00..05:00\"\njitter = \"30m\"",
+        Config {
+            schedule: Some("Sat..Sun 03:00..05:00".parse().unwrap()),
+            jitter: Some(Duration::minutes(30)),
+            ..Default::default()
+        }
+    )]
+    #[case::max_runtime(
+        "max_runtime = \"30m\"",
+        Config { max_runtime: Some(Duration::minutes(30)), ..Default::default() }
+    )]
+    #[case::jobs("jobs = 2", Config { jobs: NonZeroUsize::new(2), ..Default::default() })]
+    #[case::keep_cache(
+        "keep_cache = \"2w\"",
+        Config { keep_cache: Some(Duration::weeks(2)), ..Default::default() }
+    )]
+    #[case::size_estimation(
+        "size_estimation = \"fast\"",
+        Config { size_estimation: Some(SizeEstimation::Fast), ..Default::default() }
+    )]
+    #[case::size_cache_limit(
+        "size_cache_limit = 500",
+        Config { size_cache_limit: Some(500), ..Default::default() }
+    )]
+    #[case::per_user_budget(
+        "per_user_budget = \"20GiB\"\nper_user_floor = 2",
+        Config { per_user_budget: Some(20 << 30), per_user_floor: Some(2), ..Default::default() }
+    )]
+    #[case::power(
+        "min_battery = 30\npower_hook = \"nmcli -t -f GENERAL.METERED dev show | grep -q no\"\n",
+        Config {
+            min_battery: Some(30),
+            power_hook: Some("nmcli -t -f GENERAL.METERED dev show | grep -q no".to_string()),
+            ..Default::default()
+        }
+    )]
+    #[case::containers(
+        "[containers.web]\nkeep = \"30d\"\nkeep_at_least = 3",
+        Config {
+            containers: BTreeMap::from([(
+                "web".to_string(),
+                RetentionConfig {
+                    keep: Some(Duration::days(30)),
+                    keep_at_least: Some(3),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    )]
+    #[case::overrides(
+        "[overrides.home-manager]\nkeep_at_least = 5\n[overrides.\"/home/alice/.nix-profile\"]\nkeep_days = 1",
+        Config {
+            overrides: BTreeMap::from([
+                (
+                    ProfileSelector::Kind(ProfileKind::HomeManager),
+                    RetentionConfig { keep_at_least: Some(5), ..Default::default() },
+                ),
+                (
+                    ProfileSelector::Path("/home/alice/.nix-profile".into()),
+                    RetentionConfig { keep_days: Some(1), ..Default::default() },
+                ),
+            ]),
+            ..Default::default()
+        }
+    )]
+    #[case::exceptions(
+        "[overrides.\"/nix/var/nix/profiles/system\"]\nnever_delete = [661, 670]\nalways_delete = [\"600-602\", 610]",
+        Config {
+            overrides: BTreeMap::from([(
+                ProfileSelector::Path("/nix/var/nix/profiles/system".into()),
+                RetentionConfig {
+                    never_delete: BTreeSet::from([661, 670]),
+                    always_delete: BTreeSet::from([600, 601, 602, 610]),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    )]
+    fn parse(#[case] input: &str, #[case] expected: Config) -> Result<()> {
+        assert_eq!(Config::parse(input)?, expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::unknown_key("foo = 1")]
+    #[case::wrong_type("system = 1")]
+    #[case::invalid_duration("keep = \"12\"")]
+    #[case::invalid_schedule("schedule = \"Someday\"")]
+    #[case::unknown_flavor("runtime = \"single\"")]
+    #[case::zero_workers("worker_threads = 0")]
+    #[case::unknown_container_key("[containers.web]\nsystem = true")]
+    #[case::battery_out_of_range("min_battery = 300")]
+    #[case::unknown_profile_kind("[overrides.nixos]\nkeep_days = 1")]
+    #[case::keep_overflow("keep = \"100000000d\"")]
+    #[case::keep_days_overflow("keep_days = 999999999999999")]
+    #[case::override_keep_days_overflow("[overrides.user]\nkeep_days = 999999999999999")]
+    #[case::invalid_budget("per_user_budget = \"lots\"")]
+    #[case::unknown_order("order_by = \"size\"")]
+    #[case::negative_id("[overrides.system]\nnever_delete = [-1]")]
+    #[case::reversed_range("[overrides.system]\nalways_delete = [\"640-600\"]")]
+    #[case::not_a_range("[overrides.system]\nalways_delete = [\"600\"]")]
+    #[case::huge_range("[overrides.system]\nalways_delete = [\"1-4000000000\"]")]
+    fn parse_errors(#[case] input: &str) {
+        assert!(Config::parse(input).is_err());
+    }
+
+    #[rstest]
+    #[case::user_wins(
+        "gc = false\nkeep_days = 3",
+        "gc = true\nkeep_days = 5",
+        Some(true),
+        Some(Duration::days(5))
+    )]
+    #[case::system_remains("gc = true\nkeep = \"36h\"", "", Some(true), Some(Duration::hours(36)))]
+    #[case::days_replace_duration("keep = \"36h\"", "keep_days = 2", None, Some(Duration::days(2)))]
+    fn layers(
+        #[case] system: &str,
+        #[case] user: &str,
+        #[case] gc: Option<bool>,
+        #[case] keep: Option<Duration>,
+    ) -> Result<()> {
+        let config = Config::parse(system)?.layer(Config::parse(user)?);
+
+        assert_eq!(config.gc, gc);
+        assert_eq!(config.retention().keep, keep);
+
+        Ok(())
+    }
+
+    /// Builders of appliances embedding a configuration learn about it being
+    /// invalid from the tests instead of from every run.
+    #[cfg(feature = "embedded-config")]
+    #[test]
+    fn embedded_config_is_valid() {
+        Config::fallback().unwrap();
+    }
+
+    #[test]
+    fn layers_overrides() -> Result<()> {
+        let system =
+            Config::parse("[containers.web]\nkeep_days = 1\n[overrides.system]\nkeep_days = 2")?;
+        let user =
+            Config::parse("[containers.web]\nkeep_days = 3\n[overrides.user]\nkeep_days = 4")?;
+
+        let config = system.layer(user);
+
+        assert_eq!(
+            config.container_retention()["web"].keep,
+            Some(Duration::days(3))
+        );
+        assert_eq!(config.profile_retention().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn layers_thinning_as_a_whole() -> Result<()> {
+        let system = Config::parse(
+            "keep_daily = 7
+keep_monthly = 12",
+        )?;
+        let user = Config::parse("keep_weekly = 4")?;
+
+        assert_eq!(
+            system.layer(user).retention().thinning,
+            Some(Thinning {
+                keep_daily: 0,
+                keep_weekly: 4,
+                keep_monthly: 0,
+            })
+        );
+        assert_eq!(
+            Config::parse("keep_daily = 7")?
+                .layer(Config::default())
+                .retention()
+                .thinning
+                .map(|thinning| thinning.keep_daily),
+            Some(7)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_overrides_with_exceptions() -> Result<()> {
+        let config = Config::parse(
+            "[containers.web]\nnever_delete = [3]\n[containers.db]\nkeep_days = 1\n\
+             [overrides.user]\nalways_delete = [\"1-2\"]",
+        )?;
+
+        assert_eq!(
+            config.container_exceptions().keys().collect::<Vec<_>>(),
+            ["web"]
+        );
+        assert_eq!(
+            config.profile_exceptions()[&ProfileSelector::Kind(ProfileKind::User)],
+            Exceptions {
+                pinned: BTreeSet::new(),
+                always_delete: BTreeSet::from([1, 2]),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::empty(&[], "")]
+    #[case::single(&[661], "661")]
+    #[case::ranges(&[600, 601, 602, 610, 661, 662], "600-602, 610, 661-662")]
+    fn formats_ids(#[case] ids: &[u32], #[case] expected: &str) {
+        assert_eq!(format_ids(&ids.iter().copied().collect()), expected);
+    }
+
+    #[test]
+    fn keep_takes_precedence() -> Result<()> {
+        let config = Config::parse("keep = \"36h\"\nkeep_days = 3")?;
+
+        assert_eq!(config.retention().keep, Some(Duration::hours(36)));
+
+        Ok(())
+    }
+}
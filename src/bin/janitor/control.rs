@@ -0,0 +1,280 @@
+//! The control socket of `janitor serve` and its client `janitor ctl`.
+//!
+//! The protocol is line based: the client sends the name of a [Request] on a
+//! single line, the daemon answers with `ok` or `error` on the first line,
+//! followed by a human readable message, and closes the connection.
+
+use std::{
+    env, fmt, fs,
+    io::{Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use clap::ValueEnum;
+use eyre::{bail, eyre, Context, Result};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{redact, serve::Daemon};
+
+const SYSTEM_SOCKET: &str = "/run/nix-janitor/control.sock";
+
+/// The commands understood by the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Request {
+    /// Start a cleanup in the background.
+    Run,
+
+    /// Report whether a cleanup is running and how the last one went.
+    Status,
+
+    /// Read the configuration again, applying it from the next cleanup on.
+    Reload,
+
+    /// Refuse to start any cleanup until resumed.
+    Pause,
+
+    /// Allow cleanups again after a pause.
+    Resume,
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no request variant is skipped");
+
+        f.write_str(value.get_name())
+    }
+}
+
+impl FromStr for Request {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        <Self as ValueEnum>::from_str(s, false).map_err(|_| eyre!("unknown request: {s}"))
+    }
+}
+
+/// The default location of the control socket.
+///
+/// When running as root, this is `/run/nix-janitor/control.sock`, otherwise
+/// `$XDG_RUNTIME_DIR/nix-janitor/control.sock`.
+pub fn default_socket_path() -> Option<PathBuf> {
+    if is_root::is_root() {
+        return Some(PathBuf::from(SYSTEM_SOCKET));
+    }
+
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .map(|dir| dir.join("nix-janitor").join("control.sock"))
+}
+
+/// Sends `request` to the daemon listening on `socket` and prints its
+/// answer.
+pub fn ctl(socket: Option<&Path>, request: Request) -> Result<()> {
+    let Some(socket) = socket else {
+        bail!("no location for the control socket known, use --socket");
+    };
+
+    let message = send(socket, request)?;
+    println!("{}", redact::text(&message));
+
+    Ok(())
+}
+
+/// Sends `request` to the daemon listening on `socket` and returns its
+/// answer.
+pub fn send(socket: &Path, request: Request) -> Result<String> {
+    let mut stream = net::UnixStream::connect(socket)
+        .wrap_err_with(|| format!("Failed to connect to {}", socket.display()))?;
+
+    writeln!(stream, "{request}")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    parse_response(&response)
+}
+
+/// Listens on `socket`, which is only accessible to the current user.
+///
+/// A socket left behind by a daemon that is not running anymore is
+/// replaced.
+pub fn bind(socket: &Path) -> Result<UnixListener> {
+    if socket.exists() {
+        if net::UnixStream::connect(socket).is_ok() {
+            bail!("another daemon is listening on {}", socket.display());
+        }
+
+        tracing::debug!(?socket, "removing stale socket");
+        fs::remove_file(socket)
+            .wrap_err_with(|| format!("Failed to remove {}", socket.display()))?;
+    }
+
+    if let Some(dir) = socket.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let listener = UnixListener::bind(socket)
+        .wrap_err_with(|| format!("Failed to listen on {}", socket.display()))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+/// Answers the requests of all clients connecting to `listener`.
+pub async fn accept(listener: UnixListener, daemon: Arc<Daemon>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = daemon.clone();
+                let spawned = daemon.spawn("control-request", async move {
+                    if let Err(error) = handle(stream, &handler).await {
+                        tracing::warn!(%error, "failed to answer control request");
+                    }
+                });
+                if let Err(error) = spawned {
+                    tracing::warn!(%error, "dropping control connection");
+                }
+            }
+            Err(error) => tracing::warn!(%error, "failed to accept control connection"),
+        }
+    }
+}
+
+async fn handle(stream: UnixStream, daemon: &Arc<Daemon>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+
+    let answer = match line.trim().parse() {
+        Ok(request) => {
+            tracing::info!(%request, "received control request");
+            respond(daemon, request).await
+        }
+        Err(error) => Err(error),
+    };
+
+    let response = match answer {
+        Ok(message) => format!("ok\n{message}"),
+        Err(error) => format!("error\n{error:#}"),
+    };
+
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await?;
+
+    Ok(())
+}
+
+async fn respond(daemon: &Arc<Daemon>, request: Request) -> Result<String> {
+    match request {
+        Request::Run => {
+            daemon.trigger()?;
+            Ok("cleanup started".to_string())
+        }
+        Request::Status => Ok(daemon.status().to_string()),
+        Request::Reload => {
+            daemon.reload()?;
+            Ok("configuration reloaded, applies from the next cleanup on".to_string())
+        }
+        Request::Pause => {
+            daemon.pause();
+            Ok("paused, no cleanup is started until resumed".to_string())
+        }
+        Request::Resume => {
+            daemon.resume();
+            Ok("resumed".to_string())
+        }
+    }
+}
+
+fn parse_response(response: &str) -> Result<String> {
+    let (status, message) = response.split_once('\n').unwrap_or((response, ""));
+    let message = message.trim_end().to_string();
+
+    match status {
+        "ok" => Ok(message),
+        "error" => Err(eyre!(message)),
+        _ => bail!("unexpected response from the daemon: {response:?}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    use crate::{serve::Status, Settings};
+
+    #[test]
+    fn request_roundtrips() -> Result<()> {
+        for request in Request::value_variants() {
+            assert_eq!(request.to_string().parse::<Request>()?, *request);
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::ok("ok\nresumed\n", Some("resumed"))]
+    #[case::ok_empty("ok\n", Some(""))]
+    #[case::error("error\nthe daemon is paused\n", None)]
+    #[case::garbage("what?", None)]
+    fn responses(#[case] response: &str, #[case] expected: Option<&str>) {
+        assert_eq!(parse_response(response).ok().as_deref(), expected);
+    }
+
+    #[tokio::test]
+    async fn roundtrip() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-control-{}", std::process::id()));
+        let socket = dir.join("control.sock");
+
+        let daemon = Arc::new(Daemon::new(
+            Settings::default(),
+            Box::new(|| Ok(Settings::default())),
+        ));
+        let listener = bind(&socket)?;
+        tokio::spawn(accept(listener, daemon.clone()));
+
+        let client = socket.clone();
+        let (paused, status, run) = tokio::task::spawn_blocking(move || {
+            (
+                send(&client, Request::Pause),
+                send(&client, Request::Status),
+                send(&client, Request::Run),
+            )
+        })
+        .await?;
+        fs::remove_dir_all(&dir)?;
+
+        assert!(paused.is_ok());
+        // the task answering the status request is running itself
+        assert!(status?.starts_with(&format!(
+            "{}\ntasks: control-request",
+            Status {
+                paused: true,
+                ..Status::default()
+            }
+        )));
+        assert_eq!(run.unwrap_err().to_string(), "the daemon is paused");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! The DBus interface of `janitor serve --dbus`.
+//!
+//! On the system bus, cleaning up requires the authorization of polkit for
+//! the action [CLEAN_ACTION]. The bus and polkit policies to install are in
+//! the `data` directory of the repository.
+
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use zbus::{
+    fdo,
+    message::Header,
+    zvariant::{Type, Value},
+    Connection,
+};
+
+use crate::serve::Daemon;
+
+/// The well-known name the service is available at.
+pub const NAME: &str = "io.github.nobbz.NixJanitor";
+
+/// The path of the object implementing the interface.
+pub const PATH: &str = "/io/github/nobbz/NixJanitor";
+
+/// The polkit action required to clean up.
+pub const CLEAN_ACTION: &str = "io.github.nobbz.nixjanitor.clean";
+
+/// Allow polkit to ask the user for authentication.
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// Connects to the bus and offers the interface of the `daemon`.
+///
+/// The system bus is used when running as root, otherwise the session bus.
+/// The service is available as long as the returned connection is kept.
+pub async fn serve(daemon: Arc<Daemon>) -> eyre::Result<Connection> {
+    let system_bus = is_root::is_root();
+    let interface = Interface { daemon, system_bus };
+
+    let builder = if system_bus {
+        zbus::connection::Builder::system()?
+    } else {
+        zbus::connection::Builder::session()?
+    };
+
+    let connection = builder
+        .name(NAME)?
+        .serve_at(PATH, interface)?
+        .build()
+        .await?;
+
+    tracing::info!(name = NAME, system_bus, "registered on DBus");
+
+    Ok(connection)
+}
+
+struct Interface {
+    daemon: Arc<Daemon>,
+    system_bus: bool,
+}
+
+#[zbus::interface(name = "io.github.nobbz.NixJanitor1")]
+impl Interface {
+    /// Cleans up all profiles and returns the number of profiles processed
+    /// and generations deleted.
+    async fn clean(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<(u32, u32)> {
+        if self.system_bus {
+            authorize(connection, &header, CLEAN_ACTION).await?;
+        }
+
+        let report = self.daemon.clean().await.map_err(failed)?;
+
+        Ok((
+            report.profiles().len() as u32,
+            report.deleted_count() as u32,
+        ))
+    }
+
+    /// Returns the generations that would be deleted from each profile.
+    async fn plan(&self) -> fdo::Result<Vec<(String, Vec<u32>)>> {
+        let planned = self.daemon.plan().await.map_err(failed)?;
+
+        Ok(planned
+            .iter()
+            .map(|job| {
+                (
+                    job.path().display().to_string(),
+                    job.state().to_delete.iter().map(|g| g.id).collect(),
+                )
+            })
+            .collect())
+    }
+
+    /// Returns whether a cleanup is running, when the last one has started
+    /// and finished (seconds since the epoch, 0 if never), how many
+    /// generations it deleted and why it failed (empty if it did not).
+    async fn status(&self) -> (bool, i64, i64, u32, String) {
+        let status = self.daemon.status();
+        let timestamp =
+            |date: Option<chrono::DateTime<chrono::Utc>>| date.map_or(0, |date| date.timestamp());
+
+        (
+            status.running,
+            timestamp(status.last_started),
+            timestamp(status.last_finished),
+            status.last_deleted as u32,
+            status.last_error.unwrap_or_default(),
+        )
+    }
+}
+
+fn failed(error: eyre::Report) -> fdo::Error {
+    fdo::Error::Failed(format!("{error:#}"))
+}
+
+/// The subject of an authorization check, see the polkit documentation.
+#[derive(Debug, Serialize, Type)]
+struct Subject<'a> {
+    kind: &'a str,
+    details: HashMap<&'a str, Value<'a>>,
+}
+
+#[derive(Debug, Deserialize, Type)]
+struct AuthorizationResult {
+    is_authorized: bool,
+    is_challenge: bool,
+    details: HashMap<String, String>,
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait Authority {
+    fn check_authorization(
+        &self,
+        subject: &Subject<'_>,
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<AuthorizationResult>;
+}
+
+/// Asks polkit whether the sender of the message with `header` may perform
+/// `action`.
+async fn authorize(connection: &Connection, header: &Header<'_>, action: &str) -> fdo::Result<()> {
+    let sender = header
+        .sender()
+        .ok_or_else(|| fdo::Error::AccessDenied("unknown sender".to_string()))?;
+
+    let subject = Subject {
+        kind: "system-bus-name",
+        details: HashMap::from([("name", Value::from(sender.as_str()))]),
+    };
+
+    let result = AuthorityProxy::new(connection)
+        .await?
+        .check_authorization(&subject, action, HashMap::new(), ALLOW_USER_INTERACTION, "")
+        .await?;
+
+    tracing::debug!(
+        %sender,
+        action,
+        authorized = result.is_authorized,
+        challenge = result.is_challenge,
+        details = ?result.details,
+        "checked authorization"
+    );
+
+    if result.is_authorized {
+        Ok(())
+    } else {
+        Err(fdo::Error::AccessDenied(format!(
+            "not authorized for {action}"
+        )))
+    }
+}
@@ -0,0 +1,189 @@
+//! `janitor doctor`: checks the profiles for inconsistencies, without
+//! modifying anything, and reports which integrations of the platform are
+//! active.
+
+use std::{fmt, path::PathBuf};
+
+use futures::executor::block_on;
+
+use janitor::{
+    platform::Platform, Backend, Blocking, GenerationSet, ListingConflict, Profile, StdExecutor,
+};
+
+/// The outcome of checking a single profile.
+#[derive(Debug)]
+pub struct ProfileCheck {
+    /// The path of the profile.
+    pub path: PathBuf,
+
+    /// The number of generations found by any backend.
+    pub generations: usize,
+
+    /// The backends the profile could not be listed with, and why.
+    pub failures: Vec<(Backend, String)>,
+
+    /// The generations the backends disagree on.
+    pub conflicts: Vec<ListingConflict>,
+
+    /// The backends that listed the profile, in the order of the listings
+    /// of the `conflicts`.
+    pub listed_by: Vec<Backend>,
+}
+
+impl ProfileCheck {
+    /// Whether nothing suspicious has been found.
+    pub fn healthy(&self) -> bool {
+        self.failures.is_empty() && self.conflicts.is_empty()
+    }
+}
+
+/// The results of `janitor doctor`.
+#[derive(Debug)]
+pub struct DoctorReport {
+    /// The platform the janitor runs on.
+    pub platform: Platform,
+
+    /// The checks of all profiles, in the order of their paths.
+    pub profiles: Vec<ProfileCheck>,
+}
+
+impl DoctorReport {
+    /// The number of profiles with findings.
+    pub fn unhealthy(&self) -> usize {
+        self.profiles.iter().filter(|p| !p.healthy()).count()
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.platform)?;
+
+        if self.profiles.is_empty() {
+            return writeln!(f, "no profiles found");
+        }
+
+        for check in &self.profiles {
+            let path = check.path.display();
+            if check.healthy() {
+                writeln!(f, "{path}: ok, {} generations", check.generations)?;
+                continue;
+            }
+
+            writeln!(f, "{path}:")?;
+            for (backend, error) in &check.failures {
+                writeln!(f, "  listing with {backend} failed: {error}")?;
+            }
+            if !check.conflicts.is_empty() {
+                let backends: Vec<_> = check.listed_by.iter().map(Backend::to_string).collect();
+                writeln!(f, "  listed differently by {}:", backends.join(", "))?;
+            }
+            for conflict in &check.conflicts {
+                writeln!(f, "    {conflict}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the `profiles` with every [Backend] and compares the listings, see
+/// [GenerationSet::merge_listings], on the `platform`.
+pub fn run(platform: Platform, profiles: &[Profile]) -> DoctorReport {
+    let executor = Blocking(StdExecutor);
+
+    let mut checks: Vec<_> = profiles
+        .iter()
+        .map(|profile| {
+            let mut failures = Vec::new();
+            let mut listings = Vec::new();
+            let mut listed_by = Vec::new();
+            for backend in Backend::ALL {
+                match block_on(backend.list_generations(&executor, profile)) {
+                    Ok(listing) => {
+                        listings.push(listing);
+                        listed_by.push(backend);
+                    }
+                    Err(error) => failures.push((backend, format!("{error:#}"))),
+                }
+            }
+
+            let (merged, conflicts) = GenerationSet::merge_listings(&listings);
+            ProfileCheck {
+                path: profile.as_ref().to_path_buf(),
+                generations: merged.len(),
+                failures,
+                conflicts,
+                listed_by,
+            }
+        })
+        .collect();
+    checks.sort_by(|a, b| a.path.cmp(&b.path));
+
+    DoctorReport {
+        platform,
+        profiles: checks,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+    use janitor::{platform::Os, Generation};
+
+    #[test]
+    fn renders_findings() {
+        let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+        let (_, conflicts) = GenerationSet::merge_listings(&[
+            GenerationSet::from([Generation {
+                id: 1,
+                date,
+                current: true,
+            }]),
+            GenerationSet::default(),
+        ]);
+        let report = DoctorReport {
+            platform: Platform {
+                os: Os::NixOs,
+                homes: Os::NixOs.homes().to_path_buf(),
+                procfs: true,
+                power_supplies: false,
+                systemd: true,
+                journald: false,
+            },
+            profiles: vec![
+                ProfileCheck {
+                    path: "/profiles/a".into(),
+                    generations: 3,
+                    failures: Vec::new(),
+                    conflicts: Vec::new(),
+                    listed_by: Backend::ALL.to_vec(),
+                },
+                ProfileCheck {
+                    path: "/profiles/b".into(),
+                    generations: 1,
+                    failures: vec![(Backend::NixEnv, "boom".to_string())],
+                    conflicts,
+                    listed_by: Backend::ALL.to_vec(),
+                },
+            ],
+        };
+
+        assert_eq!(report.unhealthy(), 1);
+        assert_eq!(
+            report.to_string(),
+            "platform: NixOS\n\
+             \x20 homes: /home\n\
+             \x20 procfs mount table: active\n\
+             \x20 sysfs power supplies: inactive\n\
+             \x20 systemd: active\n\
+             \x20 journald: inactive\n\
+             /profiles/a: ok, 3 generations\n\
+             /profiles/b:\n\
+             \x20 listing with nix-env failed: boom\n\
+             \x20 listed differently by nix-env, filesystem:\n\
+             \x20   generation 1: 1970-01-01 00:00:00 (current), missing\n"
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{prelude::*, Duration};
+use eyre::{eyre, Result};
+
+use janitor::{
+    duration::format_duration,
+    planning::{self, Rules},
+    references::{Referrers, BOOTED_SYSTEM},
+    size::Estimate,
+    Blocking, Decision, Generation, GenerationOrder, GenerationSet, Profile, ProfileKind, Reason,
+    RetentionOverrides, RetentionPolicy, StdExecutor,
+};
+
+use crate::{boot, Settings};
+
+const SYNTHETIC_GENERATIONS: u32 = 20;
+const SYNTHETIC_INTERVAL_DAYS: i64 = 2;
+
+/// Renders worked examples of the retention policies that would be applied
+/// with the given `overrides`, and how generations looking like rollback
+/// targets are treated.
+///
+/// The examples are computed by planning a synthetic profile with the same
+/// [Rules] a run plans with, see [planning::plan], so that they always match
+/// what a real run would do.
+pub fn explain_policy(
+    overrides: RetentionOverrides,
+    protect_rollbacks: bool,
+    now: NaiveDateTime,
+) -> String {
+    let generations = synthetic_generations(now);
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Worked examples for a profile with generations 1..{SYNTHETIC_GENERATIONS}, \
+         one created every {SYNTHETIC_INTERVAL_DAYS} days up to now, \
+         with {SYNTHETIC_GENERATIONS} being the current one:"
+    );
+
+    for kind in [
+        ProfileKind::System,
+        ProfileKind::User,
+        ProfileKind::HomeManager,
+        ProfileKind::Channels,
+        ProfileKind::Container,
+        ProfileKind::Default,
+    ] {
+        let policy = RetentionPolicy::resolve(kind, overrides);
+        let to_delete = planning::plan(
+            &generations,
+            &Rules {
+                keep_at_least: policy.keep_at_least,
+                keep_at_most: policy.keep_at_most,
+                keep_since: policy.keep_since(now),
+                before_generation: None,
+                protect_rollback_targets: protect_rollbacks,
+                order_by: policy.order_by,
+                thinning: policy.thinning,
+            },
+        );
+        let kept = generations
+            .iter()
+            .filter(|g| !to_delete.contains(g.id))
+            .map(|g| g.id)
+            .collect::<Vec<_>>();
+
+        let _ = writeln!(out);
+        let keep = match policy.since {
+            Some(since) => format!("keep-since={since}"),
+            None => format!("keep={}", format_duration(policy.keep)),
+        };
+        let mut settings = format!("{keep}, keep-at-least={}", policy.keep_at_least);
+        if let Some(at_most) = policy.keep_at_most {
+            let _ = write!(settings, ", keep-at-most={at_most}");
+        }
+        if policy.order_by != GenerationOrder::Id {
+            let _ = write!(settings, ", order-by={}", policy.order_by);
+        }
+        for (name, n) in [
+            ("keep-daily", policy.thinning.keep_daily),
+            ("keep-weekly", policy.thinning.keep_weekly),
+            ("keep-monthly", policy.thinning.keep_monthly),
+        ] {
+            if n > 0 {
+                let _ = write!(settings, ", {name}={n}");
+            }
+        }
+        let _ = writeln!(out, "{kind} profiles ({settings}):");
+        let _ = writeln!(
+            out,
+            "  would delete: {}",
+            format_ids(to_delete.iter().map(|g| g.id))
+        );
+        let _ = writeln!(out, "  would keep:   {}", format_ids(kept));
+        if policy.keep_at_least >= generations.len() {
+            let _ = writeln!(
+                out,
+                "  note: keep-at-least={at_least} covers all {total} generations, \
+                 so nothing is deleted regardless of age",
+                at_least = policy.keep_at_least,
+                total = generations.len(),
+            );
+        }
+    }
+
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "Generations that look like rollback targets, being current while newer \
+         ones exist or dated before the generation preceding them, {}.",
+        match protect_rollbacks {
+            true => "are kept",
+            false => "are treated like any other, use --protect-rollbacks to keep them",
+        }
+    );
+
+    out
+}
+
+/// Everything known about a single generation of a profile, see
+/// [explain_generation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Details {
+    /// The profile of the generation.
+    pub profile: PathBuf,
+
+    /// Whether the generation is kept or deleted under the current policy,
+    /// and why.
+    pub decision: Decision,
+
+    /// The time the age of the generation is measured at.
+    pub now: NaiveDateTime,
+
+    /// The store path the generation links to.
+    pub store_path: Option<PathBuf>,
+
+    /// The NixOS version of a system generation.
+    pub label: Option<String>,
+
+    /// The size of the closure of the generation, including paths shared
+    /// with other generations, or only of its store path if approximated.
+    pub size: Option<Estimate>,
+
+    /// Whether the machine has been booted into the generation.
+    pub booted: bool,
+
+    /// Whether the bootloader has an entry for the generation, `None` if not
+    /// a system profile or no bootloader has been found.
+    pub boot_entry: Option<bool>,
+}
+
+impl fmt::Display for Details {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let generation = &self.decision.generation;
+        let unknown = || "unknown".to_string();
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+        let references: Vec<_> = self
+            .decision
+            .reasons
+            .iter()
+            .filter_map(|reason| match reason {
+                Reason::Referenced(reference) => Some(reference.to_string()),
+                _ => None,
+            })
+            .collect();
+
+        writeln!(
+            f,
+            "generation {} of {}",
+            generation.id,
+            self.profile.display()
+        )?;
+        writeln!(f, "  date:        {}", generation.date)?;
+        writeln!(
+            f,
+            "  age:         {}",
+            format_duration(self.now - generation.date)
+        )?;
+        writeln!(
+            f,
+            "  label:       {}",
+            self.label.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "  store path:  {}",
+            self.store_path
+                .as_ref()
+                .map_or_else(unknown, |path| path.display().to_string())
+        )?;
+        writeln!(
+            f,
+            "  size:        {}",
+            self.size.map_or_else(unknown, |size| size.to_string())
+        )?;
+        writeln!(f, "  current:     {}", yes_no(generation.current))?;
+        writeln!(f, "  booted:      {}", yes_no(self.booted))?;
+        if let Some(boot_entry) = self.boot_entry {
+            writeln!(f, "  boot entry:  {}", yes_no(boot_entry))?;
+        }
+        writeln!(
+            f,
+            "  pinned:      {}",
+            match references.is_empty() {
+                true => "no".to_string(),
+                false => references.join(", "),
+            }
+        )?;
+        writeln!(
+            f,
+            "  decision:    {}",
+            if self.decision.delete {
+                "delete"
+            } else {
+                "keep"
+            }
+        )?;
+        for reason in &self.decision.reasons {
+            writeln!(f, "    - {reason}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Looks up everything known about the generation with the id `id` of the
+/// `profile`, deciding on it with the policy of the `settings` as of `now`.
+///
+/// Details that can not be looked up, like the size of a generation whose
+/// store path is not valid, are left out.
+///
+/// # Errors
+///
+/// Fails if the profile can not be listed or has no such generation.
+pub fn explain_generation(
+    settings: &Settings,
+    profile: &Path,
+    id: u32,
+    now: NaiveDateTime,
+) -> Result<Details> {
+    let executor = Blocking(StdExecutor);
+    let source = settings.options.backend.source(&executor);
+    let profile = Profile::new(profile);
+    let path = profile.as_ref();
+
+    let job = settings.job(&profile, now)?;
+    let listed = futures::executor::block_on(source.list(path))?;
+    let referenced = Referrers::system().of_generations(path, &listed);
+    let job = job.listed(listed).referenced(referenced);
+
+    let decision = job
+        .explain(id)
+        .ok_or_else(|| eyre!("{} has no generation {id}", path.display()))?;
+    let link = decision.generation.link(path);
+
+    let sizes = settings.size_cache();
+    let size = futures::executor::block_on(sizes.generation_size(
+        &executor,
+        &link,
+        settings.options.size_estimation,
+    ))
+    .map_err(|error| tracing::debug!(%error, "could not determine the size"))
+    .ok();
+    settings.save_size_cache(&sizes);
+
+    let booted = match (fs::canonicalize(&link), fs::canonicalize(BOOTED_SYSTEM)) {
+        (Ok(generation), Ok(booted)) => generation == booted,
+        _ => false,
+    };
+    let boot_entry = (profile.kind() == ProfileKind::System)
+        .then(|| boot::entries(Path::new(boot::BOOT)))
+        .flatten()
+        .map(|entries| entries.contains(&id));
+
+    Ok(Details {
+        profile: path.to_path_buf(),
+        now,
+        store_path: fs::read_link(&link).ok(),
+        label: fs::read_to_string(link.join("nixos-version"))
+            .ok()
+            .map(|label| label.trim().to_string()),
+        size,
+        booted,
+        boot_entry,
+        decision,
+    })
+}
+
+fn synthetic_generations(now: NaiveDateTime) -> GenerationSet {
+    (1..=SYNTHETIC_GENERATIONS)
+        .map(|id| Generation {
+            id,
+            date: now
+                - Duration::days(i64::from(SYNTHETIC_GENERATIONS - id) * SYNTHETIC_INTERVAL_DAYS),
+            current: id == SYNTHETIC_GENERATIONS,
+        })
+        .collect()
+}
+
+/// Formats ascending `ids` compactly, collapsing consecutive runs into
+/// ranges.
+fn format_ids<I: IntoIterator<Item = u32>>(ids: I) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+
+    for id in ids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+
+    if ranges.is_empty() {
+        return "nothing".to_string();
+    }
+
+    ranges
+        .iter()
+        .map(|&(start, end)| match end - start {
+            0 => start.to_string(),
+            _ => format!("{start}..{end}"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use janitor::Thinning;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::empty(&[], "nothing")]
+    #[case::single(&[3], "3")]
+    #[case::range(&[1, 2, 3], "1..3")]
+    #[case::mixed(&[1, 2, 3, 5, 7, 8], "1..3, 5, 7..8")]
+    fn test_format_ids(#[case] ids: &[u32], #[case] expected: &str) {
+        assert_eq!(format_ids(ids.iter().copied()), expected);
+    }
+
+    #[test]
+    fn explains_every_kind() {
+        let now =
+            NaiveDateTime::parse_from_str("2023-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let explanation = explain_policy(RetentionOverrides::default(), false, now);
+
+        // user: 7 days cover generations 17..20 plus the one active at the cutoff
+        assert!(explanation.contains("user profiles (keep=7d, keep-at-least=5):\n  would delete: 1..15\n  would keep:   16..20\n"));
+        assert!(explanation
+            .contains("system profiles (keep=14d, keep-at-least=10):\n  would delete: 1..10\n"));
+    }
+
+    #[test]
+    fn explains_like_a_run() {
+        let now =
+            NaiveDateTime::parse_from_str("2023-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let overrides = RetentionOverrides {
+            keep: Some(Duration::days(1)),
+            keep_at_least: Some(1),
+            thinning: Some(Thinning {
+                keep_daily: 5,
+                ..Thinning::default()
+            }),
+            ..RetentionOverrides::default()
+        };
+
+        let explanation = explain_policy(overrides, false, now);
+        // without thinning, only 19, active until 20 has been created, and 20
+        // would be kept
+        assert!(explanation.contains(
+            "user profiles (keep=1d, keep-at-least=1, keep-daily=5):\n  \
+             would delete: 1..15\n  would keep:   16..20\n"
+        ));
+    }
+
+    #[rstest]
+    #[case::unprotected(false, "use --protect-rollbacks to keep them.")]
+    #[case::protected(true, "preceding them, are kept.")]
+    fn explains_rollback_protection(#[case] protect: bool, #[case] expected: &str) {
+        let now =
+            NaiveDateTime::parse_from_str("2023-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        assert!(explain_policy(RetentionOverrides::default(), protect, now).contains(expected));
+    }
+
+    #[test]
+    fn renders_details() {
+        let date = |day| {
+            NaiveDate::from_ymd_opt(2023, 6, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let details = Details {
+            profile: PathBuf::from("/nix/var/nix/profiles/system"),
+            decision: Decision {
+                generation: Generation {
+                    id: 661,
+                    date: date(1),
+                    current: false,
+                },
+                delete: false,
+                reasons: vec![
+                    Reason::Expired {
+                        keep_at_least: 10,
+                        keep_since: date(2),
+                    },
+                    Reason::Referenced(janitor::references::Reference {
+                        referrer: janitor::references::Referrer::Container("web".to_string()),
+                        specialisation: None,
+                    }),
+                ],
+            },
+            now: date(15),
+            store_path: Some(PathBuf::from("/nix/store/abc-nixos-system")),
+            label: Some("23.05.1234.abcdef (Stoat)".to_string()),
+            size: None,
+            booted: false,
+            boot_entry: Some(true),
+        };
+
+        assert_eq!(
+            details.to_string(),
+            "generation 661 of /nix/var/nix/profiles/system\n\
+             \x20 date:        2023-06-01 00:00:00\n\
+             \x20 age:         14d\n\
+             \x20 label:       23.05.1234.abcdef (Stoat)\n\
+             \x20 store path:  /nix/store/abc-nixos-system\n\
+             \x20 size:        unknown\n\
+             \x20 current:     no\n\
+             \x20 booted:      no\n\
+             \x20 boot entry:  yes\n\
+             \x20 pinned:      container web\n\
+             \x20 decision:    keep\n\
+             \x20   - delete: neither one of the 10 most recent generations nor active on or after 2023-06-02 00:00:00\n\
+             \x20   - keep: referenced by container web\n"
+        );
+    }
+
+    #[test]
+    fn notes_keep_at_least_covering_everything() {
+        let now =
+            NaiveDateTime::parse_from_str("2023-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let overrides = RetentionOverrides {
+            keep_at_least: Some(25),
+            ..Default::default()
+        };
+
+        let explanation = explain_policy(overrides, false, now);
+
+        assert!(explanation.contains("  would delete: nothing\n  would keep:   1..20\n  note: keep-at-least=25 covers all 20 generations"));
+        assert!(!explain_policy(RetentionOverrides::default(), false, now).contains("note:"));
+    }
+}
@@ -0,0 +1,358 @@
+//! Keeps the machines of a fleet sharing a binary cache or an NFS-backed
+//! store from collecting garbage all at once, see `--gc-lock`.
+//!
+//! The lock is a directory on storage all machines share, holding a file
+//! `slot-<n>` for each machine collecting garbage. A machine takes the first
+//! free one of the slots by creating its file exclusively, and removes it
+//! after the garbage collection. The holder refreshes the modification time
+//! of its slot every [REFRESH_EVERY], so a slot older than [STALE_AFTER] has
+//! been left behind by a machine that crashed and is taken over.
+//!
+//! Lock services spoken to over HTTP are not supported, the janitor has no
+//! HTTP client.
+
+use std::{
+    fs, io,
+    io::Write,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Context, Result};
+
+/// How long a slot has not been refreshed before it is taken over.
+pub const STALE_AFTER: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How often the holder of a slot refreshes it, well within [STALE_AFTER].
+pub const REFRESH_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// A lock letting at most `slots` machines collect garbage at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcLock {
+    /// The directory on shared storage holding the slots.
+    pub location: PathBuf,
+
+    /// How many machines may collect garbage at once.
+    pub slots: NonZeroUsize,
+}
+
+impl GcLock {
+    /// Takes the first free slot, `None` if all of them are held.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory of the lock can not be created or a slot can
+    /// not be checked, rather than collecting garbage unprotected.
+    pub fn acquire(&self) -> Result<Option<GcSlot>> {
+        fs::create_dir_all(&self.location)
+            .wrap_err_with(|| format!("Failed to create gc lock {}", self.location.display()))?;
+
+        for slot in 0..self.slots.get() {
+            let path = self.location.join(format!("slot-{slot}"));
+            let taken = take(&path).or_else(|error| match error.kind() {
+                io::ErrorKind::AlreadyExists if is_stale(&path) => take_over(&path),
+                _ => Err(error),
+            });
+
+            match taken {
+                Ok(()) => {
+                    tracing::debug!(?path, "took slot of the gc lock");
+                    return Ok(Some(GcSlot::held(path)));
+                }
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(error) => {
+                    return Err(error).wrap_err_with(|| {
+                        format!("Failed to take slot {} of the gc lock", path.display())
+                    })
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A slot of a [GcLock], refreshed while held and released when dropped.
+#[derive(Debug)]
+pub struct GcSlot {
+    held: Option<Held>,
+}
+
+/// The slot file of a [GcSlot] and the thread refreshing it.
+#[derive(Debug)]
+struct Held {
+    path: PathBuf,
+
+    /// Stops the refreshing when dropped.
+    stop: mpsc::Sender<()>,
+    refresher: thread::JoinHandle<()>,
+}
+
+impl GcSlot {
+    /// A slot of no lock at all, for collecting garbage without one.
+    pub fn unlocked() -> Self {
+        Self { held: None }
+    }
+
+    /// Holds the slot at `path`, refreshing it every [REFRESH_EVERY] until
+    /// dropped.
+    fn held(path: PathBuf) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let refresher = thread::spawn({
+            let path = path.clone();
+            move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(REFRESH_EVERY)
+                {
+                    if let Err(error) = refresh(&path) {
+                        tracing::warn!(?path, %error, "could not refresh slot of the gc lock");
+                    }
+                }
+            }
+        });
+
+        Self {
+            held: Some(Held {
+                path,
+                stop,
+                refresher,
+            }),
+        }
+    }
+}
+
+impl Drop for GcSlot {
+    fn drop(&mut self) {
+        let Some(Held {
+            path,
+            stop,
+            refresher,
+        }) = self.held.take()
+        else {
+            return;
+        };
+
+        drop(stop);
+        if refresher.join().is_err() {
+            tracing::warn!(?path, "refreshing the slot of the gc lock panicked");
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => tracing::debug!(?path, "released slot of the gc lock"),
+            Err(error) => tracing::warn!(?path, %error, "could not release slot of the gc lock"),
+        }
+    }
+}
+
+/// Creates the slot at `path` if it does not exist yet, noting who holds it
+/// for whoever finds it.
+fn take(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    writeln!(file, "{} {} {since}", hostname(), std::process::id())
+}
+
+/// Takes over the stale slot at `path`.
+///
+/// The stale file is renamed to a name unique to this process first, which
+/// only one of the machines finding it stale at once succeeds with. If it is
+/// no longer stale once renamed, another machine has taken the slot over in
+/// between and the file is given back.
+fn take_over(path: &Path) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".stale-{}-{}", hostname(), std::process::id()));
+    let claimed = path.with_file_name(name);
+
+    match fs::rename(path, &claimed) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(taken_by_another()),
+        Err(error) => return Err(error),
+    }
+
+    if !is_stale(&claimed) {
+        // Linking does not replace a slot taken in the meantime, unlike
+        // renaming back.
+        let restored = fs::hard_link(&claimed, path);
+        fs::remove_file(&claimed)?;
+        if let Err(error) = restored {
+            tracing::warn!(?path, %error, "could not give back slot of the gc lock");
+        }
+
+        return Err(taken_by_another());
+    }
+
+    tracing::warn!(?path, "taking over stale slot of the gc lock");
+    fs::remove_file(&claimed).or_else(ignore_missing)?;
+    take(path)
+}
+
+/// The slot has been taken over by another machine, reported like a slot
+/// held all along.
+fn taken_by_another() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "slot taken over by another machine",
+    )
+}
+
+/// Marks the slot at `path` as still being held.
+fn refresh(path: &Path) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > STALE_AFTER)
+}
+
+fn ignore_missing(error: io::Error) -> io::Result<()> {
+    match error.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(error),
+    }
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|host| host.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Parses the location of a lock, a directory given as an absolute path or
+/// a `file://` URL.
+pub fn parse_location(input: &str) -> Result<PathBuf, String> {
+    if input.starts_with("http://") || input.starts_with("https://") {
+        return Err(format!(
+            "lock services over HTTP are not supported, {input:?} has to be a directory on \
+             shared storage"
+        ));
+    }
+
+    let path = PathBuf::from(input.strip_prefix("file://").unwrap_or(input));
+    match path.is_absolute() {
+        true => Ok(path),
+        false => Err(format!("gc lock {input:?} is not an absolute path")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    use rstest::rstest;
+
+    fn lock(name: &str, slots: usize) -> GcLock {
+        GcLock {
+            location: env::temp_dir().join(format!("janitor-{name}-{}", std::process::id())),
+            slots: NonZeroUsize::new(slots).unwrap(),
+        }
+    }
+
+    #[test]
+    fn limits_holders_to_the_slots() -> Result<()> {
+        let lock = lock("gc-lock", 2);
+
+        let first = lock.acquire()?;
+        let second = lock.acquire()?;
+        let third = lock.acquire()?;
+        assert!(first.is_some() && second.is_some());
+        assert!(third.is_none());
+
+        drop(first);
+        let reused = lock.acquire()?;
+        assert!(reused.is_some());
+
+        drop((second, reused));
+        assert_eq!(fs::read_dir(&lock.location)?.count(), 0);
+        fs::remove_dir_all(&lock.location)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn takes_over_stale_slots() -> Result<()> {
+        let lock = lock("gc-lock-stale", 1);
+        fs::create_dir_all(&lock.location)?;
+        let stale = fs::File::create(lock.location.join("slot-0"))?;
+        stale.set_modified(SystemTime::now() - STALE_AFTER - Duration::from_secs(60))?;
+
+        let taken = lock.acquire()?;
+        assert!(taken.is_some());
+        assert!(lock.acquire()?.is_none());
+
+        drop(taken);
+        fs::remove_dir_all(&lock.location)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn gives_back_slots_taken_over_in_between() -> Result<()> {
+        let lock = lock("gc-lock-race", 1);
+        fs::create_dir_all(&lock.location)?;
+        let path = lock.location.join("slot-0");
+        fs::write(&path, "other 1 0\n")?;
+
+        let error = take_over(&path).unwrap_err();
+        let kept = fs::read_to_string(&path);
+        let left = fs::read_dir(&lock.location)?.count();
+        fs::remove_dir_all(&lock.location)?;
+
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept?, "other 1 0\n");
+        assert_eq!(left, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refreshing_keeps_slots_from_going_stale() -> Result<()> {
+        let lock = lock("gc-lock-refresh", 1);
+        let slot = lock.acquire()?;
+        let path = lock.location.join("slot-0");
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() - STALE_AFTER - Duration::from_secs(60))?;
+        assert!(is_stale(&path));
+
+        refresh(&path)?;
+        let stale = is_stale(&path);
+        let taken = lock.acquire()?;
+
+        drop(slot);
+        fs::remove_dir_all(&lock.location)?;
+
+        assert!(!stale);
+        assert!(taken.is_none());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::path("/mnt/shared/janitor", Some("/mnt/shared/janitor"))]
+    #[case::file_url("file:///mnt/shared/janitor", Some("/mnt/shared/janitor"))]
+    #[case::relative("shared/janitor", None)]
+    #[case::http("https://locks.example.com/gc", None)]
+    fn parses_locations(#[case] input: &str, #[case] expected: Option<&str>) {
+        assert_eq!(parse_location(input).ok(), expected.map(PathBuf::from));
+    }
+}
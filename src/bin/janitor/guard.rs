@@ -0,0 +1,104 @@
+//! Refuses to delete anything when the janitor runs where nobody expects it
+//! to, like a nix build, a nix shell or a CI pipeline, which most likely only
+//! wanted to run `--help` or check the build.
+
+use std::{ffi::OsString, fmt};
+
+use eyre::{bail, Result};
+
+/// An environment the janitor refuses to clean up from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    /// A nix build, detected by `NIX_BUILD_TOP`.
+    NixBuild,
+
+    /// A shell of `nix develop` or `nix-shell`, detected by `IN_NIX_SHELL`.
+    NixShell,
+
+    /// A CI pipeline, detected by `CI`, which most CI services set.
+    Ci,
+}
+
+impl Environment {
+    /// Detects the environment from the variables looked up with `var`.
+    pub fn detect(var: impl Fn(&str) -> Option<OsString>) -> Option<Self> {
+        let set = |name| var(name).is_some_and(|value| !value.is_empty());
+        let ci = var("CI").is_some_and(|value| !matches!(value.to_str(), Some("" | "0" | "false")));
+
+        if set("NIX_BUILD_TOP") {
+            Some(Self::NixBuild)
+        } else if set("IN_NIX_SHELL") {
+            Some(Self::NixShell)
+        } else if ci {
+            Some(Self::Ci)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::NixBuild => "a nix build (NIX_BUILD_TOP is set)",
+            Self::NixShell => "a nix shell (IN_NIX_SHELL is set)",
+            Self::Ci => "a CI pipeline (CI is set)",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Fails if the janitor runs in an [Environment] detected from the variables
+/// looked up with `var`, unless `overridden`.
+pub fn check(overridden: bool, var: impl Fn(&str) -> Option<OsString>) -> Result<()> {
+    let Some(environment) = Environment::detect(var) else {
+        return Ok(());
+    };
+
+    if overridden {
+        tracing::warn!(%environment, "cleaning up anyway, as requested");
+        return Ok(());
+    }
+
+    bail!(
+        "refusing to delete generations or collect garbage from within {environment}; \
+         pass --i-know-what-i-am-doing if this is intended"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.into())
+        }
+    }
+
+    #[rstest]
+    #[case::none(&[], None)]
+    #[case::build(&[("NIX_BUILD_TOP", "/build"), ("CI", "true")], Some(Environment::NixBuild))]
+    #[case::shell(&[("IN_NIX_SHELL", "impure")], Some(Environment::NixShell))]
+    #[case::ci(&[("CI", "true")], Some(Environment::Ci))]
+    #[case::ci_disabled(&[("CI", "false")], None)]
+    #[case::ci_zero(&[("CI", "0")], None)]
+    #[case::empty(&[("NIX_BUILD_TOP", ""), ("CI", "")], None)]
+    fn detect(#[case] set: &[(&'static str, &'static str)], #[case] expected: Option<Environment>) {
+        assert_eq!(Environment::detect(vars(set)), expected);
+    }
+
+    #[test]
+    fn check() {
+        let ci = [("CI", "true")];
+
+        assert!(super::check(false, vars(&[])).is_ok());
+        assert!(super::check(false, vars(&ci)).is_err());
+        assert!(super::check(true, vars(&ci)).is_ok());
+    }
+}
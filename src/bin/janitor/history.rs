@@ -0,0 +1,181 @@
+//! The outcomes of past cleanups, summed up per week to show the churn of
+//! the profiles in `janitor status`.
+
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use janitor::{size::format_size, RunReport};
+use serde::{Deserialize, Serialize};
+
+use crate::run_id::RunId;
+
+/// How many weeks of history are kept and shown.
+pub const WEEKS: usize = 12;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The outcome of a single successful cleanup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    /// The id of the run, `None` for runs recorded before ids were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RunId>,
+
+    /// When the cleanup has finished.
+    pub finished: DateTime<Utc>,
+
+    /// The number of generations deleted.
+    pub deleted: usize,
+
+    /// The number of bytes freed by the garbage collection.
+    pub freed: u64,
+}
+
+impl Run {
+    /// The outcome of the cleanup `id` that has finished at `finished` with
+    /// the `report`.
+    pub fn new(id: RunId, finished: DateTime<Utc>, report: &RunReport) -> Self {
+        Self {
+            id: Some(id),
+            finished,
+            deleted: report.deleted_count(),
+            freed: report.gc().map_or(0, |gc| gc.bytes_freed),
+        }
+    }
+}
+
+/// Appends `run` to the `history`, forgetting the runs of more than [WEEKS]
+/// weeks before it.
+pub fn record(history: &mut Vec<Run>, run: Run) {
+    let since = run.finished - weeks();
+    history.retain(|past| past.finished > since);
+    history.push(run);
+}
+
+/// The generations deleted and bytes freed per week, for the last [WEEKS]
+/// weeks up to `now`, the oldest week first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trend {
+    /// The generations deleted per week.
+    pub deleted: Vec<u64>,
+
+    /// The bytes freed per week.
+    pub freed: Vec<u64>,
+}
+
+impl Trend {
+    /// Sums up the `history` per week.
+    pub fn new(history: &[Run], now: DateTime<Utc>) -> Self {
+        let mut trend = Self {
+            deleted: vec![0; WEEKS],
+            freed: vec![0; WEEKS],
+        };
+
+        for run in history {
+            let ago = (now - run.finished).num_weeks();
+            let Some(week) = usize::try_from(ago)
+                .ok()
+                .filter(|ago| *ago < WEEKS)
+                .map(|ago| WEEKS - 1 - ago)
+            else {
+                continue;
+            };
+
+            trend.deleted[week] += run.deleted as u64;
+            trend.freed[week] += run.freed;
+        }
+
+        trend
+    }
+}
+
+impl fmt::Display for Trend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "last {WEEKS} weeks, oldest first:")?;
+        writeln!(
+            f,
+            "  deleted per week: {}  total {}",
+            sparkline(&self.deleted),
+            self.deleted.iter().sum::<u64>()
+        )?;
+        write!(
+            f,
+            "  freed per week:   {}  total {}",
+            sparkline(&self.freed),
+            format_size(self.freed.iter().sum())
+        )
+    }
+}
+
+/// Renders `values` as bars relative to the largest of them.
+///
+/// Weeks without anything are drawn as the lowest bar, as are all weeks if
+/// nothing happened at all.
+pub fn sparkline(values: &[u64]) -> String {
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+
+    values
+        .iter()
+        .map(|value| {
+            let level = (u128::from(*value) * (BARS.len() as u128 - 1)).div_ceil(u128::from(max));
+            BARS[level as usize]
+        })
+        .collect()
+}
+
+fn weeks() -> Duration {
+    Duration::weeks(WEEKS as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    fn run(weeks_ago: i64, deleted: usize, now: DateTime<Utc>) -> Run {
+        Run {
+            id: None,
+            finished: now - Duration::weeks(weeks_ago) - Duration::hours(1),
+            deleted,
+            freed: deleted as u64 * 1024,
+        }
+    }
+
+    #[rstest]
+    #[case::empty(&[], "")]
+    #[case::nothing(&[0, 0, 0], "▁▁▁")]
+    #[case::scaled(&[0, 1, 4, 8], "▁▂▅█")]
+    fn sparklines(#[case] values: &[u64], #[case] expected: &str) {
+        assert_eq!(sparkline(values), expected);
+    }
+
+    #[test]
+    fn sums_up_weeks() {
+        let now = Utc::now();
+        let history = [
+            run(20, 9, now),
+            run(3, 2, now),
+            run(0, 1, now),
+            run(0, 4, now),
+        ];
+
+        let trend = Trend::new(&history, now);
+
+        assert_eq!(trend.deleted.len(), WEEKS);
+        assert_eq!(trend.deleted[WEEKS - 1], 5);
+        assert_eq!(trend.deleted[WEEKS - 4], 2);
+        assert_eq!(trend.deleted.iter().sum::<u64>(), 7);
+        assert_eq!(trend.freed[WEEKS - 1], 5 * 1024);
+    }
+
+    #[test]
+    fn forgets_old_runs() {
+        let now = Utc::now();
+        let mut history = vec![run(20, 9, now), run(3, 2, now)];
+
+        record(&mut history, run(0, 1, now));
+
+        assert_eq!(history, [run(3, 2, now), run(0, 1, now)]);
+    }
+}
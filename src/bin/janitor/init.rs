@@ -0,0 +1,494 @@
+//! `janitor init`, which asks a few questions on the terminal, writes the
+//! configuration file from the answers and prints the next steps.
+
+use std::{
+    env,
+    fmt::{self, Write as _},
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Duration;
+use eyre::{bail, eyre, Context, Result};
+use janitor::{
+    duration::{format_duration, parse_duration},
+    platform::Os,
+};
+
+use crate::{config::Config, system_gc::SystemGc};
+
+/// Where the units of the system are installed.
+const SYSTEM_UNITS: &str = "/etc/systemd/system";
+
+/// The name of the units of the timer.
+const UNIT: &str = "nix-janitor";
+
+/// What the user wants the janitor to do.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Answers {
+    /// Keep generations active within this duration, `None` for the
+    /// defaults of each profile kind.
+    pub keep: Option<Duration>,
+
+    /// Keep at least this many generations, `None` for the defaults of each
+    /// profile kind.
+    pub keep_at_least: Option<usize>,
+
+    /// Whether to clean up the system profile, only asked as root.
+    pub system: Option<bool>,
+
+    /// Patterns of the profiles to clean up, empty for the default ones.
+    pub profiles: Vec<String>,
+
+    /// Whether to collect garbage after deleting generations.
+    pub gc: bool,
+
+    /// Whether to skip the garbage collection in favor of the one of the
+    /// system.
+    pub defer_to_system_gc: bool,
+
+    /// Whether to install a timer running the janitor regularly.
+    pub timer: bool,
+}
+
+impl Answers {
+    /// Renders the configuration file for the answers, commenting on the
+    /// settings left at their defaults.
+    pub fn config(&self) -> String {
+        let mut out = String::from("# Written by `janitor init`.\n\n");
+
+        let _ = match self.keep {
+            Some(keep) => writeln!(out, "keep = \"{}\"", format_duration(keep)),
+            None => writeln!(out, "# keep = \"14d\"  # default per profile kind"),
+        };
+        let _ = match self.keep_at_least {
+            Some(count) => writeln!(out, "keep_at_least = {count}"),
+            None => writeln!(out, "# keep_at_least = 5  # default per profile kind"),
+        };
+        if let Some(system) = self.system {
+            let _ = writeln!(out, "system = {system}");
+        }
+        if !self.profiles.is_empty() {
+            let profiles = toml::Value::Array(
+                self.profiles
+                    .iter()
+                    .map(|p| toml::Value::String(p.clone()))
+                    .collect(),
+            );
+            let _ = writeln!(out, "profiles = {profiles}");
+        }
+        let _ = writeln!(out, "gc = {}", self.gc);
+        if self.defer_to_system_gc {
+            let _ = writeln!(out, "defer_to_system_gc = true");
+        }
+
+        out
+    }
+}
+
+/// Asks the questions of `janitor init`, reading the answers from `input`.
+pub struct Wizard<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    /// Creates a wizard asking on `output` and reading from `input`.
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Asks all questions, `root` deciding whether the system profile is
+    /// asked about, and `system_gc` whether a garbage collection of the
+    /// system has been found.
+    pub fn answers(&mut self, root: bool, system_gc: Option<SystemGc>) -> Result<Answers> {
+        writeln!(
+            self.output,
+            "Press enter to accept the suggestion in brackets.\n"
+        )?;
+
+        let keep = self.ask(
+            "Keep generations that have been active within (e.g. 14d, 4w, or \"default\")",
+            "default",
+            |answer| match answer {
+                "default" => Ok(None),
+                answer => Ok(Some(parse_duration(answer)?)),
+            },
+        )?;
+        let keep_at_least = self.ask(
+            "Keep at least this many generations (a number, or \"default\")",
+            "default",
+            |answer| match answer {
+                "default" => Ok(None),
+                answer => answer
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| eyre!("not a number: {answer}")),
+            },
+        )?;
+
+        let system = match root {
+            true => Some(self.confirm("Clean up the system profile?", true)?),
+            false => None,
+        };
+        let profiles = self.ask(
+            "Profiles to clean up, as comma separated patterns, or \"default\" for all of yours",
+            "default",
+            |answer| match answer {
+                "default" => Ok(Vec::new()),
+                answer => Ok(answer
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(String::from)
+                    .collect()),
+            },
+        )?;
+
+        let gc = self.confirm(
+            "Collect garbage after deleting generations, to actually free the space?",
+            true,
+        )?;
+        let defer_to_system_gc = match (gc, system_gc) {
+            (true, Some(service)) => self.confirm(
+                &format!("{service} collects garbage already, leave it to that?"),
+                true,
+            )?,
+            _ => false,
+        };
+
+        let timer = self.confirm("Install a timer running the janitor weekly?", true)?;
+
+        Ok(Answers {
+            keep,
+            keep_at_least,
+            system,
+            profiles,
+            gc,
+            defer_to_system_gc,
+            timer,
+        })
+    }
+
+    /// Asks whether to do something, `default` being the answer if the user
+    /// just presses enter.
+    pub fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let suggestion = match default {
+            true => "Y/n",
+            false => "y/N",
+        };
+
+        self.ask_with(question, suggestion, |answer| {
+            match answer.to_lowercase().as_str() {
+                "" => Ok(default),
+                "y" | "yes" => Ok(true),
+                "n" | "no" => Ok(false),
+                _ => bail!("please answer yes or no"),
+            }
+        })
+    }
+
+    fn ask<T>(
+        &mut self,
+        question: &str,
+        suggestion: &str,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<T> {
+        self.ask_with(question, suggestion, |answer| match answer {
+            "" => parse(suggestion),
+            answer => parse(answer),
+        })
+    }
+
+    /// Asks `question` until `parse` accepts the answer.
+    fn ask_with<T>(
+        &mut self,
+        question: &str,
+        suggestion: &str,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            write!(self.output, "{question} [{suggestion}] ")?;
+            self.output.flush()?;
+
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                bail!("no answer given");
+            }
+
+            match parse(answer.trim()) {
+                Ok(value) => return Ok(value),
+                Err(error) => writeln!(self.output, "  {error}")?,
+            }
+        }
+    }
+}
+
+/// Where and how the timer is installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Timer {
+    /// Units written to this directory, enabled with `systemctl`, with
+    /// `--user` if `user`.
+    Units { dir: PathBuf, user: bool },
+
+    /// NixOS, where the units are declared in the system configuration.
+    NixOs,
+}
+
+impl Timer {
+    /// Where to install the timer, for the system as `root` or for the user
+    /// with the configuration directory `config_home`.
+    pub fn locate(root: bool, config_home: Option<PathBuf>) -> Option<Self> {
+        if root {
+            // On NixOS, units are declared in the system configuration
+            // instead of being written to `/etc`.
+            return Some(match Os::current() {
+                Os::NixOs => Self::NixOs,
+                _ => Self::Units {
+                    dir: PathBuf::from(SYSTEM_UNITS),
+                    user: false,
+                },
+            });
+        }
+
+        config_home.map(|dir| Self::Units {
+            dir: dir.join("systemd").join("user"),
+            user: true,
+        })
+    }
+
+    /// Installs the timer running `janitor` with the configuration at
+    /// `config`, returning what is left to do.
+    pub fn install(&self, janitor: &Path, config: &Path) -> Result<Vec<String>> {
+        let exec = format!("{} --config {}", janitor.display(), config.display());
+
+        match self {
+            Self::NixOs => Ok(vec![format!(
+                "Add the timer to your NixOS configuration:\n\n{}",
+                nixos_module(&exec)
+            )]),
+            Self::Units { dir, user } => {
+                fs::create_dir_all(dir)
+                    .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+                for (name, content) in [
+                    (format!("{UNIT}.service"), service_unit(&exec)),
+                    (format!("{UNIT}.timer"), timer_unit()),
+                ] {
+                    let path = dir.join(name);
+                    fs::write(&path, content)
+                        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+                }
+
+                let systemctl = match user {
+                    true => "systemctl --user",
+                    false => "systemctl",
+                };
+                Ok(vec![format!(
+                    "Enable the timer: {systemctl} daemon-reload && {systemctl} enable --now {UNIT}.timer"
+                )])
+            }
+        }
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Units { dir, .. } => write!(f, "{}", dir.display()),
+            Self::NixOs => f.write_str("the NixOS configuration"),
+        }
+    }
+}
+
+fn service_unit(exec: &str) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Clean up old nix generations\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={exec}\n"
+    )
+}
+
+fn timer_unit() -> String {
+    "[Unit]\n\
+     Description=Clean up old nix generations weekly\n\
+     \n\
+     [Timer]\n\
+     OnCalendar=weekly\n\
+     Persistent=true\n\
+     RandomizedDelaySec=1h\n\
+     \n\
+     [Install]\n\
+     WantedBy=timers.target\n"
+        .to_string()
+}
+
+fn nixos_module(exec: &str) -> String {
+    format!(
+        "  systemd.services.{UNIT} = {{\n\
+         \x20   description = \"Clean up old nix generations\";\n\
+         \x20   serviceConfig.Type = \"oneshot\";\n\
+         \x20   serviceConfig.ExecStart = \"{exec}\";\n\
+         \x20   startAt = \"weekly\";\n\
+         \x20 }};\n"
+    )
+}
+
+/// Runs `janitor init`, writing the configuration to `config` or the default
+/// location.
+///
+/// # Errors
+///
+/// Fails if stdin is not a terminal, the configuration can not be written, or
+/// the user declines to overwrite an existing one.
+pub fn run(config: Option<&Path>) -> Result<()> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("janitor init asks questions, but stdin is not a terminal");
+    }
+
+    let path = Config::location(config)
+        .ok_or_else(|| eyre!("no location for the configuration known, pass --config"))?;
+    let root = is_root::is_root();
+
+    let mut wizard = Wizard::new(stdin.lock(), io::stderr());
+    if path.exists()
+        && !wizard.confirm(
+            &format!("{} exists already, overwrite it?", path.display()),
+            false,
+        )?
+    {
+        bail!("not overwriting {}", path.display());
+    }
+    let answers = wizard.answers(root, SystemGc::detect(Path::new("/")))?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, answers.config())
+        .wrap_err_with(|| format!("Failed to write config {}", path.display()))?;
+    Config::load(Some(&path))?;
+    println!("Wrote {}", path.display());
+
+    let mut next = vec![
+        "Preview what would be deleted: janitor --explain-policy".to_string(),
+        "Clean up right away: janitor".to_string(),
+    ];
+    if answers.timer {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        let timer = Timer::locate(root, config_home)
+            .ok_or_else(|| eyre!("no location for the timer known"))?;
+        let janitor = env::current_exe().wrap_err("Failed to locate the janitor")?;
+
+        next.extend(timer.install(&janitor, &path)?);
+        println!("Installed the timer to {timer}");
+    }
+
+    println!("\nNext steps:");
+    for step in next {
+        println!("- {step}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    /// Reads the answers from the lines of `input` as if typed on the
+    /// terminal, as a regular user without a garbage collection of the
+    /// system.
+    fn answers(input: &str) -> Result<Answers> {
+        Wizard::new(input.as_bytes(), io::sink()).answers(false, None)
+    }
+
+    #[rstest]
+    #[case::defaults("\n\n\n\n\n", Answers { gc: true, timer: true, ..Default::default() })]
+    #[case::custom(
+        "4w\n3\n~/.local/state/nix/profiles/*\nn\nno\n",
+        Answers {
+            keep: Some(Duration::weeks(4)),
+            keep_at_least: Some(3),
+            profiles: vec!["~/.local/state/nix/profiles/*".to_string()],
+            ..Default::default()
+        }
+    )]
+    #[case::retries("soon\n2d\nmany\n\n\nmaybe\ny\nn\n",
+        Answers { keep: Some(Duration::days(2)), gc: true, ..Default::default() }
+    )]
+    fn reads_answers(#[case] input: &str, #[case] expected: Answers) -> Result<()> {
+        assert_eq!(answers(input)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_without_answers() {
+        assert!(answers("4w\n").is_err());
+    }
+
+    #[test]
+    fn asks_about_the_system_as_root() -> Result<()> {
+        let answers = Wizard::new("\n\nn\n\n\ny\nn\n".as_bytes(), io::sink())
+            .answers(true, Some(SystemGc::NixOs))?;
+
+        assert_eq!(answers.system, Some(false));
+        assert!(answers.defer_to_system_gc);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::defaults(Answers { gc: true, ..Default::default() })]
+    #[case::everything(Answers {
+        keep: Some(Duration::hours(36)),
+        keep_at_least: Some(3),
+        system: Some(true),
+        profiles: vec!["/nix/var/nix/profiles/per-user/*/profile".to_string()],
+        gc: true,
+        defer_to_system_gc: true,
+        timer: true,
+    })]
+    fn writes_valid_config(#[case] answers: Answers) -> Result<()> {
+        let config: Config = toml::from_str(&answers.config())?;
+
+        assert_eq!(config.keep, answers.keep);
+        assert_eq!(config.keep_at_least, answers.keep_at_least);
+        assert_eq!(config.system, answers.system);
+        assert_eq!(config.profiles, answers.profiles);
+        assert_eq!(config.gc, Some(answers.gc));
+        assert_eq!(
+            config.defer_to_system_gc.unwrap_or(false),
+            answers.defer_to_system_gc
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn installs_user_units() -> Result<()> {
+        let home = env::temp_dir().join(format!("janitor-init-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+
+        let timer = Timer::locate(false, Some(home.clone())).unwrap();
+        let next = timer.install(Path::new("/bin/janitor"), Path::new("/etc/janitor.toml"))?;
+        let service = fs::read_to_string(home.join("systemd/user/nix-janitor.service"))?;
+        let installed = home.join("systemd/user/nix-janitor.timer").exists();
+        fs::remove_dir_all(&home)?;
+
+        assert!(service.contains("ExecStart=/bin/janitor --config /etc/janitor.toml\n"));
+        assert!(installed);
+        assert!(next[0].contains("systemctl --user enable --now nix-janitor.timer"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1051 @@
+use std::{
+    fmt,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use clap::{Parser, Subcommand};
+use janitor::{
+    duration::{self, KeepSince},
+    size, GenerationOrder, JanitorError, RetentionOverrides,
+};
+
+#[cfg(feature = "report-db")]
+use crate::report_db::CannedQuery;
+use crate::{compat::Compat, config, gc_lock, pins::Pin, prompt::Interactive, registry::StalePins};
+#[cfg(feature = "tokio")]
+use crate::{control::Request, runtime::Flavor};
+
+/// Command line interface of the janitor.
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about)]
+pub struct NJParser {
+    /// Also clean up the system profile, the ones of nixos-containers and the
+    /// default profile of root holding nix itself.
+    ///
+    /// Defaults to on when run from a root shell and off when run via `sudo`,
+    /// unless the config file says otherwise.
+    #[arg(long, overrides_with = "no_system", global = true)]
+    system: bool,
+
+    /// Never clean up the system profile.
+    #[arg(long, overrides_with = "system", global = true)]
+    no_system: bool,
+
+    /// Clean up the profiles matching this pattern instead of the default
+    /// ones, e.g. `/nix/var/nix/profiles/per-user/*/profile`, or just the
+    /// profile at this path, which has to exist.
+    ///
+    /// The pattern is expanded by the janitor, so quote it. May be given
+    /// several times. A pattern matching more than 64 profiles is rejected.
+    #[arg(long = "profile", value_name = "PATTERN")]
+    pub profiles: Vec<String>,
+
+    /// Never clean up the profiles matching this pattern, e.g.
+    /// `/home/*/.local/state/nix/profiles/home-manager*`, even if matched by
+    /// `--profile`.
+    ///
+    /// Quote the pattern like the ones of `--profile`. May be given several
+    /// times.
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    pub excludes: Vec<String>,
+
+    /// Clean up the profiles of all users instead of only the invoking one,
+    /// those in `/nix/var/nix/profiles/per-user` and in
+    /// `~/.local/state/nix/profiles` of every home in `/home`.
+    ///
+    /// Requires root privileges. Profiles not owned by the user whose
+    /// directory they are in are skipped.
+    #[arg(long, conflicts_with = "profiles")]
+    all_users: bool,
+
+    /// Keep generations that have been active within this duration, e.g.
+    /// `36h` or `2d12h`.
+    ///
+    /// Units are w, d, h, m and s. Overrides the default of each profile
+    /// kind.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, env = "JANITOR_KEEP", global = true)]
+    keep: Option<Duration>,
+
+    /// Keep generations that have been active within this many days.
+    ///
+    /// Same as `--keep-since <DAYS>d`, kept for compatibility. Must not be
+    /// negative.
+    #[arg(
+        long,
+        value_name = "DAYS",
+        value_parser = clap::value_parser!(i64).range(0..=duration::MAX_DAYS),
+        conflicts_with = "keep",
+        env = "JANITOR_KEEP_DAYS",
+        hide = true,
+        global = true
+    )]
+    keep_days: Option<i64>,
+
+    /// Keep generations that have been active within this duration, e.g.
+    /// `2w` or `36h`, or on or after this date, e.g. `2024-05-01` or
+    /// `2024-05-01 12:00:00` in UTC.
+    ///
+    /// Overrides the default of each profile kind.
+    #[arg(
+        long,
+        value_name = "DURATION|DATE",
+        value_parser = parse_keep_since,
+        conflicts_with_all = ["keep", "keep_days"],
+        env = "JANITOR_KEEP_SINCE",
+        global = true
+    )]
+    keep_since: Option<KeepSince>,
+
+    /// Keep at least this many of the most recent generations.
+    ///
+    /// Overrides the default of each profile kind.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "JANITOR_KEEP_AT_LEAST",
+        global = true
+    )]
+    keep_at_least: Option<usize>,
+
+    /// Keep at most this many of the most recent generations, even if they
+    /// have been active within the `keep` duration.
+    ///
+    /// Must not be below `keep-at-least`. Generations kept for their own
+    /// safety, like the current one, are kept regardless.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "JANITOR_KEEP_AT_MOST",
+        global = true
+    )]
+    keep_at_most: Option<usize>,
+
+    /// Pick the most recent generations kept by `keep-at-least` by `id` or
+    /// by `date`.
+    ///
+    /// The order by id is the order of creation, which differs from the one
+    /// by date when an older generation has been re-activated, e.g. after a
+    /// rollback. Overrides the default of each profile kind, which is `id`.
+    #[arg(long, value_name = "ORDER", value_parser = parse_order, global = true)]
+    order_by: Option<GenerationOrder>,
+
+    /// Keep the last generation of each of the COUNT most recent days with
+    /// generations.
+    ///
+    /// Together with `keep-weekly` and `keep-monthly` this keeps a sparse
+    /// history reaching further back than `keep`. Overrides the thinning of
+    /// the configuration as a whole, periods not given keep nothing.
+    #[arg(long, value_name = "COUNT", env = "JANITOR_KEEP_DAILY", global = true)]
+    keep_daily: Option<usize>,
+
+    /// Keep the last generation of each of the COUNT most recent weeks with
+    /// generations, see `keep-daily`.
+    #[arg(long, value_name = "COUNT", env = "JANITOR_KEEP_WEEKLY", global = true)]
+    keep_weekly: Option<usize>,
+
+    /// Keep the last generation of each of the COUNT most recent months
+    /// with generations, see `keep-daily`.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "JANITOR_KEEP_MONTHLY",
+        global = true
+    )]
+    keep_monthly: Option<usize>,
+
+    /// Delete all generations with an id below ID, unless current or active
+    /// within the `keep` duration, even beyond `keep-at-least`.
+    ///
+    /// Prefix the id with the path of a profile and `=` to only apply it to
+    /// that profile. May be given several times, the setting for a specific
+    /// profile takes precedence.
+    #[arg(long, value_name = "[PROFILE=]ID")]
+    pub older_than_generation: Vec<GenerationCutoff>,
+
+    /// Never delete the generation ID, no matter what the retention policy
+    /// says about it.
+    ///
+    /// Prefix the id with the path of a profile and `:` to only pin it in
+    /// that profile. May be given several times. Adds to the pins in the
+    /// `pins` file next to the state.
+    #[arg(long, value_name = "[PROFILE:]ID", global = true)]
+    pub pin: Vec<Pin>,
+
+    /// Keep generations that look like the profile has been rolled back to
+    /// them: the current one if newer ones exist, and any dated before the
+    /// generation preceding it.
+    #[arg(long, global = true)]
+    protect_rollbacks: bool,
+
+    /// Run the garbage collector after deleting generations.
+    #[arg(long, env = "JANITOR_GC", global = true)]
+    gc: bool,
+
+    /// Skip the garbage collection if the system collects garbage on its
+    /// own with `nix.gc.automatic` of NixOS or nix-darwin, and only delete
+    /// generations.
+    #[arg(long, global = true)]
+    defer_to_system_gc: bool,
+
+    /// Append every store path deleted by the garbage collector to this
+    /// file, one per line.
+    #[arg(long, value_name = "PATH", global = true)]
+    pub gc_log: Option<PathBuf>,
+
+    /// Only log every this many store paths deleted by the garbage
+    /// collector, together with the number deleted so far.
+    #[arg(long, value_name = "COUNT", global = true)]
+    pub gc_log_every: Option<NonZeroU64>,
+
+    /// Append every cleanup, with the profiles cleaned up and the
+    /// generations deleted, to this SQLite database, see `janitor query`.
+    ///
+    /// Requires the `sqlite3` shell.
+    #[cfg(feature = "report-db")]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub report_db: Option<PathBuf>,
+
+    /// Verify the consistency of the nix store after the cleanup and report
+    /// any inconsistencies found.
+    #[arg(long, global = true)]
+    pub verify_store: bool,
+
+    /// Verify the nix store including contents and attempt to repair it,
+    /// reporting the repaired paths. Requires root and confirmation.
+    #[arg(long, conflicts_with = "verify_store", global = true)]
+    pub verify_repair: bool,
+
+    /// Free up at least this much space in the nix store, e.g. `20GiB`.
+    ///
+    /// Profiles are processed in the order that reclaims the most space
+    /// first. Generations are deleted a few at a time, oldest first, each
+    /// batch followed by a garbage collection, and the run stops deleting
+    /// generations as soon as the target is met. The retention policies are
+    /// respected even if that means missing the target.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, global = true)]
+    pub free_at_least: Option<u64>,
+
+    /// Keep the generations retained for each user within this much space,
+    /// e.g. `20GiB`. Only takes effect when running as root.
+    ///
+    /// The retained generations of the user, profiles of the system and of
+    /// containers aside, are sized by their closures. If they take up more
+    /// than the budget, the retention of the user's profiles is tightened
+    /// one generation at a time, oldest first, until they fit or only
+    /// `--per-user-floor` generations are left per profile. The users
+    /// exceeding their budget are reported.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, global = true)]
+    pub per_user_budget: Option<u64>,
+
+    /// Never tighten the retention of a profile for `--per-user-budget` below
+    /// this many of its most recent generations. Defaults to 1.
+    #[arg(long, value_name = "N", global = true)]
+    pub per_user_floor: Option<usize>,
+
+    /// Look for entries of the user and system flake registries pinned to
+    /// store paths without garbage collector roots after the cleanup, and
+    /// report, remove or re-pin them.
+    ///
+    /// Entries of a system registry managed by NixOS are only reported.
+    #[arg(long, value_name = "ACTION", global = true)]
+    pub stale_pins: Option<StalePins>,
+
+    /// Keep at least as many system generations as the bootloader lists, its
+    /// `configurationLimit`, so that every boot entry stays bootable.
+    #[arg(long, global = true)]
+    align_boot_limit: bool,
+
+    /// Delete generations from all profiles or from none: list and plan
+    /// every profile and check that its directory is writable and its lock
+    /// can be taken before deleting anything, and abort the run if any
+    /// profile fails these checks.
+    #[arg(long, global = true)]
+    atomic: bool,
+
+    /// Abort the run as soon as cleaning up a profile fails.
+    ///
+    /// Without it, the other profiles are cleaned up nonetheless, and the
+    /// failed ones are summarized at the end of a run that fails.
+    #[arg(long, global = true)]
+    fail_fast: bool,
+
+    /// Finish the current step and skip the remaining ones once the run
+    /// takes longer than this, e.g. `30m`.
+    ///
+    /// Profiles not started by then are left alone, and so are the garbage
+    /// collection and the steps after it. The run exits with the code 3
+    /// then.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, global = true)]
+    pub max_runtime: Option<Duration>,
+
+    /// Process up to this many profiles at once, defaults to 4.
+    ///
+    /// Every profile being listed or cleaned up runs its own `nix-env`, all
+    /// of them contending for the lock of the nix database. Lower it when
+    /// cleaning up the profiles of many users. Without the tokio runtime,
+    /// profiles are always processed one after another.
+    #[arg(long, short = 'j', value_name = "N", global = true)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Explain in the summary why nothing has been deleted from a profile,
+    /// for every such profile.
+    ///
+    /// Without it, this is only explained when nothing has been deleted from
+    /// any profile.
+    #[arg(long, global = true)]
+    explain_skip: bool,
+
+    /// Fail the run on conditions that are otherwise only warned about, like
+    /// profile patterns matching no profile or output of the garbage
+    /// collector that is not recognized.
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Assume "yes" for all confirmations.
+    ///
+    /// Destructive modes like `--verify-repair` or `--delete-old` ask for
+    /// confirmation, and only print the plan of the run without it when
+    /// stdin is not a terminal. Also skips the questions of `--interactive`.
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+
+    /// Print the generations that would be deleted and ask for confirmation
+    /// before deleting them, `once` for all profiles or for each `profile`.
+    ///
+    /// Requires stdin and stdout to be terminals.
+    #[arg(
+        long,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "once",
+        global = true
+    )]
+    interactive: Option<Interactive>,
+
+    /// Clean up even from within a nix build, a nix shell or a CI pipeline,
+    /// which the janitor refuses to by default.
+    #[arg(long, global = true)]
+    pub i_know_what_i_am_doing: bool,
+
+    /// List the profiles and plan the deletions as usual, then print which
+    /// generations would be deleted and which kept, without deleting
+    /// anything or collecting garbage.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Print the plan of `janitor plan` or `--dry-run` as a Nix attribute
+    /// set instead, e.g. to import it in a deployment check.
+    ///
+    /// The attribute set maps the path of every profile to the ids of the
+    /// generations to `delete` and to `keep`, and the id of the `current`
+    /// generation, `null` if there is none.
+    #[arg(long, global = true)]
+    pub emit_nix: bool,
+
+    /// Print worked examples of the retention policies resulting from the
+    /// given options and exit without touching any profile.
+    #[arg(long, global = true)]
+    pub explain_policy: bool,
+
+    /// Take this as the current time instead of the clock, e.g.
+    /// `2023-06-03T04:12:00Z`, or `2023-06-03 04:12:00` in UTC.
+    #[arg(long, value_name = "TIME", value_parser = parse_now, global = true)]
+    pub now: Option<DateTime<Utc>>,
+
+    /// Make runs reproducible for tests: process the profiles one after
+    /// another, never use the listing cache or jitter, sort the report and
+    /// log without timestamps, timings or resource usage.
+    ///
+    /// Requires `--now` to pin the clock.
+    #[arg(long, requires = "now", global = true)]
+    pub deterministic: bool,
+
+    /// Process the profiles one after another without an async runtime.
+    ///
+    /// Always on when built without the `tokio` feature.
+    #[arg(long, global = true)]
+    pub blocking: bool,
+
+    /// How the async runtime schedules its tasks, `current-thread` keeps the
+    /// janitor, especially the daemon, lightweight.
+    #[cfg(feature = "tokio")]
+    #[arg(long, value_name = "FLAVOR", global = true)]
+    pub runtime: Option<Flavor>,
+
+    /// The number of worker threads of a `multi-thread` runtime instead of
+    /// one per core.
+    #[cfg(feature = "tokio")]
+    #[arg(long, value_name = "COUNT", global = true)]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// Only clean up if a window of the configured `schedule` has started
+    /// since the last successful cleanup, e.g. because the machine was off.
+    ///
+    /// With `serve`, clean up right away in that case, then follow the
+    /// schedule.
+    #[arg(long, global = true)]
+    pub catch_up: bool,
+
+    /// Collect garbage regardless of the battery and the power hook of the
+    /// configuration.
+    #[arg(long, global = true)]
+    pub ignore_power: bool,
+
+    /// Only collect garbage while holding one of the slots of the lock in
+    /// this directory on shared storage, e.g. `/mnt/shared/janitor-gc` or
+    /// `file:///mnt/shared/janitor-gc`.
+    ///
+    /// Keeps a fleet of machines sharing a binary cache or an NFS-backed
+    /// store from collecting garbage all at once. The garbage collection is
+    /// skipped while all slots are held, `--free-at-least` fails instead.
+    #[arg(long, value_name = "LOCATION", value_parser = gc_lock::parse_location, global = true)]
+    pub gc_lock: Option<PathBuf>,
+
+    /// How many machines may collect garbage at once with `--gc-lock`,
+    /// defaults to 1.
+    #[arg(long, value_name = "COUNT", global = true)]
+    pub gc_lock_slots: Option<NonZeroUsize>,
+
+    /// Always list the generations of the profiles, even if they have not
+    /// been modified since the listing of an earlier run.
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+
+    /// Query the sizes of closures again instead of using the sizes cached
+    /// by earlier runs.
+    #[arg(long, global = true)]
+    pub refresh_sizes: bool,
+
+    /// Replace user and host names in the log, the output and errors with
+    /// stable pseudonyms, so that they can be shared publicly.
+    #[arg(long, global = true)]
+    pub redact: bool,
+
+    /// Read the configuration from this file instead of the default locations.
+    #[arg(long, value_name = "PATH", env = "JANITOR_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Keep the state between runs in this file instead of the default
+    /// location.
+    #[arg(long, value_name = "PATH", env = "JANITOR_STATE", global = true)]
+    pub state: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands other than cleaning up the profiles.
+#[derive(Debug, Clone, Subcommand, PartialEq, Eq)]
+pub enum Command {
+    /// Delete the generations of the profiles that the retention policies
+    /// do not keep, and collect garbage if asked to. The default without a
+    /// command.
+    Clean,
+
+    /// Collect garbage without deleting any generation.
+    Gc,
+
+    /// List the generations of every profile that would be cleaned up, with
+    /// their dates, marking the current ones.
+    List,
+
+    /// Print which generations a cleanup would delete and which it would
+    /// keep, without deleting anything, like `--dry-run`.
+    Plan {
+        /// Also write the plan to this file, to be reviewed and then carried
+        /// out with `janitor apply`.
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+
+    /// Delete exactly the generations planned by `janitor plan --out`,
+    /// refusing if generations of a profile have been created, deleted or
+    /// switched to since. The retention settings are taken from the plan,
+    /// everything else, e.g. `--gc`, from the command line and the
+    /// configuration.
+    Apply {
+        /// The file written by `janitor plan --out`.
+        plan: PathBuf,
+    },
+
+    /// Measure how long listing, deleting and garbage collection take with
+    /// each backend, and how fast the output of a huge garbage collection is
+    /// parsed, without modifying anything, and remember the fastest listing
+    /// backend for future runs.
+    Bench {
+        /// Repeat each measurement this many times, keeping the fastest.
+        #[arg(long, default_value_t = 3)]
+        iterations: usize,
+    },
+
+    /// Clean up the profiles named by jobs read from stdin, one JSON object
+    /// per line like `{"profile": "/nix/var/nix/profiles/system",
+    /// "keep_days": 3}`, answering each with a JSON object on a line of
+    /// stdout.
+    ///
+    /// A job may set `keep`, `keep_days` or `keep_since`, and
+    /// `keep_at_least`, taking precedence over the other retention settings.
+    /// Garbage is collected once after all jobs. The log is written to
+    /// stderr.
+    Batch,
+
+    /// Print everything known about a single generation: its date, age,
+    /// label, size and store path, whether it is current, booted or pinned,
+    /// and whether the current policy keeps or deletes it, and why.
+    Explain {
+        /// The id of the generation.
+        generation: u32,
+
+        /// The profile the generation belongs to.
+        #[arg(long, value_name = "PATH")]
+        profile: PathBuf,
+    },
+
+    /// List every profile with each backend and report the generations the
+    /// listings disagree on, hinting at corrupted profiles, without
+    /// modifying anything. The integrations of the platform the janitor
+    /// detected are reported as well.
+    Doctor,
+
+    /// Create a throwaway profile with a few generations, clean it up with
+    /// the configured backend and check what is left, validating the
+    /// installation and the permissions without touching any real profile.
+    Selftest,
+
+    /// Answer a question about past cleanups from the database given with
+    /// `--report-db`.
+    #[cfg(feature = "report-db")]
+    Query {
+        /// The question to answer.
+        query: CannedQuery,
+    },
+
+    /// Print when the last cleanup succeeded, and how many generations have
+    /// been deleted and how much space has been freed per week recently.
+    Status,
+
+    /// Ask about the retention, the profiles to clean up, the garbage
+    /// collection and a timer, then write the configuration file and print
+    /// the next steps.
+    Init,
+
+    /// Stand in for one of the classic nix tools, accepting its options.
+    Compat {
+        #[command(subcommand)]
+        tool: Compat,
+    },
+
+    /// Keep running and clean up the profiles on request, using the options
+    /// given on the command line and in the configuration.
+    ///
+    /// The daemon is controlled via a unix socket, see `janitor ctl`.
+    #[cfg(feature = "tokio")]
+    Serve {
+        /// Also offer the cleanup as a DBus service, on the system bus when
+        /// run as root, otherwise on the session bus.
+        #[arg(long)]
+        dbus: bool,
+
+        /// Listen for control commands on this socket instead of the default
+        /// location.
+        #[arg(long, value_name = "PATH", env = "JANITOR_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Control a running `janitor serve`.
+    #[cfg(feature = "tokio")]
+    Ctl {
+        /// What the daemon should do.
+        request: Request,
+
+        /// Connect to the daemon at this socket instead of the default
+        /// location.
+        #[arg(long, value_name = "PATH", env = "JANITOR_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+}
+
+/// The generation below which everything is deleted, for a single or for
+/// all profiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationCutoff {
+    /// The profile the cutoff applies to, all profiles if `None`.
+    pub profile: Option<PathBuf>,
+
+    /// The id of the first generation not deleted by the cutoff.
+    pub id: u32,
+}
+
+impl fmt::Display for GenerationCutoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.profile {
+            Some(profile) => write!(f, "{}={}", profile.display(), self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl FromStr for GenerationCutoff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (profile, id) = match s.rsplit_once('=') {
+            Some((profile, id)) => (Some(PathBuf::from(profile)), id),
+            None => (None, s),
+        };
+
+        if profile
+            .as_ref()
+            .is_some_and(|profile| !profile.is_absolute())
+        {
+            return Err(format!("profile path in {s:?} is not absolute"));
+        }
+        let id = id
+            .parse()
+            .map_err(|_| format!("invalid generation id in {s:?}"))?;
+
+        Ok(Self { profile, id })
+    }
+}
+
+impl NJParser {
+    /// The explicit choice about the system profile made on the command line,
+    /// if any.
+    pub fn system(&self) -> Option<bool> {
+        match (self.system, self.no_system) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Whether protecting rollback targets has been requested on the command
+    /// line.
+    pub fn protect_rollbacks(&self) -> Option<bool> {
+        self.protect_rollbacks.then_some(true)
+    }
+
+    /// Whether garbage collection has been requested on the command line.
+    pub fn gc(&self) -> Option<bool> {
+        self.gc.then_some(true)
+    }
+
+    /// Whether `--defer-to-system-gc` has been given, `None` to leave it to
+    /// the config.
+    pub fn defer_to_system_gc(&self) -> Option<bool> {
+        self.defer_to_system_gc.then_some(true)
+    }
+
+    /// Whether `--align-boot-limit` has been given, `None` to leave it to the
+    /// config.
+    pub fn align_boot_limit(&self) -> Option<bool> {
+        self.align_boot_limit.then_some(true)
+    }
+
+    /// Whether `--all-users` has been given, `None` to leave it to the
+    /// config.
+    pub fn all_users(&self) -> Option<bool> {
+        self.all_users.then_some(true)
+    }
+
+    /// Whether `--atomic` has been given, `None` to leave it to the config.
+    pub fn atomic(&self) -> Option<bool> {
+        self.atomic.then_some(true)
+    }
+
+    /// Whether `--fail-fast` has been given, `None` to leave it to the
+    /// config.
+    pub fn fail_fast(&self) -> Option<bool> {
+        self.fail_fast.then_some(true)
+    }
+
+    /// Whether `--explain-skip` has been given, `None` to leave it to the
+    /// config.
+    pub fn explain_skip(&self) -> Option<bool> {
+        self.explain_skip.then_some(true)
+    }
+
+    /// Whether `--strict` has been given, `None` to leave it to the config.
+    pub fn strict(&self) -> Option<bool> {
+        self.strict.then_some(true)
+    }
+
+    /// Whether `--yes` has been given, `None` to leave it to the config.
+    pub fn yes(&self) -> Option<bool> {
+        self.yes.then_some(true)
+    }
+
+    /// When to ask before deleting generations, `None` if not asked to or
+    /// `--yes` has been given.
+    pub fn interactive(&self) -> Option<Interactive> {
+        self.interactive.filter(|_| !self.yes)
+    }
+
+    /// The retention settings given on the command line.
+    pub fn retention(&self) -> RetentionOverrides {
+        let (keep, keep_since) = match self.keep_since {
+            Some(KeepSince::Within(keep)) => (Some(keep), None),
+            Some(KeepSince::Date(date)) => (None, Some(date)),
+            None => (self.keep.or(self.keep_days.map(Duration::days)), None),
+        };
+
+        RetentionOverrides {
+            keep,
+            keep_since,
+            keep_at_least: self.keep_at_least,
+            keep_at_most: self.keep_at_most,
+            order_by: self.order_by,
+            thinning: config::thinning(self.keep_daily, self.keep_weekly, self.keep_monthly),
+        }
+    }
+}
+
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    duration::parse_duration(input).map_err(|e| e.to_string())
+}
+
+pub fn parse_keep_since(input: &str) -> Result<KeepSince, String> {
+    duration::parse_keep_since(input).map_err(|e| e.to_string())
+}
+
+pub fn parse_now(input: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(input) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"]
+        .into_iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(input, format).ok())
+        .map(|time| time.and_utc())
+        .ok_or_else(|| format!("invalid time: {input}, expected e.g. 2023-06-03T04:12:00Z"))
+}
+
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    size::parse_size(input).map_err(|e| e.to_string())
+}
+
+pub fn parse_order(input: &str) -> Result<GenerationOrder, String> {
+    input.parse().map_err(|e: JanitorError| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::none(&["janitor"], None)]
+    #[case::system(&["janitor", "--system"], Some(true))]
+    #[case::no_system(&["janitor", "--no-system"], Some(false))]
+    #[case::last_one_wins(&["janitor", "--system", "--no-system"], Some(false))]
+    #[case::last_one_wins_reverse(&["janitor", "--no-system", "--system"], Some(true))]
+    fn system_flag(#[case] args: &[&str], #[case] expected: Option<bool>) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(parsed.system(), expected);
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None, None)]
+    #[case::days(&["janitor", "--keep-days", "3"], Some(Duration::days(3)), None)]
+    #[case::zero_days(&["janitor", "--keep-days", "0"], Some(Duration::zero()), None)]
+    #[case::hours(&["janitor", "--keep", "36h"], Some(Duration::hours(36)), None)]
+    #[case::combined(&["janitor", "--keep", "2d12h"], Some(Duration::hours(60)), None)]
+    #[case::at_least(&["janitor", "--keep-at-least", "2"], None, Some(2))]
+    fn retention(
+        #[case] args: &[&str],
+        #[case] keep: Option<Duration>,
+        #[case] keep_at_least: Option<usize>,
+    ) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(
+            parsed.retention(),
+            RetentionOverrides {
+                keep,
+                keep_at_least,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[rstest]
+    #[case::weeks(&["janitor", "--keep-since", "2w"], Some(Duration::weeks(2)), None)]
+    #[case::hours(&["janitor", "--keep-since", "36h"], Some(Duration::hours(36)), None)]
+    #[case::date(&["janitor", "--keep-since", "2024-05-01"], None, Some("2024-05-01 00:00:00"))]
+    #[case::date_time(
+        &["janitor", "clean", "--keep-since", "2024-05-01 12:00:00"],
+        None,
+        Some("2024-05-01 12:00:00")
+    )]
+    fn keep_since(
+        #[case] args: &[&str],
+        #[case] keep: Option<Duration>,
+        #[case] since: Option<&str>,
+    ) {
+        let since =
+            since.map(|since| NaiveDateTime::parse_from_str(since, "%Y-%m-%d %H:%M:%S").unwrap());
+
+        let retention = NJParser::parse_from(args).retention();
+
+        assert_eq!(retention.keep, keep);
+        assert_eq!(retention.keep_since, since);
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None, None)]
+    #[case::once(&["janitor", "--interactive"], Some(Interactive::Once), None)]
+    #[case::before_command(
+        &["janitor", "--interactive", "clean"],
+        Some(Interactive::Once),
+        Some(Command::Clean)
+    )]
+    #[case::profile(&["janitor", "--interactive=profile"], Some(Interactive::Profile), None)]
+    #[case::yes(&["janitor", "--interactive", "--yes"], None, None)]
+    fn interactive(
+        #[case] args: &[&str],
+        #[case] expected: Option<Interactive>,
+        #[case] command: Option<Command>,
+    ) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(parsed.interactive(), expected);
+        assert_eq!(parsed.command, command);
+    }
+
+    #[rstest]
+    #[case::id(&["janitor", "--order-by", "id"], Some(GenerationOrder::Id))]
+    #[case::date(&["janitor", "--order-by", "date"], Some(GenerationOrder::Date))]
+    #[case::none(&["janitor"], None)]
+    fn order_by(#[case] args: &[&str], #[case] expected: Option<GenerationOrder>) {
+        assert_eq!(NJParser::parse_from(args).retention().order_by, expected);
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None)]
+    #[case::weekly(&["janitor", "--keep-weekly", "4"], Some((0, 4, 0)))]
+    #[case::all(
+        &["janitor", "--keep-daily", "7", "--keep-weekly", "4", "--keep-monthly", "12", "plan"],
+        Some((7, 4, 12))
+    )]
+    fn thinning(#[case] args: &[&str], #[case] expected: Option<(usize, usize, usize)>) {
+        let thinning = NJParser::parse_from(args).retention().thinning;
+
+        assert_eq!(
+            thinning.map(|t| (t.keep_daily, t.keep_weekly, t.keep_monthly)),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None)]
+    #[case::at_most(&["janitor", "--keep-at-most", "20", "plan"], Some(20))]
+    fn keep_at_most(#[case] args: &[&str], #[case] expected: Option<usize>) {
+        assert_eq!(
+            NJParser::parse_from(args).retention().keep_at_most,
+            expected
+        );
+    }
+
+    #[test]
+    fn order_by_rejects_unknown() {
+        assert!(NJParser::try_parse_from(["janitor", "--order-by", "size"]).is_err());
+    }
+
+    #[rstest]
+    #[case::clean(&["janitor", "clean", "--keep", "3d", "--gc"], Some(Command::Clean))]
+    #[case::gc(&["janitor", "gc"], Some(Command::Gc))]
+    #[case::list(&["janitor", "list"], Some(Command::List))]
+    #[case::batch(&["janitor", "--dry-run", "batch"], Some(Command::Batch))]
+    #[case::plan(&["janitor", "--keep-at-least", "2", "plan"], Some(Command::Plan { out: None }))]
+    #[case::plan_out(
+        &["janitor", "plan", "--out", "plan.json"],
+        Some(Command::Plan { out: Some(PathBuf::from("plan.json")) })
+    )]
+    #[case::apply(
+        &["janitor", "apply", "plan.json"],
+        Some(Command::Apply { plan: PathBuf::from("plan.json") })
+    )]
+    #[case::default(&["janitor", "--keep", "3d"], None)]
+    fn commands(#[case] args: &[&str], #[case] expected: Option<Command>) {
+        assert_eq!(NJParser::parse_from(args).command, expected);
+    }
+
+    #[rstest]
+    #[case::negative(&["janitor", "--keep-days", "-1"])]
+    #[case::negative_equals(&["janitor", "--keep-days=-7"])]
+    #[case::negative_duration(&["janitor", "--keep=-7d"])]
+    #[case::days_overflow(&["janitor", "--keep-days", "999999999999999"])]
+    #[case::duration_overflow(&["janitor", "--keep", "100000000d"])]
+    #[case::without_unit(&["janitor", "--keep", "7"])]
+    #[case::both(&["janitor", "--keep", "7d", "--keep-days", "7"])]
+    #[case::since_and_keep(&["janitor", "--keep", "7d", "--keep-since", "7d"])]
+    #[case::since_and_days(&["janitor", "--keep-days", "7", "--keep-since", "7d"])]
+    #[case::since_bare_number(&["janitor", "--keep-since", "7"])]
+    #[case::since_invalid_date(&["janitor", "--keep-since", "2024-13-01"])]
+    fn keep_rejects_invalid(#[case] args: &[&str]) {
+        assert!(NJParser::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn all_users_conflicts_with_profile() {
+        assert!(NJParser::try_parse_from(["janitor", "--all-users", "--profile", "/p/*"]).is_err());
+        assert_eq!(
+            NJParser::parse_from(["janitor", "--all-users"]).all_users(),
+            Some(true)
+        );
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None)]
+    #[case::bytes(&["janitor", "--free-at-least", "1000"], Some(1000))]
+    #[case::gib(&["janitor", "--free-at-least", "20GiB"], Some(20 << 30))]
+    fn free_at_least(#[case] args: &[&str], #[case] expected: Option<u64>) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(parsed.free_at_least, expected);
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None, None)]
+    #[case::budget(&["janitor", "--per-user-budget", "20GiB"], Some(20 << 30), None)]
+    #[case::floor(
+        &["janitor", "--per-user-budget", "1GiB", "--per-user-floor", "3"],
+        Some(1 << 30),
+        Some(3)
+    )]
+    fn per_user_budget(
+        #[case] args: &[&str],
+        #[case] budget: Option<u64>,
+        #[case] floor: Option<usize>,
+    ) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(parsed.per_user_budget, budget);
+        assert_eq!(parsed.per_user_floor, floor);
+    }
+
+    #[test]
+    fn free_at_least_rejects_garbage() {
+        assert!(NJParser::try_parse_from(["janitor", "--free-at-least", "lots"]).is_err());
+    }
+
+    #[rstest]
+    #[case::rfc3339("2023-06-03T06:12:00+02:00")]
+    #[case::utc("2023-06-03T04:12:00Z")]
+    #[case::naive("2023-06-03 04:12:00")]
+    #[case::naive_t("2023-06-03T04:12:00")]
+    fn now(#[case] input: &str) {
+        let parsed = NJParser::parse_from(["janitor", "--now", input]);
+
+        assert_eq!(parsed.now, "2023-06-03T04:12:00Z".parse().ok());
+    }
+
+    #[rstest]
+    #[case::invalid_now(&["janitor", "--now", "yesterday"])]
+    #[case::deterministic_without_now(&["janitor", "--deterministic"])]
+    fn deterministic_rejects(#[case] args: &[&str]) {
+        assert!(NJParser::try_parse_from(args).is_err());
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], None)]
+    #[case::bench(&["janitor", "bench"], Some(Command::Bench { iterations: 3 }))]
+    #[case::bench_iterations(
+        &["janitor", "bench", "--iterations", "5"],
+        Some(Command::Bench { iterations: 5 })
+    )]
+    fn command(#[case] args: &[&str], #[case] expected: Option<Command>) {
+        let parsed = NJParser::parse_from(args);
+
+        assert_eq!(parsed.command, expected);
+    }
+
+    #[rstest]
+    #[case::all(&["janitor", "--older-than-generation", "120"], &[(None, 120)])]
+    #[case::per_profile(
+        &["janitor", "--older-than-generation", "/nix/var/nix/profiles/system=120", "--older-than-generation", "30"],
+        &[(Some("/nix/var/nix/profiles/system"), 120), (None, 30)]
+    )]
+    fn older_than_generation(#[case] args: &[&str], #[case] expected: &[(Option<&str>, u32)]) {
+        let parsed = NJParser::parse_from(args);
+
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(profile, id)| GenerationCutoff {
+                profile: profile.map(PathBuf::from),
+                id: *id,
+            })
+            .collect();
+        assert_eq!(parsed.older_than_generation, expected);
+    }
+
+    #[rstest]
+    #[case::relative(&["janitor", "--older-than-generation", "profile=3"])]
+    #[case::negative(&["janitor", "--older-than-generation", "-3"])]
+    #[case::garbage(&["janitor", "--older-than-generation", "/p=x"])]
+    fn older_than_generation_rejects(#[case] args: &[&str]) {
+        assert!(NJParser::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn pins() {
+        let pins = |args: &[&str]| -> Vec<String> {
+            let parsed = NJParser::parse_from(args);
+            parsed.pin.iter().map(ToString::to_string).collect()
+        };
+
+        assert_eq!(
+            pins(&[
+                "janitor",
+                "--pin",
+                "/nix/var/nix/profiles/system:412",
+                "--pin",
+                "7"
+            ]),
+            ["/nix/var/nix/profiles/system:412", "7"]
+        );
+        assert_eq!(pins(&["janitor", "batch", "--pin", "7"]), ["7"]);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn serve_command() {
+        let parsed = NJParser::parse_from([
+            "janitor",
+            "serve",
+            "--dbus",
+            "--socket",
+            "/ctl",
+            "--catch-up",
+        ]);
+
+        assert!(parsed.catch_up);
+        assert_eq!(
+            parsed.command,
+            Some(Command::Serve {
+                dbus: true,
+                socket: Some(PathBuf::from("/ctl"))
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn ctl_command() {
+        let parsed = NJParser::parse_from(["janitor", "ctl", "pause"]);
+
+        assert_eq!(
+            parsed.command,
+            Some(Command::Ctl {
+                request: Request::Pause,
+                socket: None
+            })
+        );
+    }
+}
@@ -0,0 +1,1957 @@
+mod batch;
+mod bench;
+mod boot;
+mod cache;
+mod commands;
+mod compat;
+mod config;
+#[cfg(feature = "tokio")]
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod doctor;
+mod explain;
+mod gc_lock;
+mod guard;
+mod history;
+mod init;
+mod interface;
+mod pins;
+mod pipeline;
+mod plan;
+mod power;
+mod preflight;
+mod prompt;
+mod quota;
+mod redact;
+mod registry;
+#[cfg(feature = "report-db")]
+mod report_db;
+mod run_id;
+#[cfg(feature = "tokio")]
+mod runtime;
+mod selftest;
+#[cfg(feature = "tokio")]
+mod serve;
+mod size_cache;
+mod state;
+mod system_gc;
+#[cfg(feature = "tokio")]
+mod tasks;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fmt,
+    io::{self, IsTerminal},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Mutex,
+};
+
+use chrono::prelude::*;
+use clap::Parser;
+use eyre::{bail, Result};
+use tracing::Level;
+use tracing_subscriber::{fmt::format::FmtSpan, FmtSubscriber};
+
+use janitor::{
+    duration::format_duration,
+    nix_store::DeletionLog,
+    platform::Platform,
+    schedule::Schedule,
+    size::{self, SizeEstimation},
+    state::Discovered,
+    system_by_default, Backend, Exceptions, Explanation, Job, Profile, ProfileKind, ProfileReport,
+    Progress, RetentionOverrides, RetentionPolicy, RunReport,
+};
+#[cfg(feature = "report-db")]
+use janitor::{Blocking, StdExecutor};
+
+#[cfg(feature = "tokio")]
+use crate::runtime::RuntimeOptions;
+use crate::{
+    boot::BootLimit,
+    cache::ListingCache,
+    commands::{Context, Driver},
+    compat::Compat,
+    config::{Config, ProfileSelector},
+    gc_lock::GcLock,
+    history::{Run, Trend},
+    interface::{Command, GenerationCutoff, NJParser},
+    pins::Pin,
+    power::PowerPolicy,
+    prompt::{Consent, Interactive},
+    redact::Redactor,
+    registry::StalePins,
+    run_id::RunId,
+    size_cache::SizeCache,
+    state::State,
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Why profiles [managed by the system](Profile::managed_by_system) are
+/// skipped.
+pub const MANAGED_BY_SYSTEM: &str =
+    "managed by the system generation through users.users.<name>.packages";
+
+/// The exit code of a run that exceeded `--max-runtime` and skipped some of
+/// its steps.
+pub const PARTIAL_SUCCESS: u8 = 3;
+
+/// The run exceeded `--max-runtime` and skipped the steps in it, exiting
+/// with [PARTIAL_SUCCESS].
+#[derive(Debug)]
+pub struct PartialRun(pub Vec<String>);
+
+impl fmt::Display for PartialRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "exceeded the maximum runtime, skipped {}",
+            self.0.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for PartialRun {}
+
+/// Options controlling the steps of a run beyond cleaning up the profiles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    /// Run the garbage collector after deleting generations.
+    pub gc: bool,
+
+    /// Verify the store after the cleanup.
+    pub verify_store: bool,
+
+    /// Verify and repair the store after the cleanup.
+    pub repair_store: bool,
+
+    /// The backend used to list generations.
+    pub backend: Backend,
+
+    /// Delete generations in batches, collecting garbage after each, until
+    /// this many bytes are free in the store.
+    pub free_at_least: Option<u64>,
+
+    /// Tighten the retention of the profiles of each user whose retained
+    /// generations take up more than this many bytes, see [quota].
+    pub per_user_budget: Option<u64>,
+
+    /// Never tighten the retention of a profile below this many of its most
+    /// recent generations.
+    pub per_user_floor: usize,
+
+    /// Deal with registry entries pinned to store paths without roots after
+    /// the cleanup.
+    pub stale_pins: Option<StalePins>,
+
+    /// Stop the garbage collection after freeing this many bytes.
+    pub max_freed: Option<u64>,
+
+    /// How the store paths deleted by the garbage collector are logged.
+    pub gc_log: DeletionLog,
+
+    /// When the garbage collection is skipped to save power.
+    pub power: PowerPolicy,
+
+    /// The lock limiting how many machines collect garbage at once, if any.
+    pub gc_lock: Option<GcLock>,
+
+    /// Validate all profiles before deleting from any of them, and delete
+    /// nothing if one fails.
+    pub atomic: bool,
+
+    /// Abort the run when cleaning up a profile fails, instead of cleaning
+    /// up the others and failing at the end.
+    pub fail_fast: bool,
+
+    /// Skip the remaining steps once the run takes longer than this.
+    pub max_runtime: Option<chrono::Duration>,
+
+    /// How many profiles are processed at once, the default of the pipeline
+    /// if `None`.
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Fail the run on conditions that are otherwise only warned about.
+    pub strict: bool,
+
+    /// How the retention of the system profile relates to the bootloader.
+    pub boot: BootLimit,
+
+    /// Skip the garbage collection if the system collects garbage on its
+    /// own.
+    pub defer_to_system_gc: bool,
+
+    /// How the sizes of generations are determined.
+    pub size_estimation: SizeEstimation,
+
+    /// When to ask before deleting generations, never if `None`.
+    pub interactive: Option<Interactive>,
+
+    /// The callbacks notified of deleted generations and store paths.
+    pub progress: Progress,
+}
+
+/// Everything a cleanup needs, resolved from the command line and the
+/// configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// Whether the system profile is cleaned up.
+    pub include_system: bool,
+
+    /// Patterns of the profiles cleaned up instead of the default ones.
+    pub profile_patterns: Vec<String>,
+
+    /// Patterns of the profiles never cleaned up.
+    pub exclude_patterns: Vec<String>,
+
+    /// Whether the profiles of all users are cleaned up by default, instead
+    /// of only the ones of the invoking user.
+    pub all_users: bool,
+
+    /// Retention settings taking precedence over the defaults of each
+    /// profile kind.
+    pub overrides: RetentionOverrides,
+
+    /// Retention settings for the system profiles of single
+    /// nixos-containers, by container name, taking precedence over
+    /// `overrides`.
+    pub container_overrides: BTreeMap<String, RetentionOverrides>,
+
+    /// Retention settings for the profiles of a kind or single profiles,
+    /// taking precedence over `overrides`, the ones for a single profile
+    /// also over `container_overrides`.
+    pub profile_overrides: BTreeMap<ProfileSelector, RetentionOverrides>,
+
+    /// The generations never or always deleted from the system profiles of
+    /// single nixos-containers, by container name.
+    pub container_exceptions: BTreeMap<String, Exceptions>,
+
+    /// The generations never or always deleted from the profiles of a kind
+    /// or single profiles.
+    pub profile_exceptions: BTreeMap<ProfileSelector, Exceptions>,
+
+    /// The generations below which everything is deleted, per profile.
+    pub cutoffs: Vec<GenerationCutoff>,
+
+    /// The generations never deleted, per profile, in addition to the ones
+    /// in the pin file.
+    pub pins: Vec<Pin>,
+
+    /// Where the pin file is kept, `None` to only pin the generations in
+    /// `pins`.
+    pub pins_path: Option<PathBuf>,
+
+    /// Whether generations that look like rollback targets are kept.
+    pub protect_rollbacks: bool,
+
+    /// Whether the summary explains why nothing has been deleted from a
+    /// profile even if something has been deleted from others.
+    pub explain_skip: bool,
+
+    /// The steps of a run beyond cleaning up the profiles.
+    pub options: RunOptions,
+
+    /// Where the listing cache is kept, `None` to always list generations.
+    pub cache_path: Option<PathBuf>,
+
+    /// How long cached listings are kept without being used, `None` for
+    /// [cache::DEFAULT_KEEP_DAYS].
+    pub keep_cache: Option<chrono::Duration>,
+
+    /// Where the state is kept, `None` to not remember successful cleanups.
+    pub state_path: Option<PathBuf>,
+
+    /// The SQLite database cleanups are appended to, if any.
+    #[cfg(feature = "report-db")]
+    pub report_db: Option<PathBuf>,
+
+    /// Where the sizes of closures are cached, `None` to always query them.
+    pub size_cache_path: Option<PathBuf>,
+
+    /// How many sizes are cached at most, `None` for
+    /// [size_cache::DEFAULT_LIMIT].
+    pub size_cache_limit: Option<usize>,
+
+    /// Whether to query the sizes of closures again instead of using the
+    /// cached ones.
+    pub refresh_sizes: bool,
+
+    /// When `janitor serve` cleans up on its own, `None` to only clean up on
+    /// request.
+    pub schedule: Option<Schedule>,
+
+    /// How long after the start of a window a scheduled cleanup may start,
+    /// `None` for anywhere within the window.
+    pub jitter: Option<chrono::Duration>,
+}
+
+impl Settings {
+    /// Resolves the settings from the command line `args`, which take
+    /// precedence over the `config`, and the `state` of earlier runs.
+    pub fn resolve(args: &NJParser, config: &Config, state: &State) -> Self {
+        let options = RunOptions {
+            gc: args.gc().or(config.gc).unwrap_or(false),
+            verify_store: args.verify_store,
+            repair_store: args.verify_repair,
+            free_at_least: args.free_at_least,
+            per_user_budget: args.per_user_budget.or(config.per_user_budget),
+            per_user_floor: args
+                .per_user_floor
+                .or(config.per_user_floor)
+                .unwrap_or(quota::DEFAULT_FLOOR),
+            stale_pins: args.stale_pins,
+            max_freed: None,
+            gc_log: DeletionLog {
+                every: args
+                    .gc_log_every
+                    .or(config.gc_log_every)
+                    .unwrap_or(DeletionLog::DEFAULT_EVERY),
+                file: args.gc_log.clone().or_else(|| config.gc_log.clone()),
+            },
+            backend: state.preferred_backend.unwrap_or_default(),
+            power: match args.ignore_power {
+                true => PowerPolicy::default(),
+                false => PowerPolicy {
+                    min_battery: config.min_battery,
+                    hook: config.power_hook.clone(),
+                },
+            },
+            gc_lock: args
+                .gc_lock
+                .clone()
+                .or_else(|| config.gc_lock.clone())
+                .map(|location| GcLock {
+                    location,
+                    slots: args
+                        .gc_lock_slots
+                        .or(config.gc_lock_slots)
+                        .unwrap_or(NonZeroUsize::MIN),
+                }),
+            atomic: args.atomic().or(config.atomic).unwrap_or(false),
+            fail_fast: args.fail_fast().or(config.fail_fast).unwrap_or(false),
+            max_runtime: args.max_runtime.or(config.max_runtime),
+            jobs: args.jobs.or(config.jobs),
+            strict: args.strict().or(config.strict).unwrap_or(false),
+            boot: BootLimit {
+                configured: config.boot_limit,
+                align: args
+                    .align_boot_limit()
+                    .or(config.align_boot_limit)
+                    .unwrap_or(false),
+            },
+            defer_to_system_gc: args
+                .defer_to_system_gc()
+                .or(config.defer_to_system_gc)
+                .unwrap_or(false),
+            size_estimation: config.size_estimation.unwrap_or_default(),
+            interactive: args.interactive(),
+            progress: Progress::default(),
+        };
+
+        Self {
+            include_system: args
+                .system()
+                .or(config.system)
+                .unwrap_or_else(system_by_default),
+            profile_patterns: match args.profiles.is_empty() {
+                true => config.profiles.clone(),
+                false => args.profiles.clone(),
+            },
+            exclude_patterns: match args.excludes.is_empty() {
+                true => config.exclude.clone(),
+                false => args.excludes.clone(),
+            },
+            all_users: args.all_users().or(config.all_users).unwrap_or(false),
+            overrides: args.retention().or(config.retention()),
+            container_overrides: config.container_retention(),
+            profile_overrides: config.profile_retention(),
+            container_exceptions: config.container_exceptions(),
+            profile_exceptions: config.profile_exceptions(),
+            cutoffs: args.older_than_generation.clone(),
+            pins: args.pin.clone(),
+            pins_path: args
+                .state
+                .clone()
+                .or_else(state::default_path)
+                .map(|state| pins::default_path(&state)),
+            protect_rollbacks: args
+                .protect_rollbacks()
+                .or(config.protect_rollbacks)
+                .unwrap_or(false),
+            explain_skip: args.explain_skip().or(config.explain_skip).unwrap_or(false),
+            options,
+            cache_path: if args.no_cache || args.deterministic {
+                None
+            } else {
+                cache::default_path()
+            },
+            keep_cache: config.keep_cache,
+            state_path: args.state.clone().or_else(state::default_path),
+            #[cfg(feature = "report-db")]
+            report_db: args.report_db.clone().or_else(|| config.report_db.clone()),
+            size_cache_path: match args.deterministic {
+                true => None,
+                false => args
+                    .state
+                    .clone()
+                    .or_else(state::default_path)
+                    .map(|state| size_cache::default_path(&state)),
+            },
+            size_cache_limit: config.size_cache_limit,
+            refresh_sizes: args.refresh_sizes,
+            schedule: config.schedule,
+            jitter: config.jitter.filter(|_| !args.deterministic),
+        }
+    }
+
+    /// Describes the settings that differ in `new`, as the name of the
+    /// setting with its old and new value.
+    pub fn changes(&self, new: &Self) -> Vec<(&'static str, String, String)> {
+        fn show<T: ToString>(value: Option<T>) -> String {
+            value.map_or_else(|| "default".to_string(), |value| value.to_string())
+        }
+
+        let settings = [
+            (
+                "system",
+                self.include_system.to_string(),
+                new.include_system.to_string(),
+            ),
+            (
+                "profiles",
+                show(self.profiles_shown()),
+                show(new.profiles_shown()),
+            ),
+            (
+                "exclude",
+                show(self.excludes_shown()),
+                show(new.excludes_shown()),
+            ),
+            (
+                "all-users",
+                self.all_users.to_string(),
+                new.all_users.to_string(),
+            ),
+            (
+                "keep",
+                show(self.overrides.keep.map(format_duration)),
+                show(new.overrides.keep.map(format_duration)),
+            ),
+            (
+                "keep-since",
+                show(self.overrides.keep_since),
+                show(new.overrides.keep_since),
+            ),
+            (
+                "keep-at-least",
+                show(self.overrides.keep_at_least),
+                show(new.overrides.keep_at_least),
+            ),
+            (
+                "keep-at-most",
+                show(self.overrides.keep_at_most),
+                show(new.overrides.keep_at_most),
+            ),
+            (
+                "order-by",
+                show(self.overrides.order_by),
+                show(new.overrides.order_by),
+            ),
+            (
+                "thinning",
+                show(self.overrides.thinning),
+                show(new.overrides.thinning),
+            ),
+            (
+                "containers",
+                show(self.containers_shown()),
+                show(new.containers_shown()),
+            ),
+            (
+                "overrides",
+                show(self.profile_overrides_shown()),
+                show(new.profile_overrides_shown()),
+            ),
+            (
+                "exceptions",
+                show(self.exceptions_shown()),
+                show(new.exceptions_shown()),
+            ),
+            (
+                "older-than-generation",
+                show(self.cutoffs_shown()),
+                show(new.cutoffs_shown()),
+            ),
+            ("pin", show(self.pins_shown()), show(new.pins_shown())),
+            (
+                "protect-rollbacks",
+                self.protect_rollbacks.to_string(),
+                new.protect_rollbacks.to_string(),
+            ),
+            (
+                "gc",
+                self.options.gc.to_string(),
+                new.options.gc.to_string(),
+            ),
+            (
+                "defer-to-system-gc",
+                self.options.defer_to_system_gc.to_string(),
+                new.options.defer_to_system_gc.to_string(),
+            ),
+            (
+                "gc-log",
+                show(self.options.gc_log.file.as_ref().map(|p| p.display())),
+                show(new.options.gc_log.file.as_ref().map(|p| p.display())),
+            ),
+            (
+                "gc-log-every",
+                self.options.gc_log.every.to_string(),
+                new.options.gc_log.every.to_string(),
+            ),
+            (
+                "min-battery",
+                show(self.options.power.min_battery),
+                show(new.options.power.min_battery),
+            ),
+            (
+                "power-hook",
+                show(self.options.power.hook.as_ref()),
+                show(new.options.power.hook.as_ref()),
+            ),
+            (
+                "gc-lock",
+                show(self.gc_lock_shown()),
+                show(new.gc_lock_shown()),
+            ),
+            (
+                "boot-limit",
+                show(self.options.boot.configured),
+                show(new.options.boot.configured),
+            ),
+            (
+                "align-boot-limit",
+                self.options.boot.align.to_string(),
+                new.options.boot.align.to_string(),
+            ),
+            (
+                "atomic",
+                self.options.atomic.to_string(),
+                new.options.atomic.to_string(),
+            ),
+            (
+                "fail-fast",
+                self.options.fail_fast.to_string(),
+                new.options.fail_fast.to_string(),
+            ),
+            (
+                "max-runtime",
+                show(self.options.max_runtime.map(format_duration)),
+                show(new.options.max_runtime.map(format_duration)),
+            ),
+            ("jobs", show(self.options.jobs), show(new.options.jobs)),
+            (
+                "interactive",
+                show(self.options.interactive),
+                show(new.options.interactive),
+            ),
+            (
+                "strict",
+                self.options.strict.to_string(),
+                new.options.strict.to_string(),
+            ),
+            (
+                "explain-skip",
+                self.explain_skip.to_string(),
+                new.explain_skip.to_string(),
+            ),
+            (
+                "verify-store",
+                self.options.verify_store.to_string(),
+                new.options.verify_store.to_string(),
+            ),
+            (
+                "free-at-least",
+                show(self.options.free_at_least.map(size::format_size)),
+                show(new.options.free_at_least.map(size::format_size)),
+            ),
+            (
+                "per-user-budget",
+                show(self.options.per_user_budget.map(size::format_size)),
+                show(new.options.per_user_budget.map(size::format_size)),
+            ),
+            (
+                "per-user-floor",
+                self.options.per_user_floor.to_string(),
+                new.options.per_user_floor.to_string(),
+            ),
+            (
+                "size-estimation",
+                self.options.size_estimation.to_string(),
+                new.options.size_estimation.to_string(),
+            ),
+            (
+                "backend",
+                self.options.backend.to_string(),
+                new.options.backend.to_string(),
+            ),
+            (
+                "stale-pins",
+                show(self.options.stale_pins),
+                show(new.options.stale_pins),
+            ),
+            (
+                "keep-cache",
+                show(self.keep_cache.map(format_duration)),
+                show(new.keep_cache.map(format_duration)),
+            ),
+            (
+                "size-cache-limit",
+                show(self.size_cache_limit),
+                show(new.size_cache_limit),
+            ),
+            ("schedule", show(self.schedule), show(new.schedule)),
+            (
+                "jitter",
+                show(self.jitter.map(format_duration)),
+                show(new.jitter.map(format_duration)),
+            ),
+        ];
+
+        settings
+            .into_iter()
+            .filter(|(_, old, new)| old != new)
+            .collect()
+    }
+
+    /// The generation below which everything is deleted from `profile`, if
+    /// any.
+    fn cutoff(&self, profile: &Path) -> Option<u32> {
+        let specific = self
+            .cutoffs
+            .iter()
+            .find(|cutoff| cutoff.profile.as_deref() == Some(profile));
+        let general = || self.cutoffs.iter().find(|cutoff| cutoff.profile.is_none());
+
+        specific.or_else(general).map(|cutoff| cutoff.id)
+    }
+
+    /// The ids of the generations of `profile` that are never deleted, those
+    /// pinned on the command line and in the pin file.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pin file can not be read or is invalid.
+    fn pinned(&self, profile: &Path) -> Result<BTreeSet<u32>> {
+        let mut pins = self.pins.clone();
+        if let Some(path) = &self.pins_path {
+            pins.extend(pins::load(path)?);
+        }
+
+        Ok(pins::pinned(&pins, profile))
+    }
+
+    /// The generations of the `profile` never or always deleted, those
+    /// configured for its kind, its container and the single profile, merged
+    /// with the ones [pinned](Settings::pinned).
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pin file can not be read or is invalid.
+    fn exceptions(&self, profile: &Profile) -> Result<Exceptions> {
+        let by_selector = |selector| self.profile_exceptions.get(&selector);
+        let kind = by_selector(ProfileSelector::Kind(profile.kind()));
+        let container = profile
+            .container()
+            .and_then(|name| self.container_exceptions.get(name));
+        let path = by_selector(ProfileSelector::Path(profile.as_ref().to_path_buf()));
+
+        let mut exceptions = Exceptions {
+            pinned: self.pinned(profile.as_ref())?,
+            always_delete: BTreeSet::new(),
+        };
+        for configured in [kind, container, path].into_iter().flatten() {
+            exceptions.pinned.extend(&configured.pinned);
+            exceptions.always_delete.extend(&configured.always_delete);
+        }
+
+        Ok(exceptions)
+    }
+
+    fn exceptions_shown(&self) -> Option<String> {
+        let containers = self
+            .container_exceptions
+            .iter()
+            .map(|(name, exceptions)| (format!("container {name}"), exceptions));
+        let profiles = self
+            .profile_exceptions
+            .iter()
+            .map(|(selector, exceptions)| (selector.to_string(), exceptions));
+        let shown: Vec<_> = containers
+            .chain(profiles)
+            .map(|(key, exceptions)| {
+                format!(
+                    "{key} (never-delete={}, always-delete={})",
+                    config::format_ids(&exceptions.pinned),
+                    config::format_ids(&exceptions.always_delete),
+                )
+            })
+            .collect();
+
+        (!shown.is_empty()).then(|| shown.join(", "))
+    }
+
+    /// Raises the generations kept by the system profile `policy` to the
+    /// number the bootloader lists, see [boot].
+    fn align_boot_limit(&self, policy: &mut RetentionPolicy) {
+        match self.options.boot.limit(Path::new(boot::BOOT)) {
+            Some(limit) if limit > policy.keep_at_least => {
+                tracing::info!(
+                    keep_at_least = policy.keep_at_least,
+                    limit,
+                    "keeping at least as many system generations as the bootloader lists"
+                );
+                policy.keep_at_least = limit;
+            }
+            Some(_) => {}
+            None => tracing::warn!("no bootloader entries found, not aligning the retention"),
+        }
+    }
+
+    /// The retention settings for the `profile`, the most specific ones
+    /// taking precedence: those for the single profile, for its container,
+    /// for its kind, then the general ones.
+    fn overrides(&self, profile: &Profile) -> RetentionOverrides {
+        let by_selector = |selector| self.profile_overrides.get(&selector);
+        let kind = by_selector(ProfileSelector::Kind(profile.kind()));
+        let container = profile
+            .container()
+            .and_then(|name| self.container_overrides.get(name));
+        let path = by_selector(ProfileSelector::Path(profile.as_ref().to_path_buf()));
+
+        [kind, container, path]
+            .into_iter()
+            .flatten()
+            .fold(self.overrides, |general, specific| specific.or(general))
+    }
+
+    fn gc_lock_shown(&self) -> Option<String> {
+        self.options
+            .gc_lock
+            .as_ref()
+            .map(|lock| format!("{} (slots={})", lock.location.display(), lock.slots))
+    }
+
+    fn containers_shown(&self) -> Option<String> {
+        retention_shown(&self.container_overrides)
+    }
+
+    fn profile_overrides_shown(&self) -> Option<String> {
+        retention_shown(&self.profile_overrides)
+    }
+
+    /// How long cached listings are kept without being used.
+    pub fn cache_retention(&self) -> chrono::Duration {
+        self.keep_cache
+            .unwrap_or_else(|| chrono::Duration::days(cache::DEFAULT_KEEP_DAYS))
+    }
+
+    /// The sizes of closures cached by earlier runs, empty if the cache is
+    /// disabled.
+    pub fn size_cache(&self) -> SizeCache {
+        match &self.size_cache_path {
+            Some(path) => SizeCache::load_or_default(path, self.refresh_sizes),
+            None => SizeCache::default(),
+        }
+    }
+
+    /// Writes the sizes of closures looked up by this run to the cache, if
+    /// enabled.
+    pub fn save_size_cache(&self, cache: &SizeCache) {
+        if let Some(path) = &self.size_cache_path {
+            let limit = self.size_cache_limit.unwrap_or(size_cache::DEFAULT_LIMIT);
+            cache.save_or_warn(path, limit);
+        }
+    }
+
+    fn profiles_shown(&self) -> Option<String> {
+        (!self.profile_patterns.is_empty()).then(|| self.profile_patterns.join(", "))
+    }
+
+    fn excludes_shown(&self) -> Option<String> {
+        (!self.exclude_patterns.is_empty()).then(|| self.exclude_patterns.join(", "))
+    }
+
+    fn pins_shown(&self) -> Option<String> {
+        (!self.pins.is_empty()).then(|| {
+            self.pins
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    }
+
+    fn cutoffs_shown(&self) -> Option<String> {
+        (!self.cutoffs.is_empty()).then(|| {
+            self.cutoffs
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    }
+
+    /// The profiles to clean up, those matching the profile patterns, or the
+    /// default ones if there are none.
+    ///
+    /// Profiles matched by several patterns are only cleaned up once. A
+    /// pattern without wildcards names a single profile, which has to exist.
+    /// Profiles matching an exclude pattern are skipped, as are the ones
+    /// [managed by the system](Profile::managed_by_system).
+    pub fn profiles(&self) -> Result<Vec<Profile>> {
+        let mut profiles = Vec::new();
+        for profile in self.matched_profiles()? {
+            if profile.managed_by_system() {
+                tracing::info!(
+                    path = %profile.as_ref().display(),
+                    reason = MANAGED_BY_SYSTEM,
+                    "skipping profile"
+                );
+                continue;
+            }
+            match self.excluded_by(&profile)? {
+                Some(pattern) => {
+                    tracing::info!(path = %profile.as_ref().display(), pattern, "profile is excluded, skipping it")
+                }
+                None => profiles.push(profile),
+            }
+        }
+
+        Ok(profiles)
+    }
+
+    /// The first exclude pattern matching the `profile`, if any.
+    fn excluded_by(&self, profile: &Profile) -> Result<Option<&str>> {
+        for pattern in &self.exclude_patterns {
+            if profile.matches(pattern)? {
+                return Ok(Some(pattern));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The profiles matching the profile patterns, or the default ones, of
+    /// all users if `all_users`.
+    fn matched_profiles(&self) -> Result<Vec<Profile>> {
+        if self.profile_patterns.is_empty() && self.all_users {
+            if !is_root::is_root() {
+                bail!("--all-users requires root privileges");
+            }
+            return Ok(Profile::all_users(self.include_system));
+        }
+        if self.profile_patterns.is_empty() {
+            return Ok(Profile::all(self.include_system));
+        }
+
+        let mut profiles = Vec::new();
+        for pattern in &self.profile_patterns {
+            let matched = Profile::expand(pattern)?;
+            if matched.is_empty() && !pattern.contains(['*', '?', '[']) {
+                bail!("profile {pattern} does not exist");
+            }
+            if matched.is_empty() {
+                if self.options.strict {
+                    bail!("profile pattern {pattern} matches no profile");
+                }
+                tracing::warn!(pattern, "profile pattern matches no profile");
+            }
+            profiles.extend(matched);
+        }
+        profiles.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        profiles.dedup_by(|a, b| a.as_ref() == b.as_ref());
+
+        Ok(profiles)
+    }
+
+    /// Creates a job for each profile, with the retention policy resolved as
+    /// of `now`.
+    pub fn jobs(&self, now: NaiveDateTime) -> Result<Vec<Job<Discovered>>> {
+        let profile_paths = self.profiles()?;
+
+        // "print welcome"
+        tracing::info!(
+            start_time = %now,
+            profiles = ?profile_paths,
+            version = VERSION,
+            "Starting janitor"
+        );
+
+        for cutoff in &self.cutoffs {
+            if let Some(path) = &cutoff.profile {
+                if !profile_paths.iter().any(|p| p.as_ref() == path.as_path()) {
+                    tracing::warn!(?path, "not cleaning up this profile, ignoring its cutoff");
+                }
+            }
+        }
+
+        for pin in &self.pins {
+            if let Some(path) = &pin.profile {
+                if !profile_paths.iter().any(|p| p.as_ref() == path.as_path()) {
+                    tracing::warn!(?path, "not cleaning up this profile, ignoring its pin");
+                }
+            }
+        }
+
+        for selector in self.profile_overrides.keys() {
+            if let ProfileSelector::Path(path) = selector {
+                if !profile_paths.iter().any(|p| p.as_ref() == path.as_path()) {
+                    tracing::warn!(?path, "not cleaning up this profile, ignoring its settings");
+                }
+            }
+        }
+
+        for name in self.container_overrides.keys() {
+            if !profile_paths.iter().any(|p| p.container() == Some(name)) {
+                tracing::warn!(
+                    container = name,
+                    "not cleaning up this container, ignoring its settings"
+                );
+            }
+        }
+
+        profile_paths
+            .iter()
+            .map(|profile| self.job(profile, now))
+            .collect()
+    }
+
+    /// Creates the job for the `profile`, with the retention policy resolved
+    /// as of `now`.
+    pub fn job(&self, profile: &Profile, now: NaiveDateTime) -> Result<Job<Discovered>> {
+        self.job_with(profile, RetentionOverrides::default(), now)
+    }
+
+    /// Creates the job for the `profile` like [Settings::job], with the
+    /// `overrides` taking precedence over all configured retention settings.
+    pub fn job_with(
+        &self,
+        profile: &Profile,
+        overrides: RetentionOverrides,
+        now: NaiveDateTime,
+    ) -> Result<Job<Discovered>> {
+        let kind = profile.kind();
+        let mut policy = RetentionPolicy::resolve(kind, overrides.or(self.overrides(profile)));
+        if kind == ProfileKind::System && self.options.boot.align {
+            self.align_boot_limit(&mut policy);
+        }
+        let derivation = policy.derive_keep_since(now);
+        let exceptions = self.exceptions(profile)?;
+        let job = Job::builder()
+            .path(profile)
+            .keep_since_derived(derivation)
+            .keep_at_least(policy.keep_at_least)
+            .keep_at_most(policy.keep_at_most)
+            .order_by(policy.order_by)
+            .thinning(policy.thinning)
+            .before_generation(self.cutoff(profile.as_ref()))
+            .protect_rollback_targets(self.protect_rollbacks)
+            .pinned(exceptions.pinned)
+            .always_delete(exceptions.always_delete)
+            .now(now)
+            .build()?;
+        tracing::info!(
+            job_id = %job.id(),
+            path = ?profile.as_ref(),
+            %kind,
+            keep_since = %derivation,
+            keep_at_least = policy.keep_at_least,
+            keep_at_most = ?policy.keep_at_most,
+            order_by = %policy.order_by,
+            thinning = %policy.thinning,
+            before_generation = ?job.before_generation(),
+            pinned = ?job.exceptions().pinned,
+            always_delete = ?job.exceptions().always_delete,
+            "resolved retention policy"
+        );
+
+        Ok(job)
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    match janitor() {
+        Err(error) if error.is::<PartialRun>() => {
+            tracing::warn!(%error, "finished partially");
+            Ok(ExitCode::from(PARTIAL_SUCCESS))
+        }
+        result => result.map(|()| ExitCode::SUCCESS),
+    }
+}
+
+fn janitor() -> Result<()> {
+    let args = NJParser::parse_from(compat::args(env::args_os()));
+
+    if args.redact {
+        redact::install(Redactor::discover());
+    }
+
+    // Configure and initialize logging, leaving timestamps and colors to the
+    // journal when logging to it.
+    let journald = Platform::current().journald;
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::TRACE)
+        .with_ansi(!journald)
+        .with_writer(match (&args.command, args.emit_nix) {
+            // The results of the batch and the Nix expression are written to
+            // stdout.
+            (Some(Command::Batch), _) | (_, true) => redact::Writer::Stderr,
+            _ => redact::Writer::Stdout,
+        });
+    match (args.deterministic, journald) {
+        (true, _) => subscriber.without_time().init(),
+        (false, true) => subscriber
+            .without_time()
+            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+            .init(),
+        (false, false) => subscriber
+            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+            .init(),
+    }
+
+    // Every cleanup of the daemon is a run of its own.
+    let run_id = match (args.deterministic, args.now) {
+        (true, Some(now)) => RunId::at(now),
+        _ => RunId::new(),
+    };
+    #[cfg(feature = "tokio")]
+    let serving = matches!(args.command, Some(Command::Serve { .. }));
+    #[cfg(not(feature = "tokio"))]
+    let serving = false;
+    let _run = (!serving).then(|| tracing::info_span!("run", %run_id).entered());
+
+    #[cfg(feature = "tokio")]
+    if let Some(Command::Ctl { request, socket }) = &args.command {
+        let socket = socket.clone().or_else(control::default_socket_path);
+        return control::ctl(socket.as_deref(), *request);
+    }
+
+    if let Some(Command::Init) = &args.command {
+        return init::run(args.config.as_deref());
+    }
+
+    let config = Config::load(args.config.as_deref())?;
+    let state_path = args.state.clone().or_else(state::default_path);
+    let mut state = load_state(state_path.as_deref());
+
+    #[cfg(feature = "report-db")]
+    if let Some(Command::Query { query }) = args.command {
+        let Some(db) = args.report_db.clone().or(config.report_db) else {
+            bail!("no report database given, use --report-db");
+        };
+        let answer =
+            futures::executor::block_on(report_db::query(&Blocking(StdExecutor), &db, query))?;
+        print!("{}", redact::text(&answer));
+        return Ok(());
+    }
+
+    if let Some(Command::Status) = &args.command {
+        let last = state
+            .last_success
+            .map_or_else(|| "never".to_string(), |last| last.to_rfc3339());
+        let trend = Trend::new(&state.history, Utc::now());
+        println!(
+            "{}",
+            redact::text(&format!("last success: {last}\n{trend}"))
+        );
+        return Ok(());
+    }
+
+    let mut settings = Settings::resolve(&args, &config, &state);
+    let mut delete_generations = true;
+    if let Some(Command::Compat {
+        tool: Compat::NixCollectGarbage(options),
+    }) = &args.command
+    {
+        settings = options.apply(settings);
+        delete_generations = options.deletes_generations();
+    }
+
+    if !settings.include_system && is_root::is_root() {
+        tracing::info!("skipping the system profile, use --system to include it");
+    }
+
+    let now = args.now.unwrap_or_else(Utc::now).naive_utc();
+
+    if let Some(Command::Bench { iterations }) = args.command {
+        let report = bench::run(&settings.profiles()?, iterations);
+        print!("{}", redact::text(&report.to_string()));
+
+        if let Some(backend) = report.preferred {
+            state.preferred_backend = Some(backend);
+            match &state_path {
+                Some(path) => state.save(path)?,
+                None => tracing::warn!("no location for the state known, not saving it"),
+            }
+        }
+
+        return Ok(());
+    }
+
+    if let Some(Command::Explain {
+        generation,
+        profile,
+    }) = &args.command
+    {
+        let details = explain::explain_generation(&settings, profile, *generation, now)?;
+        print!("{}", redact::text(&details.to_string()));
+        return Ok(());
+    }
+
+    if let Some(Command::Doctor) = args.command {
+        let report = doctor::run(Platform::current(), &settings.profiles()?);
+        print!("{}", redact::text(&report.to_string()));
+
+        return match report.unhealthy() {
+            0 => Ok(()),
+            n => bail!("found inconsistencies in {n} profiles"),
+        };
+    }
+
+    if let Some(Command::Selftest) = args.command {
+        let report = selftest::run(&settings.options, now);
+        print!("{}", redact::text(&report.to_string()));
+
+        return match report.passed() {
+            true => Ok(()),
+            false => bail!("the self-test failed"),
+        };
+    }
+
+    if args.explain_policy {
+        print!(
+            "{}",
+            explain::explain_policy(settings.overrides, settings.protect_rollbacks, now)
+        );
+        return Ok(());
+    }
+
+    guard::check(args.i_know_what_i_am_doing, |name| env::var_os(name))?;
+
+    if args.verify_repair && !is_root::is_root() {
+        bail!("--verify-repair requires root privileges");
+    }
+
+    let mut dry_run = args.dry_run;
+    let destructive = destructive_modes(&args);
+    let assume_yes = args.yes().or(config.assume_yes).unwrap_or(false);
+    if !dry_run {
+        match prompt::consent(&destructive, assume_yes)? {
+            Consent::Given => {}
+            Consent::Declined => bail!("the destructive modes have not been confirmed"),
+            Consent::PlanOnly if serving => {
+                bail!("stdin is not a terminal, pass --yes to serve with destructive modes")
+            }
+            Consent::PlanOnly => {
+                tracing::warn!(
+                    modes = ?destructive,
+                    "stdin is not a terminal to confirm the destructive modes, only printing \
+                     the plan; pass --yes or set assume_yes to proceed"
+                );
+                dry_run = true;
+            }
+        }
+    }
+
+    if settings.options.interactive.is_some() && !dry_run {
+        if serving || matches!(args.command, Some(Command::Batch)) {
+            bail!("--interactive can only be used when cleaning up once");
+        }
+        if !io::stdout().is_terminal() {
+            bail!("--interactive requires stdout to be a terminal, pass --yes to proceed without asking");
+        }
+    }
+
+    let missed = args
+        .catch_up
+        .then(|| missed_cleanup(&settings, &state))
+        .transpose()?;
+
+    #[cfg(feature = "tokio")]
+    let runtime = RuntimeOptions::resolve(&args, &config);
+
+    #[cfg(feature = "tokio")]
+    if let Some(Command::Serve { dbus, socket }) = args.command.clone() {
+        let socket = socket.or_else(control::default_socket_path);
+        let config = Config::location(args.config.as_deref());
+        let reload = move || -> Result<Settings> {
+            let config = Config::load(args.config.as_deref())?;
+            let state = load_state(state_path.as_deref());
+
+            Ok(Settings::resolve(&args, &config, &state))
+        };
+
+        let catch_up = missed == Some(true);
+        return serve::serve(
+            settings,
+            Box::new(reload),
+            socket,
+            config,
+            dbus,
+            catch_up,
+            runtime,
+        );
+    }
+
+    if missed == Some(false) {
+        tracing::info!("no scheduled cleanup has been missed, nothing to catch up on");
+        return Ok(());
+    }
+
+    #[cfg(feature = "tokio")]
+    // The questions of `--interactive` are asked one at a time.
+    let driver = match args.blocking || args.deterministic || settings.options.interactive.is_some()
+    {
+        true => Driver::Blocking,
+        false => Driver::Tokio(runtime),
+    };
+    #[cfg(not(feature = "tokio"))]
+    let driver = Driver::Blocking;
+
+    let plans = match args.command {
+        Some(Command::Plan { .. }) => true,
+        Some(Command::Batch | Command::List) => false,
+        _ => dry_run,
+    };
+    if args.emit_nix && !plans {
+        bail!("--emit-nix only applies to `janitor plan` and --dry-run");
+    }
+
+    let context = Context {
+        settings,
+        driver,
+        now,
+        run_id,
+        deterministic: args.deterministic,
+        emit_nix: args.emit_nix,
+    };
+    match args.command {
+        Some(Command::Batch) => batch::run(&context, dry_run),
+        Some(Command::List) => commands::list(&context),
+        Some(Command::Plan { out }) => commands::plan(&context, out.as_deref()),
+        Some(Command::Apply { plan }) => commands::apply(&context, &plan, dry_run),
+        _ if dry_run => commands::plan(&context, None),
+        Some(Command::Gc) => commands::gc(context),
+        _ => commands::clean(&context, delete_generations),
+    }
+}
+
+/// Lists the retention settings per container or profile, `None` if there
+/// are none.
+fn retention_shown<K: std::fmt::Display>(
+    overrides: &BTreeMap<K, RetentionOverrides>,
+) -> Option<String> {
+    (!overrides.is_empty()).then(|| {
+        overrides
+            .iter()
+            .map(|(key, overrides)| {
+                let keep = overrides.keep.map(format_duration);
+                format!(
+                    "{key} (keep={}, keep-at-least={})",
+                    keep.as_deref().unwrap_or("default"),
+                    overrides
+                        .keep_at_least
+                        .map_or_else(|| "default".to_string(), |n| n.to_string()),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+/// Describes the destructive modes requested by the command line `args`,
+/// which need consent before the run.
+fn destructive_modes(args: &NJParser) -> Vec<&'static str> {
+    let mut modes = Vec::new();
+
+    if let Some(Command::Compat {
+        tool: Compat::NixCollectGarbage(options),
+    }) = &args.command
+    {
+        if options.delete_old {
+            modes.push("--delete-old: delete all but the current generation of every profile");
+        }
+    }
+    if args.verify_repair {
+        modes.push("--verify-repair: verify all store contents and attempt to repair the store");
+    }
+
+    modes
+}
+
+/// Prunes the listing `cache` and saves it, if enabled.
+fn save_listing_cache(settings: &Settings, cache: Option<Mutex<ListingCache>>) {
+    if let (Some(path), Some(cache)) = (&settings.cache_path, cache) {
+        let mut cache = cache.into_inner().unwrap_or_else(|e| e.into_inner());
+        cache.prune(Utc::now(), settings.cache_retention());
+        cache.save_or_warn(path);
+    }
+}
+
+/// Whether a window of the schedule has started since the last successful
+/// cleanup recorded in the `state`.
+fn missed_cleanup(settings: &Settings, state: &State) -> Result<bool> {
+    let Some(schedule) = settings.schedule else {
+        bail!("--catch-up requires a schedule in the configuration");
+    };
+
+    let last = state
+        .last_success
+        .map(|last| last.with_timezone(&Local).naive_local());
+    let missed = schedule.missed(last, Local::now().naive_local());
+    tracing::debug!(?last, %schedule, missed, "checked for missed cleanups");
+
+    Ok(missed)
+}
+
+/// Remembers in the state of the `settings` that the cleanup `run_id` has
+/// just succeeded with the `report`, and appends it to the report database.
+fn record_success(settings: &Settings, run_id: RunId, report: &RunReport) {
+    let now = Utc::now();
+
+    #[cfg(feature = "report-db")]
+    if let Some(db) = &settings.report_db {
+        let appended = report_db::append(&Blocking(StdExecutor), db, run_id, now, report);
+        if let Err(error) = futures::executor::block_on(appended) {
+            tracing::warn!(
+                error = format!("{error:#}"),
+                "failed to append the run to the report database"
+            );
+        }
+    }
+
+    let Some(path) = settings.state_path.as_deref() else {
+        return;
+    };
+
+    let mut state = load_state(Some(path));
+    state.last_success = Some(now);
+    history::record(&mut state.history, Run::new(run_id, now, report));
+    if let Err(error) = state.save(path) {
+        tracing::warn!(error = format!("{error:#}"), "failed to save the state");
+    }
+}
+
+/// Loads the state from `path`, falling back to the default state if there
+/// is no path or the state can not be read.
+fn load_state(path: Option<&Path>) -> State {
+    match path.map(State::load).transpose() {
+        Ok(state) => state.unwrap_or_default(),
+        Err(error) => {
+            tracing::warn!(%error, "ignoring unreadable state");
+            State::default()
+        }
+    }
+}
+
+fn log_report(report: &RunReport, explain_skip: bool) {
+    tracing::info!(
+        profiles = report.profiles().len(),
+        deleted = report.deleted_count(),
+        appeared = report.appeared_count(),
+        skipped = report.skipped().len(),
+        failed = report.failures().len(),
+        "Finished janitor"
+    );
+    for failure in report.failures() {
+        tracing::error!(
+            path = %failure.path.display(),
+            error = %failure.error,
+            "failed to clean up profile"
+        );
+    }
+    for (path, explanation) in explanations(report, explain_skip) {
+        tracing::info!(
+            path = %path.display(),
+            %explanation,
+            "nothing deleted from profile"
+        );
+    }
+    if let Some(gc) = report.gc() {
+        tracing::info!(
+            paths_deleted = gc.paths_deleted,
+            freed = %size::format_size(gc.bytes_freed),
+            "garbage collected"
+        );
+    }
+    if let Some(verification) = report.verification() {
+        for path in &verification.repaired {
+            tracing::info!(%path, "repaired store path");
+        }
+        if !verification.repaired.is_empty() {
+            tracing::info!(
+                repaired = verification.repaired.len(),
+                "store paths have been repaired"
+            );
+        }
+        if verification.is_consistent() {
+            tracing::info!("store verified, no inconsistencies found");
+        } else {
+            for issue in &verification.issues {
+                tracing::warn!(%issue, "store inconsistency");
+            }
+            tracing::warn!(
+                issues = verification.issues.len(),
+                "store verification found inconsistencies"
+            );
+        }
+    }
+    if report.appeared_count() > 0 {
+        tracing::info!(
+            "{} new generation(s) appeared during run, untouched",
+            report.appeared_count()
+        );
+    }
+    if report.is_partial() {
+        tracing::warn!(
+            skipped = %report.cut_short().join(", "),
+            "maximum runtime exceeded, the run has been cut short"
+        );
+    }
+    if !report.quotas().is_empty() {
+        let users: Vec<_> = report.quotas().iter().map(|q| q.user.as_str()).collect();
+        let still_exceeding = report.quotas().iter().filter(|q| !q.met()).count();
+        tracing::warn!(
+            users = %users.join(", "),
+            still_exceeding,
+            "{} user(s) exceeded their budget",
+            users.len()
+        );
+    }
+}
+
+/// Fails if cleaning up any profile of the `report` has failed, after the
+/// others have been cleaned up.
+fn check_failures(report: &RunReport) -> Result<()> {
+    match report.failures().len() {
+        0 => Ok(()),
+        n => bail!(
+            "failed to clean up {n} of {} profiles",
+            n + report.profiles().len()
+        ),
+    }
+}
+
+/// Why nothing has been deleted from the profiles the retention policy
+/// deletes nothing from, by path, if nothing has been deleted at all or
+/// `explain_skip` is set.
+fn explanations(report: &RunReport, explain_skip: bool) -> Vec<(&Path, &Explanation)> {
+    if !explain_skip && report.deleted_count() > 0 {
+        return Vec::new();
+    }
+
+    report
+        .profiles()
+        .iter()
+        .filter_map(|profile| Some((profile.path.as_path(), profile.explanation.as_ref()?)))
+        .collect()
+}
+
+/// Logs the resources used by the commands spawned during the run, if they
+/// have been accounted.
+fn log_resources(report: &RunReport) {
+    if let Some(usage) = report.resources() {
+        tracing::info!(
+            cpu_time = ?usage.cpu_time(),
+            user_time = ?usage.user_time,
+            system_time = ?usage.system_time,
+            peak_rss = %size::format_size(usage.max_rss),
+            read = %size::format_size(usage.bytes_read),
+            written = %size::format_size(usage.bytes_written),
+            "resources used by spawned commands"
+        );
+    }
+}
+
+fn record_profile(report: &mut RunReport, profile: ProfileReport, total: usize) {
+    tracing::info!(
+        job_id = %profile.job_id,
+        finished = report.profiles().len() + 1,
+        total,
+        deleted = report.deleted_count() + profile.deleted.len(),
+        keep_since = profile.keep_since.as_ref().map(tracing::field::display),
+        "finished profile"
+    );
+    report.record(profile);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{ffi::OsString, fs};
+
+    use chrono::Duration;
+    use clap::Parser;
+    use rstest::rstest;
+
+    #[test]
+    fn changes() {
+        let old = Settings::default();
+        let new = Settings {
+            overrides: RetentionOverrides {
+                keep: Some(Duration::days(3)),
+                keep_since: None,
+                keep_at_least: None,
+                keep_at_most: None,
+                order_by: None,
+                thinning: None,
+            },
+            options: RunOptions {
+                gc: true,
+                ..RunOptions::default()
+            },
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                ("keep", "default".to_string(), "3d".to_string()),
+                ("gc", "false".to_string(), "true".to_string()),
+            ]
+        );
+        assert!(new.changes(&new).is_empty());
+    }
+
+    #[rstest]
+    #[case::defaults(&["janitor"], "", &[])]
+    #[case::config(&["janitor"], "profiles = [\"/p/*\"]", &["/p/*"])]
+    #[case::args_take_precedence(
+        &["janitor", "--profile", "/q/*", "--profile", "/r"],
+        "profiles = [\"/p/*\"]",
+        &["/q/*", "/r"]
+    )]
+    fn profile_patterns(#[case] args: &[&str], #[case] config: &str, #[case] expected: &[&str]) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+        let settings = Settings::resolve(&args, &config, &State::default());
+
+        assert_eq!(settings.profile_patterns, expected);
+    }
+
+    #[rstest]
+    #[case::config(&["janitor"], Some(30))]
+    #[case::ignored(&["janitor", "--ignore-power"], None)]
+    fn power_policy(#[case] args: &[&str], #[case] min_battery: Option<u8>) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str("min_battery = 30\npower_hook = \"true\"").unwrap();
+        let power = Settings::resolve(&args, &config, &State::default())
+            .options
+            .power;
+
+        assert_eq!(power.min_battery, min_battery);
+        assert_eq!(power.hook.is_some(), min_battery.is_some());
+    }
+
+    #[rstest]
+    #[case::none(&["janitor"], "", None)]
+    #[case::config(&["janitor"], "gc_lock = \"file:///mnt/gc\"", Some(("/mnt/gc", 1)))]
+    #[case::slots_from_config(
+        &["janitor", "--gc-lock", "/mnt/other"],
+        "gc_lock = \"/mnt/gc\"\ngc_lock_slots = 3",
+        Some(("/mnt/other", 3))
+    )]
+    #[case::slots_from_args(
+        &["janitor", "--gc-lock-slots", "2"],
+        "gc_lock = \"/mnt/gc\"",
+        Some(("/mnt/gc", 2))
+    )]
+    fn gc_lock(
+        #[case] args: &[&str],
+        #[case] config: &str,
+        #[case] expected: Option<(&str, usize)>,
+    ) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+        let lock = Settings::resolve(&args, &config, &State::default())
+            .options
+            .gc_lock;
+
+        assert_eq!(
+            lock.map(|lock| (lock.location, lock.slots.get())),
+            expected.map(|(location, slots)| (PathBuf::from(location), slots))
+        );
+    }
+
+    #[rstest]
+    #[case::default(&["janitor"], "", None)]
+    #[case::config(&["janitor"], "jobs = 2", Some(2))]
+    #[case::flag_over_config(&["janitor", "-j", "1"], "jobs = 2", Some(1))]
+    fn jobs(#[case] args: &[&str], #[case] config: &str, #[case] expected: Option<usize>) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+        let settings = Settings::resolve(&args, &config, &State::default());
+
+        assert_eq!(settings.options.jobs.map(NonZeroUsize::get), expected);
+    }
+
+    #[rstest]
+    #[case::default(&["janitor"], "", false)]
+    #[case::flag(&["janitor", "--atomic"], "", true)]
+    #[case::config(&["janitor"], "atomic = true", true)]
+    #[case::flag_over_config(&["janitor", "--atomic"], "atomic = false", true)]
+    fn atomic(#[case] args: &[&str], #[case] config: &str, #[case] expected: bool) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+        let settings = Settings::resolve(&args, &config, &State::default());
+
+        assert_eq!(settings.options.atomic, expected);
+    }
+
+    #[test]
+    fn fails_after_cleaning_up_the_others() {
+        let mut report = RunReport::default();
+        assert!(check_failures(&report).is_ok());
+
+        let id = Job::new("/a", Default::default(), 0).id();
+        report.record(ProfileReport::new(id, "/a", Default::default()));
+        report.record_failure("/b", &eyre::eyre!("broken output of nix-env"));
+
+        let error = check_failures(&report).unwrap_err();
+        assert_eq!(error.to_string(), "failed to clean up 1 of 2 profiles");
+    }
+
+    #[rstest]
+    #[case::default(&["janitor"], "", false)]
+    #[case::flag(&["janitor", "--fail-fast"], "", true)]
+    #[case::config(&["janitor"], "fail_fast = true", true)]
+    fn fail_fast(#[case] args: &[&str], #[case] config: &str, #[case] expected: bool) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+        let settings = Settings::resolve(&args, &config, &State::default());
+
+        assert_eq!(settings.options.fail_fast, expected);
+    }
+
+    #[rstest]
+    #[case::lenient(&["janitor"], true)]
+    #[case::strict(&["janitor", "--strict"], false)]
+    fn strict_profiles(#[case] args: &[&str], #[case] expected: bool) {
+        let missing = env::temp_dir().join(format!("janitor-missing-{}", std::process::id()));
+        let args = NJParser::parse_from(
+            args.iter()
+                .copied()
+                .chain(["--profile", missing.join("*").to_str().unwrap()]),
+        );
+        let settings = Settings::resolve(&args, &Config::default(), &State::default());
+
+        assert_eq!(settings.profiles().is_ok(), expected);
+    }
+
+    #[rstest]
+    #[case::lenient(&["janitor"])]
+    #[case::strict(&["janitor", "--strict"])]
+    fn missing_profile_path(#[case] args: &[&str]) {
+        let missing = env::temp_dir().join(format!("janitor-missing-{}", std::process::id()));
+        let args = NJParser::parse_from(
+            args.iter()
+                .copied()
+                .chain(["--profile", missing.to_str().unwrap()]),
+        );
+        let settings = Settings::resolve(&args, &Config::default(), &State::default());
+
+        assert!(settings.profiles().is_err());
+    }
+
+    #[test]
+    fn profile_paths() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-profile-paths-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        std::os::unix::fs::symlink(&dir, dir.join("ci-profile"))?;
+        std::os::unix::fs::symlink(&dir, dir.join("other"))?;
+
+        let path = dir.join("ci-profile");
+        let args = NJParser::parse_from(["janitor", "--profile", path.to_str().unwrap()]);
+        let settings = Settings::resolve(&args, &Config::default(), &State::default());
+        let profiles = settings.profiles();
+        fs::remove_dir_all(&dir)?;
+
+        let profiles: Vec<_> = profiles?.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        assert_eq!(profiles, [path]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::args(&["janitor", "--exclude", "{dir}/home-manager*"], "")]
+    #[case::config(&["janitor"], "exclude = [\"{dir}/home-manager*\"]")]
+    fn excluded_profiles(#[case] args: &[&str], #[case] config: &str) -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-excluded-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        for name in ["profile", "home-manager"] {
+            std::os::unix::fs::symlink(&dir, dir.join(name))?;
+        }
+
+        let dir_shown = dir.display().to_string();
+        let args = NJParser::parse_from(
+            args.iter()
+                .map(|arg| arg.replace("{dir}", &dir_shown))
+                .chain(["--profile".to_string(), format!("{dir_shown}/*")]),
+        );
+        let config: Config = toml::from_str(&config.replace("{dir}", &dir_shown))?;
+        let settings = Settings::resolve(&args, &config, &State::default());
+        let profiles = settings.profiles();
+        fs::remove_dir_all(&dir)?;
+
+        let profiles: Vec<_> = profiles?.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        assert_eq!(profiles, [dir.join("profile")]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::nothing_deleted(2, false, &["/p/kept", "/p/other"])]
+    #[case::deleted_elsewhere(1, false, &[])]
+    #[case::explain_skip(1, true, &["/p/kept"])]
+    fn explains_skips(
+        #[case] other_keep_at_least: usize,
+        #[case] explain_skip: bool,
+        #[case] expected: &[&str],
+    ) {
+        let date = chrono::NaiveDateTime::default();
+        let generations: janitor::GenerationSet = (1..=2)
+            .map(|id| janitor::Generation {
+                id,
+                date: date + Duration::days(i64::from(id)),
+                current: id == 2,
+            })
+            .collect();
+        let mut report = RunReport::default();
+        for (path, keep_at_least) in [("/p/kept", 2), ("/p/other", other_keep_at_least)] {
+            let job = Job::new(path, date + Duration::days(3), keep_at_least)
+                .listed(generations.clone())
+                .plan();
+            let to_delete = job.state().to_delete.clone();
+            report.record(job.executed(to_delete).verify(None).into_report());
+        }
+
+        let explained: Vec<_> = explanations(&report, explain_skip)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+
+        assert_eq!(
+            explained,
+            expected.iter().map(Path::new).collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    #[case::none(&["janitor", "--gc"], 0)]
+    #[case::verify_repair(&["janitor", "--verify-repair"], 1)]
+    #[case::delete_old(&["nix-collect-garbage", "-d"], 1)]
+    #[case::delete_older_than(&["nix-collect-garbage", "--delete-older-than", "30d"], 0)]
+    #[case::both(&["janitor", "--verify-repair", "compat", "nix-collect-garbage", "-d"], 2)]
+    fn destructive(#[case] args: &[&str], #[case] expected: usize) {
+        let args = NJParser::parse_from(compat::args(args.iter().map(OsString::from)));
+
+        assert_eq!(destructive_modes(&args).len(), expected);
+    }
+
+    #[rstest]
+    #[case::raises(20, 20)]
+    #[case::keeps_higher(5, 10)]
+    fn aligns_boot_limit(#[case] limit: usize, #[case] expected: usize) {
+        let mut settings = Settings::default();
+        settings.options.boot = BootLimit {
+            configured: Some(limit),
+            align: true,
+        };
+        let mut policy = RetentionPolicy::resolve(ProfileKind::System, Default::default());
+        settings.align_boot_limit(&mut policy);
+
+        assert_eq!(policy.keep_at_least, expected);
+    }
+
+    #[test]
+    fn cutoff_prefers_specific_profile() {
+        let settings = Settings {
+            cutoffs: vec![
+                "30".parse().unwrap(),
+                "/nix/var/nix/profiles/system=120".parse().unwrap(),
+            ],
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            settings.cutoff(Path::new("/nix/var/nix/profiles/system")),
+            Some(120)
+        );
+        assert_eq!(
+            settings.cutoff(Path::new("/home/user/.nix-profile")),
+            Some(30)
+        );
+        assert_eq!(Settings::default().cutoff(Path::new("/p")), None);
+    }
+
+    #[test]
+    fn pins_merge_with_pin_file() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-pin-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let pins_path = dir.join("pins");
+        std::fs::write(&pins_path, "# known good\n/p/a:5\n")?;
+        let settings = Settings {
+            pins: vec!["3".parse().unwrap(), "/p/b:8".parse().unwrap()],
+            pins_path: Some(pins_path.clone()),
+            ..Settings::default()
+        };
+
+        assert_eq!(settings.pinned(Path::new("/p/a"))?, BTreeSet::from([3, 5]));
+        assert_eq!(settings.pinned(Path::new("/p/b"))?, BTreeSet::from([3, 8]));
+
+        std::fs::write(&pins_path, "/p/a:five\n")?;
+        assert!(settings.pinned(Path::new("/p/a")).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn exceptions_merge() -> Result<()> {
+        let exceptions = |pinned: &[u32], always_delete: &[u32]| Exceptions {
+            pinned: pinned.iter().copied().collect(),
+            always_delete: always_delete.iter().copied().collect(),
+        };
+        let settings = Settings {
+            pins: vec!["1".parse().unwrap()],
+            container_exceptions: BTreeMap::from([("web".to_string(), exceptions(&[2], &[]))]),
+            profile_exceptions: BTreeMap::from([
+                (
+                    ProfileSelector::Kind(ProfileKind::Container),
+                    exceptions(&[], &[3]),
+                ),
+                (
+                    ProfileSelector::Path("/nix/var/nix/profiles/per-container/web/system".into()),
+                    exceptions(&[4], &[5, 6]),
+                ),
+            ]),
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            settings.exceptions(&Profile::new(
+                "/nix/var/nix/profiles/per-container/web/system"
+            ))?,
+            exceptions(&[1, 2, 4], &[3, 5, 6])
+        );
+        assert_eq!(
+            settings.exceptions(&Profile::new(
+                "/nix/var/nix/profiles/per-container/db/system"
+            ))?,
+            exceptions(&[1], &[3])
+        );
+        assert_eq!(
+            settings.exceptions(&Profile::new("/nix/var/nix/profiles/system"))?,
+            exceptions(&[1], &[])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn profile_overrides_take_precedence() {
+        let days = |days| RetentionOverrides {
+            keep: Some(Duration::days(days)),
+            keep_since: None,
+            keep_at_least: None,
+            keep_at_most: None,
+            order_by: None,
+            thinning: None,
+        };
+        let settings = Settings {
+            overrides: RetentionOverrides {
+                keep: Some(Duration::days(1)),
+                keep_since: None,
+                keep_at_least: Some(2),
+                keep_at_most: None,
+                order_by: None,
+                thinning: None,
+            },
+            container_overrides: BTreeMap::from([("web".to_string(), days(3))]),
+            profile_overrides: BTreeMap::from([
+                (ProfileSelector::Kind(ProfileKind::Container), days(2)),
+                (ProfileSelector::Kind(ProfileKind::User), days(4)),
+                (
+                    ProfileSelector::Path("/nix/var/nix/profiles/per-container/web/system".into()),
+                    days(5),
+                ),
+            ]),
+            ..Settings::default()
+        };
+        let keep = |path: &str| settings.overrides(&Profile::new(path)).keep;
+
+        assert_eq!(
+            keep("/nix/var/nix/profiles/per-container/web/system"),
+            Some(Duration::days(5))
+        );
+        assert_eq!(
+            keep("/nix/var/nix/profiles/per-container/db/system"),
+            Some(Duration::days(2))
+        );
+        assert_eq!(keep("/home/user/.nix-profile"), Some(Duration::days(4)));
+        assert_eq!(
+            keep("/nix/var/nix/profiles/system"),
+            Some(Duration::days(1))
+        );
+        assert_eq!(
+            settings
+                .overrides(&Profile::new("/home/user/.nix-profile"))
+                .keep_at_least,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn container_overrides_take_precedence() {
+        let settings = Settings {
+            overrides: RetentionOverrides {
+                keep: Some(Duration::days(1)),
+                keep_since: None,
+                keep_at_least: Some(2),
+                keep_at_most: None,
+                order_by: None,
+                thinning: None,
+            },
+            container_overrides: BTreeMap::from([(
+                "web".to_string(),
+                RetentionOverrides {
+                    keep: Some(Duration::days(30)),
+                    keep_since: None,
+                    keep_at_least: None,
+                    keep_at_most: None,
+                    order_by: None,
+                    thinning: None,
+                },
+            )]),
+            ..Settings::default()
+        };
+
+        let web = settings.overrides(&Profile::new(
+            "/nix/var/nix/profiles/per-container/web/system",
+        ));
+        assert_eq!(web.keep, Some(Duration::days(30)));
+        assert_eq!(web.keep_at_least, Some(2));
+
+        let db = settings.overrides(&Profile::new(
+            "/nix/var/nix/profiles/per-container/db/system",
+        ));
+        assert_eq!(db, settings.overrides);
+    }
+}
@@ -0,0 +1,171 @@
+//! Generations pinned so that no cleanup ever deletes them, see `--pin`.
+//!
+//! Besides the pins given on the command line, pins are read from the pin
+//! file next to the state, which is edited by hand. It holds one pin per
+//! line in the format of `--pin`, blank lines and lines starting with `#`
+//! are skipped:
+//!
+//! ```text
+//! # The last system that booted on the old kernel.
+//! /nix/var/nix/profiles/system:412
+//! 7
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use eyre::{eyre, Context, Result};
+
+/// A generation that is never deleted, of a single or of all profiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    /// The profile the pin applies to, all profiles if `None`.
+    pub profile: Option<PathBuf>,
+
+    /// The id of the pinned generation.
+    pub id: u32,
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.profile {
+            Some(profile) => write!(f, "{}:{}", profile.display(), self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (profile, id) = match s.rsplit_once(':') {
+            Some((profile, id)) => (Some(PathBuf::from(profile)), id),
+            None => (None, s),
+        };
+
+        if profile
+            .as_ref()
+            .is_some_and(|profile| !profile.is_absolute())
+        {
+            return Err(format!("profile path in {s:?} is not absolute"));
+        }
+        let id = id
+            .parse()
+            .map_err(|_| format!("invalid generation id in {s:?}"))?;
+
+        Ok(Self { profile, id })
+    }
+}
+
+/// The ids of the generations of the `profile` pinned by any of the `pins`.
+pub fn pinned(pins: &[Pin], profile: &Path) -> BTreeSet<u32> {
+    pins.iter()
+        .filter(|pin| pin.profile.as_deref().is_none_or(|p| p == profile))
+        .map(|pin| pin.id)
+        .collect()
+}
+
+/// Reads the pins from the pin file at `path`, none if it does not exist.
+///
+/// # Errors
+///
+/// Fails if the file can not be read or any of its pins is invalid, rather
+/// than risking to delete a generation meant to be pinned.
+pub fn load(path: &Path) -> Result<Vec<Pin>> {
+    tracing::debug!(?path, "reading pins");
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(error).wrap_err_with(|| format!("Failed to read pins {}", path.display()))
+        }
+    };
+
+    parse(&content).wrap_err_with(|| format!("Invalid pins {}", path.display()))
+}
+
+fn parse(content: &str) -> Result<Vec<Pin>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            line.parse()
+                .map_err(|error| eyre!("line {number}: {error}"))
+        })
+        .collect()
+}
+
+/// The default location of the pin file, next to the state at
+/// `state_path`.
+pub fn default_path(state_path: &Path) -> PathBuf {
+    state_path.with_file_name("pins")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::general("7", None, 7)]
+    #[case::profile(
+        "/nix/var/nix/profiles/system:412",
+        Some("/nix/var/nix/profiles/system"),
+        412
+    )]
+    fn parses(#[case] input: &str, #[case] profile: Option<&str>, #[case] id: u32) {
+        let pin: Pin = input.parse().unwrap();
+
+        assert_eq!(pin.profile.as_deref(), profile.map(Path::new));
+        assert_eq!(pin.id, id);
+        assert_eq!(pin.to_string(), input);
+    }
+
+    #[rstest]
+    #[case::relative("profile:3")]
+    #[case::no_id("/p:")]
+    #[case::not_a_number("/p:latest")]
+    #[case::negative("-1")]
+    fn rejects(#[case] input: &str) {
+        assert!(input.parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn merges_general_and_specific_pins() {
+        let pins = [
+            "3".parse().unwrap(),
+            "/p/a:5".parse().unwrap(),
+            "/p/b:8".parse().unwrap(),
+        ];
+
+        assert_eq!(pinned(&pins, Path::new("/p/a")), BTreeSet::from([3, 5]));
+        assert_eq!(pinned(&pins, Path::new("/p/c")), BTreeSet::from([3]));
+    }
+
+    #[test]
+    fn parses_pin_file() {
+        let pins = parse("# known good\n\n/p/a:5\n  3  \n").unwrap();
+
+        assert_eq!(pins, ["/p/a:5".parse().unwrap(), "3".parse().unwrap()]);
+        assert_eq!(
+            parse("3\n/p/a:x\n").unwrap_err().to_string(),
+            "line 2: invalid generation id in \"/p/a:x\""
+        );
+    }
+
+    #[test]
+    fn missing_pin_file_pins_nothing() {
+        let dir = std::env::temp_dir().join(format!("janitor-pins-{}", std::process::id()));
+
+        assert_eq!(load(&dir.join("pins")).unwrap(), []);
+    }
+}
@@ -0,0 +1,1054 @@
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use eyre::{bail, eyre, Context, Result};
+use futures::{stream, StreamExt, TryStreamExt};
+use tracing::Instrument;
+
+use janitor::{
+    mounts::MountTable,
+    nix_env, nix_store,
+    references::Referrers,
+    rusage::ResourceUsage,
+    size::{self, format_size, Estimate, NIX_STORE},
+    state::{Discovered, Executed, Listed, Planned, Verified},
+    Blocking, Executor, GenerationSet, GenerationSource, Job, Profile, ProfileKind, ProfileReport,
+    QuotaReport, RunReport, StdExecutor,
+};
+
+#[cfg(feature = "tokio")]
+use crate::runtime::RuntimeOptions;
+use crate::{
+    boot::{self, Mismatch},
+    cache::{ListingCache, Modified},
+    gc_lock::GcSlot,
+    plan,
+    power::POWER_SUPPLIES,
+    preflight,
+    prompt::{self, Interactive},
+    quota, record_profile, registry, system_gc, RunOptions,
+};
+
+/// The number of generations deleted at once when freeing up space, before
+/// checking whether enough space has been freed.
+const DELETION_BATCH_SIZE: usize = 3;
+
+/// How many profiles are processed at once on a tokio runtime, unless
+/// limited otherwise with `--jobs`.
+#[cfg(feature = "tokio")]
+const MAX_CONCURRENT_JOBS: usize = 4;
+
+/// How many lines of output of the garbage collector may be unrecognized
+/// before it is warned about, or with `--strict` fails the run.
+const MAX_UNRECOGNIZED_GC_LINES: u64 = 10;
+
+/// Runs all `jobs` on a tokio runtime, processing up to `--jobs`, by default
+/// [MAX_CONCURRENT_JOBS], profiles at once.
+///
+/// Listings are taken from and added to the `cache`, if given. The runtime is
+/// built according to `runtime`.
+#[cfg(feature = "tokio")]
+pub fn run_tokio(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+    runtime: RuntimeOptions,
+) -> Result<RunReport> {
+    let pipeline = Pipeline {
+        executor: &janitor::TokioExecutor,
+        source: options.backend.source(&janitor::TokioExecutor),
+        deadline: deadline(&options),
+        concurrency: concurrency(&options),
+        options,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    runtime.build()?.block_on(pipeline.run(jobs))
+}
+
+/// Runs all `jobs` on the tokio runtime it is awaited on, like [run_tokio].
+#[cfg(feature = "tokio")]
+pub async fn run_async(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+) -> Result<RunReport> {
+    let pipeline = Pipeline {
+        executor: &janitor::TokioExecutor,
+        source: options.backend.source(&janitor::TokioExecutor),
+        deadline: deadline(&options),
+        concurrency: concurrency(&options),
+        options,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    pipeline.run(jobs).await
+}
+
+/// Lists the generations of the profiles of all `jobs` and plans their
+/// deletion on the tokio runtime it is awaited on, without deleting
+/// anything.
+#[cfg(feature = "dbus")]
+pub async fn plan_async(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+) -> Result<Vec<Job<Planned>>> {
+    let pipeline = Pipeline {
+        executor: &janitor::TokioExecutor,
+        source: options.backend.source(&janitor::TokioExecutor),
+        deadline: deadline(&options),
+        concurrency: concurrency(&options),
+        options,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    pipeline.plan_all(jobs).await
+}
+
+/// Lists the generations of the profiles of all `jobs` and plans their
+/// deletion on a tokio runtime, like [run_tokio] but without deleting
+/// anything.
+#[cfg(feature = "tokio")]
+pub fn plan_tokio(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+    runtime: RuntimeOptions,
+) -> Result<Vec<Job<Planned>>> {
+    let pipeline = Pipeline {
+        executor: &janitor::TokioExecutor,
+        source: options.backend.source(&janitor::TokioExecutor),
+        deadline: deadline(&options),
+        concurrency: concurrency(&options),
+        options,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    runtime.build()?.block_on(pipeline.plan_all(jobs))
+}
+
+/// Lists the generations of the profiles of all `jobs` and plans their
+/// deletion one after another, like [run_blocking] but without deleting
+/// anything.
+pub fn plan_blocking(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+) -> Result<Vec<Job<Planned>>> {
+    let pipeline = Pipeline {
+        executor: &Blocking(StdExecutor),
+        source: options.backend.source(&Blocking(StdExecutor)),
+        deadline: deadline(&options),
+        options,
+        concurrency: 1,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    futures::executor::block_on(pipeline.plan_all(jobs))
+}
+
+/// Runs all `jobs` one after another on the current thread, without an async
+/// runtime.
+///
+/// Listings are taken from and added to the `cache`, if given.
+pub fn run_blocking(
+    jobs: Vec<Job<Discovered>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+) -> Result<RunReport> {
+    let pipeline = Pipeline {
+        executor: &Blocking(StdExecutor),
+        source: options.backend.source(&Blocking(StdExecutor)),
+        deadline: deadline(&options),
+        options,
+        concurrency: 1,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    futures::executor::block_on(pipeline.run(jobs))
+}
+
+/// Deletes the generations planned by the `jobs` on a tokio runtime, after
+/// checking that none of their profiles changed since, like [apply_blocking].
+#[cfg(feature = "tokio")]
+pub fn apply_tokio(
+    jobs: Vec<Job<Planned>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+    runtime: RuntimeOptions,
+) -> Result<RunReport> {
+    let pipeline = Pipeline {
+        executor: &janitor::TokioExecutor,
+        source: options.backend.source(&janitor::TokioExecutor),
+        deadline: deadline(&options),
+        concurrency: concurrency(&options),
+        options,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    runtime.build()?.block_on(pipeline.apply(jobs))
+}
+
+/// Deletes the generations planned by the `jobs` one after another, after
+/// checking that none of their profiles changed since, and performs the
+/// further steps of the run.
+pub fn apply_blocking(
+    jobs: Vec<Job<Planned>>,
+    options: RunOptions,
+    cache: Option<&Mutex<ListingCache>>,
+) -> Result<RunReport> {
+    let pipeline = Pipeline {
+        executor: &Blocking(StdExecutor),
+        source: options.backend.source(&Blocking(StdExecutor)),
+        deadline: deadline(&options),
+        options,
+        concurrency: 1,
+        cache,
+        referrers: OnceLock::new(),
+    };
+
+    futures::executor::block_on(pipeline.apply(jobs))
+}
+
+/// How many profiles to process at once on a tokio runtime with the
+/// `options`.
+#[cfg(feature = "tokio")]
+fn concurrency(options: &RunOptions) -> usize {
+    options.jobs.map_or(MAX_CONCURRENT_JOBS, |jobs| jobs.get())
+}
+
+/// The steps of a run, independent of how the external commands are
+/// executed.
+struct Pipeline<'a> {
+    executor: &'a dyn Executor,
+    source: Box<dyn GenerationSource + 'a>,
+
+    /// When the run exceeds `--max-runtime`, if it is limited.
+    deadline: Option<Instant>,
+    options: RunOptions,
+    concurrency: usize,
+    cache: Option<&'a Mutex<ListingCache>>,
+
+    /// The references to store paths from outside of the profiles, scanned
+    /// once for the first profile listed.
+    referrers: OnceLock<Referrers>,
+}
+
+/// A planned job, together with the space its deletions would reclaim.
+struct Estimated {
+    job: Job<Planned>,
+    reclaimable: u64,
+}
+
+impl Pipeline<'_> {
+    async fn run(&self, jobs: Vec<Job<Discovered>>) -> Result<RunReport> {
+        let profiles = jobs.iter().map(|job| job.path().as_path());
+        let gc = preflight::check(profiles, &self.options, &MountTable::system())?;
+        let before = ResourceUsage::children()
+            .map_err(|error| tracing::debug!(%error, "not accounting resource usage"))
+            .ok();
+
+        let report = match self.options.free_at_least {
+            Some(target) => self.run_prioritized(jobs, target).await?,
+            None if self.options.atomic
+                || self.budget().is_some()
+                || self.options.interactive == Some(Interactive::Once) =>
+            {
+                self.run_planned(jobs).await?
+            }
+            None => self.run_all(jobs).await?,
+        };
+
+        self.finish_run(report, gc, before).await
+    }
+
+    /// Deletes the generations planned by the `jobs`, refusing to delete
+    /// anything if the profile of any of them changed since it has been
+    /// planned, and performs the further steps of the run.
+    async fn apply(&self, jobs: Vec<Job<Planned>>) -> Result<RunReport> {
+        let profiles = jobs.iter().map(|job| job.path().as_path());
+        let gc = preflight::check(profiles, &self.options, &MountTable::system())?;
+        let before = ResourceUsage::children()
+            .map_err(|error| tracing::debug!(%error, "not accounting resource usage"))
+            .ok();
+
+        let mut changes = Vec::new();
+        for job in &jobs {
+            let relisted = self
+                .source
+                .list(job.path())
+                .await
+                .map_err(|error| job.fail(error))?;
+            changes.extend(plan::changes_since_planned(job, &relisted));
+        }
+        if !changes.is_empty() {
+            bail!(
+                "{} profile(s) changed since the plan has been made, not deleting any \
+                 generations; plan again:\n  {}",
+                changes.len(),
+                changes.join("\n  ")
+            );
+        }
+
+        if self.options.atomic {
+            validate_all(&jobs)?;
+        }
+        self.confirm_all(&jobs)?;
+
+        let report = self.finish_all(jobs, RunReport::default()).await?;
+
+        self.finish_run(report, gc, before).await
+    }
+
+    /// Performs the steps of the run after the deletions, adding to the
+    /// `report`: the garbage collection if `gc`, checking the registry pins
+    /// and verifying the store, and the resources used since `before`.
+    async fn finish_run(
+        &self,
+        mut report: RunReport,
+        gc: bool,
+        before: Option<ResourceUsage>,
+    ) -> Result<RunReport> {
+        if gc
+            && self.options.free_at_least.is_none()
+            && system_gc::allows_gc(self.options.defer_to_system_gc, Path::new("/"))
+            && self
+                .options
+                .power
+                .allows_gc(self.executor, Path::new(POWER_SUPPLIES))
+                .await
+            && !self.cut_short(&mut report, "collecting garbage".to_string())
+        {
+            match self.take_gc_slot()? {
+                Some(_slot) => report.record_gc(self.perform_gc(self.options.max_freed).await?),
+                None => tracing::warn!(
+                    "all slots of the gc lock are held, skipping the garbage collection"
+                ),
+            }
+        }
+
+        if let Some(action) = self.options.stale_pins {
+            if !self.cut_short(&mut report, "checking registry pins".to_string()) {
+                let stale = registry::clean(self.executor, action)
+                    .instrument(tracing::info_span!("stale_pins"))
+                    .await?;
+                tracing::info!(stale = stale.len(), "checked registry pins");
+            }
+        }
+
+        let verify = self.options.repair_store || self.options.verify_store;
+        if verify && !self.cut_short(&mut report, "verifying the store".to_string()) {
+            match self.options.repair_store {
+                true => report.record_verification(self.repair_store().await?),
+                false => report.record_verification(self.verify_store().await?),
+            }
+        }
+
+        if let (Some(before), Ok(after)) = (before, ResourceUsage::children()) {
+            report.record_resources(after.since(&before));
+        }
+
+        Ok(report)
+    }
+
+    async fn run_all(&self, jobs: Vec<Job<Discovered>>) -> Result<RunReport> {
+        let total = jobs.len();
+
+        stream::iter(jobs)
+            .map(|job| async move {
+                let path = job.path().clone();
+                let outcome = match self.past_deadline() {
+                    true => None,
+                    false => Some(self.process_profile(job).await),
+                };
+                (path, outcome)
+            })
+            .buffer_unordered(self.concurrency)
+            .map(Ok)
+            .try_fold(
+                RunReport::default(),
+                |mut report, (path, outcome)| async move {
+                    self.record(&mut report, &path, outcome, total)?;
+
+                    Ok(report)
+                },
+            )
+            .instrument(tracing::info_span!("processing_profiles"))
+            .await
+    }
+
+    /// Lists and plans all profiles before deleting from any of them, to
+    /// enforce the per-user budgets or, if atomic, to delete nothing unless
+    /// all of them pass [validate_all].
+    ///
+    /// Listing or planning a profile failing aborts the run before anything
+    /// is deleted. A deletion failing after the validation only aborts it
+    /// with `--fail-fast`, with the profiles processed until then cleaned
+    /// up.
+    async fn run_planned(&self, jobs: Vec<Job<Discovered>>) -> Result<RunReport> {
+        let (planned, quotas) = self.plan_within_budgets(jobs).await?;
+
+        if self.options.atomic {
+            validate_all(&planned)?;
+        }
+        self.confirm_all(&planned)?;
+
+        let mut report = RunReport::default();
+        for quota in quotas {
+            report.record_quota(quota);
+        }
+
+        self.finish_all(planned, report).await
+    }
+
+    /// Deletes the generations planned by the `jobs` and verifies the
+    /// deletions, recording the outcomes in the `report`.
+    async fn finish_all(&self, jobs: Vec<Job<Planned>>, report: RunReport) -> Result<RunReport> {
+        let total = jobs.len();
+
+        stream::iter(jobs)
+            .map(|job| async move {
+                let path = job.path().clone();
+                let outcome = match self.past_deadline() {
+                    true => None,
+                    false => Some(self.finish_profile(job).await),
+                };
+                (path, outcome)
+            })
+            .buffer_unordered(self.concurrency)
+            .map(Ok)
+            .try_fold(report, |mut report, (path, outcome)| async move {
+                self.record(&mut report, &path, outcome, total)?;
+
+                Ok(report)
+            })
+            .instrument(tracing::info_span!("processing_profiles"))
+            .await
+    }
+
+    /// Records the `outcome` of cleaning up the profile at `path` in the
+    /// `report`, `None` if it has not been cleaned up as the run exceeded
+    /// `--max-runtime`.
+    ///
+    /// A failure aborts the run with `--fail-fast`, otherwise it is recorded
+    /// and the other profiles are cleaned up nonetheless.
+    fn record(
+        &self,
+        report: &mut RunReport,
+        path: &Path,
+        outcome: Option<Result<ProfileReport>>,
+        total: usize,
+    ) -> Result<()> {
+        match outcome {
+            None => {
+                self.cut_short(report, format!("cleaning up {}", path.display()));
+            }
+            Some(Ok(profile)) => record_profile(report, profile, total),
+            Some(Err(error)) if self.options.fail_fast => return Err(error),
+            Some(Err(error)) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = format!("{error:#}"),
+                    "failed to clean up profile, continuing with the others"
+                );
+                report.record_failure(path, &error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists and plans all profiles, without deleting anything.
+    async fn plan_all(&self, jobs: Vec<Job<Discovered>>) -> Result<Vec<Job<Planned>>> {
+        Ok(self.plan_within_budgets(jobs).await?.0)
+    }
+
+    /// Lists and plans all profiles like [Pipeline::plan_all], enforcing the
+    /// per-user budget, if any, and returning the users exceeding it.
+    async fn plan_within_budgets(
+        &self,
+        jobs: Vec<Job<Discovered>>,
+    ) -> Result<(Vec<Job<Planned>>, Vec<QuotaReport>)> {
+        let Some(budget) = self.budget() else {
+            let planned = stream::iter(jobs)
+                .map(|job| self.plan(job))
+                .buffer_unordered(self.concurrency)
+                .try_collect()
+                .instrument(tracing::info_span!("planning_profiles"))
+                .await?;
+
+            return Ok((planned, Vec::new()));
+        };
+
+        let listed = stream::iter(jobs)
+            .map(|job| self.list(job))
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .instrument(tracing::info_span!("listing_profiles"))
+            .await?;
+        let (listed, quotas) = quota::enforce(
+            self.executor,
+            listed,
+            budget,
+            self.options.per_user_floor,
+            self.options.size_estimation,
+        )
+        .instrument(tracing::info_span!("enforcing_budgets"))
+        .await;
+
+        let planned = listed
+            .into_iter()
+            .map(|job| self.plan_listed(job))
+            .collect();
+
+        Ok((planned, quotas))
+    }
+
+    /// The per-user budget, if one is set and the janitor runs as root, as
+    /// only root can clean up the profiles of other users.
+    fn budget(&self) -> Option<u64> {
+        let budget = self.options.per_user_budget?;
+        if !is_root::is_root() {
+            tracing::warn!("per-user budgets are only enforced when running as root");
+            return None;
+        }
+
+        Some(budget)
+    }
+
+    /// Frees up space until at least `target` bytes are available in the
+    /// store.
+    ///
+    /// Profiles are processed in the order that reclaims the most space
+    /// first. Their generations are deleted in batches of
+    /// [DELETION_BATCH_SIZE], oldest first, each followed by a garbage
+    /// collection bounded to the space still missing, until the target is met
+    /// or the retention policies forbid deleting any further generation.
+    async fn run_prioritized(&self, jobs: Vec<Job<Discovered>>, target: u64) -> Result<RunReport> {
+        let total = jobs.len();
+
+        let (planned, quotas) = self.plan_within_budgets(jobs).await?;
+        let mut planned = stream::iter(planned)
+            .map(|job| self.estimate(job))
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .instrument(tracing::info_span!("estimating_profiles"))
+            .await;
+
+        planned.sort_by_key(|p| std::cmp::Reverse(p.reclaimable));
+
+        if self.options.atomic {
+            validate_all(planned.iter().map(|p| &p.job))?;
+        }
+        self.confirm_all(planned.iter().map(|p| &p.job))?;
+
+        // Freeing space relies on collecting garbage after each batch, so
+        // the slot is held throughout instead of skipping the collections.
+        let _slot = self
+            .take_gc_slot()?
+            .ok_or_else(|| eyre!("all slots of the gc lock are held, not freeing space"))?;
+
+        let mut report = RunReport::default();
+        for quota in quotas {
+            report.record_quota(quota);
+        }
+
+        for Estimated { job, reclaimable } in planned {
+            if self.cut_short(&mut report, format!("cleaning up {}", job.path().display())) {
+                continue;
+            }
+            if missing_space(target)?.is_none() {
+                tracing::info!(
+                    job_id = %job.id(),
+                    path = %job.path().display(),
+                    target = %format_size(target),
+                    "enough free space, skipping profile"
+                );
+                report.record_skipped(job.path());
+                continue;
+            }
+
+            let span = tracing::info_span!("job", job_id = %job.id(), path = %job.path().display());
+            let profile = self
+                .free_from_profile(job, reclaimable, target, &mut report)
+                .instrument(span)
+                .await?;
+
+            record_profile(&mut report, profile, total);
+        }
+
+        if let Some(missing) = missing_space(target)? {
+            tracing::warn!(
+                missing = %format_size(missing),
+                "retention policies forbid deleting further generations, free space target not met"
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Deletes the planned generations of a single profile batch by batch,
+    /// collecting garbage after each batch, until the `target` is met.
+    async fn free_from_profile(
+        &self,
+        job: Job<Planned>,
+        reclaimable: u64,
+        target: u64,
+        report: &mut RunReport,
+    ) -> Result<ProfileReport> {
+        if !self.confirmed(&job)? {
+            let job = job.executed(GenerationSet::default());
+            return Ok(self.verify(job).await.into_report());
+        }
+        tracing::info!(reclaimable = %format_size(reclaimable), "freeing space");
+
+        let mut deleted = Vec::new();
+
+        for batch in job.state().to_delete.batches(DELETION_BATCH_SIZE) {
+            let Some(missing) = missing_space(target)? else {
+                tracing::info!(target = %format_size(target), "free space target met");
+                break;
+            };
+            let step = format!("freeing further space from {}", job.path().display());
+            if self.cut_short(report, step) {
+                break;
+            }
+
+            let removed = self
+                .delete(job.path(), &batch)
+                .await
+                .map_err(|error| job.fail(error))?;
+            let batch = self.confirm_deleted(job.path(), &batch, removed).await;
+            self.options
+                .progress
+                .generations_deleted(job.path(), &batch);
+            deleted.extend(batch);
+
+            report.record_gc(self.perform_gc(Some(missing)).await?);
+        }
+
+        let job = job.executed(GenerationSet::from(deleted));
+
+        Ok(self.verify(job).await.into_report())
+    }
+
+    #[tracing::instrument(name = "job", skip_all, fields(job_id = %job.id(), path = %job.path().display()))]
+    async fn process_profile(&self, job: Job<Discovered>) -> Result<ProfileReport> {
+        let job = self.plan(job).await?;
+        let job = self.execute(job).await?;
+
+        Ok(self.verify(job).await.into_report())
+    }
+
+    #[tracing::instrument(name = "job", skip_all, fields(job_id = %job.id(), path = %job.path().display()))]
+    async fn finish_profile(&self, job: Job<Planned>) -> Result<ProfileReport> {
+        let job = self.execute(job).await?;
+
+        Ok(self.verify(job).await.into_report())
+    }
+
+    #[tracing::instrument(name = "job", skip_all, fields(job_id = %job.id(), path = %job.path().display()))]
+    async fn estimate(&self, job: Job<Planned>) -> Estimated {
+        let Planned { listed, to_delete } = job.state();
+        let kept = listed.difference(to_delete);
+
+        let estimation = self.options.size_estimation;
+        let reclaimable = match size::estimate_reclaimable_with(
+            self.executor,
+            job.path(),
+            to_delete,
+            &kept,
+            estimation,
+        )
+        .await
+        {
+            Ok(reclaimable) => reclaimable,
+            Err(error) => {
+                tracing::warn!(%error, "failed to estimate reclaimable space");
+
+                Estimate::default()
+            }
+        };
+
+        tracing::info!(%reclaimable, "estimated reclaimable space");
+
+        Estimated {
+            job,
+            reclaimable: reclaimable.bytes,
+        }
+    }
+
+    /// Lists the generations of the profile of the `job` and plans their
+    /// deletion, warning if the plan for the system profile does not match
+    /// the boot entries.
+    async fn plan(&self, job: Job<Discovered>) -> Result<Job<Planned>> {
+        Ok(self.plan_listed(self.list(job).await?))
+    }
+
+    /// Plans the deletion of the listed `job` like [Pipeline::plan].
+    fn plan_listed(&self, job: Job<Listed>) -> Job<Planned> {
+        let system = Profile::new(job.path().as_path()).kind() == ProfileKind::System;
+        let job = job.plan();
+
+        if system {
+            self.check_boot_limit(&job);
+        }
+
+        job
+    }
+
+    /// Warns if the system generations kept by the plan of the `job` differ
+    /// from the ones the bootloader lists, see [boot].
+    fn check_boot_limit(&self, job: &Job<Planned>) {
+        let Some(limit) = self.options.boot.limit(Path::new(boot::BOOT)) else {
+            tracing::debug!("no bootloader entries found, not comparing the retention");
+            return;
+        };
+        let Planned { listed, to_delete } = job.state();
+        let kept = listed.len().saturating_sub(to_delete.len());
+
+        match boot::compare(kept, listed.len(), limit) {
+            Some(Mismatch::FewerKept) => tracing::warn!(
+                kept,
+                limit,
+                "keeping fewer system generations than the bootloader lists, \
+                 booting the entries of deleted ones fails after the garbage collection \
+                 until the next switch; raise keep-at-least or set align_boot_limit"
+            ),
+            Some(Mismatch::MoreKept) => tracing::warn!(
+                kept,
+                limit,
+                "keeping more system generations than the bootloader lists, \
+                 the older ones can not be booted; lower keep-at-least or raise \
+                 the configurationLimit"
+            ),
+            None => tracing::debug!(kept, limit, "retention matches the bootloader"),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn list(&self, job: Job<Discovered>) -> Result<Job<Listed>> {
+        let parsed = self
+            .list_generations(job.path())
+            .await
+            .map_err(|error| job.fail(error))?;
+
+        let referenced = self
+            .referrers
+            .get_or_init(Referrers::system)
+            .of_generations(job.path(), &parsed);
+
+        Ok(job.listed(parsed).referenced(referenced))
+    }
+
+    /// Lists the generations of the profile at `path` with the configured
+    /// backend, unless the cache holds a listing taken since the profile has
+    /// been modified last.
+    async fn list_generations(&self, path: &Path) -> Result<GenerationSet> {
+        let modified = self.cache.and_then(|cache| match Modified::of(path) {
+            Ok(modified) => Some((cache, modified)),
+            Err(error) => {
+                tracing::debug!(%error, "not caching the listing");
+
+                None
+            }
+        });
+        let backend = self.options.backend;
+
+        if let Some((cache, modified)) = modified {
+            let cached = cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(path, backend, modified);
+            if let Some(generations) = cached {
+                tracing::debug!(?path, "using cached listing");
+
+                return Ok(generations);
+            }
+        }
+
+        let generations = self.source.list(path).await?;
+
+        if let Some((cache, modified)) = modified {
+            cache.lock().unwrap_or_else(|e| e.into_inner()).insert(
+                path,
+                backend,
+                modified,
+                &generations,
+            );
+        }
+
+        Ok(generations)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn execute(&self, job: Job<Planned>) -> Result<Job<Executed>> {
+        if !self.confirmed(&job)? {
+            return Ok(job.executed(GenerationSet::default()));
+        }
+
+        let to_delete = &job.state().to_delete;
+        let removed = self
+            .delete(job.path(), to_delete)
+            .await
+            .map_err(|error| job.fail(error))?;
+
+        let deleted = self.confirm_deleted(job.path(), to_delete, removed).await;
+        self.options
+            .progress
+            .generations_deleted(job.path(), &deleted);
+
+        Ok(job.executed(deleted))
+    }
+
+    /// Asks once whether to delete the planned generations of all `jobs`,
+    /// if `--interactive` asks so, failing if that is declined.
+    fn confirm_all<'j>(&self, jobs: impl IntoIterator<Item = &'j Job<Planned>>) -> Result<()> {
+        if self.options.interactive == Some(Interactive::Once) && !prompt::confirm_deletions(jobs)?
+        {
+            bail!("the deletions have not been confirmed");
+        }
+
+        Ok(())
+    }
+
+    /// Whether the planned generations of the `job` may be deleted, asking
+    /// if `--interactive` asks for each profile.
+    fn confirmed(&self, job: &Job<Planned>) -> Result<bool> {
+        if self.options.interactive != Some(Interactive::Profile)
+            || prompt::confirm_deletions([job])?
+        {
+            return Ok(true);
+        }
+
+        tracing::info!("the deletions have not been confirmed, keeping all generations");
+        Ok(false)
+    }
+
+    /// Deletes the `generations` from the profile at `path`, returning the
+    /// ids the generation source reported removing, if it did.
+    async fn delete(
+        &self,
+        path: &Path,
+        generations: &GenerationSet,
+    ) -> Result<Option<BTreeSet<u32>>> {
+        let ids: Vec<_> = generations.iter().map(|g| g.id).collect();
+
+        tracing::info!(?path, ?ids, "deleting generations");
+
+        if let Some(cache) = self.cache {
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .invalidate(path);
+        }
+        let removed = self.source.delete(path, generations).await?;
+
+        tracing::info!(?path, ?ids, "deleted generations");
+
+        Ok(removed)
+    }
+
+    /// Cross-checks the generations `nix-env` reported as `removed` with the
+    /// `requested` ones, returning those actually deleted.
+    ///
+    /// Requested generations not reported as removed are looked up in a new
+    /// listing of the profile, those still listed are logged with the reason
+    /// and left out. If `nix-env` did not report anything or the profile
+    /// can not be listed, all requested generations are taken as deleted.
+    async fn confirm_deleted(
+        &self,
+        path: &Path,
+        requested: &GenerationSet,
+        removed: Option<BTreeSet<u32>>,
+    ) -> GenerationSet {
+        let Some(removed) = removed else {
+            tracing::debug!(?path, "nix-env did not report the removed generations");
+            return requested.clone();
+        };
+        if requested.iter().all(|g| removed.contains(&g.id)) {
+            return requested.clone();
+        }
+
+        let relisted = match self.list_generations(path).await {
+            Ok(relisted) => relisted,
+            Err(error) => {
+                tracing::warn!(%error, ?path, "failed to list generations to check the deletion");
+                return requested.clone();
+            }
+        };
+
+        let skipped = nix_env::skipped(requested, &removed, &relisted);
+        for (id, reason) in &skipped {
+            tracing::warn!(?path, generation = id, %reason, "generation has not been deleted");
+        }
+
+        requested
+            .iter()
+            .filter(|g| !skipped.contains_key(&g.id))
+            .cloned()
+            .collect()
+    }
+
+    /// Lists the generations of the profile again after the deletion, to find
+    /// generations that have been created while the janitor was running.
+    #[tracing::instrument(skip_all)]
+    async fn verify(&self, job: Job<Executed>) -> Job<Verified> {
+        let relisted = match self.list_generations(job.path()).await {
+            Ok(relisted) => Some(relisted),
+            Err(error) => {
+                tracing::warn!(%error, "failed to list generations again after deletion");
+
+                None
+            }
+        };
+
+        let job = job.verify(relisted.as_ref());
+
+        let appeared = &job.state().appeared;
+        if !appeared.is_empty() {
+            let ids: Vec<_> = appeared.iter().map(|g| g.id).collect();
+            tracing::warn!(?ids, "generations appeared during the run, left untouched");
+        }
+
+        job
+    }
+
+    /// Whether the run has exceeded `--max-runtime`.
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether the `step` is skipped as the run exceeded `--max-runtime`,
+    /// recording it in the `report` if so.
+    fn cut_short(&self, report: &mut RunReport, step: String) -> bool {
+        let past = self.past_deadline();
+        if past {
+            tracing::warn!(%step, "maximum runtime exceeded, skipping");
+            report.record_cut_short(step);
+        }
+
+        past
+    }
+
+    /// Takes a slot of the [GcLock](crate::gc_lock::GcLock), `None` if all
+    /// of them are held, or an unlocked slot without a lock.
+    fn take_gc_slot(&self) -> Result<Option<GcSlot>> {
+        match &self.options.gc_lock {
+            Some(lock) => lock.acquire(),
+            None => Ok(Some(GcSlot::unlocked())),
+        }
+    }
+
+    /// Collects garbage, stopping once `max_freed` bytes have been freed if
+    /// given.
+    #[tracing::instrument(skip(self))]
+    async fn perform_gc(&self, max_freed: Option<u64>) -> Result<nix_store::GcReport> {
+        tracing::info!("collecting garbage");
+
+        let gc = nix_store::collect_garbage_with_progress(
+            self.executor,
+            max_freed,
+            &self.options.gc_log,
+            &self.options.progress,
+        )
+        .await?;
+
+        tracing::info!(
+            paths_deleted = gc.paths_deleted,
+            bytes_freed = gc.bytes_freed,
+            "collected garbage"
+        );
+
+        if gc.unrecognized > MAX_UNRECOGNIZED_GC_LINES {
+            if self.options.strict {
+                bail!(
+                    "{} lines of output of the garbage collector have not been recognized",
+                    gc.unrecognized
+                );
+            }
+            tracing::warn!(
+                unrecognized = gc.unrecognized,
+                "output of the garbage collector has not been recognized"
+            );
+        }
+
+        Ok(gc)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn verify_store(&self) -> Result<nix_store::VerifyReport> {
+        tracing::info!("verifying store");
+
+        Ok(nix_store::verify_store(self.executor).await?)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn repair_store(&self) -> Result<nix_store::VerifyReport> {
+        tracing::info!("verifying and repairing store");
+
+        Ok(nix_store::repair_store(self.executor).await?)
+    }
+}
+
+/// When a run started now exceeds the maximum runtime of the `options`, if
+/// it is limited.
+fn deadline(options: &RunOptions) -> Option<Instant> {
+    let max_runtime = options.max_runtime?.to_std().ok()?;
+
+    Instant::now().checked_add(max_runtime)
+}
+
+/// Checks with [preflight::validate] that generations can be deleted from
+/// all profiles of the `jobs` with something to delete.
+///
+/// # Errors
+///
+/// Fails listing every profile failing the checks.
+fn validate_all<'j>(jobs: impl IntoIterator<Item = &'j Job<Planned>>) -> Result<()> {
+    let failures: Vec<_> = jobs
+        .into_iter()
+        .filter(|job| !job.state().to_delete.is_empty())
+        .filter_map(|job| {
+            preflight::validate(job.path())
+                .err()
+                .map(|error| format!("{}: {error:#}", job.path().display()))
+        })
+        .collect();
+
+    if !failures.is_empty() {
+        bail!(
+            "{} profile(s) failed validation, not deleting any generations:\n  {}",
+            failures.len(),
+            failures.join("\n  ")
+        );
+    }
+
+    tracing::info!("validated all profiles, deleting generations");
+
+    Ok(())
+}
+
+/// Returns how many bytes are missing to have `target` bytes available in
+/// the store, or `None` if there is enough space already.
+fn missing_space(target: u64) -> Result<Option<u64>> {
+    let free = size::free_space(NIX_STORE)
+        .wrap_err_with(|| format!("Failed to determine free space of {NIX_STORE}"))?;
+
+    tracing::debug!(free = %format_size(free), target = %format_size(target), "checked free space");
+
+    Ok((free < target).then(|| target - free))
+}
@@ -0,0 +1,466 @@
+//! Renders the deletion plan of `janitor plan`, also as a Nix expression,
+//! and the generations listed by `janitor list`, and reads and writes the
+//! plan files of `janitor plan --out` and `janitor apply`.
+
+use std::{fmt, fs, path::Path};
+
+use eyre::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use janitor::{state::Planned, GenerationSet, Job};
+
+/// The version of the format of plan files, a plan file of another version
+/// is refused.
+const PLAN_FILE_VERSION: u32 = 1;
+
+/// A plan file as written, see [write_plan].
+#[derive(Debug, Serialize)]
+struct Written<'a> {
+    version: u32,
+    jobs: &'a [Job<Planned>],
+}
+
+/// A plan file as read, see [read_plan].
+#[derive(Debug, Deserialize)]
+struct Read {
+    version: u32,
+    jobs: Vec<Job<Planned>>,
+}
+
+/// Writes the `planned` jobs to a plan file at `path` as JSON, with the
+/// generations listed when planning and the ones to delete.
+pub fn write_plan(path: &Path, planned: &[Job<Planned>]) -> Result<()> {
+    let written = Written {
+        version: PLAN_FILE_VERSION,
+        jobs: planned,
+    };
+    let json = serde_json::to_string_pretty(&written)?;
+
+    fs::write(path, json + "\n")
+        .wrap_err_with(|| format!("Failed to write plan {}", path.display()))
+}
+
+/// Reads the planned jobs from the plan file at `path`.
+///
+/// The jobs are not planned again, so a plan that could not have been made,
+/// e.g. one edited by hand, is refused, see [check_planned].
+///
+/// # Errors
+///
+/// Fails if the file can not be read or parsed, has been written in another
+/// version of the format, or contains an impossible plan.
+pub fn read_plan(path: &Path) -> Result<Vec<Job<Planned>>> {
+    let json = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read plan {}", path.display()))?;
+    let read: Read = serde_json::from_str(&json)
+        .wrap_err_with(|| format!("Failed to parse plan {}", path.display()))?;
+
+    if read.version != PLAN_FILE_VERSION {
+        bail!(
+            "plan {} has version {}, only version {PLAN_FILE_VERSION} is supported",
+            path.display(),
+            read.version
+        );
+    }
+    for job in &read.jobs {
+        check_planned(job).wrap_err_with(|| format!("Invalid plan {}", path.display()))?;
+    }
+
+    Ok(read.jobs)
+}
+
+/// Fails unless the `job` could have been planned: its profile is given by
+/// an absolute path, and it deletes only listed generations, none of them
+/// the current one.
+fn check_planned(job: &Job<Planned>) -> Result<()> {
+    let path = job.path();
+    if path.is_relative() {
+        bail!("profile path {} is not absolute", path.display());
+    }
+
+    let Planned { listed, to_delete } = job.state();
+    let unlisted = to_delete.difference(listed);
+    if !unlisted.is_empty() {
+        bail!(
+            "{}: deletes generations {} that have not been listed",
+            path.display(),
+            plain_ids(&unlisted)
+        );
+    }
+    if let Some(current) = to_delete
+        .iter()
+        .find(|g| g.current || listed.get(g.id).is_some_and(|g| g.current))
+    {
+        bail!(
+            "{}: deletes the current generation {}",
+            path.display(),
+            current.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Describes how the profile of the planned `job` changed since it has been
+/// planned, given its generations as `relisted` now, `None` if it did not.
+pub fn changes_since_planned(job: &Job<Planned>, relisted: &GenerationSet) -> Option<String> {
+    let listed = &job.state().listed;
+    let current =
+        |generations: &GenerationSet| generations.iter().find(|g| g.current).map(|g| g.id);
+
+    let mut changes = Vec::new();
+    let appeared = relisted.difference(listed);
+    if !appeared.is_empty() {
+        changes.push(format!("generations {} appeared", plain_ids(&appeared)));
+    }
+    let disappeared = listed.difference(relisted);
+    if !disappeared.is_empty() {
+        changes.push(format!(
+            "generations {} disappeared",
+            plain_ids(&disappeared)
+        ));
+    }
+    if current(listed) != current(relisted) {
+        let shown = |id: Option<u32>| id.map_or_else(|| "none".to_string(), |id| id.to_string());
+        changes.push(format!(
+            "the current generation changed from {} to {}",
+            shown(current(listed)),
+            shown(current(relisted))
+        ));
+    }
+
+    (!changes.is_empty()).then(|| format!("{}: {}", job.path().display(), changes.join(", ")))
+}
+
+/// The generations each profile would delete and keep.
+#[derive(Debug)]
+pub struct Plan<'a>(pub &'a [Job<Planned>]);
+
+impl fmt::Display for Plan<'_> {
+    /// Lists the ids of the generations per profile, in the order of the
+    /// profile paths, marking the current generation.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut jobs: Vec<_> = self.0.iter().collect();
+        jobs.sort_by(|a, b| a.path().cmp(b.path()));
+
+        if jobs.is_empty() {
+            return writeln!(f, "no profiles to clean up");
+        }
+
+        for job in jobs {
+            let Planned { listed, to_delete } = job.state();
+
+            writeln!(f, "{}", job.path().display())?;
+            writeln!(f, "  delete: {}", ids(to_delete))?;
+            writeln!(f, "  keep:   {}", ids(&listed.difference(to_delete)))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The generations each profile would delete and keep, as a Nix attribute
+/// set for `--emit-nix`.
+#[derive(Debug)]
+pub struct NixPlan<'a>(pub &'a [Job<Planned>]);
+
+impl fmt::Display for NixPlan<'_> {
+    /// Maps the path of every profile to the ids of the generations to
+    /// `delete` and to `keep` and the id of the `current` one, in the order
+    /// of the profile paths.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut jobs: Vec<_> = self.0.iter().collect();
+        jobs.sort_by(|a, b| a.path().cmp(b.path()));
+
+        writeln!(f, "{{")?;
+        for job in jobs {
+            let Planned { listed, to_delete } = job.state();
+            let current = listed
+                .iter()
+                .find(|g| g.current)
+                .map_or_else(|| "null".to_string(), |g| g.id.to_string());
+
+            writeln!(
+                f,
+                "  {} = {{",
+                nix_string(&job.path().display().to_string())
+            )?;
+            writeln!(f, "    delete = {};", nix_list(to_delete))?;
+            writeln!(f, "    keep = {};", nix_list(&listed.difference(to_delete)))?;
+            writeln!(f, "    current = {current};")?;
+            writeln!(f, "  }};")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// The generations of each profile.
+#[derive(Debug)]
+pub struct Listing<'a>(pub &'a [Job<Planned>]);
+
+impl fmt::Display for Listing<'_> {
+    /// Lists the generations per profile with their dates, in the order of
+    /// the profile paths, marking the current generation.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut jobs: Vec<_> = self.0.iter().collect();
+        jobs.sort_by(|a, b| a.path().cmp(b.path()));
+
+        if jobs.is_empty() {
+            return writeln!(f, "no profiles found");
+        }
+
+        for job in jobs {
+            writeln!(f, "{}", job.path().display())?;
+            for generation in &job.state().listed {
+                write!(f, "  {:>5}  {}", generation.id, generation.date)?;
+                match generation.current {
+                    true => writeln!(f, "  (current)")?,
+                    false => writeln!(f)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn ids(generations: &GenerationSet) -> String {
+    if generations.is_empty() {
+        return "none".to_string();
+    }
+
+    generations
+        .iter()
+        .map(|g| match g.current {
+            true => format!("{} (current)", g.id),
+            false => g.id.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn plain_ids(generations: &GenerationSet) -> String {
+    generations
+        .iter()
+        .map(|g| g.id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn nix_list(generations: &GenerationSet) -> String {
+    match generations.is_empty() {
+        true => "[ ]".to_string(),
+        false => format!(
+            "[ {} ]",
+            generations
+                .iter()
+                .map(|g| g.id.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+    }
+}
+
+/// Quotes `text` as a Nix string, escaping what would be interpolated.
+fn nix_string(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::{Duration, NaiveDateTime};
+    use eyre::Result;
+    use janitor::Generation;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn job(path: &str, count: u32, keep_at_least: usize) -> Result<Job<Planned>> {
+        let now = NaiveDateTime::parse_from_str("2023-06-20 00:00:00", "%Y-%m-%d %H:%M:%S")?;
+        let listed: GenerationSet = (1..=count)
+            .map(|id| Generation {
+                id,
+                date: now - Duration::days(i64::from(count - id) * 10),
+                current: id == count,
+            })
+            .collect();
+
+        Ok(Job::builder()
+            .path(path)
+            .keep_since(now - Duration::days(1))
+            .keep_at_least(keep_at_least)
+            .now(now)
+            .build()?
+            .listed(listed)
+            .plan())
+    }
+
+    #[test]
+    fn renders_profiles_in_order() -> Result<()> {
+        let planned = [job("/profiles/b", 2, 5)?, job("/profiles/a", 4, 2)?];
+
+        assert_eq!(
+            Plan(&planned).to_string(),
+            "/profiles/a\n\
+             \x20 delete: 1, 2\n\
+             \x20 keep:   3, 4 (current)\n\
+             /profiles/b\n\
+             \x20 delete: none\n\
+             \x20 keep:   1, 2 (current)\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_generations() -> Result<()> {
+        let planned = [job("/profiles/a", 2, 1)?];
+
+        assert_eq!(
+            Listing(&planned).to_string(),
+            "/profiles/a\n\
+             \x20     1  2023-06-10 00:00:00\n\
+             \x20     2  2023-06-20 00:00:00  (current)\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn renders_nix() -> Result<()> {
+        let planned = [job("/profiles/b", 2, 5)?, job("/profiles/a", 3, 1)?];
+
+        assert_eq!(
+            NixPlan(&planned).to_string(),
+            "{\n\
+             \x20 \"/profiles/a\" = {\n\
+             \x20   delete = [ 1 ];\n\
+             \x20   keep = [ 2 3 ];\n\
+             \x20   current = 3;\n\
+             \x20 };\n\
+             \x20 \"/profiles/b\" = {\n\
+             \x20   delete = [ ];\n\
+             \x20   keep = [ 1 2 ];\n\
+             \x20   current = 2;\n\
+             \x20 };\n\
+             }\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn quotes_nix_strings() {
+        assert_eq!(
+            nix_string(r#"/a "b" \c ${d} $e"#),
+            r#""/a \"b\" \\c \${d} $e""#
+        );
+    }
+
+    #[test]
+    fn renders_nothing_to_do() {
+        assert_eq!(Plan(&[]).to_string(), "no profiles to clean up\n");
+    }
+
+    #[test]
+    fn roundtrips_plan_files() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("janitor-plan-{}.json", std::process::id()));
+        let planned = [job("/profiles/a", 4, 2)?];
+
+        write_plan(&path, &planned)?;
+        let read = read_plan(&path)?;
+        fs::remove_file(&path)?;
+
+        assert_eq!(Plan(&read).to_string(), Plan(&planned).to_string());
+        assert_eq!(read[0].state(), planned[0].state());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::relative_path("/jobs/0/path", json!("profiles/a"), "profile path profiles/a is not absolute")]
+    #[case::unlisted(
+        "/jobs/0/state/to_delete/0/id",
+        json!(7),
+        "/profiles/a: deletes generations 7 that have not been listed"
+    )]
+    #[case::current(
+        "/jobs/0/state/to_delete/0/id",
+        json!(4),
+        "/profiles/a: deletes the current generation 4"
+    )]
+    #[case::marked_current(
+        "/jobs/0/state/to_delete/0/current",
+        json!(true),
+        "/profiles/a: deletes the current generation 1"
+    )]
+    fn refuses_tampered_plans(
+        #[case] pointer: &str,
+        #[case] value: serde_json::Value,
+        #[case] expected: &str,
+    ) -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "janitor-plan-tampered-{}-{}.json",
+            pointer.replace('/', "-"),
+            std::process::id()
+        ));
+        let planned = [job("/profiles/a", 4, 2)?];
+        write_plan(&path, &planned)?;
+
+        let mut plan: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        *plan.pointer_mut(pointer).unwrap() = value;
+        fs::write(&path, plan.to_string())?;
+
+        let error = read_plan(&path).unwrap_err();
+        fs::remove_file(&path)?;
+
+        assert_eq!(format!("{:#}", error.root_cause()), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_other_versions() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("janitor-plan-v2-{}.json", std::process::id()));
+        fs::write(&path, r#"{"version": 2, "jobs": []}"#)?;
+
+        let error = read_plan(&path).unwrap_err();
+        fs::remove_file(&path)?;
+
+        assert!(error.to_string().contains("only version 1 is supported"));
+        Ok(())
+    }
+
+    #[test]
+    fn detects_changes_since_planned() -> Result<()> {
+        let planned = job("/profiles/a", 3, 1)?;
+        let listed = planned.state().listed.clone();
+        assert_eq!(changes_since_planned(&planned, &listed), None);
+
+        let mut generations: Vec<Generation> = listed.into();
+        generations.remove(0);
+        for generation in &mut generations {
+            generation.current = false;
+        }
+        generations.push(Generation {
+            id: 4,
+            date: generations[1].date + Duration::hours(1),
+            current: true,
+        });
+
+        assert_eq!(
+            changes_since_planned(&planned, &GenerationSet::from(generations)).as_deref(),
+            Some(
+                "/profiles/a: generations 4 appeared, generations 1 disappeared, \
+                 the current generation changed from 3 to 4"
+            )
+        );
+        Ok(())
+    }
+}
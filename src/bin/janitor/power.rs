@@ -0,0 +1,245 @@
+//! Skips the garbage collection, the heavy part of a cleanup, while a laptop
+//! runs on a low battery or a user provided hook advises against it, e.g. on
+//! a metered connection.
+
+use std::{fs, path::Path};
+
+use janitor::{CommandLine, Executor};
+
+/// Where the kernel lists the power supplies.
+pub const POWER_SUPPLIES: &str = "/sys/class/power_supply";
+
+/// When to skip the garbage collection to save power.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PowerPolicy {
+    /// Skip it while running on a battery charged below this many percent.
+    pub min_battery: Option<u8>,
+
+    /// Skip it unless this shell command succeeds.
+    pub hook: Option<String>,
+}
+
+impl PowerPolicy {
+    /// Whether the garbage collection may run, checking the power supplies
+    /// in `supplies` and running the hook on the `executor`.
+    ///
+    /// A hook that can not be run is ignored with a warning.
+    pub async fn allows_gc(&self, executor: &dyn Executor, supplies: &Path) -> bool {
+        if let Some(min_battery) = self.min_battery {
+            let power = PowerState::read(supplies);
+            tracing::debug!(?power, min_battery, "checked power supplies");
+
+            if let Some(capacity) = power.battery().filter(|&c| c < min_battery) {
+                tracing::warn!(
+                    capacity,
+                    min_battery,
+                    "running on a low battery, skipping the garbage collection"
+                );
+                return false;
+            }
+        }
+
+        let Some(hook) = &self.hook else {
+            return true;
+        };
+        let command = CommandLine::new("sh").arg("-c").arg(hook);
+        match executor.output(command).await {
+            Ok(output) if output.status.success() => true,
+            Ok(output) => {
+                tracing::warn!(
+                    hook,
+                    status = %output.status,
+                    "the power hook advises against it, skipping the garbage collection"
+                );
+                false
+            }
+            Err(error) => {
+                tracing::warn!(hook, %error, "could not run the power hook, ignoring it");
+                true
+            }
+        }
+    }
+}
+
+/// The state of the power supplies of the machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PowerState {
+    /// Whether a mains supply is online.
+    pub mains: bool,
+
+    /// Whether a battery is discharging.
+    pub discharging: bool,
+
+    /// The lowest charge of all batteries, in percent.
+    pub capacity: Option<u8>,
+}
+
+impl PowerState {
+    /// Reads the state of the power supplies listed in `supplies`, usually
+    /// [POWER_SUPPLIES].
+    ///
+    /// Supplies that can not be read are skipped, so a machine without any
+    /// is taken to run on mains.
+    pub fn read(supplies: &Path) -> Self {
+        let mut state = Self::default();
+        let Ok(entries) = fs::read_dir(supplies) else {
+            return state;
+        };
+
+        for supply in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            let attribute = |name| fs::read_to_string(supply.join(name)).ok();
+            let attribute = |name| attribute(name).map(|value| value.trim().to_string());
+
+            match attribute("type").as_deref() {
+                Some("Mains") => state.mains |= attribute("online").as_deref() == Some("1"),
+                Some("Battery") => {
+                    state.discharging |= attribute("status").as_deref() == Some("Discharging");
+                    if let Some(capacity) = attribute("capacity").and_then(|c| c.parse().ok()) {
+                        state.capacity = Some(state.capacity.map_or(capacity, |c| c.min(capacity)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        state
+    }
+
+    /// The charge of the batteries in percent, if the machine runs on them.
+    pub fn battery(&self) -> Option<u8> {
+        (self.discharging && !self.mains)
+            .then_some(self.capacity)
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, io,
+        path::PathBuf,
+        process::Output,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    use eyre::Result;
+    use futures::future::BoxFuture;
+    use rstest::rstest;
+
+    /// A fresh directory for the power supplies of a single test.
+    fn temp_dir() -> io::Result<PathBuf> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("janitor-power-{}-{count}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
+    /// Creates the power supplies `(name, [(attribute, value)])` in `dir`.
+    fn supplies(dir: &Path, supplies: &[(&str, &[(&str, &str)])]) -> io::Result<()> {
+        for (name, attributes) in supplies {
+            let supply = dir.join(name);
+            fs::create_dir_all(&supply)?;
+            for (attribute, value) in *attributes {
+                fs::write(supply.join(attribute), format!("{value}\n"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct Hook(io::Result<i32>);
+
+    impl Executor for Hook {
+        fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+            use std::os::unix::process::ExitStatusExt;
+
+            assert_eq!(command.program, "sh");
+            let output = match &self.0 {
+                Ok(code) => Ok(Output {
+                    status: ExitStatusExt::from_raw(code << 8),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+                Err(error) => Err(io::Error::new(error.kind(), error.to_string())),
+            };
+
+            Box::pin(async move { output })
+        }
+    }
+
+    const BATTERY: (&str, &[(&str, &str)]) = (
+        "BAT0",
+        &[
+            ("type", "Battery"),
+            ("status", "Discharging"),
+            ("capacity", "15"),
+        ],
+    );
+    const CHARGING: (&str, &[(&str, &str)]) = (
+        "BAT0",
+        &[
+            ("type", "Battery"),
+            ("status", "Charging"),
+            ("capacity", "15"),
+        ],
+    );
+    const AC_ONLINE: (&str, &[(&str, &str)]) = ("AC", &[("type", "Mains"), ("online", "1")]);
+    const AC_OFFLINE: (&str, &[(&str, &str)]) = ("AC", &[("type", "Mains"), ("online", "0")]);
+
+    #[rstest]
+    #[case::no_supplies(&[], None)]
+    #[case::on_battery(&[BATTERY, AC_OFFLINE], Some(15))]
+    #[case::on_mains(&[BATTERY, AC_ONLINE], None)]
+    #[case::charging(&[CHARGING], None)]
+    #[case::lowest(
+        &[BATTERY, ("BAT1", &[("type", "Battery"), ("status", "Discharging"), ("capacity", "80")])],
+        Some(15)
+    )]
+    fn battery(
+        #[case] setup: &[(&str, &[(&str, &str)])],
+        #[case] expected: Option<u8>,
+    ) -> Result<()> {
+        let dir = temp_dir()?;
+        let state = supplies(&dir, setup).map(|()| PowerState::read(&dir));
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(state?.battery(), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::no_policy(None, None, Ok(1), true)]
+    #[case::low_battery(Some(20), None, Ok(0), false)]
+    #[case::enough_battery(Some(10), None, Ok(0), true)]
+    #[case::hook_succeeds(None, Some("true"), Ok(0), true)]
+    #[case::hook_fails(None, Some("metered"), Ok(1), false)]
+    #[case::hook_missing(None, Some("metered"), Err(io::ErrorKind::NotFound.into()), true)]
+    fn allows_gc(
+        #[case] min_battery: Option<u8>,
+        #[case] hook: Option<&str>,
+        #[case] result: io::Result<i32>,
+        #[case] expected: bool,
+    ) -> Result<()> {
+        let dir = temp_dir()?;
+        supplies(&dir, &[BATTERY, AC_OFFLINE])?;
+
+        let policy = PowerPolicy {
+            min_battery,
+            hook: hook.map(ToOwned::to_owned),
+        };
+        let allowed = futures::executor::block_on(policy.allows_gc(&Hook(result), &dir));
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(allowed, expected);
+
+        Ok(())
+    }
+}
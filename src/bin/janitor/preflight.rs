@@ -0,0 +1,211 @@
+//! Checks before a run that the profiles and the store can be written to, so
+//! that a read-only mount fails the run early with guidance instead of
+//! confusingly halfway through.
+
+use std::{
+    ffi::{CString, OsString},
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::Path,
+};
+
+use eyre::{bail, eyre, Context, Result};
+use janitor::{
+    mounts::{Mount, MountTable},
+    size::NIX_STORE,
+};
+
+use crate::RunOptions;
+
+/// Checks the mounts of the `profiles` and the store in the `mounts`
+/// against the `options`, returning whether garbage can be collected.
+///
+/// # Errors
+///
+/// Fails if a profile is on a read-only mount, or if the store can not be
+/// written to but the run depends on it, freeing space or repairing the
+/// store. A garbage collection alone is skipped with a warning instead.
+pub fn check<'a>(
+    profiles: impl IntoIterator<Item = &'a Path>,
+    options: &RunOptions,
+    mounts: &MountTable,
+) -> Result<bool> {
+    for profile in profiles {
+        // Generations are links next to the profile, resolve its directory
+        // to find the mount they are created on.
+        let dir = profile.parent().unwrap_or(profile);
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+
+        if let Some(mount) = mounts.mount_of(&dir).filter(|mount| mount.read_only()) {
+            bail!(
+                "the profile {} is on the read-only {}, its generations can not be deleted; \
+                 remount it read-write, or leave the profile out with --profile",
+                profile.display(),
+                describe(mount),
+            );
+        }
+    }
+
+    let Some(store) = mounts
+        .mount_of(NIX_STORE)
+        .filter(|mount| !mount.can_remount_writable())
+    else {
+        return Ok(options.gc);
+    };
+
+    if options.free_at_least.is_some() {
+        bail!(
+            "the store is on the read-only {}, --free-at-least can not free any space; \
+             run the janitor where the store is writable",
+            describe(store),
+        );
+    }
+    if options.repair_store {
+        bail!(
+            "the store is on the read-only {}, it can not be repaired; \
+             run the janitor where the store is writable",
+            describe(store),
+        );
+    }
+    if options.gc {
+        tracing::warn!(
+            mount = %describe(store),
+            "the store is read-only, skipping the garbage collection"
+        );
+    }
+
+    Ok(false)
+}
+
+/// Checks that generations can be deleted from the `profile` right now: its
+/// directory, where the generation links live, is writable, and no other
+/// process holds the lock `nix-env` takes while modifying the profile.
+///
+/// The lock is released again right away, so this can not rule out another
+/// process modifying the profile before the deletion.
+///
+/// # Errors
+///
+/// Fails with the reason if either check fails.
+pub fn validate(profile: &Path) -> Result<()> {
+    let dir = profile.parent().unwrap_or(profile);
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+
+    // SAFETY: `c_dir` is a valid, nul terminated string.
+    if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } != 0 {
+        return Err(io::Error::last_os_error())
+            .wrap_err_with(|| format!("{} is not writable", dir.display()));
+    }
+
+    let mut lock = OsString::from(profile.as_os_str());
+    lock.push(".lock");
+    let lock = Path::new(&lock);
+
+    let file = match File::open(lock) {
+        Ok(file) => file,
+        // Nobody has ever locked the profile, so nobody holds the lock.
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => {
+            return Err(error).wrap_err_with(|| format!("Failed to open {}", lock.display()))
+        }
+    };
+
+    // SAFETY: `file` is open for as long as the call, closing it releases
+    // the lock.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() == io::ErrorKind::WouldBlock {
+            return Err(eyre!("{} is held by another process", lock.display()));
+        }
+        return Err(error).wrap_err_with(|| format!("Failed to lock {}", lock.display()));
+    }
+
+    Ok(())
+}
+
+fn describe(mount: &Mount) -> String {
+    format!(
+        "{} mount of {} at {}",
+        mount.fstype,
+        mount.source,
+        mount.mount_point.display()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    const WRITABLE: &str = "\
+1 0 0:1 / / rw - ext4 /dev/sda1 rw
+2 1 0:1 /nix/store /nix/store ro - ext4 /dev/sda1 rw
+";
+
+    const READ_ONLY_STORE: &str = "\
+1 0 0:1 / / rw - ext4 /dev/sda1 rw
+2 1 0:2 / /nix/store ro - squashfs /dev/loop0 ro
+";
+
+    const READ_ONLY_PROFILES: &str = "\
+1 0 0:1 / / rw - ext4 /dev/sda1 rw
+2 1 0:2 / /nix/var ro - ext4 /dev/sda2 ro
+";
+
+    fn options(gc: bool, free_at_least: Option<u64>, repair_store: bool) -> RunOptions {
+        RunOptions {
+            gc,
+            free_at_least,
+            repair_store,
+            ..RunOptions::default()
+        }
+    }
+
+    #[test]
+    fn validates_lock() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("janitor-preflight-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let profile = dir.join("profile");
+
+        let unlocked = validate(&profile);
+        let held = File::create(dir.join("profile.lock"))?;
+        // SAFETY: `held` is open for as long as the call.
+        assert_eq!(
+            unsafe { libc::flock(held.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        let locked = validate(&profile);
+        drop(held);
+        let released = validate(&profile);
+        let missing = validate(&dir.join("missing").join("profile"));
+        fs::remove_dir_all(&dir)?;
+
+        assert!(unlocked.is_ok());
+        assert!(locked
+            .unwrap_err()
+            .to_string()
+            .contains("held by another process"));
+        assert!(released.is_ok());
+        assert!(missing.unwrap_err().to_string().contains("is not writable"));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::writable(WRITABLE, options(true, None, false), Some(true))]
+    #[case::unknown("", options(true, None, false), Some(true))]
+    #[case::skips_gc(READ_ONLY_STORE, options(true, None, false), Some(false))]
+    #[case::no_gc(READ_ONLY_STORE, options(false, None, false), Some(false))]
+    #[case::free_at_least(READ_ONLY_STORE, options(false, Some(1), false), None)]
+    #[case::repair(READ_ONLY_STORE, options(false, None, true), None)]
+    #[case::profiles(READ_ONLY_PROFILES, options(false, None, false), None)]
+    fn checks(#[case] table: &str, #[case] options: RunOptions, #[case] expected: Option<bool>) {
+        let profiles = [Path::new("/nix/var/nix/profiles/system")];
+        let result = check(profiles, &options, &MountTable::parse(table));
+
+        assert_eq!(result.ok(), expected);
+    }
+}
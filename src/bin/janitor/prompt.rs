@@ -0,0 +1,165 @@
+use std::{
+    fmt,
+    io::{self, BufRead, IsTerminal, Write},
+};
+
+use clap::ValueEnum;
+use eyre::{bail, Result};
+use janitor::{state::Planned, Job};
+
+use crate::{plan::Plan, redact};
+
+/// When `--interactive` asks before deleting generations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Interactive {
+    /// Once for all profiles, after planning the deletions of every one.
+    Once,
+
+    /// For each profile, right before deleting its generations.
+    Profile,
+}
+
+impl fmt::Display for Interactive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no interactive variant is skipped");
+
+        f.write_str(value.get_name())
+    }
+}
+
+/// Asks the user to confirm `question` on the terminal.
+///
+/// Only an explicit "y" or "yes" counts as confirmation. Fails if stdin is
+/// not a terminal, as there is nobody to ask then.
+pub fn confirm(question: &str) -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("can not ask for confirmation, stdin is not a terminal; pass --yes to proceed");
+    }
+
+    let mut stderr = io::stderr();
+    write!(stderr, "{question} [y/N] ")?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+
+    Ok(is_yes(&answer))
+}
+
+/// Whether a run may go ahead with its destructive modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consent {
+    /// Assumed with `--yes` or `assume_yes`, confirmed on the terminal, or
+    /// not needed as no destructive mode is used.
+    Given,
+
+    /// Declined on the terminal.
+    Declined,
+
+    /// Nobody to ask, as stdin is not a terminal: the run only plans.
+    PlanOnly,
+}
+
+/// Asks for consent to the destructive `modes`, one description each.
+///
+/// Without a terminal to ask on, the consent is neither given nor declined,
+/// and the run is expected to only print its plan.
+pub fn consent(modes: &[&str], assume_yes: bool) -> Result<Consent> {
+    if modes.is_empty() || assume_yes {
+        return Ok(Consent::Given);
+    }
+    if !io::stdin().is_terminal() {
+        return Ok(Consent::PlanOnly);
+    }
+
+    let question = format!("This run will\n  - {}\nProceed?", modes.join("\n  - "));
+    match confirm(&question)? {
+        true => Ok(Consent::Given),
+        false => Ok(Consent::Declined),
+    }
+}
+
+/// Prints the planned deletions of the `jobs` and asks whether to go ahead
+/// with them.
+///
+/// Jobs deleting nothing are left out, and nothing is asked if none deletes
+/// anything.
+pub fn confirm_deletions<'j>(jobs: impl IntoIterator<Item = &'j Job<Planned>>) -> Result<bool> {
+    let mut jobs: Vec<_> = jobs
+        .into_iter()
+        .filter(|job| !job.state().to_delete.is_empty())
+        .collect();
+    if jobs.is_empty() {
+        return Ok(true);
+    }
+    jobs.sort_by(|a, b| a.path().cmp(b.path()));
+
+    let plan: String = jobs
+        .iter()
+        .map(|job| Plan(std::slice::from_ref(*job)).to_string())
+        .collect();
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", redact::text(&plan))?;
+    stdout.flush()?;
+
+    let count: usize = jobs.iter().map(|job| job.state().to_delete.len()).sum();
+    let question = match jobs.as_slice() {
+        [job] => format!("Delete {count} generations of {}?", job.path().display()),
+        jobs => format!("Delete {count} generations of {} profiles?", jobs.len()),
+    };
+
+    confirm(&redact::text(&question))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::y("y\n", true)]
+    #[case::yes("Yes\n", true)]
+    #[case::n("n\n", false)]
+    #[case::empty("\n", false)]
+    #[case::other("sure\n", false)]
+    fn test_is_yes(#[case] answer: &str, #[case] expected: bool) {
+        assert_eq!(is_yes(answer), expected);
+    }
+
+    #[test]
+    fn confirms_nothing_to_delete_without_asking() -> Result<()> {
+        let job = Job::new("/p/profile", Default::default(), 1)
+            .listed(Default::default())
+            .plan();
+
+        assert!(confirm_deletions([&job])?);
+        assert!(confirm_deletions([])?);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::once("once", Interactive::Once)]
+    #[case::profile("profile", Interactive::Profile)]
+    fn interactive_names(#[case] name: &str, #[case] interactive: Interactive) {
+        assert_eq!(interactive.to_string(), name);
+        assert_eq!(Interactive::from_str(name, false), Ok(interactive));
+    }
+
+    #[rstest]
+    #[case::nothing_destructive(&[], false)]
+    #[case::assumed(&["delete everything"], true)]
+    fn consent_without_asking(#[case] modes: &[&str], #[case] assume_yes: bool) -> Result<()> {
+        assert_eq!(consent(modes, assume_yes)?, Consent::Given);
+
+        Ok(())
+    }
+}
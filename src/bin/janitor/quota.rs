@@ -0,0 +1,319 @@
+//! Budgets for the space taken up by the generations retained for each user
+//! on shared machines, see `--per-user-budget`.
+//!
+//! The retention policies of the profiles of a user exceeding their budget
+//! are tightened one generation at a time, releasing the oldest generation
+//! retained over all of their profiles first, until the retained
+//! generations fit or only the floor of generations is left.
+
+use std::{collections::BTreeMap, ffi::CStr, path::PathBuf};
+
+use eyre::Result;
+use tracing::Instrument;
+
+use janitor::{
+    planning,
+    size::{self, format_size, SizeEstimation},
+    state::Listed,
+    Executor, GenerationSet, Job, Profile, ProfileKind, QuotaReport,
+};
+
+/// The number of most recent generations of each profile never released
+/// unless configured otherwise.
+pub const DEFAULT_FLOOR: usize = 1;
+
+/// Tightens the retention of the profiles of each user whose retained
+/// generations take up more than `budget` bytes, keeping at least `floor`
+/// generations per profile.
+///
+/// The profiles of the system and of containers, and profiles whose owner
+/// can not be determined, are left as they are. Returns all `jobs`, and the
+/// users that exceeded their budget.
+pub async fn enforce(
+    executor: &dyn Executor,
+    jobs: Vec<Job<Listed>>,
+    budget: u64,
+    floor: usize,
+    estimation: SizeEstimation,
+) -> (Vec<Job<Listed>>, Vec<QuotaReport>) {
+    let mut by_user: BTreeMap<u32, Vec<Job<Listed>>> = BTreeMap::new();
+    let mut enforced = Vec::new();
+    for job in jobs {
+        let profile = Profile::new(job.path());
+        let budgeted = !matches!(profile.kind(), ProfileKind::System | ProfileKind::Container);
+        match profile.owner().filter(|_| budgeted) {
+            Some(uid) => by_user.entry(uid).or_default().push(job),
+            None => enforced.push(job),
+        }
+    }
+
+    let mut reports = Vec::new();
+    for (uid, mut jobs) in by_user {
+        let user = user_name(uid);
+        let span = tracing::info_span!("quota", %user);
+        match enforce_user(executor, &user, &mut jobs, budget, floor, estimation)
+            .instrument(span)
+            .await
+        {
+            Ok(Some(report)) => reports.push(report),
+            Ok(None) => {}
+            Err(error) => tracing::warn!(
+                %error,
+                %user,
+                "failed to size the retained generations, not enforcing the budget"
+            ),
+        }
+        enforced.extend(jobs);
+    }
+
+    (enforced, reports)
+}
+
+/// Tightens the retention of the `jobs` of a single `user`, returning the
+/// report if they exceeded the `budget`.
+async fn enforce_user(
+    executor: &dyn Executor,
+    user: &str,
+    jobs: &mut [Job<Listed>],
+    budget: u64,
+    floor: usize,
+    estimation: SizeEstimation,
+) -> Result<Option<QuotaReport>> {
+    let initially_kept = kept_count(jobs);
+    let retained = retained_size(executor, jobs, estimation).await?;
+    if retained <= budget {
+        tracing::debug!(retained = %format_size(retained), "within budget");
+        return Ok(None);
+    }
+
+    let mut tightened = retained;
+    let mut kept = initially_kept;
+    while tightened > budget {
+        let oldest = jobs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, job)| {
+                planning::tighten(&job.state().generations, &job.rules(), floor)
+                    .map(|tightening| (index, tightening.released))
+            })
+            // Generations of different profiles sharing a date are released
+            // in the order of their ids, then of the profiles.
+            .min_by_key(|(index, released)| (released.date, released.id, jobs[*index].path()));
+        let Some((index, _)) = oldest else {
+            break;
+        };
+        jobs[index].tighten(floor);
+
+        // Released generations might still be kept, e.g. if they are
+        // referenced from outside of the profile, the size is the same then.
+        let now_kept = kept_count(jobs);
+        if now_kept != kept {
+            kept = now_kept;
+            tightened = retained_size(executor, jobs, estimation).await?;
+        }
+    }
+
+    let report = QuotaReport {
+        user: user.to_string(),
+        budget,
+        retained,
+        tightened,
+        released: initially_kept - kept,
+    };
+    match report.met() {
+        true => tracing::warn!(
+            budget = %format_size(budget),
+            retained = %format_size(retained),
+            tightened = %format_size(tightened),
+            released = report.released,
+            "user exceeded their budget, tightened the retention"
+        ),
+        false => tracing::warn!(
+            budget = %format_size(budget),
+            retained = %format_size(retained),
+            tightened = %format_size(tightened),
+            released = report.released,
+            floor,
+            "user exceeds their budget even with the retention tightened to the floor"
+        ),
+    }
+
+    Ok(Some(report))
+}
+
+/// The generations of the profile of the `job` its retention policy keeps.
+fn kept(job: &Job<Listed>) -> GenerationSet {
+    let Listed {
+        generations,
+        referenced,
+    } = job.state();
+
+    generations.difference(&planning::to_delete(&planning::decide(
+        generations,
+        referenced,
+        job.exceptions(),
+        &job.rules(),
+    )))
+}
+
+fn kept_count(jobs: &[Job<Listed>]) -> usize {
+    jobs.iter().map(|job| kept(job).len()).sum()
+}
+
+/// The size of the generations the `jobs` keep, together.
+async fn retained_size(
+    executor: &dyn Executor,
+    jobs: &[Job<Listed>],
+    estimation: SizeEstimation,
+) -> Result<u64> {
+    let links: Vec<PathBuf> = jobs
+        .iter()
+        .flat_map(|job| {
+            kept(job)
+                .iter()
+                .map(|generation| generation.link(job.path()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    Ok(size::closure_size(executor, &links, estimation)
+        .await?
+        .bytes)
+}
+
+/// The name of the user with the id `uid`, or the id itself if the user has
+/// no name.
+fn user_name(uid: u32) -> String {
+    let mut buffer = vec![0; 4096];
+    let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: `passwd` and `buffer` are valid for writes of their sizes, the
+    // strings `passwd` points to are stored in `buffer`, which outlives
+    // their use below.
+    let name = unsafe {
+        let status = libc::getpwuid_r(
+            uid,
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        if status != 0 || result.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(passwd.assume_init().pw_name).to_string_lossy())
+        }
+    };
+
+    name.map_or_else(|| uid.to_string(), |name| name.into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, os::unix::process::ExitStatusExt, process::Output};
+
+    use super::*;
+
+    use chrono::{Duration, NaiveDateTime};
+    use futures::future::BoxFuture;
+    use janitor::{CommandLine, Generation};
+
+    /// Answers `du` with 1 MiB for every path.
+    #[derive(Debug)]
+    struct Du;
+
+    impl Executor for Du {
+        fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+            assert_eq!(command.program, "du");
+            let stdout = command
+                .args
+                .iter()
+                .filter(|arg| !arg.to_string_lossy().starts_with('-'))
+                .map(|path| format!("1024\t{}\n", path.to_string_lossy()))
+                .collect::<String>();
+
+            Box::pin(async move {
+                Ok(Output {
+                    status: ExitStatusExt::from_raw(0),
+                    stdout: stdout.into_bytes(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
+    }
+
+    const MIB: u64 = 1 << 20;
+
+    fn job(path: &str, days: &[i64]) -> Job<Listed> {
+        let base = NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap();
+        let generations = days
+            .iter()
+            .zip(1..)
+            .map(|(day, id)| Generation {
+                id,
+                date: base + Duration::days(*day),
+                current: id as usize == days.len(),
+            })
+            .collect();
+
+        Job::new(path, base, days.len()).listed(generations)
+    }
+
+    fn enforce(jobs: &mut [Job<Listed>], budget: u64, floor: usize) -> Option<QuotaReport> {
+        futures::executor::block_on(enforce_user(
+            &Du,
+            "alice",
+            jobs,
+            budget,
+            floor,
+            SizeEstimation::Fast,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn within_budget() {
+        let mut jobs = [job("/p/profile", &[1, 2, 3])];
+
+        assert_eq!(enforce(&mut jobs, 3 * MIB, 1), None);
+        assert_eq!(jobs[0].keep_at_least(), 3);
+    }
+
+    #[test]
+    fn releases_oldest_over_all_profiles() {
+        let mut jobs = [
+            job("/p/profile", &[1, 4, 5]),
+            job("/p/home-manager", &[2, 3, 6]),
+        ];
+
+        let report = enforce(&mut jobs, 3 * MIB, 1).unwrap();
+
+        assert_eq!(
+            report,
+            QuotaReport {
+                user: "alice".to_string(),
+                budget: 3 * MIB,
+                retained: 6 * MIB,
+                tightened: 3 * MIB,
+                released: 3,
+            }
+        );
+        let kept: Vec<_> = jobs
+            .iter()
+            .map(|job| kept(job).iter().map(|g| g.id).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(kept, [vec![2, 3], vec![3]]);
+    }
+
+    #[test]
+    fn stops_at_the_floor() {
+        let mut jobs = [job("/p/profile", &[1, 2, 3, 4])];
+
+        let report = enforce(&mut jobs, MIB, 2).unwrap();
+
+        assert!(!report.met());
+        assert_eq!(report.tightened, 2 * MIB);
+        assert_eq!(report.released, 2);
+    }
+}
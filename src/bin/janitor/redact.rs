@@ -0,0 +1,288 @@
+//! Pseudonymization of user and host names in everything the janitor
+//! outputs, see `--redact`.
+//!
+//! Every name is replaced by a pseudonym derived from a hash of the name, so
+//! that the same account is recognizable across the log, the report and
+//! errors, even of different runs, without revealing its name.
+
+use std::{
+    borrow::Cow,
+    env,
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    sync::OnceLock,
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The directories holding a directory per user.
+const USER_DIRS: &[&str] = &["/home", "/Users", "/nix/var/nix/profiles/per-user"];
+
+/// Names that are not personal and occur in paths, which are never
+/// replaced.
+const PUBLIC_NAMES: &[&str] = &[
+    "root",
+    "nix",
+    "nixos",
+    "store",
+    "var",
+    "home",
+    "profiles",
+    "per-user",
+    "localhost",
+    "nobody",
+    "system",
+];
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+/// Replaces user and host names with pseudonyms.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Redactor {
+    /// The names with their pseudonyms, longest names first, so that a name
+    /// containing another one is replaced as a whole.
+    replacements: Vec<(String, String)>,
+}
+
+impl Redactor {
+    /// Creates a redactor for the given `users` and `hosts` names.
+    pub fn new<U, H>(users: U, hosts: H) -> Self
+    where
+        U: IntoIterator<Item = String>,
+        H: IntoIterator<Item = String>,
+    {
+        let mut replacements: Vec<_> = users
+            .into_iter()
+            .map(|name| (pseudonym("user", &name), name))
+            .chain(
+                hosts
+                    .into_iter()
+                    .map(|name| (pseudonym("host", &name), name)),
+            )
+            .filter(|(_, name)| name.len() > 1 && !PUBLIC_NAMES.contains(&name.as_str()))
+            .map(|(pseudonym, name)| (name, pseudonym))
+            .collect();
+        replacements.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        replacements.dedup_by(|(a, _), (b, _)| a == b);
+
+        Self { replacements }
+    }
+
+    /// Creates a redactor for the names of the current and invoking user,
+    /// the users with a home directory or profiles, and the host.
+    pub fn discover() -> Self {
+        let mut users: Vec<String> = ["USER", "LOGNAME", "SUDO_USER"]
+            .into_iter()
+            .filter_map(|var| env::var(var).ok())
+            .collect();
+        for dir in USER_DIRS {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            users.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| entry.file_name().into_string().ok()),
+            );
+        }
+
+        let hosts = fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .ok()
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .map(|host| {
+                let short = host.split('.').next().unwrap_or(&host).to_string();
+                vec![host, short]
+            })
+            .unwrap_or_default();
+
+        Self::new(users, hosts)
+    }
+
+    /// Replaces the names in `text` occurring as a whole word, or as a part
+    /// of a path.
+    pub fn redact<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let mut text = Cow::Borrowed(text);
+
+        for (name, pseudonym) in &self.replacements {
+            if let Some(replaced) = replace_words(&text, name, pseudonym) {
+                text = Cow::Owned(replaced);
+            }
+        }
+
+        text
+    }
+}
+
+/// Replaces the occurrences of `name` in `text` that are not part of a
+/// longer word, returning `None` if there are none.
+fn replace_words(text: &str, name: &str, pseudonym: &str) -> Option<String> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+
+    let mut out = String::new();
+    let mut rest = 0;
+    for (start, _) in text.match_indices(name) {
+        let end = start + name.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        if before.is_some_and(is_word) || after.is_some_and(is_word) || start < rest {
+            continue;
+        }
+
+        out.push_str(&text[rest..start]);
+        out.push_str(pseudonym);
+        rest = end;
+    }
+
+    if rest == 0 {
+        return None;
+    }
+    out.push_str(&text[rest..]);
+
+    Some(out)
+}
+
+/// A stable pseudonym for `name`, like `user-3f0a9c12`.
+fn pseudonym(kind: &str, name: &str) -> String {
+    // FNV-1a, stable across runs and platforms unlike the hasher of std.
+    let hash = name.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+
+    format!("{kind}-{hash:08x}")
+}
+
+/// Redacts everything output from now on with `redactor`: the log, errors
+/// and the output printed with [text].
+pub fn install(redactor: Redactor) {
+    if REDACTOR.set(redactor).is_err() {
+        return;
+    }
+
+    if let Err(error) = eyre::set_hook(Box::new(|_| Box::new(Handler))) {
+        tracing::warn!(%error, "failed to redact errors");
+    }
+}
+
+/// `text` with the names replaced, if redacting.
+pub fn text(text: &str) -> Cow<'_, str> {
+    match REDACTOR.get() {
+        Some(redactor) => redactor.redact(text),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Makes the writers of the log redact what is written to stdout, or to
+/// stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Writer {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
+impl<'a> MakeWriter<'a> for Writer {
+    type Writer = Redacting<Box<dyn Write>>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        match self {
+            Self::Stdout => Redacting(Box::new(io::stdout())),
+            Self::Stderr => Redacting(Box::new(io::stderr())),
+        }
+    }
+}
+
+/// Redacts every write before passing it on.
+///
+/// The log writes every event at once, so names are never split between
+/// writes.
+#[derive(Debug)]
+pub struct Redacting<W>(W);
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(s) => self.0.write_all(text(s).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Reports errors like eyre does by default, redacted.
+struct Handler;
+
+impl eyre::EyreHandler for Handler {
+    fn debug(&self, error: &(dyn Error + 'static), f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", text(&error.to_string()))?;
+
+        let mut source = error.source();
+        if source.is_some() {
+            write!(f, "\n\nCaused by:")?;
+        }
+        for i in 0.. {
+            let Some(cause) = source else {
+                break;
+            };
+            write!(f, "\n    {i}: {}", text(&cause.to_string()))?;
+            source = cause.source();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    fn redactor() -> Redactor {
+        Redactor::new(
+            ["alice".to_string(), "al".to_string(), "root".to_string()],
+            ["wonderland".to_string()],
+        )
+    }
+
+    #[rstest]
+    #[case::path(
+        "/nix/var/nix/profiles/per-user/alice/profile",
+        "/nix/var/nix/profiles/per-user/user-{alice}/profile"
+    )]
+    #[case::home("/home/alice/.local/state", "/home/user-{alice}/.local/state")]
+    #[case::repeated("alice and al", "user-{alice} and user-{al}")]
+    #[case::part_of_word("alicette album", "alicette album")]
+    #[case::host("listing on wonderland", "listing on host-{wonderland}")]
+    #[case::public("/root/.nix-profile", "/root/.nix-profile")]
+    fn redacts(#[case] input: &str, #[case] expected: &str) {
+        let expected = expected
+            .replace("{alice}", &pseudonym("user", "alice")[5..])
+            .replace("{al}", &pseudonym("user", "al")[5..])
+            .replace("{wonderland}", &pseudonym("host", "wonderland")[5..]);
+
+        assert_eq!(redactor().redact(input), expected);
+    }
+
+    #[test]
+    fn pseudonyms_are_stable() {
+        assert_eq!(pseudonym("user", "alice"), pseudonym("user", "alice"));
+        assert_ne!(pseudonym("user", "alice"), pseudonym("user", "bob"));
+        assert_eq!(pseudonym("user", "").len(), "user-".len() + 8);
+    }
+
+    #[test]
+    fn leaves_clean_text_borrowed() {
+        assert!(matches!(
+            redactor().redact("nothing here"),
+            Cow::Borrowed(_)
+        ));
+    }
+}
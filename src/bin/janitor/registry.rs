@@ -0,0 +1,124 @@
+//! Cleaning up flake registry entries pinned to store paths that have been
+//! or are about to be garbage collected.
+
+use std::{fmt, fs, path::Path};
+
+use clap::ValueEnum;
+use eyre::{Context, Result};
+
+use janitor::{
+    nix_store,
+    registry::{self, RegistryEntry, Scope},
+    Executor,
+};
+
+const SYSTEM_REGISTRY: &str = "/etc/nix/registry.json";
+
+/// What to do with registry entries pinned to store paths without roots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StalePins {
+    /// Only log the entries.
+    Report,
+
+    /// Remove the entries, so that the flakes are looked up in the global
+    /// registry again.
+    Remove,
+
+    /// Pin the entries again to what the flakes resolve to without them.
+    Repin,
+}
+
+impl fmt::Display for StalePins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no action variant is skipped");
+
+        f.write_str(value.get_name())
+    }
+}
+
+/// Finds the entries of the user and system registries pinned to store paths
+/// that do not exist or have no garbage collector roots, and deals with them
+/// according to `action`.
+///
+/// Returns the stale entries found.
+pub async fn clean(executor: &dyn Executor, action: StalePins) -> Result<Vec<RegistryEntry>> {
+    let mut stale = Vec::new();
+
+    for entry in registry::list(executor).await? {
+        if entry.scope == Scope::Global {
+            continue;
+        }
+        let Some(path) = entry.store_path() else {
+            continue;
+        };
+        if path.exists() && !nix_store::roots(executor, &path).await?.is_empty() {
+            continue;
+        }
+
+        tracing::warn!(
+            scope = %entry.scope,
+            from = entry.from,
+            path = %path.display(),
+            "registry entry pinned to a store path without roots"
+        );
+        if action != StalePins::Report {
+            fix(executor, &entry, action)
+                .await
+                .wrap_err_with(|| format!("Failed to {action} {}", entry.from))?;
+        }
+
+        stale.push(entry);
+    }
+
+    Ok(stale)
+}
+
+async fn fix(executor: &dyn Executor, entry: &RegistryEntry, action: StalePins) -> Result<()> {
+    let file = match entry.scope {
+        Scope::System => {
+            let file = Path::new(SYSTEM_REGISTRY);
+            if !is_root::is_root() || is_managed(file) {
+                tracing::warn!(
+                    from = entry.from,
+                    "can not change the system registry, leaving the entry alone"
+                );
+                return Ok(());
+            }
+
+            Some(file)
+        }
+        _ => None,
+    };
+
+    registry::remove(executor, &entry.from, file).await?;
+    if action == StalePins::Repin {
+        registry::pin(executor, &entry.from, file).await?;
+    }
+
+    tracing::info!(from = entry.from, %action, "fixed registry entry");
+
+    Ok(())
+}
+
+/// Whether the registry `file` is generated by the system configuration, as
+/// on NixOS, where it is a link into the store.
+fn is_managed(file: &Path) -> bool {
+    fs::symlink_metadata(file).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn action_names() {
+        let names: Vec<_> = StalePins::value_variants()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(names, ["report", "remove", "repin"]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Appends the reports of runs to an SQLite database for long-term analysis,
+//! see `--report-db` and `janitor query`.
+//!
+//! The database is written and read with the `sqlite3` shell, like the
+//! store is with the nix tools. Every run is appended in a single
+//! transaction, with a row in `runs`, one per profile in `profiles` and one
+//! per deleted generation in `deletions`.
+
+use std::{fmt, path::Path};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use eyre::{bail, Context, Result};
+use tracing::Instrument;
+
+use janitor::{CommandLine, Executor, RunReport};
+
+use crate::run_id::RunId;
+
+/// The tables of the database, created if they do not exist yet.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS runs (\
+        id TEXT PRIMARY KEY, \
+        finished TEXT NOT NULL, \
+        profiles INTEGER NOT NULL, \
+        deleted INTEGER NOT NULL, \
+        freed INTEGER NOT NULL, \
+        paths_deleted INTEGER NOT NULL\
+    );",
+    "CREATE TABLE IF NOT EXISTS profiles (\
+        run TEXT NOT NULL REFERENCES runs (id), \
+        path TEXT NOT NULL, \
+        deleted INTEGER NOT NULL, \
+        appeared INTEGER NOT NULL\
+    );",
+    "CREATE TABLE IF NOT EXISTS deletions (\
+        run TEXT NOT NULL REFERENCES runs (id), \
+        profile TEXT NOT NULL, \
+        generation INTEGER NOT NULL, \
+        created TEXT NOT NULL\
+    );",
+];
+
+/// The format of the dates in the database, which SQLite compares and
+/// computes with.
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// The questions `janitor query` answers from the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CannedQuery {
+    /// The space freed by the garbage collection within the last month.
+    FreedLastMonth,
+
+    /// The profile the most generations have been deleted from.
+    BusiestProfile,
+
+    /// The last ten runs with the generations they deleted and the space
+    /// they freed.
+    RecentRuns,
+}
+
+impl CannedQuery {
+    /// The SQL answering the question.
+    fn sql(self) -> &'static str {
+        match self {
+            Self::FreedLastMonth => {
+                "SELECT COUNT(*) AS runs, COALESCE(SUM(deleted), 0) AS deleted, \
+                 printf('%.2f GiB', COALESCE(SUM(freed), 0) / 1073741824.0) AS freed \
+                 FROM runs WHERE finished >= datetime('now', '-1 month');"
+            }
+            Self::BusiestProfile => {
+                "SELECT path, SUM(deleted) AS deleted, COUNT(*) AS runs \
+                 FROM profiles GROUP BY path ORDER BY SUM(deleted) DESC, path LIMIT 1;"
+            }
+            Self::RecentRuns => {
+                "SELECT id, finished, profiles, deleted, \
+                 printf('%.2f MiB', freed / 1048576.0) AS freed \
+                 FROM runs ORDER BY finished DESC LIMIT 10;"
+            }
+        }
+    }
+}
+
+impl fmt::Display for CannedQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no query variant is skipped");
+
+        f.write_str(value.get_name())
+    }
+}
+
+/// Appends the run `run_id` that has finished at `finished` with the
+/// `report` to the database at `db`, creating it if needed.
+///
+/// # Errors
+///
+/// Fails if `sqlite3` can not be spawned or exits unsuccessfully.
+pub async fn append<E>(
+    executor: &E,
+    db: &Path,
+    run_id: RunId,
+    finished: DateTime<Utc>,
+    report: &RunReport,
+) -> Result<()>
+where
+    E: Executor + ?Sized,
+{
+    let command = CommandLine::new("sqlite3")
+        .args(["-bail", "-batch"])
+        .arg(db)
+        .stdin(statements(run_id, finished, report).join("\n"));
+
+    sqlite(executor, command).await?;
+
+    Ok(())
+}
+
+/// Answers the `query` from the database at `db`, as a table with a header.
+///
+/// # Errors
+///
+/// Fails if the database does not exist, or `sqlite3` can not be spawned or
+/// exits unsuccessfully.
+pub async fn query<E>(executor: &E, db: &Path, query: CannedQuery) -> Result<String>
+where
+    E: Executor + ?Sized,
+{
+    if !db.exists() {
+        bail!("no report database at {}", db.display());
+    }
+
+    let command = CommandLine::new("sqlite3")
+        .args(["-readonly", "-header", "-column"])
+        .arg(db)
+        .arg(query.sql());
+
+    sqlite(executor, command).await
+}
+
+/// Runs `sqlite3` as given by `command`, returning its output.
+async fn sqlite<E>(executor: &E, command: CommandLine) -> Result<String>
+where
+    E: Executor + ?Sized,
+{
+    let output = executor
+        .output(command)
+        .instrument(tracing::debug_span!("sqlite3"))
+        .await
+        .wrap_err("Failed to run sqlite3")?;
+
+    if !output.status.success() {
+        bail!(
+            "sqlite3 failed: {stderr}",
+            stderr = String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The statements appending the run, written to `sqlite3` one per line, as
+/// arguments they would exceed the limits of the command line for runs
+/// deleting many generations.
+fn statements(run_id: RunId, finished: DateTime<Utc>, report: &RunReport) -> Vec<String> {
+    let run = quote(&run_id.to_string());
+    let (freed, paths_deleted) = report
+        .gc()
+        .map_or((0, 0), |gc| (gc.bytes_freed, gc.paths_deleted));
+
+    let mut statements: Vec<_> = SCHEMA.iter().map(ToString::to_string).collect();
+    statements.push("BEGIN;".to_string());
+    statements.push(format!(
+        "INSERT INTO runs VALUES ({run}, {finished}, {profiles}, {deleted}, {freed}, {paths_deleted});",
+        finished = quote(&finished.format(DATE_FORMAT).to_string()),
+        profiles = report.profiles().len(),
+        deleted = report.deleted_count(),
+    ));
+    for profile in report.profiles() {
+        let path = quote(&profile.path.to_string_lossy());
+        statements.push(format!(
+            "INSERT INTO profiles VALUES ({run}, {path}, {deleted}, {appeared});",
+            deleted = profile.deleted.len(),
+            appeared = profile.appeared.len(),
+        ));
+        for generation in profile.deleted.iter() {
+            statements.push(format!(
+                "INSERT INTO deletions VALUES ({run}, {path}, {id}, {created});",
+                id = generation.id,
+                created = quote(&generation.date.format(DATE_FORMAT).to_string()),
+            ));
+        }
+    }
+    statements.push("COMMIT;".to_string());
+
+    statements
+}
+
+/// Quotes `text` as an SQL string literal.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::{NaiveDateTime, TimeZone};
+    use std::{io, os::unix::process::ExitStatusExt, process::Output};
+
+    use futures::future::BoxFuture;
+    use janitor::{nix_store::GcReport, Generation, GenerationSet, Job, ProfileReport};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::plain("/nix/var/nix/profiles/system", "'/nix/var/nix/profiles/system'")]
+    #[case::quote("/home/o'brien/profile", "'/home/o''brien/profile'")]
+    fn quotes(#[case] text: &str, #[case] expected: &str) {
+        assert_eq!(quote(text), expected);
+    }
+
+    #[test]
+    fn appends_run() {
+        let date = NaiveDateTime::parse_from_str("2023-06-01 10:00:00", DATE_FORMAT).unwrap();
+        let deleted = GenerationSet::from([Generation {
+            id: 3,
+            date,
+            current: false,
+        }]);
+        let mut report = RunReport::default();
+        let job_id = Job::new("/p/it's", date, 0).id();
+        report.record(ProfileReport::new(job_id, "/p/it's", deleted));
+        report.record_gc(GcReport {
+            paths_deleted: 2,
+            bytes_freed: 1024,
+            ..Default::default()
+        });
+        let finished = Utc.with_ymd_and_hms(2023, 6, 2, 3, 4, 5).unwrap();
+        let run_id = RunId::at(finished);
+
+        let statements = statements(run_id, finished, &report);
+
+        assert_eq!(
+            &statements[SCHEMA.len()..],
+            [
+                "BEGIN;".to_string(),
+                format!("INSERT INTO runs VALUES ('{run_id}', '2023-06-02 03:04:05', 1, 1, 1024, 2);"),
+                format!("INSERT INTO profiles VALUES ('{run_id}', '/p/it''s', 1, 0);"),
+                format!(
+                    "INSERT INTO deletions VALUES ('{run_id}', '/p/it''s', 3, '2023-06-01 10:00:00');"
+                ),
+                "COMMIT;".to_string(),
+            ]
+        );
+    }
+
+    /// Answers like a successful `sqlite3`, recording the commands.
+    #[derive(Debug, Default)]
+    struct Sqlite(std::sync::Mutex<Vec<CommandLine>>);
+
+    impl Executor for Sqlite {
+        fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+            self.0.lock().unwrap().push(command);
+
+            Box::pin(async {
+                Ok(Output {
+                    status: ExitStatusExt::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn appends_on_stdin() -> Result<()> {
+        let date = NaiveDateTime::parse_from_str("2023-06-01 10:00:00", DATE_FORMAT)?;
+        let deleted: GenerationSet = (1..=20_000)
+            .map(|id| Generation {
+                id,
+                date,
+                current: false,
+            })
+            .collect();
+        let mut report = RunReport::default();
+        let job_id = Job::new("/p", date, 0).id();
+        report.record(ProfileReport::new(job_id, "/p", deleted));
+        let finished = Utc.with_ymd_and_hms(2023, 6, 2, 3, 4, 5).unwrap();
+        let sqlite = Sqlite::default();
+
+        append(
+            &sqlite,
+            Path::new("/db"),
+            RunId::at(finished),
+            finished,
+            &report,
+        )
+        .await?;
+
+        let commands = sqlite.0.into_inner().unwrap();
+        assert_eq!(commands[0].args, ["-bail", "-batch", "/db"]);
+        let stdin = String::from_utf8(commands[0].stdin.clone().unwrap_or_default())?;
+        assert_eq!(stdin.lines().count(), SCHEMA.len() + 2 + 1 + 1 + 20_000);
+        assert!(stdin.ends_with("COMMIT;"));
+        Ok(())
+    }
+
+    #[test]
+    fn query_names_roundtrip() {
+        for query in CannedQuery::value_variants() {
+            assert_eq!(
+                CannedQuery::from_str(&query.to_string(), false).as_ref(),
+                Ok(query)
+            );
+        }
+    }
+}
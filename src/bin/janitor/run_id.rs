@@ -0,0 +1,160 @@
+//! Unique ids of runs, to find everything a single run logged and recorded.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
+
+/// The alphabet of Crockford's base32, as used by ULIDs.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The number of characters of a ULID.
+const LEN: usize = 26;
+
+/// The id of a run, a [ULID](https://github.com/ulid/spec): the time the run
+/// started in milliseconds, followed by 80 random bits, so that ids sort by
+/// the time of their runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RunId(u128);
+
+impl RunId {
+    /// A new id for a run starting now.
+    pub fn new() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self::from_parts(now.as_millis() as u64, random())
+    }
+
+    /// The id of a run started at `time` without any randomness, for
+    /// reproducible runs.
+    pub fn at(time: DateTime<Utc>) -> Self {
+        Self::from_parts(time.timestamp_millis() as u64, 0)
+    }
+
+    fn from_parts(millis: u64, random: u128) -> Self {
+        let millis = u128::from(millis) & ((1 << 48) - 1);
+        let random = random & ((1 << 80) - 1);
+
+        Self(millis << 80 | random)
+    }
+}
+
+impl Default for RunId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 80 random bits, not suitable for cryptography but unique enough to tell
+/// runs started within the same millisecond apart.
+fn random() -> u128 {
+    let bits = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u32(std::process::id());
+        hasher.finish()
+    };
+
+    u128::from(bits(0)) << 64 | u128::from(bits(1))
+}
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = [0; LEN];
+        for (i, c) in out.iter_mut().enumerate() {
+            let shift = (LEN - 1 - i) * 5;
+            *c = ALPHABET[(self.0 >> shift) as usize & 0x1f];
+        }
+
+        f.write_str(std::str::from_utf8(&out).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl FromStr for RunId {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        if s.len() != LEN {
+            bail!("invalid run id {s:?}, expected {LEN} characters");
+        }
+
+        let value = s.bytes().try_fold(0u128, |value, c| {
+            let digit = ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())
+                .ok_or_else(|| eyre!("invalid run id {s:?}"))?;
+
+            value
+                .checked_mul(32)
+                .map(|value| value | digit as u128)
+                .ok_or_else(|| eyre!("invalid run id {s:?}"))
+        })?;
+
+        Ok(Self(value))
+    }
+}
+
+impl TryFrom<String> for RunId {
+    type Error = eyre::Report;
+
+    fn try_from(s: String) -> eyre::Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<RunId> for String {
+    fn from(id: RunId) -> Self {
+        id.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[test]
+    fn formats_like_ulid() {
+        let id = RunId::from_parts(1_469_922_850_259, 0);
+
+        assert_eq!(id.to_string(), "01ARZ3NDEK0000000000000000");
+    }
+
+    #[test]
+    fn roundtrips() -> eyre::Result<()> {
+        let id = RunId::new();
+
+        assert_eq!(id.to_string().parse::<RunId>()?, id);
+        assert_eq!(id.to_string().to_lowercase().parse::<RunId>()?, id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unique_and_sorted() {
+        let first = RunId::from_parts(1, random());
+        let second = RunId::from_parts(2, random());
+
+        assert_ne!(RunId::new(), RunId::new());
+        assert!(first < second);
+    }
+
+    #[rstest]
+    #[case::short("01ARYZ6S4K")]
+    #[case::invalid_character("01ARYZ6S4K000000000000000U")]
+    #[case::overflow("ZZZZZZZZZZZZZZZZZZZZZZZZZZ")]
+    fn rejects(#[case] input: &str) {
+        assert!(input.parse::<RunId>().is_err());
+    }
+}
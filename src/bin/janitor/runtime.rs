@@ -0,0 +1,126 @@
+//! Tuning of the async runtime the profiles are processed and the daemon
+//! runs on.
+//!
+//! By default a multi-threaded runtime with one worker per core is used. On
+//! small machines, a daemon idling most of the time is better off with a
+//! single thread.
+
+use std::{fmt, num::NonZeroUsize};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{config::Config, interface::NJParser};
+
+/// How the async runtime schedules its tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flavor {
+    /// Spread the tasks over a pool of worker threads.
+    #[default]
+    MultiThread,
+
+    /// Run all tasks on the thread the runtime is started on.
+    CurrentThread,
+}
+
+impl fmt::Display for Flavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no flavor variant is skipped");
+
+        f.write_str(value.get_name())
+    }
+}
+
+/// The configuration of the async runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuntimeOptions {
+    /// How tasks are scheduled.
+    pub flavor: Flavor,
+
+    /// The number of worker threads of a [Flavor::MultiThread] runtime,
+    /// `None` for one per core.
+    pub worker_threads: Option<NonZeroUsize>,
+}
+
+impl RuntimeOptions {
+    /// Resolves the runtime options from the command line `args`, which take
+    /// precedence over the `config`.
+    pub fn resolve(args: &NJParser, config: &Config) -> Self {
+        Self {
+            flavor: args.runtime.or(config.runtime).unwrap_or_default(),
+            worker_threads: args.worker_threads.or(config.worker_threads),
+        }
+    }
+
+    /// Builds the runtime.
+    ///
+    /// A number of worker threads is ignored with a warning for a
+    /// [Flavor::CurrentThread] runtime.
+    pub fn build(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = match self.flavor {
+            Flavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+            Flavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        };
+
+        match (self.flavor, self.worker_threads) {
+            (Flavor::MultiThread, Some(workers)) => {
+                builder.worker_threads(workers.get());
+            }
+            (Flavor::CurrentThread, Some(workers)) => {
+                tracing::warn!(
+                    workers,
+                    "a current-thread runtime has no worker threads, ignoring their number"
+                );
+            }
+            (_, None) => {}
+        }
+
+        tracing::debug!(flavor = %self.flavor, workers = ?self.worker_threads, "starting runtime");
+
+        builder.enable_all().build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use clap::Parser;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::defaults(&["janitor"], "", RuntimeOptions::default())]
+    #[case::config(
+        &["janitor"],
+        "runtime = \"current-thread\"",
+        RuntimeOptions { flavor: Flavor::CurrentThread, worker_threads: None }
+    )]
+    #[case::args_take_precedence(
+        &["janitor", "--runtime", "multi-thread", "--worker-threads", "2"],
+        "runtime = \"current-thread\"\nworker_threads = 8",
+        RuntimeOptions { flavor: Flavor::MultiThread, worker_threads: NonZeroUsize::new(2) }
+    )]
+    fn resolve(#[case] args: &[&str], #[case] config: &str, #[case] expected: RuntimeOptions) {
+        let args = NJParser::parse_from(args);
+        let config: Config = toml::from_str(config).unwrap();
+
+        assert_eq!(RuntimeOptions::resolve(&args, &config), expected);
+    }
+
+    #[rstest]
+    #[case::multi_thread(RuntimeOptions { flavor: Flavor::MultiThread, worker_threads: NonZeroUsize::new(1) })]
+    #[case::current_thread(RuntimeOptions { flavor: Flavor::CurrentThread, worker_threads: NonZeroUsize::new(4) })]
+    fn build(#[case] options: RuntimeOptions) {
+        let runtime = options.build().unwrap();
+
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+    }
+
+    #[test]
+    fn zero_workers_rejected() {
+        assert!(NJParser::try_parse_from(["janitor", "--worker-threads", "0"]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! `janitor selftest`: cleans up a throwaway profile the way a run cleans up
+//! the real ones, validating the nix installation and the permissions
+//! without risking any real profile.
+//!
+//! The profile is created in a temporary directory, with [GENERATIONS]
+//! generations set to trivial store paths added with `nix-store --add`. It
+//! is planned and cleaned up with the configured backend, keeping the [KEPT]
+//! most recent generations, and listed again to check that exactly those
+//! are left. The store paths are left to the next garbage collection.
+
+use std::{
+    env, fmt, fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::{Duration, NaiveDateTime};
+use eyre::{bail, Context as _, Result};
+use futures::executor::block_on;
+
+use janitor::{
+    state::Discovered, Blocking, BlockingExecutor, CommandLine, Job, Profile, StdExecutor,
+};
+
+use crate::{pipeline, RunOptions};
+
+/// The number of generations the throwaway profile is created with.
+pub const GENERATIONS: u32 = 4;
+
+/// The number of most recent generations the cleanup keeps.
+pub const KEPT: usize = 2;
+
+/// A step of the self-test, and why it failed.
+#[derive(Debug)]
+pub struct Step {
+    /// What has been done.
+    pub name: &'static str,
+
+    /// Why the step failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+/// The results of `janitor selftest`, the steps up to the first failing
+/// one.
+#[derive(Debug, Default)]
+pub struct SelftestReport {
+    /// The profile that has been cleaned up.
+    pub profile: PathBuf,
+
+    /// The steps performed, in order.
+    pub steps: Vec<Step>,
+}
+
+impl SelftestReport {
+    /// Whether every step succeeded.
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.error.is_none())
+    }
+}
+
+impl fmt::Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "throwaway profile {}:", self.profile.display())?;
+        for step in &self.steps {
+            match &step.error {
+                None => writeln!(f, "  {}: ok", step.name)?,
+                Some(error) => writeln!(f, "  {}: failed: {error}", step.name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a throwaway profile, cleans it up with the `options` and checks
+/// the result, removing the profile afterwards.
+pub fn run(options: &RunOptions, now: NaiveDateTime) -> SelftestReport {
+    let dir = env::temp_dir().join(format!("janitor-selftest-{}", process::id()));
+    let profile = dir.join("profile");
+
+    // Only the deletion is tested, none of the steps affecting the rest of
+    // the system runs.
+    let mut options = options.clone();
+    options.gc = false;
+    options.verify_store = false;
+    options.repair_store = false;
+    options.stale_pins = None;
+    options.free_at_least = None;
+    options.per_user_budget = None;
+    options.interactive = None;
+    options.max_runtime = None;
+
+    let steps: [(&'static str, &dyn Fn() -> Result<()>); 4] = [
+        ("create profile", &|| create(&dir, &profile)),
+        ("plan", &|| plan(&profile, &options, now)),
+        ("clean up", &|| clean(&profile, &options, now)),
+        ("list again", &|| check_remaining(&profile, &options)),
+    ];
+
+    let mut report = SelftestReport {
+        profile: profile.clone(),
+        steps: Vec::new(),
+    };
+    for (name, step) in steps {
+        let error = step().err().map(|error| format!("{error:#}"));
+        let failed = error.is_some();
+        report.steps.push(Step { name, error });
+        if failed {
+            break;
+        }
+    }
+
+    if let Err(error) = fs::remove_dir_all(&dir) {
+        tracing::warn!(?dir, %error, "could not remove the throwaway profile");
+    }
+
+    report
+}
+
+/// Creates the `profile` in `dir` with [GENERATIONS] generations.
+fn create(dir: &Path, profile: &Path) -> Result<()> {
+    fs::create_dir_all(dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    for id in 1..=GENERATIONS {
+        let content = dir.join(format!("janitor-selftest-{id}"));
+        fs::write(
+            &content,
+            format!("generation {id} of the janitor self-test\n"),
+        )
+        .wrap_err_with(|| format!("Failed to write {}", content.display()))?;
+
+        let store_path = nix(CommandLine::new("nix-store").arg("--add").arg(&content))?;
+        nix(CommandLine::new("nix-env")
+            .arg("--profile")
+            .arg(profile)
+            .arg("--set")
+            .arg(store_path.trim()))?;
+    }
+
+    Ok(())
+}
+
+/// A job deleting all but the [KEPT] most recent generations of the
+/// `profile`.
+fn job(profile: &Path, now: NaiveDateTime) -> Job<Discovered> {
+    Job::new(profile, now + Duration::days(1), KEPT)
+}
+
+/// Plans the cleanup of the `profile`, checking which generations would be
+/// deleted.
+fn plan(profile: &Path, options: &RunOptions, now: NaiveDateTime) -> Result<()> {
+    let planned = pipeline::plan_blocking(vec![job(profile, now)], options.clone(), None)?;
+    let to_delete = planned
+        .iter()
+        .flat_map(|job| job.state().to_delete.iter().map(|g| g.id));
+
+    expect_ids("planned to delete", to_delete, deleted())
+}
+
+/// Cleans up the `profile`, checking which generations have been deleted.
+fn clean(profile: &Path, options: &RunOptions, now: NaiveDateTime) -> Result<()> {
+    let report = pipeline::run_blocking(vec![job(profile, now)], options.clone(), None)?;
+    if let Some(failure) = report.failures().first() {
+        bail!("{}", failure.error);
+    }
+    let deleted_ids = report
+        .profiles()
+        .iter()
+        .flat_map(|profile| profile.deleted.iter().map(|g| g.id));
+
+    expect_ids("deleted", deleted_ids, deleted())
+}
+
+/// Lists the `profile` again, checking that the kept generations are left.
+fn check_remaining(profile: &Path, options: &RunOptions) -> Result<()> {
+    let executor = Blocking(StdExecutor);
+    let listed = block_on(
+        options
+            .backend
+            .list_generations(&executor, Profile::new(profile)),
+    )?;
+
+    expect_ids("left", listed.iter().map(|g| g.id), kept())
+}
+
+/// The ids of the generations the cleanup deletes.
+fn deleted() -> RangeInclusive<u32> {
+    1..=GENERATIONS - KEPT as u32
+}
+
+/// The ids of the generations the cleanup keeps.
+fn kept() -> RangeInclusive<u32> {
+    GENERATIONS - KEPT as u32 + 1..=GENERATIONS
+}
+
+/// Fails unless the `actual` ids are exactly the `expected` ones.
+fn expect_ids(
+    what: &str,
+    actual: impl Iterator<Item = u32>,
+    expected: RangeInclusive<u32>,
+) -> Result<()> {
+    let actual: Vec<_> = actual.collect();
+    let expected: Vec<_> = expected.collect();
+    if actual != expected {
+        bail!("{what} generations {actual:?}, expected {expected:?}");
+    }
+
+    Ok(())
+}
+
+/// Runs the nix `command`, returning its stdout.
+fn nix(command: CommandLine) -> Result<String> {
+    let program = command.program.clone();
+    let output = StdExecutor
+        .output(command)
+        .wrap_err_with(|| format!("Failed to run {program}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "{program} failed: {stderr}",
+            stderr = String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent() {
+        assert_eq!(deleted().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(kept().collect::<Vec<_>>(), [3, 4]);
+    }
+
+    #[test]
+    fn expects_exact_ids() {
+        assert!(expect_ids("left", [3, 4].into_iter(), kept()).is_ok());
+
+        let error = expect_ids("left", [2, 3, 4].into_iter(), kept()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "left generations [2, 3, 4], expected [3, 4]"
+        );
+    }
+
+    #[test]
+    fn renders_up_to_the_failure() {
+        let report = SelftestReport {
+            profile: PathBuf::from("/tmp/janitor-selftest-1/profile"),
+            steps: vec![
+                Step {
+                    name: "create profile",
+                    error: None,
+                },
+                Step {
+                    name: "plan",
+                    error: Some("nix-env failed: permission denied".to_string()),
+                },
+            ],
+        };
+
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "throwaway profile /tmp/janitor-selftest-1/profile:\n  create profile: ok\n  \
+             plan: failed: nix-env failed: permission denied\n"
+        );
+    }
+}
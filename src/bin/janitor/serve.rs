@@ -0,0 +1,593 @@
+//! `janitor serve`, which keeps running and cleans up the profiles on
+//! request or on [schedule](janitor::schedule).
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use eyre::{bail, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, Notify},
+};
+use tracing::Instrument;
+
+use janitor::RunReport;
+
+use crate::{
+    cache::ListingCache, check_failures, control, log_report, log_resources, pipeline,
+    record_success, run_id::RunId, runtime::RuntimeOptions, tasks::Tasks, Settings,
+};
+
+/// How long to wait for further changes of the configuration before
+/// reloading it.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// How long a running cleanup may take to finish when stopping.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Resolves the settings again, when asked to reload the configuration.
+pub type Reload = Box<dyn Fn() -> Result<Settings> + Send + Sync>;
+
+/// What the daemon is doing and how its last cleanup went.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    /// Whether a cleanup is running right now.
+    pub running: bool,
+
+    /// Whether starting cleanups has been paused.
+    pub paused: bool,
+
+    /// When the last cleanup has been started.
+    pub last_started: Option<DateTime<Utc>>,
+
+    /// The id of the last cleanup.
+    pub last_run: Option<RunId>,
+
+    /// When the last cleanup has finished, successfully or not.
+    pub last_finished: Option<DateTime<Utc>>,
+
+    /// The number of generations deleted by the last successful cleanup.
+    pub last_deleted: usize,
+
+    /// Why the last cleanup failed, if it did.
+    pub last_error: Option<String>,
+
+    /// The names of the tasks of the daemon running right now.
+    pub tasks: Vec<&'static str>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match (self.running, self.paused) {
+            (true, _) => "running",
+            (false, true) => "paused",
+            (false, false) => "idle",
+        };
+        let date = |date: Option<DateTime<Utc>>| {
+            date.map_or_else(|| "never".to_string(), |date| date.to_rfc3339())
+        };
+
+        writeln!(f, "state: {state}")?;
+        writeln!(f, "last started: {}", date(self.last_started))?;
+        if let Some(run) = self.last_run {
+            writeln!(f, "last run: {run}")?;
+        }
+        writeln!(f, "last finished: {}", date(self.last_finished))?;
+        write!(f, "last deleted: {}", self.last_deleted)?;
+        if let Some(error) = &self.last_error {
+            write!(f, "\nlast error: {error}")?;
+        }
+        if !self.tasks.is_empty() {
+            write!(f, "\ntasks: {}", self.tasks.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The state shared by all interfaces of the daemon.
+pub struct Daemon {
+    settings: Mutex<Settings>,
+    reload: Reload,
+    cache: Option<Mutex<ListingCache>>,
+    running: AtomicBool,
+    paused: AtomicBool,
+    status: Mutex<Status>,
+    reloaded: Notify,
+    tasks: Tasks,
+}
+
+impl fmt::Debug for Daemon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Daemon")
+            .field("settings", &self.settings)
+            .field("status", &self.status)
+            .field("tasks", &self.tasks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Daemon {
+    pub fn new(settings: Settings, reload: Reload) -> Self {
+        let cache = settings
+            .cache_path
+            .as_deref()
+            .map(|path| Mutex::new(ListingCache::load_or_default(path)));
+
+        Self {
+            settings: Mutex::new(settings),
+            reload,
+            cache,
+            running: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            status: Mutex::default(),
+            reloaded: Notify::new(),
+            tasks: Tasks::default(),
+        }
+    }
+
+    /// Cleans up all profiles, unless a cleanup is running already or the
+    /// daemon is paused.
+    pub async fn clean(&self) -> Result<RunReport> {
+        if self.paused.load(Ordering::Acquire) {
+            bail!("the daemon is paused");
+        }
+        if self.running.swap(true, Ordering::AcqRel) {
+            bail!("a cleanup is running already");
+        }
+
+        let run_id = RunId::new();
+        self.update_status(|status| {
+            status.running = true;
+            status.last_started = Some(Utc::now());
+            status.last_run = Some(run_id);
+        });
+
+        let result = self
+            .run(run_id)
+            .instrument(tracing::info_span!("run", %run_id))
+            .await;
+
+        self.update_status(|status| {
+            status.running = false;
+            status.last_finished = Some(Utc::now());
+            match &result {
+                Ok(report) => {
+                    status.last_deleted = report.deleted_count();
+                    status.last_error = None;
+                }
+                Err(error) => status.last_error = Some(format!("{error:#}")),
+            }
+        });
+        self.running.store(false, Ordering::Release);
+
+        result
+    }
+
+    /// Starts a cleanup in the background, failing right away if it could
+    /// not be started.
+    pub fn trigger(self: &Arc<Self>) -> Result<()> {
+        if self.paused.load(Ordering::Acquire) {
+            bail!("the daemon is paused");
+        }
+        if self.running.load(Ordering::Acquire) {
+            bail!("a cleanup is running already");
+        }
+
+        let daemon = self.clone();
+        self.tasks.spawn("cleanup", async move {
+            if let Err(error) = daemon.clean().await {
+                tracing::error!(error = format!("{error:#}"), "cleanup failed");
+            }
+        })
+    }
+
+    /// Plans the cleanup of all profiles, without deleting anything.
+    #[cfg(feature = "dbus")]
+    pub async fn plan(&self) -> Result<Vec<janitor::Job<janitor::state::Planned>>> {
+        let settings = self.settings();
+        let jobs = settings.jobs(Utc::now().naive_utc())?;
+        let planned = pipeline::plan_async(jobs, settings.options, self.cache.as_ref()).await;
+        self.save_cache();
+
+        planned
+    }
+
+    /// Resolves the settings again, using them from the next cleanup on, and
+    /// logs the settings that have changed.
+    pub fn reload(&self) -> Result<()> {
+        let new = (self.reload)()?;
+        let mut settings = self.settings.lock().unwrap_or_else(|e| e.into_inner());
+
+        let changes = settings.changes(&new);
+        if changes.is_empty() {
+            tracing::info!("reloaded settings, nothing changed");
+        }
+        for (setting, old, new) in changes {
+            tracing::info!(setting, %old, %new, "setting changed");
+        }
+
+        *settings = new;
+        self.reloaded.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Refuses to start cleanups until [resumed](Self::resume). A running
+    /// cleanup is not interrupted.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+        self.update_status(|status| status.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.update_status(|status| status.paused = false);
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            tasks: self.tasks.running(),
+            ..self
+                .status
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+        }
+    }
+
+    /// Spawns the work `future` as a task of the daemon called `name`, see
+    /// [Tasks::spawn].
+    pub fn spawn<F>(&self, name: &'static str, future: F) -> Result<()>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(name, future)
+    }
+
+    fn settings(&self) -> Settings {
+        self.settings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    async fn run(&self, run_id: RunId) -> Result<RunReport> {
+        let settings = self.settings();
+        let jobs = settings.jobs(Utc::now().naive_utc())?;
+        let report = pipeline::run_async(jobs, settings.options.clone(), self.cache.as_ref()).await;
+        self.save_cache();
+
+        let report = report?;
+        log_resources(&report);
+        log_report(&report, settings.explain_skip);
+        check_failures(&report)?;
+        record_success(&settings, run_id, &report);
+
+        Ok(report)
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut Status)) {
+        update(&mut self.status.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    fn save_cache(&self) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+
+        let settings = self.settings();
+        if let Some(path) = &settings.cache_path {
+            let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+            cache.prune(Utc::now(), settings.cache_retention());
+            cache.save_or_warn(path);
+        }
+    }
+}
+
+/// Runs the daemon until it is interrupted or terminated, listening for
+/// control requests on `socket` and offering the DBus interface if `dbus`
+/// is set.
+///
+/// The settings are reloaded on `SIGHUP` and whenever the file at `config`
+/// changes. With `catch_up` set, the profiles are cleaned up right away,
+/// before following the schedule. The daemon runs on a runtime built
+/// according to `runtime`.
+pub fn serve(
+    settings: Settings,
+    reload: Reload,
+    socket: Option<PathBuf>,
+    config: Option<PathBuf>,
+    dbus: bool,
+    catch_up: bool,
+    runtime: RuntimeOptions,
+) -> Result<()> {
+    let Some(socket) = socket else {
+        bail!("no location for the control socket known, use --socket");
+    };
+
+    #[cfg(not(feature = "dbus"))]
+    if dbus {
+        bail!("janitor has been built without DBus support");
+    }
+
+    runtime.build()?.block_on(async {
+        let daemon = Arc::new(Daemon::new(settings, reload));
+
+        let listener = control::bind(&socket)?;
+        daemon
+            .tasks
+            .spawn_service("control", control::accept(listener, daemon.clone()))?;
+        daemon
+            .tasks
+            .spawn_service("schedule", schedule(daemon.clone(), catch_up))?;
+
+        #[cfg(feature = "dbus")]
+        let _connection = match dbus {
+            true => Some(crate::dbus::serve(daemon.clone()).await?),
+            false => None,
+        };
+
+        let (changed, mut changes) = mpsc::unbounded_channel();
+        let _watcher = match &config {
+            Some(config) => match watch(config, changed) {
+                Ok(watcher) => Some(watcher),
+                Err(error) => {
+                    tracing::warn!(%error, "not reloading the configuration on changes");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        tracing::info!(socket = %socket.display(), "serving, stop with ctrl-c");
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => break result?,
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => {
+                    tracing::info!("received SIGHUP, reloading the configuration");
+                    reload_settings(&daemon);
+                }
+                Some(()) = changes.recv() => {
+                    // editors tend to write a file in several steps
+                    tokio::time::sleep(SETTLE_TIME).await;
+                    while changes.try_recv().is_ok() {}
+
+                    tracing::info!("configuration changed, reloading it");
+                    reload_settings(&daemon);
+                }
+            }
+        }
+        tracing::info!(tasks = ?daemon.tasks.running(), "stopping");
+        daemon.tasks.shutdown(SHUTDOWN_GRACE).await;
+
+        if let Err(error) = fs::remove_file(&socket) {
+            tracing::warn!(%error, "failed to remove the control socket");
+        }
+
+        Ok(())
+    })
+}
+
+/// Cleans up whenever the schedule of the current settings is due, picking
+/// the time anew whenever the settings are reloaded.
+///
+/// With `catch_up` set, a cleanup is started right away, which counts for
+/// the window that has started last.
+async fn schedule(daemon: Arc<Daemon>, catch_up: bool) {
+    let mut after = Local::now().naive_local();
+
+    if catch_up {
+        tracing::info!("catching up on a missed scheduled cleanup");
+        if let Err(error) = daemon.clean().await {
+            tracing::warn!(error = format!("{error:#}"), "scheduled cleanup failed");
+        }
+
+        if let Some(schedule) = daemon.settings().schedule {
+            after = schedule
+                .window_before(after)
+                .end
+                .max(Local::now().naive_local());
+        }
+    }
+
+    loop {
+        // register before reading the settings, to not miss a reload
+        let reloaded = daemon.reloaded.notified();
+        tokio::pin!(reloaded);
+        reloaded.as_mut().enable();
+
+        let settings = daemon.settings();
+        let Some(schedule) = settings.schedule else {
+            reloaded.await;
+            continue;
+        };
+
+        let now = Local::now().naive_local();
+        let run = schedule.next_run(after.max(now), settings.jitter, random_up_to);
+        tracing::info!(at = %run.at, %schedule, "scheduled next cleanup");
+
+        tokio::select! {
+            _ = tokio::time::sleep((run.at - now).to_std().unwrap_or_default()) => {}
+            _ = reloaded => continue,
+        }
+
+        if let Err(error) = daemon.clean().await {
+            tracing::warn!(error = format!("{error:#}"), "scheduled cleanup failed");
+        }
+
+        // at most one cleanup per window
+        after = run.window.end.max(Local::now().naive_local());
+    }
+}
+
+/// Returns a random duration between zero and `max`.
+fn random_up_to(max: chrono::Duration) -> chrono::Duration {
+    // every `RandomState` is seeded differently, good enough to spread
+    // machines over a window
+    let random = RandomState::new().build_hasher().finish();
+    let max = max.num_milliseconds().max(0) as u64;
+
+    chrono::Duration::milliseconds((random % (max + 1)) as i64)
+}
+
+fn reload_settings(daemon: &Daemon) {
+    if let Err(error) = daemon.reload() {
+        tracing::warn!(
+            error = format!("{error:#}"),
+            "failed to reload, keeping the current settings"
+        );
+    }
+}
+
+/// Watches the file at `path`, sending to `changed` whenever it is modified,
+/// created, replaced or removed.
+///
+/// The directory containing the file is watched, so that the file does not
+/// need to exist yet and may be replaced.
+fn watch(path: &Path, changed: mpsc::UnboundedSender<()>) -> Result<RecommendedWatcher> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("can not watch {}", path.display());
+    };
+    let name = name.to_owned();
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if !event.kind.is_access()
+                    && event.paths.iter().any(|p| p.file_name() == Some(&name)) =>
+            {
+                let _ = changed.send(());
+            }
+            Ok(_) => {}
+            Err(error) => tracing::warn!(%error, "failed to watch the configuration"),
+        })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    tracing::debug!(?path, "watching the configuration");
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+
+    fn daemon() -> Daemon {
+        Daemon::new(Settings::default(), Box::new(|| bail!("no configuration")))
+    }
+
+    #[tokio::test]
+    async fn rejects_concurrent_clean() {
+        let daemon = daemon();
+        daemon.running.store(true, Ordering::Release);
+
+        assert!(daemon.clean().await.is_err());
+        assert_eq!(daemon.status(), Status::default());
+    }
+
+    #[tokio::test]
+    async fn rejects_clean_while_paused() {
+        let daemon = daemon();
+        daemon.pause();
+
+        assert!(daemon.clean().await.is_err());
+        assert!(daemon.status().paused);
+
+        daemon.resume();
+        assert!(!daemon.status().paused);
+    }
+
+    #[test]
+    fn reload_replaces_settings() -> Result<()> {
+        let reloaded = Settings {
+            include_system: true,
+            ..Settings::default()
+        };
+        let expected = reloaded.clone();
+        let daemon = Daemon::new(Settings::default(), Box::new(move || Ok(reloaded.clone())));
+
+        daemon.reload()?;
+
+        assert_eq!(daemon.settings(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_reload_keeps_settings() {
+        let daemon = daemon();
+
+        assert!(daemon.reload().is_err());
+        assert_eq!(daemon.settings(), Settings::default());
+    }
+
+    #[tokio::test]
+    async fn watch_notices_changes() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let config = dir.join("config.toml");
+
+        let (changed, mut changes) = mpsc::unbounded_channel();
+        let watcher = watch(&config, changed)?;
+
+        fs::write(dir.join("unrelated.toml"), "")?;
+        fs::write(&config, "gc = true")?;
+        let noticed = tokio::time::timeout(Duration::from_secs(5), changes.recv()).await;
+
+        drop(watcher);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(noticed?, Some(()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn random_up_to_stays_in_range() {
+        let max = chrono::Duration::minutes(5);
+
+        for _ in 0..100 {
+            let random = random_up_to(max);
+            assert!(random >= chrono::Duration::zero() && random <= max);
+        }
+        assert_eq!(
+            random_up_to(chrono::Duration::zero()),
+            chrono::Duration::zero()
+        );
+    }
+
+    #[test]
+    fn status_display() {
+        let status = Status {
+            last_error: Some("nix-env failed".to_string()),
+            ..Status::default()
+        };
+
+        assert_eq!(
+            status.to_string(),
+            "state: idle\n\
+             last started: never\n\
+             last finished: never\n\
+             last deleted: 0\n\
+             last error: nix-env failed"
+        );
+    }
+}
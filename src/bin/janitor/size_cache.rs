@@ -0,0 +1,358 @@
+//! Closure sizes of earlier runs, so that looking up the size of a
+//! generation again is instant.
+//!
+//! Store paths never change once built, so the size of a closure is keyed by
+//! the hash of its store path and never goes stale. The cache is shared by
+//! concurrent jobs and processes: writes merge with the file on disk under a
+//! lock, and replace it atomically.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use janitor::{
+    size::{self, Estimate, SizeEstimation},
+    Executor,
+};
+use serde::{Deserialize, Serialize};
+
+/// How many sizes are kept by default, see [SizeCache::save].
+pub const DEFAULT_LIMIT: usize = 10_000;
+
+/// The length of the hash of a store path, in base32.
+const HASH_LEN: usize = 32;
+
+/// A cached closure size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    bytes: u64,
+
+    /// When the size has been used last.
+    used: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct File {
+    #[serde(default)]
+    sizes: BTreeMap<String, Entry>,
+}
+
+/// Closure sizes of earlier runs, keyed by the hash of the store path.
+#[derive(Debug, Default)]
+pub struct SizeCache {
+    entries: Mutex<BTreeMap<String, Entry>>,
+
+    /// Whether to query all sizes again, replacing the cached ones.
+    refresh: bool,
+}
+
+impl SizeCache {
+    /// Loads the cache from `path`, starting with an empty cache if it can not
+    /// be read.
+    ///
+    /// With `refresh`, the cached sizes are not used, but replaced by the
+    /// ones queried anew.
+    pub fn load_or_default(path: &Path, refresh: bool) -> Self {
+        let entries = read(path).unwrap_or_else(|error| {
+            tracing::warn!(%error, "ignoring unreadable size cache");
+            BTreeMap::new()
+        });
+
+        Self {
+            entries: Mutex::new(entries),
+            refresh,
+        }
+    }
+
+    /// The size of the generation linked to by `link`, like
+    /// [size::generation_size], but taken from the cache if known.
+    ///
+    /// Only exact sizes are cached, which are used regardless of the
+    /// `estimation`.
+    pub async fn generation_size<E, P>(
+        &self,
+        executor: &E,
+        link: P,
+        estimation: SizeEstimation,
+    ) -> Result<Estimate>
+    where
+        E: Executor + ?Sized,
+        P: AsRef<Path>,
+    {
+        let link = link.as_ref();
+        let key = fs::read_link(link).ok().and_then(|path| hash(&path));
+
+        if let Some(bytes) = key.as_deref().and_then(|key| self.get(key)) {
+            tracing::debug!(?link, bytes, "using the cached closure size");
+            return Ok(Estimate {
+                bytes,
+                approximate: false,
+            });
+        }
+
+        let estimate = size::generation_size(executor, link, estimation).await?;
+        if let (Some(key), false) = (key, estimate.approximate) {
+            self.insert(key, estimate.bytes);
+        }
+
+        Ok(estimate)
+    }
+
+    /// The cached size of the closure of the store path with the hash `key`.
+    pub fn get(&self, key: &str) -> Option<u64> {
+        if self.refresh {
+            return None;
+        }
+
+        let mut entries = self.lock();
+        let entry = entries.get_mut(key)?;
+        entry.used = Utc::now();
+
+        Some(entry.bytes)
+    }
+
+    /// Remembers the size of the closure of the store path with the hash
+    /// `key`.
+    pub fn insert(&self, key: String, bytes: u64) {
+        let entry = Entry {
+            bytes,
+            used: Utc::now(),
+        };
+
+        self.lock().insert(key, entry);
+    }
+
+    /// Writes the cache to `path`, only logging failures, as the cache is
+    /// merely an optimization.
+    pub fn save_or_warn(&self, path: &Path, limit: usize) {
+        if let Err(error) = self.save(path, limit) {
+            tracing::warn!(%error, "failed to save the size cache");
+        }
+    }
+
+    /// Writes the cache to `path`, keeping at most `limit` sizes, the ones
+    /// used most recently.
+    ///
+    /// Sizes cached by other processes in the meantime are merged in, while
+    /// holding a lock on `path` with the extension `lock`.
+    pub fn save(&self, path: &Path, limit: usize) -> Result<()> {
+        tracing::debug!(?path, "writing size cache");
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        let lock = path.with_extension("lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock)
+            .wrap_err_with(|| format!("Failed to open {}", lock.display()))?;
+        // SAFETY: the descriptor stays open for the duration of the call, the
+        // lock is released when `lock` is dropped.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error()).wrap_err("Failed to lock the size cache");
+        }
+
+        let mut sizes = read(path).unwrap_or_default();
+        for (key, entry) in self.lock().iter() {
+            sizes
+                .entry(key.clone())
+                .and_modify(|cached| *cached = (*cached).max_by_use(*entry))
+                .or_insert(*entry);
+        }
+        prune(&mut sizes, limit);
+
+        let tmp = path.with_extension(format!("toml.{}.tmp", std::process::id()));
+        fs::write(&tmp, toml::to_string(&File { sizes })?)
+            .wrap_err_with(|| format!("Failed to write cache {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .wrap_err_with(|| format!("Failed to write cache {}", path.display()))?;
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Entry {
+    /// The entry used more recently, the sizes of both being the same.
+    fn max_by_use(self, other: Self) -> Self {
+        match other.used > self.used {
+            true => other,
+            false => self,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, Entry>> {
+    tracing::debug!(?path, "reading size cache");
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(error) => {
+            return Err(error).wrap_err_with(|| format!("Failed to read cache {}", path.display()))
+        }
+    };
+    let file: File =
+        toml::from_str(&content).wrap_err_with(|| format!("Invalid cache {}", path.display()))?;
+
+    Ok(file.sizes)
+}
+
+/// Forgets the sizes used least recently, until at most `limit` are left.
+fn prune(sizes: &mut BTreeMap<String, Entry>, limit: usize) {
+    if sizes.len() <= limit {
+        return;
+    }
+
+    let mut by_use: Vec<_> = sizes.iter().map(|(key, entry)| (entry.used, key)).collect();
+    by_use.sort_unstable_by(|a, b| b.cmp(a));
+    let pruned: Vec<_> = by_use[limit..]
+        .iter()
+        .map(|(_, key)| (*key).clone())
+        .collect();
+
+    for key in &pruned {
+        sizes.remove(key);
+    }
+
+    tracing::info!(pruned = pruned.len(), limit, "pruned sizes from the cache");
+}
+
+/// The hash of the store path `path`, like `/nix/store/<hash>-<name>`.
+fn hash(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let (hash, _) = name.split_once('-')?;
+
+    (hash.len() == HASH_LEN && hash.bytes().all(|b| b.is_ascii_alphanumeric()))
+        .then(|| hash.to_string())
+}
+
+/// The default location of the size cache, next to the state at
+/// `state_path`.
+pub fn default_path(state_path: &Path) -> PathBuf {
+    state_path.with_file_name("sizes.toml")
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+
+    use chrono::Duration;
+    use rstest::rstest;
+
+    const HASH: &str = "0c5yzn9ka3k6rqvb8xm0r2jzpd9c1xgc";
+
+    fn temp_dir(name: &str) -> io::Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("janitor-sizes-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
+    #[rstest]
+    #[case::store_path("/nix/store/0c5yzn9ka3k6rqvb8xm0r2jzpd9c1xgc-nixos-system", Some(HASH))]
+    #[case::short_hash("/nix/store/0c5yzn9k-nixos-system", None)]
+    #[case::no_name("/nix/store/0c5yzn9ka3k6rqvb8xm0r2jzpd9c1xgc", None)]
+    fn hashes(#[case] path: &str, #[case] expected: Option<&str>) {
+        assert_eq!(hash(Path::new(path)).as_deref(), expected);
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let dir = temp_dir("roundtrip")?;
+        let path = dir.join("sizes.toml");
+
+        let cache = SizeCache::default();
+        cache.insert(HASH.to_string(), 4096);
+        cache.save(&path, DEFAULT_LIMIT)?;
+
+        let loaded = SizeCache::load_or_default(&path, false);
+        let refreshed = SizeCache::load_or_default(&path, true);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(loaded.get(HASH), Some(4096));
+        assert_eq!(loaded.get("missing"), None);
+        assert_eq!(refreshed.get(HASH), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_concurrent_saves() -> Result<()> {
+        let dir = temp_dir("merge")?;
+        let path = dir.join("sizes.toml");
+
+        let first = SizeCache::load_or_default(&path, false);
+        let second = SizeCache::load_or_default(&path, false);
+        first.insert("first".to_string(), 1);
+        second.insert("second".to_string(), 2);
+        first.save(&path, DEFAULT_LIMIT)?;
+        second.save(&path, DEFAULT_LIMIT)?;
+
+        let merged = SizeCache::load_or_default(&path, false);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(merged.get("first"), Some(1));
+        assert_eq!(merged.get("second"), Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prunes_least_recently_used() {
+        let now = Utc::now();
+        let mut sizes: BTreeMap<_, _> = (0..5)
+            .map(|i| {
+                let entry = Entry {
+                    bytes: i,
+                    used: now - Duration::days(i as i64),
+                };
+                (format!("path-{i}"), entry)
+            })
+            .collect();
+
+        prune(&mut sizes, 3);
+
+        assert_eq!(
+            sizes.keys().collect::<Vec<_>>(),
+            ["path-0", "path-1", "path-2"]
+        );
+    }
+
+    #[test]
+    fn prunes_ties_to_the_limit() {
+        let now = Utc::now();
+        let mut sizes: BTreeMap<_, _> = (0..5)
+            .map(|i| {
+                (
+                    format!("path-{i}"),
+                    Entry {
+                        bytes: i,
+                        used: now,
+                    },
+                )
+            })
+            .collect();
+
+        prune(&mut sizes, 2);
+
+        assert_eq!(sizes.len(), 2);
+    }
+}
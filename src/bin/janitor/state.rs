@@ -0,0 +1,154 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use janitor::Backend;
+use serde::{Deserialize, Serialize};
+
+use crate::history::Run;
+
+const SYSTEM_STATE: &str = "/var/lib/nix-janitor/state.toml";
+
+/// State the janitor keeps between runs.
+///
+/// Unlike the [Config](crate::config::Config), the state is written by the
+/// janitor itself. Unknown keys are ignored, so that older versions can read
+/// the state written by newer ones.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct State {
+    /// The listing backend found to be the fastest by `janitor bench`.
+    pub preferred_backend: Option<Backend>,
+
+    /// When the last cleanup has finished successfully.
+    pub last_success: Option<DateTime<Utc>>,
+
+    /// The outcomes of the successful cleanups of the last weeks, see
+    /// [record](crate::history::record).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Run>,
+}
+
+impl State {
+    /// Loads the state from `path`, returning the default state if the file
+    /// does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        tracing::debug!(?path, "reading state");
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(error) => {
+                return Err(error)
+                    .wrap_err_with(|| format!("Failed to read state {}", path.display()))
+            }
+        };
+
+        Self::parse(&content).wrap_err_with(|| format!("Invalid state {}", path.display()))
+    }
+
+    /// Writes the state to `path`, creating its directory if necessary.
+    ///
+    /// The state is written to a temporary file first and then moved into
+    /// place, so that it is never left half written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        tracing::debug!(?path, "writing state");
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, toml::to_string(self)?)
+            .wrap_err_with(|| format!("Failed to write state {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .wrap_err_with(|| format!("Failed to write state {}", path.display()))?;
+
+        Ok(())
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+/// The default location of the state file.
+///
+/// When running in a root shell, this is `/var/lib/nix-janitor/state.toml`,
+/// otherwise `$XDG_STATE_HOME/nix-janitor/state.toml`, falling back to
+/// `~/.local/state`.
+pub fn default_path() -> Option<PathBuf> {
+    if janitor::system_by_default() {
+        return Some(PathBuf::from(SYSTEM_STATE));
+    }
+
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join("nix-janitor").join("state.toml"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    use crate::run_id::RunId;
+
+    #[rstest]
+    #[case::empty("", State::default())]
+    #[case::backend(
+        "preferred_backend = \"filesystem\"",
+        State { preferred_backend: Some(Backend::Filesystem), ..State::default() }
+    )]
+    #[case::last_success(
+        "last_success = \"2023-06-03T04:12:00Z\"",
+        State { last_success: "2023-06-03T04:12:00Z".parse().ok(), ..State::default() }
+    )]
+    #[case::unknown_key("future_key = 1", State::default())]
+    fn parse(#[case] input: &str, #[case] expected: State) -> Result<()> {
+        assert_eq!(State::parse(input)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-state-{}", std::process::id()));
+        let path = dir.join("nested").join("state.toml");
+
+        let state = State {
+            preferred_backend: Some(Backend::NixEnv),
+            last_success: Some(Utc::now()),
+            history: vec![Run {
+                id: Some(RunId::new()),
+                finished: Utc::now(),
+                deleted: 3,
+                freed: 1024,
+            }],
+        };
+        state.save(&path)?;
+        let loaded = State::load(&path);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(loaded?, state);
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_missing_is_default() -> Result<()> {
+        let path = env::temp_dir().join("janitor-state-does-not-exist.toml");
+
+        assert_eq!(State::load(&path)?, State::default());
+
+        Ok(())
+    }
+}
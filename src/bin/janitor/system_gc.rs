@@ -0,0 +1,117 @@
+//! Detects the automatic garbage collection of NixOS and nix-darwin,
+//! `nix.gc.automatic`, which overlaps with the garbage collection of the
+//! janitor.
+
+use std::{fmt, path::Path};
+
+/// The unit NixOS runs `nix.gc.automatic` with, relative to the root.
+const NIXOS_UNIT: &str = "etc/systemd/system/nix-gc.timer";
+
+/// The daemon nix-darwin runs `nix.gc.automatic` with, relative to the root.
+const DARWIN_DAEMON: &str = "Library/LaunchDaemons/org.nixos.nix-gc.plist";
+
+/// A service of the system collecting garbage on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemGc {
+    /// The `nix-gc` timer of NixOS.
+    NixOs,
+
+    /// The `org.nixos.nix-gc` launchd daemon of nix-darwin.
+    Darwin,
+}
+
+impl SystemGc {
+    /// Detects the service in the system mounted at `root`, usually `/`.
+    pub fn detect(root: &Path) -> Option<Self> {
+        if root.join(NIXOS_UNIT).exists() {
+            Some(Self::NixOs)
+        } else if root.join(DARWIN_DAEMON).exists() {
+            Some(Self::Darwin)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for SystemGc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::NixOs => "the nix-gc.timer of NixOS",
+            Self::Darwin => "the org.nixos.nix-gc daemon of nix-darwin",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Whether the janitor collects garbage itself, given a service of the
+/// system found in `root` and whether to `defer` to it.
+///
+/// Without deferring, overlapping with the service is only warned about.
+pub fn allows_gc(defer: bool, root: &Path) -> bool {
+    let Some(service) = SystemGc::detect(root) else {
+        if defer {
+            tracing::info!(
+                "no automatic garbage collection of the system found, collecting garbage"
+            );
+        }
+        return true;
+    };
+
+    if defer {
+        tracing::info!(%service, "deferring the garbage collection to the system");
+        return false;
+    }
+
+    tracing::warn!(
+        %service,
+        "the system collects garbage on its own as well; disable nix.gc.automatic, \
+         or pass --defer-to-system-gc to only delete generations"
+    );
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use super::*;
+
+    use eyre::Result;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::none(None, false, true)]
+    #[case::none_deferred(None, true, true)]
+    #[case::nixos(Some(NIXOS_UNIT), false, true)]
+    #[case::nixos_deferred(Some(NIXOS_UNIT), true, false)]
+    #[case::darwin_deferred(Some(DARWIN_DAEMON), true, false)]
+    fn allows(
+        #[case] service: Option<&str>,
+        #[case] defer: bool,
+        #[case] expected: bool,
+    ) -> Result<()> {
+        let name = service.map_or("none", |s| s.rsplit('/').next().unwrap_or(s));
+        let root = env::temp_dir().join(format!(
+            "janitor-system-gc-{name}-{defer}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root)?;
+        if let Some(service) = service {
+            let path = root.join(service);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "")?;
+        }
+
+        let detected = SystemGc::detect(&root);
+        let allowed = allows_gc(defer, &root);
+        fs::remove_dir_all(&root)?;
+
+        assert_eq!(detected.is_some(), service.is_some());
+        assert_eq!(allowed, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! The tasks of the daemon, named and tracked, so that it can tell what it
+//! is doing and stop deterministically.
+
+use std::{collections::BTreeMap, future::Future, mem, sync::Mutex, time::Duration};
+
+use eyre::{bail, Result};
+use tokio::task::{AbortHandle, Id, JoinSet};
+use tracing::Instrument;
+
+/// The most tasks running at once, beyond which new ones are refused.
+pub const MAX_TASKS: usize = 64;
+
+/// The tasks of the daemon.
+///
+/// Work, like a cleanup or answering a request, is waited for when shutting
+/// down, while services, like accepting connections, are aborted right
+/// away.
+#[derive(Debug, Default)]
+pub struct Tasks {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    set: JoinSet<()>,
+    running: BTreeMap<Id, Task>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Task {
+    name: &'static str,
+    service: bool,
+    handle: AbortHandle,
+}
+
+impl Tasks {
+    /// Spawns the work `future` as a task called `name`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if [MAX_TASKS] are running already or the tasks have been shut
+    /// down.
+    pub fn spawn<F>(&self, name: &'static str, future: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.start(name, false, future)
+    }
+
+    /// Spawns the service `future`, which runs until aborted, as a task
+    /// called `name`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [Tasks::spawn].
+    pub fn spawn_service<F>(&self, name: &'static str, future: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.start(name, true, future)
+    }
+
+    /// The names of the running tasks, in the order they have been spawned.
+    pub fn running(&self) -> Vec<&'static str> {
+        let mut inner = self.lock();
+        inner.reap();
+
+        inner.running.values().map(|task| task.name).collect()
+    }
+
+    /// Refuses new tasks, aborts the services and waits up to `grace` for
+    /// the remaining work, aborting whatever is still running after that.
+    pub async fn shutdown(&self, grace: Duration) {
+        let mut set = {
+            let mut inner = self.lock();
+            inner.closed = true;
+            for task in inner.running.values().filter(|task| task.service) {
+                tracing::debug!(task = task.name, "aborting service");
+                task.handle.abort();
+            }
+            inner.running.clear();
+
+            mem::take(&mut inner.set)
+        };
+
+        let drained = tokio::time::timeout(grace, async {
+            while let Some(result) = set.join_next().await {
+                match result {
+                    Err(error) if !error.is_cancelled() => tracing::warn!(%error, "task failed"),
+                    _ => {}
+                }
+            }
+        })
+        .await;
+
+        if drained.is_err() {
+            tracing::warn!(
+                tasks = set.len(),
+                grace = ?grace,
+                "tasks still running after the grace period, aborting them"
+            );
+            set.shutdown().await;
+        }
+    }
+
+    fn start<F>(&self, name: &'static str, service: bool, future: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut inner = self.lock();
+        inner.reap();
+
+        if inner.closed {
+            bail!("the daemon is stopping, not starting {name}");
+        }
+        if inner.set.len() >= MAX_TASKS {
+            bail!("{MAX_TASKS} tasks are running already, not starting {name}");
+        }
+
+        let handle = inner
+            .set
+            .spawn(future.instrument(tracing::info_span!("task", name)));
+        tracing::debug!(task = name, id = %handle.id(), "spawned task");
+        inner.running.insert(
+            handle.id(),
+            Task {
+                name,
+                service,
+                handle,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Inner {
+    /// Forgets the tasks that have finished.
+    fn reap(&mut self) {
+        while let Some(result) = self.set.try_join_next_with_id() {
+            let id = match result {
+                Ok((id, ())) => id,
+                Err(error) => {
+                    if !error.is_cancelled() {
+                        tracing::warn!(%error, "task failed");
+                    }
+                    error.id()
+                }
+            };
+            self.running.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn tracks_running_tasks() -> Result<()> {
+        let tasks = Tasks::default();
+        let (finish, finished) = oneshot::channel::<()>();
+
+        tasks.spawn_service("accept", std::future::pending())?;
+        tasks.spawn("cleanup", async {
+            let _ = finished.await;
+        })?;
+        assert_eq!(tasks.running(), ["accept", "cleanup"]);
+
+        finish.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tasks.running().len() > 1 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        assert_eq!(tasks.running(), ["accept"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caps_tasks() -> Result<()> {
+        let tasks = Tasks::default();
+
+        for _ in 0..MAX_TASKS {
+            tasks.spawn_service("idle", std::future::pending())?;
+        }
+
+        assert!(tasks.spawn("cleanup", async {}).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_work() -> Result<()> {
+        let tasks = Tasks::default();
+        let (done, mut finished) = oneshot::channel();
+
+        tasks.spawn_service("accept", std::future::pending())?;
+        tasks.spawn("cleanup", async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let _ = done.send(());
+        })?;
+        tasks.spawn("stuck", std::future::pending())?;
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tasks.shutdown(Duration::from_millis(100)),
+        )
+        .await?;
+
+        assert_eq!(finished.try_recv(), Ok(()));
+        assert!(tasks.running().is_empty());
+        assert!(tasks.spawn("cleanup", async {}).is_err());
+
+        Ok(())
+    }
+}
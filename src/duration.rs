@@ -0,0 +1,271 @@
+//! Parsing and formatting of retention durations like `2d12h`, and of
+//! cutoffs given as either a duration or a date.
+
+use crate::error::Result;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+const UNITS: &[(char, i64)] = &[
+    ('w', 7 * 24 * 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('h', 60 * 60),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// The longest duration accepted, in days, about a century.
+///
+/// Retention beyond it is as good as keeping everything, and any date
+/// can have it subtracted without overflowing.
+pub const MAX_DAYS: i64 = 100 * 366;
+
+/// Parses a duration made of one or more amounts with a unit each.
+///
+/// The units are `w` (weeks), `d` (days), `h` (hours), `m` (minutes) and `s`
+/// (seconds). They may be combined, e.g. `2d12h`, and are summed up. The
+/// duration may not be longer than [MAX_DAYS].
+///
+/// # Examples
+///
+/// ```
+/// use chrono::Duration;
+/// use janitor::duration::parse_duration;
+///
+/// assert_eq!(parse_duration("36h").unwrap(), Duration::hours(36));
+/// assert_eq!(parse_duration("2d12h").unwrap(), Duration::hours(60));
+/// assert!(parse_duration("12").is_err());
+/// ```
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(parse_error!("empty duration"));
+    }
+
+    let mut seconds: i64 = 0;
+    let mut amount = String::new();
+
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            amount.push(c);
+            continue;
+        }
+
+        let (_, factor) = UNITS
+            .iter()
+            .find(|(unit, _)| *unit == c.to_ascii_lowercase())
+            .ok_or_else(|| parse_error!("unknown duration unit {c:?} in {input:?}"))?;
+
+        if amount.is_empty() {
+            return Err(parse_error!("missing amount before {c:?} in {input:?}"));
+        }
+
+        seconds = amount
+            .parse::<i64>()
+            .ok()
+            .and_then(|amount| amount.checked_mul(*factor))
+            .and_then(|part| seconds.checked_add(part))
+            .ok_or_else(|| parse_error!("duration {input:?} is too long"))?;
+        amount.clear();
+    }
+
+    if !amount.is_empty() {
+        return Err(parse_error!(
+            "missing unit after {amount} in {input:?}, e.g. {amount}d"
+        ));
+    }
+
+    if seconds > MAX_DAYS * 24 * 60 * 60 {
+        return Err(parse_error!(
+            "duration {input:?} is too long, at most {MAX_DAYS}d"
+        ));
+    }
+
+    Ok(Duration::seconds(seconds))
+}
+
+/// The duration of this many `days`, which may neither be negative nor
+/// exceed [MAX_DAYS].
+///
+/// # Examples
+///
+/// ```
+/// use chrono::Duration;
+/// use janitor::duration::days;
+///
+/// assert_eq!(days(3).unwrap(), Duration::days(3));
+/// assert!(days(-1).is_err());
+/// assert!(days(999_999_999_999_999).is_err());
+/// ```
+pub fn days(days: i64) -> Result<Duration> {
+    match days {
+        0..=MAX_DAYS => Ok(Duration::days(days)),
+        ..=-1 => Err(parse_error!("{days} days must not be negative")),
+        _ => Err(parse_error!("{days} days is too long, at most {MAX_DAYS}")),
+    }
+}
+
+/// The formats of the dates accepted by [parse_keep_since], besides plain
+/// dates like `2024-05-01`.
+const DATE_TIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"];
+
+/// A cutoff for the generations to keep, relative to now or absolute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepSince {
+    /// Keep generations that have been active within this duration.
+    Within(Duration),
+
+    /// Keep generations that have been active on or after this date, in
+    /// UTC.
+    Date(NaiveDateTime),
+}
+
+/// Parses a cutoff given either as a duration accepted by [parse_duration]
+/// or as a date like `2024-05-01`, optionally with a time like
+/// `2024-05-01 12:00:00`.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::{Duration, NaiveDate};
+/// use janitor::duration::{parse_keep_since, KeepSince};
+///
+/// assert_eq!(parse_keep_since("2w").unwrap(), KeepSince::Within(Duration::weeks(2)));
+/// assert_eq!(
+///     parse_keep_since("2024-05-01").unwrap(),
+///     KeepSince::Date(NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()),
+/// );
+/// assert!(parse_keep_since("yesterday").is_err());
+/// ```
+pub fn parse_keep_since(input: &str) -> Result<KeepSince> {
+    let input = input.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(KeepSince::Date(date.and_time(Default::default())));
+    }
+    if let Some(date) = DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(input, format).ok())
+    {
+        return Ok(KeepSince::Date(date));
+    }
+
+    parse_duration(input)
+        .map(KeepSince::Within)
+        .map_err(|error| {
+            parse_error!("{error}, expected a duration like 2w or a date like 2024-05-01")
+        })
+}
+
+/// Formats `duration` in the format accepted by [parse_duration], using the
+/// largest units possible, except for weeks.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::Duration;
+/// use janitor::duration::format_duration;
+///
+/// assert_eq!(format_duration(Duration::hours(60)), "2d12h");
+/// assert_eq!(format_duration(Duration::days(14)), "14d");
+/// assert_eq!(format_duration(Duration::zero()), "0s");
+/// ```
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.num_seconds();
+    if seconds == 0 {
+        return "0s".to_string();
+    }
+
+    let mut out = String::new();
+    if seconds < 0 {
+        out.push('-');
+        seconds = -seconds;
+    }
+
+    for (unit, factor) in UNITS.iter().skip(1) {
+        let amount = seconds / factor;
+        if amount > 0 {
+            out.push_str(&format!("{amount}{unit}"));
+            seconds %= factor;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::hours("36h", Duration::hours(36))]
+    #[case::combined("2d12h", Duration::hours(60))]
+    #[case::weeks("1w", Duration::days(7))]
+    #[case::all("1w1d1h1m1s", Duration::seconds(694_861))]
+    #[case::upper("2D", Duration::days(2))]
+    #[case::spaced(" 7d ", Duration::days(7))]
+    #[case::zero("0d", Duration::zero())]
+    fn parse(#[case] input: &str, #[case] expected: Duration) {
+        assert_eq!(parse_duration(input).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::bare_number("12")]
+    #[case::trailing_number("1d12")]
+    #[case::unit_only("h")]
+    #[case::unknown_unit("3y")]
+    #[case::negative("-1d")]
+    #[case::fraction("1.5d")]
+    #[case::overflow("99999999999999999999d")]
+    #[case::beyond_dates("100000000d")]
+    #[case::beyond_a_century("36601d")]
+    fn parse_errors(#[case] input: &str) {
+        assert!(parse_duration(input).is_err());
+    }
+
+    #[rstest]
+    #[case::zero(0, Some(Duration::zero()))]
+    #[case::century(MAX_DAYS, Some(Duration::days(MAX_DAYS)))]
+    #[case::negative(-1, None)]
+    #[case::beyond_a_century(MAX_DAYS + 1, None)]
+    #[case::beyond_durations(999_999_999_999_999, None)]
+    fn parse_days(#[case] input: i64, #[case] expected: Option<Duration>) {
+        assert_eq!(days(input).ok(), expected);
+    }
+
+    fn date(input: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[rstest]
+    #[case::weeks("2w", KeepSince::Within(Duration::weeks(2)))]
+    #[case::hours("36h", KeepSince::Within(Duration::hours(36)))]
+    #[case::date("2024-05-01", KeepSince::Date(date("2024-05-01 00:00:00")))]
+    #[case::date_time("2024-05-01 12:30:15", KeepSince::Date(date("2024-05-01 12:30:15")))]
+    #[case::iso("2024-05-01T12:30:15", KeepSince::Date(date("2024-05-01 12:30:15")))]
+    #[case::minutes("2024-05-01 12:30", KeepSince::Date(date("2024-05-01 12:30:00")))]
+    #[case::spaced(" 2024-05-01 ", KeepSince::Date(date("2024-05-01 00:00:00")))]
+    fn keep_since(#[case] input: &str, #[case] expected: KeepSince) {
+        assert_eq!(parse_keep_since(input).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::bare_number("14")]
+    #[case::invalid_date("2024-02-30")]
+    #[case::partial_date("2024-05")]
+    #[case::word("yesterday")]
+    fn keep_since_errors(#[case] input: &str) {
+        assert!(parse_keep_since(input).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn format_roundtrips(seconds in 0..100_000_000i64) {
+            let duration = Duration::seconds(seconds);
+
+            prop_assert_eq!(parse_duration(&format_duration(duration)).unwrap(), duration);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! The errors of the library, to be matched on by their kind instead of
+//! their message.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output},
+};
+
+use crate::generation::UnrecognizedFormat;
+
+/// A [JanitorError::Parse] with the message formatted like [format!].
+macro_rules! parse_error {
+    ($($arg:tt)*) => {
+        $crate::error::JanitorError::parse(format!($($arg)*))
+    };
+}
+
+/// The boxed source of an error.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// A result whose error defaults to [JanitorError].
+pub type Result<T, E = JanitorError> = std::result::Result<T, E>;
+
+/// What can go wrong in the library.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::{Generation, JanitorError};
+///
+/// let error = Generation::parse_many("this is not a listing").unwrap_err();
+/// assert!(matches!(error, JanitorError::UnrecognizedFormat(_)));
+/// ```
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum JanitorError {
+    /// Input, e.g. a setting, a listing or the output of a command, could
+    /// not be parsed.
+    #[error("{message}")]
+    Parse {
+        /// What could not be parsed, and why.
+        message: String,
+
+        /// The error of the underlying parser, if any.
+        #[source]
+        source: Option<BoxError>,
+    },
+
+    /// Not a single line of a listing could be parsed, see
+    /// [UnrecognizedFormat].
+    #[error(transparent)]
+    UnrecognizedFormat(#[from] UnrecognizedFormat),
+
+    /// An external command could not be run.
+    #[error("Failed to run {command}")]
+    Spawn {
+        /// The name of the command.
+        command: String,
+
+        /// Why it could not be run.
+        #[source]
+        source: io::Error,
+    },
+
+    /// An external command exited unsuccessfully.
+    #[error("{command} failed: {stderr}")]
+    Command {
+        /// The name of the command.
+        command: String,
+
+        /// How it exited.
+        status: ExitStatus,
+
+        /// What it wrote to stderr.
+        stderr: String,
+    },
+
+    /// A profile pattern matches more profiles than the janitor is willing
+    /// to clean up at once.
+    #[error("profile pattern {pattern:?} matches more than {limit} profiles, refusing to clean that many")]
+    TooManyProfiles {
+        /// The pattern.
+        pattern: String,
+
+        /// How many profiles a pattern may match at most.
+        limit: usize,
+    },
+
+    /// A profile does not exist.
+    #[error("profile {} does not exist", .0.display())]
+    MissingProfile(PathBuf),
+
+    /// The janitor lacks the permissions to access a file.
+    #[error("{context}")]
+    PermissionDenied {
+        /// What has been attempted.
+        context: String,
+
+        /// The error of the denied access.
+        #[source]
+        source: io::Error,
+    },
+
+    /// Any other failure to access a file.
+    #[error("{context}")]
+    Io {
+        /// What has been attempted.
+        context: String,
+
+        /// Why it failed.
+        #[source]
+        source: io::Error,
+    },
+
+    /// Any other failure, e.g. of a [GenerationSource](crate::GenerationSource)
+    /// outside of the library.
+    #[error(transparent)]
+    Other(#[from] BoxError),
+}
+
+impl JanitorError {
+    /// Input failing to parse for the reason given in `message`.
+    pub(crate) fn parse<S: Into<String>>(message: S) -> Self {
+        Self::Parse {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Input failing to parse for the reason given in `message`, as the
+    /// underlying parser failed with `source`.
+    pub(crate) fn parse_with<S, E>(message: S, source: E) -> Self
+    where
+        S: Into<String>,
+        E: Into<BoxError>,
+    {
+        Self::Parse {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// Accessing a file failing with `source` while attempting what is
+    /// described by `context`.
+    pub(crate) fn io<S: Into<String>>(context: S, source: io::Error) -> Self {
+        let context = context.into();
+        match source.kind() {
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied { context, source },
+            _ => Self::Io { context, source },
+        }
+    }
+
+    /// Accessing the profile at `profile` failing with `source` while
+    /// attempting what is described by `context`, telling a missing profile
+    /// apart.
+    pub(crate) fn profile_io<S: Into<String>>(
+        profile: &Path,
+        context: S,
+        source: io::Error,
+    ) -> Self {
+        match source.kind() {
+            io::ErrorKind::NotFound => Self::MissingProfile(profile.to_path_buf()),
+            _ => Self::io(context, source),
+        }
+    }
+
+    /// Checks the `output` of the `command`, failing if it exited
+    /// unsuccessfully.
+    pub(crate) fn check_output(command: &str, output: &Output) -> Result<()> {
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(Self::Command {
+                command: command.to_string(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }),
+        }
+    }
+}
+
+/// Turns the errors of running external commands into [JanitorError]s.
+pub(crate) trait SpawnContext<T> {
+    /// Fails with [JanitorError::Spawn] if the `command` could not be run.
+    fn spawning(self, command: &str) -> Result<T>;
+}
+
+impl<T> SpawnContext<T> for io::Result<T> {
+    fn spawning(self, command: &str) -> Result<T> {
+        self.map_err(|source| JanitorError::Spawn {
+            command: command.to_string(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::error::Error;
+
+    #[test]
+    fn tells_permission_problems_apart() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+
+        assert!(matches!(
+            JanitorError::io("Failed to read /p", denied),
+            JanitorError::PermissionDenied { .. }
+        ));
+        assert!(matches!(
+            JanitorError::profile_io(Path::new("/p"), "Failed to read /p", missing),
+            JanitorError::MissingProfile(path) if path == Path::new("/p")
+        ));
+    }
+
+    #[test]
+    fn keeps_the_source() {
+        let error = JanitorError::parse_with("invalid size: 1x", "x".parse::<u8>().unwrap_err());
+
+        assert_eq!(error.to_string(), "invalid size: 1x");
+        assert!(error.source().is_some());
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{ExitStatus, Output, Stdio},
+};
+
+use futures::future::BoxFuture;
+
+/// A command to be run by an [Executor].
+///
+/// # Examples
+///
+/// ```
+/// use janitor::CommandLine;
+///
+/// let command = CommandLine::new("nix-env").arg("--list-generations");
+/// assert_eq!(command.program, "nix-env");
+/// assert_eq!(command.args, vec!["--list-generations"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    /// The program to run.
+    pub program: String,
+
+    /// The arguments passed to the program.
+    pub args: Vec<OsString>,
+
+    /// The input written to the program, none at all if `None`.
+    pub stdin: Option<Vec<u8>>,
+}
+
+impl CommandLine {
+    /// Creates a new command line for `program` without any arguments.
+    pub fn new<S: Into<String>>(program: S) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            stdin: None,
+        }
+    }
+
+    /// Appends a single argument.
+    pub fn arg<S: Into<OsString>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Appends multiple arguments.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Writes `input` to the program, e.g. for input too long to be passed
+    /// as arguments.
+    pub fn stdin<B: Into<Vec<u8>>>(mut self, input: B) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+}
+
+/// Runs external commands on behalf of the janitor.
+///
+/// This abstracts over how commands are spawned, so that the library
+/// functions can be reused with other runtimes or be tested without a nix
+/// installation.
+pub trait Executor: Debug + Send + Sync {
+    /// Runs `command` to completion and collects its output.
+    fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>>;
+
+    /// Runs `command` to completion like [Executor::output], but hands every
+    /// line of its stderr, without the line break, to `on_stderr` as soon as
+    /// it has been read instead of collecting all of it.
+    ///
+    /// Only the lines `on_stderr` returns `false` for are kept in the stderr
+    /// of the returned output, e.g. to explain a failure. By default, the
+    /// output is collected first and the lines are handed over afterwards.
+    fn output_streaming<'a>(
+        &'a self,
+        command: CommandLine,
+        on_stderr: &'a mut (dyn FnMut(&[u8]) -> bool + Send),
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        Box::pin(async move { Ok(stream_collected(self.output(command).await?, on_stderr)) })
+    }
+}
+
+/// Runs external commands synchronously, blocking the current thread.
+///
+/// This is the counterpart to [Executor] for running without an async
+/// runtime.
+pub trait BlockingExecutor: Debug {
+    /// Runs `command` to completion and collects its output.
+    fn output(&self, command: CommandLine) -> io::Result<Output>;
+
+    /// Runs `command` to completion, handing every line of its stderr to
+    /// `on_stderr` as soon as it has been read.
+    ///
+    /// This is the blocking counterpart of [Executor::output_streaming].
+    fn output_streaming(
+        &self,
+        command: CommandLine,
+        on_stderr: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        Ok(stream_collected(self.output(command)?, on_stderr))
+    }
+}
+
+/// An [Executor] spawning commands on the tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioExecutor;
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioExecutor {
+    fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+        Box::pin(async move { self.output_streaming(command, &mut |_| false).await })
+    }
+
+    fn output_streaming<'a>(
+        &'a self,
+        mut command: CommandLine,
+        on_stderr: &'a mut (dyn FnMut(&[u8]) -> bool + Send),
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(&command.program)
+                .args(&command.args)
+                .stdin(stdin_for(&command))
+                .stderr(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+
+            let stdin_pipe = child.stdin.take();
+            let input = command.stdin.take().unwrap_or_default();
+            let write_stdin = async move {
+                if let Some(mut stdin_pipe) = stdin_pipe {
+                    stdin_pipe
+                        .write_all(&input)
+                        .await
+                        .or_else(ignore_broken_pipe)?;
+                }
+                io::Result::Ok(())
+            };
+
+            let mut stdout_pipe = child.stdout.take().expect("stdout is piped");
+            let read_stdout = async {
+                let mut stdout = Vec::new();
+                stdout_pipe.read_to_end(&mut stdout).await?;
+                io::Result::Ok(stdout)
+            };
+
+            let mut stderr_pipe =
+                tokio::io::BufReader::new(child.stderr.take().expect("stderr is piped"));
+            let read_stderr = async {
+                let mut kept = Vec::new();
+                let mut line = Vec::with_capacity(LINE_CAPACITY);
+                loop {
+                    line.clear();
+                    if stderr_pipe.read_until(b'\n', &mut line).await? == 0 {
+                        return Ok(kept);
+                    }
+                    stream_line(&line, on_stderr, &mut kept);
+                }
+            };
+
+            let read = tokio::try_join!(write_stdin, read_stdout, read_stderr);
+            let status = child.wait().await?;
+            let ((), stdout, stderr) = read?;
+
+            Ok(Output {
+                status,
+                stdout,
+                stderr,
+            })
+        })
+    }
+}
+
+/// Adapts a [BlockingExecutor] to the [Executor] trait.
+///
+/// Commands are run synchronously when the returned future is first polled,
+/// blocking the thread. This makes it possible to drive the async library
+/// functions with a minimal executor like [futures::executor::block_on]
+/// instead of a full async runtime.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_env, Blocking, StdExecutor};
+///
+/// # fn main() -> eyre::Result<()> {
+/// let generations = futures::executor::block_on(nix_env::list_generations(
+///     &Blocking(StdExecutor),
+///     "/nix/var/nix/profiles/system",
+/// ))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Blocking<E>(pub E);
+
+impl<E> Executor for Blocking<E>
+where
+    E: BlockingExecutor + Send + Sync,
+{
+    fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+        Box::pin(async move { self.0.output(command) })
+    }
+
+    fn output_streaming<'a>(
+        &'a self,
+        command: CommandLine,
+        on_stderr: &'a mut (dyn FnMut(&[u8]) -> bool + Send),
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        Box::pin(async move { self.0.output_streaming(command, on_stderr) })
+    }
+}
+
+/// A [BlockingExecutor] spawning commands using [std::process::Command].
+///
+/// With the `system` feature, commands are waited for with `wait4`, and the
+/// resources each of them used are logged.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdExecutor;
+
+impl BlockingExecutor for StdExecutor {
+    fn output(&self, command: CommandLine) -> io::Result<Output> {
+        self.output_streaming(command, &mut |_| false)
+    }
+
+    fn output_streaming(
+        &self,
+        mut command: CommandLine,
+        on_stderr: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> io::Result<Output> {
+        let mut child = std::process::Command::new(&command.program)
+            .args(&command.args)
+            .stdin(stdin_for(&command))
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Write stdin on its own thread as well, the program may only read
+        // all of it after writing some output.
+        let stdin_pipe = child.stdin.take();
+        let input = command.stdin.take().unwrap_or_default();
+        let stdin = std::thread::spawn(move || -> io::Result<()> {
+            match stdin_pipe {
+                Some(mut stdin_pipe) => stdin_pipe.write_all(&input).or_else(ignore_broken_pipe),
+                None => Ok(()),
+            }
+        });
+
+        // Read stdout on its own thread, so that neither pipe fills up while
+        // the other is being read.
+        let mut stdout_pipe = child.stdout.take().expect("stdout is piped");
+        let stdout = std::thread::spawn(move || -> io::Result<_> {
+            let mut stdout = Vec::new();
+            stdout_pipe.read_to_end(&mut stdout)?;
+            Ok(stdout)
+        });
+        let stderr = stream_lines(
+            BufReader::new(child.stderr.take().expect("stderr is piped")),
+            on_stderr,
+        );
+
+        let stdout = stdout.join().expect("reading stdout does not panic");
+        let written = stdin.join().expect("writing stdin does not panic");
+        let status = wait(&command, &mut child)?;
+        written?;
+
+        Ok(Output {
+            status,
+            stdout: stdout?,
+            stderr: stderr?,
+        })
+    }
+}
+
+/// Pipes stdin if the `command` has any input, otherwise the program gets
+/// none at all.
+fn stdin_for(command: &CommandLine) -> Stdio {
+    match command.stdin {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    }
+}
+
+/// A program may exit without reading all of its input, which is up to it
+/// to report.
+fn ignore_broken_pipe(error: io::Error) -> io::Result<()> {
+    match error.kind() {
+        io::ErrorKind::BrokenPipe => Ok(()),
+        _ => Err(error),
+    }
+}
+
+/// Waits for the `child` with [crate::rusage::wait] to log the resources it
+/// used.
+#[cfg(feature = "system")]
+fn wait(command: &CommandLine, child: &mut std::process::Child) -> io::Result<ExitStatus> {
+    let (status, usage) = crate::rusage::wait(child.id())?;
+    tracing::debug!(program = command.program, %usage, "command finished");
+
+    Ok(status)
+}
+
+#[cfg(not(feature = "system"))]
+fn wait(_command: &CommandLine, child: &mut std::process::Child) -> io::Result<ExitStatus> {
+    child.wait()
+}
+
+/// The initial capacity of the buffer lines of stderr are read into.
+const LINE_CAPACITY: usize = 256;
+
+/// Hands every line read from `reader` to `on_line`, returning the lines it
+/// did not handle.
+fn stream_lines<R: BufRead>(
+    mut reader: R,
+    on_line: &mut dyn FnMut(&[u8]) -> bool,
+) -> io::Result<Vec<u8>> {
+    let mut kept = Vec::new();
+    let mut line = Vec::with_capacity(LINE_CAPACITY);
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(kept);
+        }
+        stream_line(&line, on_line, &mut kept);
+    }
+}
+
+/// Hands the lines of the already collected stderr of `output` to `on_line`,
+/// keeping only the ones it did not handle.
+fn stream_collected(mut output: Output, on_line: &mut dyn FnMut(&[u8]) -> bool) -> Output {
+    let mut kept = Vec::new();
+    for line in output.stderr.split_inclusive(|&byte| byte == b'\n') {
+        stream_line(line, on_line, &mut kept);
+    }
+    output.stderr = kept;
+
+    output
+}
+
+/// Hands `line` without its line break to `on_line`, appending it to `kept`
+/// unless handled.
+fn stream_line(line: &[u8], on_line: &mut dyn FnMut(&[u8]) -> bool, kept: &mut Vec<u8>) {
+    if !on_line(line.strip_suffix(b"\n").unwrap_or(line)) {
+        kept.extend_from_slice(line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn std_executor_collects_output() -> io::Result<()> {
+        let output = StdExecutor.output(
+            CommandLine::new("sh")
+                .arg("-c")
+                .arg("echo out; echo err >&2; exit 3"),
+        )?;
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn std_executor_writes_stdin() -> io::Result<()> {
+        let input = "line\n".repeat(100_000);
+
+        let output = StdExecutor.output(CommandLine::new("cat").stdin(input.clone()))?;
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, input.as_bytes());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_executor_writes_stdin() -> io::Result<()> {
+        let input = "line\n".repeat(100_000);
+
+        let output = TokioExecutor
+            .output(CommandLine::new("cat").stdin(input.clone()))
+            .await?;
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, input.as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn std_executor_streams_stderr() -> io::Result<()> {
+        let mut streamed = Vec::new();
+        let output = StdExecutor.output_streaming(
+            CommandLine::new("sh")
+                .arg("-c")
+                .arg("echo out; echo keep >&2; echo drop >&2; printf last >&2"),
+            &mut |line| {
+                streamed.push(line.to_vec());
+                line != b"keep"
+            },
+        )?;
+
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"keep\n");
+        assert_eq!(streamed, [&b"keep"[..], b"drop", b"last"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn streams_collected_stderr() {
+        let output = Output {
+            status: Default::default(),
+            stdout: Vec::new(),
+            stderr: b"one\ntwo\nthree".to_vec(),
+        };
+
+        let output = stream_collected(output, &mut |line| line == b"two");
+
+        assert_eq!(output.stderr, b"one\nthree");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_executor_streams_stderr() -> io::Result<()> {
+        let mut streamed = 0;
+        let output = TokioExecutor
+            .output_streaming(
+                CommandLine::new("sh")
+                    .arg("-c")
+                    .arg("echo out; echo one >&2; echo two >&2; exit 2"),
+                &mut |_| {
+                    streamed += 1;
+                    true
+                },
+            )
+            .await?;
+
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(output.stdout, b"out\n");
+        assert!(output.stderr.is_empty());
+        assert_eq!(streamed, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn std_executor_missing_program() {
+        assert!(StdExecutor
+            .output(CommandLine::new("janitor-does-not-exist"))
+            .is_err());
+    }
+}
@@ -0,0 +1,413 @@
+//! A C ABI to the listing parser and the retention policies, so that tools
+//! not written in Rust can plan deletions exactly like the janitor does.
+//!
+//! Only available with the `ffi` feature. A shared library can be built
+//! with:
+//!
+//! ```sh
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! The matching declarations are in `include/janitor.h`.
+//!
+//! Dates are passed as "naive" timestamps: the seconds since
+//! `1970-01-01 00:00:00` in the same local time `nix-env` prints the dates of
+//! the generations in.
+//!
+//! Functions returning a pointer return `NULL` on failure, in which case
+//! [janitor_last_error] describes what went wrong. A panic never unwinds into
+//! the caller, it is reported as a failure like any other.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use chrono::{Duration, NaiveDateTime};
+
+use crate::{
+    duration::MAX_DAYS,
+    planning::{self, Rules},
+    Generation, GenerationSet, Profile, RetentionOverrides, RetentionPolicy,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A single generation, as seen from C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JanitorGeneration {
+    /// The id of the generation.
+    pub id: u32,
+
+    /// The naive timestamp of the creation of the generation.
+    pub date: i64,
+
+    /// Whether this generation is the currently active one.
+    pub current: bool,
+}
+
+/// An opaque set of generations, to be released with
+/// [janitor_generations_free].
+#[derive(Debug)]
+pub struct JanitorGenerations(GenerationSet);
+
+/// Returns a description of the last error that occurred on this thread, or
+/// `NULL` if there was none.
+///
+/// The string is owned by the library and valid until the next call into
+/// the library on the same thread.
+#[no_mangle]
+pub extern "C" fn janitor_last_error() -> *const c_char {
+    guarded(ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map_or(ptr::null(), |error| error.as_ptr())
+        })
+    })
+}
+
+/// Parses the output of `nix-env --list-generations`.
+///
+/// # Safety
+///
+/// `listing` must be a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn janitor_parse_listing(listing: *const c_char) -> *mut JanitorGenerations {
+    clear_error();
+
+    guarded(ptr::null_mut(), || {
+        let Some(listing) = str_arg(listing, "listing") else {
+            return ptr::null_mut();
+        };
+
+        match Generation::parse_many(listing) {
+            Ok(generations) => into_handle(generations.into()),
+            Err(error) => fail(format!("{error:#}")),
+        }
+    })
+}
+
+/// Computes the generations that the janitor would delete from the
+/// `generations` of the profile at `profile` at the time `now`.
+///
+/// The retention policy is the default of the kind of the profile, with
+/// `keep_seconds` and `keep_at_least` overriding it unless negative.
+/// `keep_seconds` may not exceed [MAX_DAYS], nor reach back before the
+/// earliest date.
+///
+/// # Safety
+///
+/// `generations` must have been returned by this library and not been freed
+/// yet, `profile` must be a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn janitor_plan(
+    generations: *const JanitorGenerations,
+    profile: *const c_char,
+    keep_seconds: i64,
+    keep_at_least: isize,
+    now: i64,
+) -> *mut JanitorGenerations {
+    clear_error();
+
+    guarded(ptr::null_mut(), || {
+        let Some(generations) = generations.as_ref() else {
+            return fail("generations must not be NULL");
+        };
+        let Some(profile) = str_arg(profile, "profile") else {
+            return ptr::null_mut();
+        };
+        let Some(now) = NaiveDateTime::from_timestamp_opt(now, 0) else {
+            return fail(format!("now out of range: {now}"));
+        };
+        let keep = match keep_seconds {
+            ..=-1 => None,
+            0..=MAX_KEEP_SECONDS => Some(Duration::seconds(keep_seconds)),
+            _ => return fail("keep_seconds out of range"),
+        };
+        if keep.is_some_and(|keep| now.checked_sub_signed(keep).is_none()) {
+            return fail("keep_seconds out of range");
+        }
+
+        let overrides = RetentionOverrides {
+            keep,
+            keep_since: None,
+            keep_at_least: usize::try_from(keep_at_least).ok(),
+            keep_at_most: None,
+            order_by: None,
+            thinning: None,
+        };
+        let policy = RetentionPolicy::resolve(Profile::new(profile).kind(), overrides);
+
+        let rules = Rules::new(policy.keep_at_least, policy.keep_since(now));
+
+        into_handle(planning::plan(&generations.0, &rules))
+    })
+}
+
+/// The number of generations in `generations`, `0` if it is `NULL`.
+///
+/// # Safety
+///
+/// `generations` must be `NULL` or have been returned by this library and
+/// not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn janitor_generations_len(generations: *const JanitorGenerations) -> usize {
+    guarded(0, || {
+        generations
+            .as_ref()
+            .map_or(0, |generations| generations.0.len())
+    })
+}
+
+/// Writes the generation at `index` of `generations`, ordered by id, to
+/// `out`. Returns `false` if `index` is out of bounds.
+///
+/// # Safety
+///
+/// `generations` must be `NULL` or have been returned by this library and
+/// not been freed yet, `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn janitor_generations_get(
+    generations: *const JanitorGenerations,
+    index: usize,
+    out: *mut JanitorGeneration,
+) -> bool {
+    guarded(false, || {
+        let Some(generation) = generations
+            .as_ref()
+            .and_then(|generations| generations.0.iter().nth(index))
+        else {
+            return false;
+        };
+
+        if out.is_null() {
+            return false;
+        }
+
+        out.write(JanitorGeneration {
+            id: generation.id,
+            date: generation.date.timestamp(),
+            current: generation.current,
+        });
+
+        true
+    })
+}
+
+/// Releases `generations`. Does nothing if it is `NULL`.
+///
+/// # Safety
+///
+/// `generations` must be `NULL` or have been returned by this library and
+/// not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn janitor_generations_free(generations: *mut JanitorGenerations) {
+    guarded((), || {
+        if !generations.is_null() {
+            drop(Box::from_raw(generations));
+        }
+    })
+}
+
+/// The longest `keep_seconds` accepted by [janitor_plan].
+const MAX_KEEP_SECONDS: i64 = MAX_DAYS * 24 * 60 * 60;
+
+/// Runs `body`, failing with `default` instead of unwinding into the caller
+/// if it panics.
+fn guarded<T>(default: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        fail::<()>(format!("panicked: {message}"));
+
+        default
+    })
+}
+
+fn into_handle(generations: GenerationSet) -> *mut JanitorGenerations {
+    Box::into_raw(Box::new(JanitorGenerations(generations)))
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Option<&'a str> {
+    if arg.is_null() {
+        fail::<()>(format!("{name} must not be NULL"));
+        return None;
+    }
+
+    match CStr::from_ptr(arg).to_str() {
+        Ok(arg) => Some(arg),
+        Err(error) => {
+            fail::<()>(format!("{name} is not valid UTF-8: {error}"));
+            None
+        }
+    }
+}
+
+fn clear_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+fn fail<T>(error: impl Display) -> *mut T {
+    let message =
+        CString::new(error.to_string().replace('\0', "")).expect("all NUL bytes have been removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+
+    ptr::null_mut()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ids(generations: *const JanitorGenerations) -> Vec<u32> {
+        let len = unsafe { janitor_generations_len(generations) };
+
+        (0..len)
+            .map(|index| {
+                let mut out = JanitorGeneration {
+                    id: 0,
+                    date: 0,
+                    current: false,
+                };
+                assert!(unsafe { janitor_generations_get(generations, index, &mut out) });
+
+                out.id
+            })
+            .collect()
+    }
+
+    fn naive(s: &str) -> i64 {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn parse_and_plan() {
+        let listing = CString::new(
+            "1 2023-06-01 08:00:00\n2 2023-06-10 08:00:00\n3 2023-06-20 08:00:00 (current)\n",
+        )
+        .unwrap();
+        let profile = CString::new("/home/user/.nix-profile").unwrap();
+
+        unsafe {
+            let generations = janitor_parse_listing(listing.as_ptr());
+            assert_eq!(ids(generations), vec![1, 2, 3]);
+
+            let plan = janitor_plan(
+                generations,
+                profile.as_ptr(),
+                Duration::days(5).num_seconds(),
+                1,
+                naive("2023-06-21 00:00:00"),
+            );
+            // 2 has been active until 3 has been created within the last 5 days
+            assert_eq!(ids(plan), vec![1]);
+
+            janitor_generations_free(plan);
+            janitor_generations_free(generations);
+        }
+    }
+
+    #[test]
+    fn plan_uses_defaults_of_kind() {
+        let listing =
+            CString::new("1 2023-06-01 08:00:00\n2 2023-06-20 08:00:00 (current)\n").unwrap();
+        let profile = CString::new("/nix/var/nix/profiles/system").unwrap();
+
+        unsafe {
+            let generations = janitor_parse_listing(listing.as_ptr());
+            let plan = janitor_plan(
+                generations,
+                profile.as_ptr(),
+                -1,
+                -1,
+                naive("2023-06-21 00:00:00"),
+            );
+
+            // the system profile keeps at least 10 generations by default
+            assert_eq!(ids(plan), Vec::<u32>::new());
+
+            janitor_generations_free(plan);
+            janitor_generations_free(generations);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let listing = CString::new("not a listing").unwrap();
+
+        unsafe {
+            assert!(janitor_parse_listing(listing.as_ptr()).is_null());
+            assert!(!janitor_last_error().is_null());
+
+            assert!(janitor_parse_listing(ptr::null()).is_null());
+            let error = CStr::from_ptr(janitor_last_error());
+            assert_eq!(error.to_str().unwrap(), "listing must not be NULL");
+        }
+    }
+
+    #[test]
+    fn keep_out_of_range() {
+        let listing = CString::new("1 2023-06-01 08:00:00 (current)").unwrap();
+        let profile = CString::new("/home/user/.nix-profile").unwrap();
+
+        unsafe {
+            let generations = janitor_parse_listing(listing.as_ptr());
+            for keep_seconds in [i64::MAX, MAX_KEEP_SECONDS + 1] {
+                let plan = janitor_plan(
+                    generations,
+                    profile.as_ptr(),
+                    keep_seconds,
+                    1,
+                    naive("2023-06-21 00:00:00"),
+                );
+
+                assert!(plan.is_null());
+                let error = CStr::from_ptr(janitor_last_error());
+                assert_eq!(error.to_str().unwrap(), "keep_seconds out of range");
+            }
+
+            janitor_generations_free(generations);
+        }
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let result = guarded(ptr::null_mut::<JanitorGenerations>(), || panic!("boom"));
+
+        assert!(result.is_null());
+        let error = unsafe { CStr::from_ptr(janitor_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "panicked: boom");
+    }
+
+    #[test]
+    fn get_out_of_bounds() {
+        let listing = CString::new("1 2023-06-01 08:00:00").unwrap();
+
+        unsafe {
+            let generations = janitor_parse_listing(listing.as_ptr());
+            let mut out = JanitorGeneration {
+                id: 0,
+                date: 0,
+                current: false,
+            };
+
+            assert!(janitor_generations_get(generations, 0, &mut out));
+            assert_eq!(out.date, naive("2023-06-01 08:00:00"));
+            assert!(!janitor_generations_get(generations, 1, &mut out));
+
+            janitor_generations_free(generations);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Lists generations by reading the generation links of a profile directly,
+//! without spawning `nix-env`.
+//!
+//! A profile `/nix/var/nix/profiles/system` is a symlink to one of its
+//! generation links `/nix/var/nix/profiles/system-<id>-link`, which in turn
+//! point into the store. Like `nix-env`, the modification time of a link is
+//! taken as the creation date of its generation, in local time.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{JanitorError, Result},
+    generation::Generation,
+    generation_set::GenerationSet,
+};
+use chrono::prelude::*;
+
+/// Lists all generations of the profile at `profile` from its generation
+/// links.
+///
+/// # Errors
+///
+/// Fails with [JanitorError::MissingProfile] if the directory containing the
+/// profile does not exist, and if it can not be read.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::filesystem;
+///
+/// let generations = filesystem::list_generations("/nix/var/nix/profiles/system")?;
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn list_generations<P: AsRef<Path>>(profile: P) -> Result<GenerationSet> {
+    let profile = profile.as_ref();
+    let (dir, name) = split_profile(profile)?;
+
+    let current = fs::read_link(profile)
+        .ok()
+        .and_then(|target| target.file_name().map(|name| name.to_owned()));
+
+    let mut generations = Vec::new();
+
+    let read_failed = |error| {
+        JanitorError::profile_io(profile, format!("Failed to read {}", dir.display()), error)
+    };
+    for entry in fs::read_dir(&dir).map_err(read_failed)? {
+        let entry = entry.map_err(read_failed)?;
+        let file_name = entry.file_name();
+        let Some(id) = file_name
+            .to_str()
+            .and_then(|link| parse_link_name(name, link))
+        else {
+            continue;
+        };
+
+        let modified = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map_err(|error| {
+                JanitorError::io(format!("Failed to stat {}", entry.path().display()), error)
+            })?;
+
+        generations.push(Generation {
+            id,
+            date: DateTime::<Local>::from(modified).naive_local(),
+            current: current.as_deref() == Some(file_name.as_os_str()),
+        });
+    }
+
+    Ok(generations.into())
+}
+
+/// Splits `profile` into the directory holding its generation links and its
+/// name.
+pub(crate) fn split_profile(profile: &Path) -> Result<(PathBuf, &str)> {
+    let name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| parse_error!("invalid profile path {}", profile.display()))?;
+    let dir = match profile.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    Ok((dir, name))
+}
+
+/// Extracts the generation id from the name of a generation link of the
+/// profile named `profile`, e.g. `661` from `system-661-link`.
+pub(crate) fn parse_link_name(profile: &str, link: &str) -> Option<u32> {
+    link.strip_prefix(profile)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, os::unix::fs::symlink};
+
+    use super::*;
+
+    use eyre::Result;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::system("system", "system-661-link", Some(661))]
+    #[case::other_profile("system", "profile-661-link", None)]
+    #[case::prefix_of_other("system", "system-foo-1-link", None)]
+    #[case::no_link("system", "system-661", None)]
+    #[case::profile_itself("system", "system", None)]
+    fn link_names(#[case] profile: &str, #[case] link: &str, #[case] expected: Option<u32>) {
+        assert_eq!(parse_link_name(profile, link), expected);
+    }
+
+    #[test]
+    fn lists_links() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-filesystem-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        for id in [1, 2, 3] {
+            symlink(
+                "/nix/store/some-path",
+                dir.join(format!("profile-{id}-link")),
+            )?;
+        }
+        symlink("/nix/store/other-path", dir.join("other-4-link"))?;
+        symlink("profile-2-link", dir.join("profile"))?;
+
+        let generations = list_generations(dir.join("profile"));
+        fs::remove_dir_all(&dir)?;
+        let generations = generations?;
+
+        assert_eq!(
+            generations.iter().map(|g| g.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(generations.get(2).unwrap().current);
+        assert!(!generations.get(3).unwrap().current);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,66 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::error::{JanitorError, Result};
 use chrono::prelude::*;
-use eyre::{eyre, Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Error returned by [Generation::parse_many] if not a single line of the
+/// input could be parsed as a generation.
+///
+/// This usually means that the input is not the output of
+/// `nix-env --list-generations` at all, e.g. because the profile is managed
+/// by a different tool and needs another backend to be listed.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::{Generation, UnrecognizedFormat};
+///
+/// let err = Generation::parse_many("this is not a listing").unwrap_err();
+/// assert!(matches!(err, janitor::JanitorError::UnrecognizedFormat(UnrecognizedFormat)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnrecognizedFormat;
+
+impl fmt::Display for UnrecognizedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(
+            "format not recognized, is this nix-env output? \
+             The profile might be managed by a tool requiring a different backend",
+        )
+    }
+}
+
+impl std::error::Error for UnrecognizedFormat {}
+
+/// How a generation came to be, as far as it can be told from the listing
+/// of its profile, see [GenerationSet::origin](crate::GenerationSet::origin).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// Built after the generation before it, the usual case.
+    Rebuild,
+
+    /// Looks like the profile has been rolled back to it: it is current
+    /// although newer generations exist, or it is dated before the
+    /// generation preceding it.
+    RollbackTarget,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Rebuild => "rebuild",
+            Self::RollbackTarget => "rollback target",
+        };
+
+        f.write_str(name)
+    }
+}
 
 /// Represents a single generation of a nix profile.
 ///
@@ -21,7 +82,11 @@ use eyre::{eyre, Context, Result};
 ///     current: false,
 /// };
 /// ```
+///
+/// With the `serde` feature, the date is serialized in the format of
+/// `nix-env --list-generations`, with fractional seconds if there are any.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Generation {
     /// The ID of this generation.
     ///
@@ -29,6 +94,7 @@ pub struct Generation {
     pub id: u32,
 
     /// The date and time this generation was created.
+    #[cfg_attr(feature = "serde", serde(with = "date_format"))]
     pub date: NaiveDateTime,
 
     /// Whether this generation is the currently active one.
@@ -37,7 +103,7 @@ pub struct Generation {
 
 impl PartialOrd for Generation {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.id.partial_cmp(&other.id)
+        Some(self.cmp(other))
     }
 }
 
@@ -54,14 +120,16 @@ impl Generation {
     ///
     /// * `input` - The input string to parse. Should contain the id, date, time
     ///   and optionally "(current)" to indicate if this is the current generation.
+    ///   The fields may be separated by any whitespace, a leading byte order
+    ///   mark as well as surrounding whitespace and control characters (e.g.
+    ///   the `\r` of CRLF line endings) are ignored, and so is anything
+    ///   following the time and flag.
     ///
     /// # Errors
     ///
-    /// Returns an `eyre::Result` which can fail with:
-    ///
-    /// - An `eyre::Error` if the id fails to parse as a `u32`.
-    /// - An `eyre::Error` if the date or time strings are missing.
-    /// - A `chrono::ParseError` if the date/time fails to parse.
+    /// Fails with [JanitorError::Parse] if the id is missing or fails to
+    /// parse as a `u32`, or if the date or time are missing or fail to parse,
+    /// with the `chrono::ParseError` as its source.
     ///
     /// # Examples
     ///
@@ -87,30 +155,135 @@ impl Generation {
     where
         S: AsRef<str>,
     {
-        let mut parts = input.as_ref().split_whitespace();
+        let mut parts = input
+            .as_ref()
+            .trim_matches(|c: char| c.is_whitespace() || c.is_control() || c == '\u{feff}')
+            .split_whitespace();
 
         let id = parts
             .next()
-            .unwrap()
+            .ok_or_else(|| JanitorError::parse("Id missing"))?
             .parse::<u32>()
-            .wrap_err("Failed to parse generation id")?;
-        let date_str = parts.next().ok_or_else(|| eyre!("Date missing"))?;
-        let time_str = parts.next().ok_or_else(|| eyre!("Time missing"))?;
+            .map_err(|error| JanitorError::parse_with("Failed to parse generation id", error))?;
+        let date_str = parts
+            .next()
+            .ok_or_else(|| JanitorError::parse("Date missing"))?;
+        let time_str = parts
+            .next()
+            .ok_or_else(|| JanitorError::parse("Time missing"))?;
         let date_time_str = format!("{} {}", date_str, time_str);
-        let date = NaiveDateTime::parse_from_str(&date_time_str, "%Y-%m-%d %H:%M:%S")?;
+        let date = NaiveDateTime::parse_from_str(&date_time_str, "%Y-%m-%d %H:%M:%S").map_err(
+            |error| JanitorError::parse_with(format!("Invalid date {date_time_str:?}"), error),
+        )?;
 
-        let current = match parts.next() {
-            Some("(current)") => true,
-            None => false,
-            _ => return Err(eyre!("Invalid current flag")),
-        };
+        // Anything following the date and the flag is trailing garbage.
+        let current = parts.next() == Some("(current)");
 
         Ok(Self { id, date, current })
     }
 
+    /// Guesses the [Origin] of this generation from the generation
+    /// `preceding` it and the id of the `newest` generation of the profile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{Generation, Origin};
+    ///
+    /// let date = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+    /// let previous = Generation { id: 1, date: date("2023-06-02 00:00"), current: false };
+    /// let generation = Generation { id: 2, date: date("2023-06-01 00:00"), current: false };
+    ///
+    /// assert_eq!(generation.origin(Some(&previous), 2), Origin::RollbackTarget);
+    /// assert_eq!(previous.origin(None, 2), Origin::Rebuild);
+    /// ```
+    pub fn origin(&self, preceding: Option<&Generation>, newest: u32) -> Origin {
+        let rolled_back_to = self.current && self.id < newest;
+        let dated_back = preceding.is_some_and(|preceding| self.date < preceding.date);
+
+        match rolled_back_to || dated_back {
+            true => Origin::RollbackTarget,
+            false => Origin::Rebuild,
+        }
+    }
+
+    /// Returns the path of the symlink pointing to this generation's store
+    /// path, for the profile at `profile`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use janitor::Generation;
+    ///
+    /// let generation = Generation { id: 661, date: Default::default(), current: false };
+    /// assert_eq!(
+    ///     generation.link("/nix/var/nix/profiles/system"),
+    ///     PathBuf::from("/nix/var/nix/profiles/system-661-link"),
+    /// );
+    /// ```
+    pub fn link<P: AsRef<Path>>(&self, profile: P) -> PathBuf {
+        let mut link = profile.as_ref().as_os_str().to_owned();
+        link.push(format!("-{}-link", self.id));
+
+        PathBuf::from(link)
+    }
+
+    /// Returns [Generation::date] as a [SystemTime].
+    ///
+    /// Like everywhere else in the janitor, the date is interpreted as UTC.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use chrono::NaiveDateTime;
+    /// use janitor::Generation;
+    ///
+    /// let generation = Generation {
+    ///     id: 1,
+    ///     date: NaiveDateTime::from_timestamp_opt(86_400, 0).unwrap(),
+    ///     current: false,
+    /// };
+    /// assert_eq!(
+    ///     generation.system_time(),
+    ///     SystemTime::UNIX_EPOCH + Duration::from_secs(86_400),
+    /// );
+    /// ```
+    pub fn system_time(&self) -> SystemTime {
+        DateTime::<Utc>::from_naive_utc_and_offset(self.date, Utc).into()
+    }
+
+    /// Returns [Generation::date] as a [time::OffsetDateTime] in UTC.
+    ///
+    /// Only available with the `time` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::Generation;
+    ///
+    /// let generation = Generation {
+    ///     id: 1,
+    ///     date: NaiveDateTime::from_timestamp_opt(86_400, 0).unwrap(),
+    ///     current: false,
+    /// };
+    /// assert_eq!(generation.offset_date_time().unix_timestamp(), 86_400);
+    /// ```
+    #[cfg(feature = "time")]
+    pub fn offset_date_time(&self) -> time::OffsetDateTime {
+        self.system_time().into()
+    }
+
     /// Parses multiple generations from a string with each generation on a new line.
     ///
-    /// Empty lines, or those only containing whitespace, will be ignored.
+    /// Empty lines, or those only containing whitespace, will be ignored, as
+    /// will be lines that can not be parsed following the last generation,
+    /// e.g. the footer of a log the listing has been captured from. Before
+    /// parsing, CRLF line endings are normalized to LF, tabs to spaces and a
+    /// leading byte order mark is removed.
     ///
     /// # Arguments
     ///
@@ -119,8 +292,10 @@ impl Generation {
     ///
     /// # Errors
     ///
-    /// Returns an `eyre::Result` which will accumulate any errors from the individual
-    /// calls to [Generation::parse] on each line.
+    /// Returns an [UnrecognizedFormat] error if none of the non-empty lines
+    /// could be parsed. Otherwise fails with the first error of the
+    /// individual calls to [Generation::parse] on the lines up to the last
+    /// generation.
     ///
     /// # Examples
     ///
@@ -142,12 +317,55 @@ impl Generation {
     where
         S: AsRef<str>,
     {
-        input
+        let normalized = input
             .as_ref()
+            .trim_start_matches('\u{feff}')
+            .replace("\r\n", "\n")
+            .replace('\t', " ");
+
+        let mut results = normalized
             .lines()
             .filter(|line| !line.trim().is_empty())
             .map(Self::parse)
-            .collect::<Result<Vec<Self>>>()
+            .collect::<Vec<_>>();
+
+        let Some(last) = results.iter().rposition(Result::is_ok) else {
+            if let Some(Err(first)) = results.first() {
+                tracing::debug!(error = %first, "no line could be parsed");
+                return Err(UnrecognizedFormat.into());
+            }
+            return Ok(Vec::new());
+        };
+
+        for error in results.drain(last + 1..).filter_map(Result::err) {
+            tracing::warn!(%error, "ignoring trailing garbage after the listing");
+        }
+
+        results.into_iter().collect::<Result<Vec<Self>>>()
+    }
+}
+
+/// The stable format dates are serialized in with the `serde` feature, the
+/// one of `nix-env --list-generations` with optional fractional seconds.
+#[cfg(feature = "serde")]
+pub(crate) mod date_format {
+    use chrono::NaiveDateTime;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+    pub fn serialize<S: Serializer>(
+        date: &NaiveDateTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&date.format(FORMAT))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<NaiveDateTime, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        NaiveDateTime::parse_from_str(&date, FORMAT).map_err(D::Error::custom)
     }
 }
 
@@ -155,6 +373,7 @@ impl Generation {
 mod test {
     use super::*;
 
+    use proptest::prelude::*;
     use rstest::rstest;
 
     use lazy_static::lazy_static;
@@ -332,11 +551,56 @@ mod test {
     #[case::invalid_date("123 2023-01-32 00:00:00")]
     #[case::missing_date("123")]
     #[case::invalid_id("abc 2023-01-01 00:00:00")]
-    #[case::invalid_current("123 2023-01-01 00:00:00 (invalid)")]
+    #[case::empty("")]
+    #[case::only_whitespace(" \t\r")]
+    #[case::only_bom("\u{feff}")]
     fn parse_errors(#[case] input: &str) {
         assert!(Generation::parse(input).is_err());
     }
 
+    #[rstest]
+    #[case::garbage("this is not a listing")]
+    #[case::json("{\"elements\": {}, \"version\": 3}")]
+    #[case::multiple("foo\nbar\r\nbaz")]
+    fn parse_many_unrecognized(#[case] input: &str) {
+        let err = Generation::parse_many(input).unwrap_err();
+
+        assert!(matches!(err, JanitorError::UnrecognizedFormat(_)));
+    }
+
+    #[rstest]
+    #[case::broken_line("661 2023-06-01 08:10:47\n662 2023-06-05\n663 2023-06-06 08:10:47")]
+    fn parse_many_partially_broken(#[case] input: &str) {
+        let err = Generation::parse_many(input).unwrap_err();
+
+        assert!(matches!(err, JanitorError::Parse { .. }));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::blank(" \r\n\t\n")]
+    #[case::bom("\u{feff}")]
+    fn parse_many_empty(#[case] input: &str) -> Result<()> {
+        assert!(Generation::parse_many(input)?.is_empty());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::bom("\u{feff}681 2023-07-16 11:35:46")]
+    #[case::tabs("681\t2023-07-16\t11:35:46")]
+    #[case::crlf("681 2023-07-16 11:35:46\r")]
+    #[case::trailing_control("681 2023-07-16 11:35:46\u{0}\u{1b}")]
+    #[case::trailing_token("681 2023-07-16 11:35:46 [build-host]")]
+    fn parse_normalizes(#[case] input: &str) -> Result<()> {
+        assert_eq!(
+            Generation::parse(input)?,
+            generation!(681, "2023-07-16 11:35:46")
+        );
+
+        Ok(())
+    }
+
     #[rstest]
     #[case::without_current(INPUT_WITHOUT_CURRENT, GENERATIONS_WITHOUT_CURRENT.clone())]
     #[case::with_current(INPUT_WITH_CURRENT, GENERATIONS_WITH_CURRENT.clone())]
@@ -344,6 +608,22 @@ mod test {
         INPUT_WITH_CURRENT_IN_THE_MIDDLE,
         GENERATIONS_WITH_CURRENT_IN_THE_MIDDLE.clone()
     )]
+    #[case::crlf(
+        &INPUT_WITH_CURRENT.replace('\n', "\r\n"),
+        GENERATIONS_WITH_CURRENT.clone()
+    )]
+    #[case::bom(
+        &format!("\u{feff}{INPUT_WITH_CURRENT}"),
+        GENERATIONS_WITH_CURRENT.clone()
+    )]
+    #[case::trailing_token(
+        &INPUT_WITH_CURRENT.replace("(current)", "(current) *"),
+        GENERATIONS_WITH_CURRENT.clone()
+    )]
+    #[case::trailing_garbage_line(
+        &format!("{INPUT_WITH_CURRENT}\n-- captured by ci, exit status 0 --\n682 2023-07"),
+        GENERATIONS_WITH_CURRENT.clone()
+    )]
     fn parse_many<G>(#[case] input: &str, #[case] expected: G)
     where
         G: AsRef<[Generation]>,
@@ -352,4 +632,89 @@ mod test {
 
         assert_eq!(parsed, expected.as_ref());
     }
+
+    proptest! {
+        #[test]
+        fn parse_roundtrips_with_arbitrary_whitespace(
+            id in any::<u32>(),
+            timestamp in 0..4_000_000_000i64,
+            current in any::<bool>(),
+            bom in any::<bool>(),
+            crlf in any::<bool>(),
+            lead in "[ \t]{0,4}",
+            seps in proptest::collection::vec("[ \t]{1,4}", 3),
+            trail in "[ \t]{0,4}",
+        ) {
+            let date = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap();
+            let input = format!(
+                "{bom}{lead}{id}{}{}{}{}{}{trail}{crlf}",
+                seps[0],
+                date.format("%Y-%m-%d"),
+                seps[1],
+                date.format("%H:%M:%S"),
+                if current { format!("{}(current)", seps[2]) } else { String::new() },
+                bom = if bom { "\u{feff}" } else { "" },
+                crlf = if crlf { "\r" } else { "" },
+            );
+
+            let parsed = Generation::parse(&input).unwrap();
+
+            prop_assert_eq!(parsed, Generation { id, date, current });
+        }
+
+        #[test]
+        fn parse_never_panics(input in "\\PC*") {
+            let _ = Generation::parse(input);
+        }
+
+        #[test]
+        fn parse_many_never_panics(input in "(\\PC*(\r?\n)?){0,5}") {
+            let _ = Generation::parse_many(input);
+        }
+
+        #[test]
+        fn system_time_matches_timestamp(timestamp in 0..4_000_000_000i64) {
+            let generation = Generation {
+                id: 1,
+                date: NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap(),
+                current: false,
+            };
+
+            let since_epoch = generation.system_time().duration_since(SystemTime::UNIX_EPOCH)?;
+            prop_assert_eq!(since_epoch.as_secs(), timestamp as u64);
+
+            #[cfg(feature = "time")]
+            prop_assert_eq!(generation.offset_date_time().unix_timestamp(), timestamp);
+        }
+
+        #[cfg(feature = "serde")]
+        #[test]
+        fn serde_roundtrips(
+            id in 0..u32::MAX,
+            timestamp in 0..4_000_000_000i64,
+            nanos in prop_oneof![Just(0u32), 0..1_000_000_000u32],
+            current: bool,
+        ) {
+            let generation = Generation {
+                id,
+                date: NaiveDateTime::from_timestamp_opt(timestamp, nanos).unwrap(),
+                current,
+            };
+
+            let json = serde_json::to_string(&generation)?;
+            prop_assert_eq!(serde_json::from_str::<Generation>(&json)?, generation);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_listing_dates() -> Result<(), serde_json::Error> {
+        let generation = generation!(661, "2023-06-01 08:10:47", true);
+
+        assert_eq!(
+            serde_json::to_string(&generation)?,
+            r#"{"id":661,"date":"2023-06-01 08:10:47","current":true}"#
+        );
+        Ok(())
+    }
 }
@@ -1,19 +1,217 @@
-use std::collections::BTreeSet;
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt::{self, Display},
+    str::FromStr,
+};
 
+use crate::error::{JanitorError, Result};
 use chrono::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-use crate::generation::Generation;
+use crate::{
+    generation::{Generation, Origin},
+    policy_chain::{PolicyChain, Verdict},
+};
+
+/// The order deciding which generations are the most recent ones, see
+/// [GenerationSet::get_last_n_generations_by].
+///
+/// Generations sharing a date, e.g. when a script rebuilt a profile several
+/// times within a second, are ordered by [Generation::id] in either order, so
+/// the selection does not depend on how the generations are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum GenerationOrder {
+    /// By [Generation::id], the order the generations have been created in.
+    #[default]
+    Id,
+
+    /// By [Generation::date], ties broken by id. Differs from the order by
+    /// id when dates are not increasing with the ids, e.g. after a rollback
+    /// re-activated an older generation.
+    Date,
+}
+
+impl GenerationOrder {
+    /// Compares two generations in this order, the older one first. Ties are
+    /// broken by [Generation::id], the one created first being older.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cmp::Ordering;
+    ///
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{Generation, GenerationOrder};
+    ///
+    /// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    /// let first = Generation { id: 1, date, current: false };
+    /// let second = Generation { id: 2, date, current: false };
+    ///
+    /// assert_eq!(GenerationOrder::Date.compare(&first, &second), Ordering::Less);
+    /// assert_eq!(GenerationOrder::Id.compare(&second, &first), Ordering::Greater);
+    /// ```
+    pub fn compare(self, a: &Generation, b: &Generation) -> Ordering {
+        match self {
+            Self::Id => a.id.cmp(&b.id),
+            Self::Date => (a.date, a.id).cmp(&(b.date, b.id)),
+        }
+    }
+}
+
+impl Display for GenerationOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Id => "id",
+            Self::Date => "date",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for GenerationOrder {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "id" => Ok(Self::Id),
+            "date" => Ok(Self::Date),
+            other => Err(parse_error!(
+                "unknown generation order: {other}, expected id or date"
+            )),
+        }
+    }
+}
+
+/// A calendar period generations are thinned out by, see
+/// [GenerationSet::last_per_period].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Period {
+    /// A calendar day.
+    Day,
+
+    /// An ISO 8601 week, starting on Monday.
+    Week,
+
+    /// A calendar month.
+    Month,
+}
+
+impl Period {
+    /// Identifies the period `date` falls into.
+    fn of(self, date: NaiveDateTime) -> (i32, u32) {
+        match self {
+            Self::Day => (date.year(), date.ordinal()),
+            Self::Week => (date.iso_week().year(), date.iso_week().week()),
+            Self::Month => (date.year(), date.month()),
+        }
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// How many days, weeks and months to keep a generation of, for a sparse
+/// history reaching further back than the other retention settings, see
+/// [GenerationSet::thin].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Thinning {
+    /// The number of most recent days to keep the last generation of.
+    pub keep_daily: usize,
+
+    /// The number of most recent weeks to keep the last generation of.
+    pub keep_weekly: usize,
+
+    /// The number of most recent months to keep the last generation of.
+    pub keep_monthly: usize,
+}
+
+impl Thinning {
+    /// Whether this keeps no generation at all.
+    pub fn is_empty(&self) -> bool {
+        self.periods().iter().all(|(_, n)| *n == 0)
+    }
+
+    /// The number of most recent periods to keep a generation of, by period.
+    pub fn periods(&self) -> [(Period, usize); 3] {
+        [
+            (Period::Day, self.keep_daily),
+            (Period::Week, self.keep_weekly),
+            (Period::Month, self.keep_monthly),
+        ]
+    }
+}
+
+impl Display for Thinning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "daily={}, weekly={}, monthly={}",
+            self.keep_daily, self.keep_weekly, self.keep_monthly
+        )
+    }
+}
 
 /// Represents a set of [Generation]s.
 ///
 /// The generations are stored in a [BTreeSet] and kept in order by
 /// [Generation::id].
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// At most one generation in the set is [Generation::current]. If more than
+/// one generation is marked as current on construction, which can happen
+/// after a profile got corrupted, only the newest of them is kept as current
+/// and a warning is emitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(from = "Vec<Generation>", into = "Vec<Generation>")
+)]
 pub struct GenerationSet {
     generations: BTreeSet<Generation>,
 }
 
 impl GenerationSet {
+    fn new(mut generations: BTreeSet<Generation>) -> Self {
+        let current = generations
+            .iter()
+            .filter(|g| g.current)
+            .map(|g| g.id)
+            .collect::<Vec<_>>();
+
+        if let [_, .., newest] = current[..] {
+            tracing::warn!(
+                current = ?current,
+                kept = newest,
+                "multiple generations marked as current, keeping the newest"
+            );
+
+            generations = generations
+                .into_iter()
+                .map(|g| Generation {
+                    current: g.id == newest,
+                    ..g
+                })
+                .collect();
+        }
+
+        Self { generations }
+    }
+
     /// Returns a new [GenerationSet] containing only the `n` most recent
     /// [Generation]s in this set.
     ///
@@ -46,9 +244,35 @@ impl GenerationSet {
     /// assert_eq!(recent.iter().map(|g| g.id).collect::<Vec<_>>(), vec![2, 3]);
     /// ```
     pub fn get_last_n_generations(&self, n: usize) -> Self {
-        let mut generations = self.generations.iter().cloned().collect::<Vec<_>>();
+        self.get_last_n_generations_by(n, GenerationOrder::Id)
+    }
 
-        generations.sort_by(|a, b| a.id.cmp(&b.id));
+    /// Returns a new [GenerationSet] containing only the `n` most recent
+    /// [Generation]s in this set, the most recent ones according to `order`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationOrder, GenerationSet};
+    ///
+    /// // Generation 2 has been rolled back to and rebuilt after generation 3.
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-06-01 00:00:00\n\
+    ///      2 2023-06-05 00:00:00 (current)\n\
+    ///      3 2023-06-03 00:00:00",
+    /// )?
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    ///
+    /// let ids = |set: GenerationSet| set.iter().map(|g| g.id).collect::<Vec<_>>();
+    /// assert_eq!(ids(generations.get_last_n_generations_by(2, GenerationOrder::Id)), [2, 3]);
+    /// assert_eq!(ids(generations.get_last_n_generations_by(2, GenerationOrder::Date)), [2, 3]);
+    /// assert_eq!(ids(generations.get_last_n_generations_by(1, GenerationOrder::Id)), [3]);
+    /// assert_eq!(ids(generations.get_last_n_generations_by(1, GenerationOrder::Date)), [2]);
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn get_last_n_generations_by(&self, n: usize, order: GenerationOrder) -> Self {
+        let generations = self.sorted_by(order);
 
         if n >= generations.len() {
             return generations.into();
@@ -57,11 +281,21 @@ impl GenerationSet {
         generations[generations.len() - n..].into()
     }
 
+    /// The generations of this set, the oldest first according to `order`.
+    pub(crate) fn sorted_by(&self, order: GenerationOrder) -> Vec<Generation> {
+        let mut generations = self.generations.iter().cloned().collect::<Vec<_>>();
+
+        generations.sort_by(|a, b| order.compare(a, b));
+
+        generations
+    }
+
     /// Returns a new [GenerationSet] containing the active generation on or after
     /// the provided `date`, along with any newer generations.
     ///
     /// The result will include the last generation before `date` as it potentially
-    /// has been active on `date`.
+    /// has been active on `date`. If several generations before `date` share
+    /// the latest date, that is the one with the highest [Generation::id].
     ///
     /// # Arguments
     ///
@@ -88,11 +322,11 @@ impl GenerationSet {
     /// assert_eq!(active.iter().next().unwrap().id, 2);
     /// ```
     pub fn get_active_on_or_after(&self, date: NaiveDateTime) -> Self {
-        let (newer, older): (Vec<_>, _) = self.iter().partition(|g| g.date >= date);
+        let (newer, older): (Vec<Generation>, Vec<_>) = self.iter().partition(|g| g.date >= date);
 
         older
             .iter()
-            .last()
+            .max_by_key(|g| g.id)
             .map_or_else(
                 || newer.clone(),
                 |last| {
@@ -104,21 +338,109 @@ impl GenerationSet {
             .into()
     }
 
+    /// Returns a new [GenerationSet] containing the last generation, by
+    /// [Generation::date], of each of the `n` most recent `period`s with any
+    /// generations.
+    ///
+    /// Periods without generations do not count, so a profile left alone
+    /// for a while keeps as many generations as one rebuilt every day.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet, Period};
+    ///
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-06-01 08:00:00
+    ///      2 2023-06-01 18:00:00
+    ///      3 2023-06-04 12:00:00
+    ///      4 2023-06-05 12:00:00 (current)",
+    /// )?
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    ///
+    /// let ids = |set: GenerationSet| set.iter().map(|g| g.id).collect::<Vec<_>>();
+    /// assert_eq!(ids(generations.last_per_period(Period::Day, 3)), [2, 3, 4]);
+    /// assert_eq!(ids(generations.last_per_period(Period::Week, 2)), [3, 4]);
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn last_per_period(&self, period: Period, n: usize) -> Self {
+        let mut seen = BTreeSet::new();
+
+        self.sorted_by(GenerationOrder::Date)
+            .into_iter()
+            .rev()
+            .filter(|g| seen.len() < n && seen.insert(period.of(g.date)))
+            .collect()
+    }
+
+    /// Returns a new [GenerationSet] containing the generations kept by
+    /// thinning out the history: the last generation of each of the
+    /// `keep_daily` most recent days, `keep_weekly` most recent weeks and
+    /// `keep_monthly` most recent months with any generations, see
+    /// [GenerationSet::last_per_period].
+    ///
+    /// Each period is counted on its own, a generation kept as the last one
+    /// of its day may as well be kept as the last one of its week.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    ///
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-04-20 00:00:00
+    ///      2 2023-05-10 00:00:00
+    ///      3 2023-05-30 00:00:00
+    ///      4 2023-06-01 00:00:00
+    ///      5 2023-06-02 00:00:00 (current)",
+    /// )?
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    ///
+    /// let kept = generations.thin(1, 0, 3);
+    /// assert_eq!(kept.iter().map(|g| g.id).collect::<Vec<_>>(), [1, 3, 5]);
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn thin(&self, keep_daily: usize, keep_weekly: usize, keep_monthly: usize) -> Self {
+        let thinning = Thinning {
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+        };
+
+        thinning
+            .periods()
+            .into_iter()
+            .flat_map(|(period, n)| self.last_per_period(period, n).generations)
+            .collect()
+    }
+
     /// Returns a new [GenerationSet] containing generations that should be deleted.
     ///
     /// The returned set will contain all generations except:
     ///
     /// - The `keep` most recent generations based on [Generation::id].
     /// - Any generations active on or after `date`.
+    /// - The generations with an id in `pinned`.
+    ///
+    /// Of the first two, only the `keep_at_most` most recent generations are
+    /// kept, even if that is fewer than `keep`. The cap never removes the
+    /// current generation, not even `Some(0)`.
     ///
     /// # Arguments
     ///
     /// * `keep` - The number of recent generations to keep.
+    /// * `keep_at_most` - The number of recent generations to keep at most,
+    ///   no limit if `None`.
     /// * `date` - The cutoff date. Generations active on or after this will be kept.
+    /// * `pinned` - The ids of generations that are never deleted.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::collections::BTreeSet;
+    ///
     /// use chrono::NaiveDateTime;
     /// use janitor::{Generation, GenerationSet};
     ///  
@@ -134,23 +456,346 @@ impl GenerationSet {
     ///     Generation { id: 3, date: date3, current: false }, // keep (recent)
     /// ].into_iter().collect::<GenerationSet>();
     ///
-    /// let to_delete = generations.generations_to_delete(1, threshold);
+    /// let to_delete = generations.generations_to_delete(1, None, threshold, &BTreeSet::new());
     /// assert_eq!(to_delete.len(), 1);
     /// assert_eq!(to_delete.iter().next().unwrap().id, 1);
+    ///
+    /// let pinned = generations.generations_to_delete(1, None, threshold, &BTreeSet::from([1]));
+    /// assert!(pinned.is_empty());
+    ///
+    /// let capped = generations.generations_to_delete(1, Some(1), threshold, &BTreeSet::new());
+    /// assert_eq!(capped.iter().map(|g| g.id).collect::<Vec<_>>(), [1, 2]);
     /// ```
-    pub fn generations_to_delete(&self, keep: usize, date: NaiveDateTime) -> Self {
+    pub fn generations_to_delete(
+        &self,
+        keep: usize,
+        keep_at_most: Option<usize>,
+        date: NaiveDateTime,
+        pinned: &BTreeSet<u32>,
+    ) -> Self {
         let by_count = self.get_last_n_generations(keep).generations;
 
         let by_date = self.get_active_on_or_after(date).generations;
 
-        let to_keep = by_count
+        let mut to_keep = by_count
             .union(&by_date)
             .cloned()
             .collect::<BTreeSet<Generation>>();
 
+        if let Some(at_most) = keep_at_most {
+            let cap = self.get_last_n_generations(at_most).generations;
+            to_keep.retain(|g| g.current || cap.contains(g));
+        }
+
+        self.iter()
+            .cloned()
+            .filter(|g| !to_keep.contains(g) && !pinned.contains(&g.id))
+            .collect()
+    }
+
+    /// Returns the generations kept by the rules of the `chain`, judged in
+    /// the order of their precedence, see [PolicyChain].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeSet;
+    ///
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{Generation, GenerationSet, PolicyChain};
+    ///
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-06-01 00:00:00\n\
+    ///      2 2023-06-10 00:00:00\n\
+    ///      3 2023-06-20 00:00:00 (current)",
+    /// )?
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    /// let cutoff = NaiveDateTime::parse_from_str("2023-06-25 00:00:00", "%Y-%m-%d %H:%M:%S")?;
+    ///
+    /// let chain = PolicyChain::new().pinned(BTreeSet::from([1])).age(cutoff);
+    ///
+    /// let kept = generations.retain_policy_chain(&chain);
+    /// assert_eq!(kept.iter().map(|g| g.id).collect::<Vec<_>>(), [1, 3]);
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn retain_policy_chain(&self, chain: &PolicyChain) -> Self {
+        let verdicts = chain.judge(self);
+
+        self.iter()
+            .filter(|g| verdicts.get(&g.id) == Some(&Verdict::Keep))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the generations with an id below `id`, which are neither the
+    /// current generation nor have been active on or after `date`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{Generation, GenerationSet};
+    ///
+    /// let date1 = NaiveDateTime::parse_from_str("2020-01-01 00:00", "%Y-%m-%d %H:%M").unwrap();
+    /// let date2 = NaiveDateTime::parse_from_str("2020-02-01 00:00", "%Y-%m-%d %H:%M").unwrap();
+    /// let date3 = NaiveDateTime::parse_from_str("2020-03-01 00:00", "%Y-%m-%d %H:%M").unwrap();
+    ///
+    /// let threshold = NaiveDateTime::parse_from_str("2020-02-15 00:00", "%Y-%m-%d %H:%M").unwrap();
+    ///
+    /// let generations = vec![
+    ///     Generation { id: 1, date: date1, current: false }, // delete
+    ///     Generation { id: 2, date: date2, current: false }, // keep (active until 3)
+    ///     Generation { id: 3, date: date3, current: true },
+    /// ].into_iter().collect::<GenerationSet>();
+    ///
+    /// let before = generations.generations_before(3, threshold);
+    /// assert_eq!(before.iter().map(|g| g.id).collect::<Vec<_>>(), vec![1]);
+    /// ```
+    pub fn generations_before(&self, id: u32, date: NaiveDateTime) -> Self {
+        let active = self.get_active_on_or_after(date);
+
+        self.iter()
+            .filter(|g| g.id < id && !g.current && !active.contains(g.id))
+            .cloned()
+            .collect()
+    }
+
+    /// Guesses the [Origin] of the generation with the id `id`, from the
+    /// generations around it, see [Generation::origin].
+    ///
+    /// Returns `None` if there is no such generation.
+    pub fn origin(&self, id: u32) -> Option<Origin> {
+        let generation = self.get(id)?;
+        let preceding = self.iter().take_while(|g| g.id < id).last();
+        let newest = self.iter().last().map_or(id, |g| g.id);
+
+        Some(generation.origin(preceding, newest))
+    }
+
+    /// Returns the generations that look like the profile has been rolled
+    /// back to them, see [Origin::RollbackTarget].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    ///
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-06-01 00:00:00\n\
+    ///      2 2023-06-02 00:00:00 (current)\n\
+    ///      3 2023-06-03 00:00:00",
+    /// )
+    /// .unwrap()
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    ///
+    /// let targets = generations.rollback_targets();
+    /// assert_eq!(targets.iter().map(|g| g.id).collect::<Vec<_>>(), vec![2]);
+    /// ```
+    pub fn rollback_targets(&self) -> Self {
+        let newest = self.iter().last().map_or(0, |g| g.id);
+        let mut preceding = None;
+
+        self.iter()
+            .filter(|g| {
+                let origin = g.origin(preceding, newest);
+                preceding = Some(*g);
+
+                origin == Origin::RollbackTarget
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a new [GenerationSet] containing the generations of this set
+    /// whose ids are not contained in `other`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    /// use chrono::prelude::*;
+    ///
+    /// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    ///
+    /// let before = vec![
+    ///     Generation { id: 1, current: true, date },
+    /// ].into_iter().collect::<GenerationSet>();
+    /// let after = vec![
+    ///     Generation { id: 1, current: false, date },
+    ///     Generation { id: 2, current: true, date },
+    /// ].into_iter().collect::<GenerationSet>();
+    ///
+    /// let new = after.difference(&before);
+    /// assert_eq!(new.iter().map(|g| g.id).collect::<Vec<_>>(), vec![2]);
+    /// ```
+    pub fn difference(&self, other: &Self) -> Self {
         self.iter()
+            .filter(|g| !other.contains(g.id))
             .cloned()
-            .filter(|g| !to_keep.contains(g))
+            .collect()
+    }
+
+    /// Merges several `listings` of the same profile, e.g. by different
+    /// [Backend](crate::Backend)s, into their union, together with the
+    /// generations they disagree on.
+    ///
+    /// A generation is taken from the first listing containing it. The
+    /// listings disagree on a generation if it is missing from some of them,
+    /// or listed with different dates or current flags, which hints at a
+    /// corrupted profile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    /// use chrono::prelude::*;
+    ///
+    /// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    ///
+    /// let nix_env = GenerationSet::from([
+    ///     Generation { id: 1, current: false, date },
+    ///     Generation { id: 2, current: true, date },
+    /// ]);
+    /// let filesystem = GenerationSet::from([Generation { id: 2, current: true, date }]);
+    ///
+    /// let (merged, conflicts) = GenerationSet::merge_listings(&[nix_env, filesystem]);
+    /// assert_eq!(merged.len(), 2);
+    /// assert_eq!(conflicts.len(), 1);
+    /// assert_eq!(conflicts[0].id, 1);
+    /// ```
+    pub fn merge_listings(listings: &[Self]) -> (Self, Vec<ListingConflict>) {
+        let ids: BTreeSet<u32> = listings
+            .iter()
+            .flat_map(|l| l.iter().map(|g| g.id))
+            .collect();
+
+        let mut merged = BTreeSet::new();
+        let mut conflicts = Vec::new();
+        for id in ids {
+            let listed: Vec<_> = listings.iter().map(|l| l.get(id).copied()).collect();
+
+            let first = listed.iter().flatten().next().copied();
+            merged.extend(first);
+
+            // Generations are equal if their ids, dates and current flags are.
+            let agree = listed.iter().all(|generation| *generation == first);
+            if !agree {
+                conflicts.push(ListingConflict { id, listed });
+            }
+        }
+
+        (Self::new(merged), conflicts)
+    }
+
+    /// Splits the set into consecutive batches of at most `size`
+    /// generations, the oldest batch first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    /// use chrono::prelude::*;
+    ///
+    /// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    ///
+    /// let generations = (1..=5)
+    ///     .map(|id| Generation { id, current: false, date })
+    ///     .collect::<GenerationSet>();
+    ///
+    /// let batches = generations.batches(2);
+    /// assert_eq!(batches.len(), 3);
+    /// assert_eq!(batches[2].iter().map(|g| g.id).collect::<Vec<_>>(), vec![5]);
+    /// ```
+    pub fn batches(&self, size: usize) -> Vec<Self> {
+        assert!(size > 0, "batch size must be positive");
+
+        self.generations
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>()
+            .chunks(size)
+            .map(Self::from)
+            .collect()
+    }
+
+    /// Returns the arguments selecting the generations in this set for
+    /// `nix-env --delete-generations`: their ids in ascending order, each
+    /// once, as plain decimal numbers.
+    ///
+    /// `nix-env` also accepts arguments like `+5`, `30d` or `old`, deleting
+    /// far more than the listed generations. Only ever build the arguments
+    /// with this method, and check arguments from elsewhere with
+    /// [GenerationSet::parse_delete_args].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet};
+    /// use chrono::prelude::*;
+    ///
+    /// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    ///
+    /// let generations = [12, 3, 7]
+    ///     .into_iter()
+    ///     .map(|id| Generation { id, current: false, date })
+    ///     .collect::<GenerationSet>();
+    ///
+    /// assert_eq!(generations.to_delete_args(), ["3", "7", "12"]);
+    /// ```
+    pub fn to_delete_args(&self) -> Vec<String> {
+        self.generations
+            .iter()
+            .map(|g| g.id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    /// Parses arguments for `nix-env --delete-generations` back into the
+    /// generation ids they select, as produced by
+    /// [GenerationSet::to_delete_args].
+    ///
+    /// # Errors
+    ///
+    /// Fails on any argument that is not a plain decimal id, in particular
+    /// on the relative forms `+N`, `Nd` and `old`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::GenerationSet;
+    ///
+    /// let ids = GenerationSet::parse_delete_args(["3", "7", "12"])?;
+    /// assert_eq!(ids.into_iter().collect::<Vec<_>>(), [3, 7, 12]);
+    ///
+    /// assert!(GenerationSet::parse_delete_args(["+5"]).is_err());
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn parse_delete_args<I, S>(args: I) -> Result<BTreeSet<u32>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        args.into_iter()
+            .map(|arg| {
+                let arg = arg.as_ref();
+                if arg.is_empty() || !arg.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(parse_error!(
+                        "{arg:?} does not select a single generation by id"
+                    ));
+                }
+
+                arg.parse().map_err(|error| {
+                    JanitorError::parse_with(format!("invalid generation id {arg:?}"), error)
+                })
+            })
             .collect()
     }
 
@@ -195,9 +840,7 @@ impl<'a> IntoIterator for &'a GenerationSet {
 
 impl FromIterator<Generation> for GenerationSet {
     fn from_iter<T: IntoIterator<Item = Generation>>(iter: T) -> Self {
-        Self {
-            generations: iter.into_iter().collect(),
-        }
+        Self::new(iter.into_iter().collect())
     }
 }
 
@@ -218,9 +861,35 @@ where
     S: AsRef<[Generation]>,
 {
     fn from(iter: S) -> Self {
-        Self {
-            generations: iter.as_ref().iter().cloned().collect(),
+        Self::new(iter.as_ref().iter().cloned().collect())
+    }
+}
+
+/// A generation that listings of the same profile disagree on, see
+/// [GenerationSet::merge_listings].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingConflict {
+    /// The id of the generation.
+    pub id: u32,
+
+    /// The generation as listed by each listing, in the order of the
+    /// listings, `None` if it is missing from a listing.
+    pub listed: Vec<Option<Generation>>,
+}
+
+impl Display for ListingConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "generation {}:", self.id)?;
+        for (i, generation) in self.listed.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            match generation {
+                Some(g) if g.current => write!(f, "{separator} {} (current)", g.date)?,
+                Some(g) => write!(f, "{separator} {}", g.date)?,
+                None => write!(f, "{separator} missing")?,
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -229,6 +898,7 @@ mod test {
     use std::ops::RangeBounds;
 
     use eyre::Result;
+    use proptest::prelude::*;
     use rstest::{fixture, rstest};
 
     use crate::generation::Generation;
@@ -290,6 +960,132 @@ mod test {
         Ok(())
     }
 
+    /// A profile rolled back to generation 663 after 665 has been built,
+    /// whose link got re-created by the rollback.
+    const INPUT_ROLLED_BACK: &str = r#" 661   2023-06-01 08:10:47
+    662   2023-06-05 21:35:55
+    663   2023-07-01 13:17:20   (current)
+    664   2023-06-06 18:29:49
+    665   2023-06-07 07:57:08"#;
+
+    #[fixture]
+    fn rolled_back() -> Result<GenerationSet> {
+        Ok(Generation::parse_many(INPUT_ROLLED_BACK)?.into())
+    }
+
+    #[rstest]
+    #[case::by_id(GenerationOrder::Id, 2, &[664, 665])]
+    #[case::by_date(GenerationOrder::Date, 2, &[663, 665])]
+    #[case::by_date_one(GenerationOrder::Date, 1, &[663])]
+    #[case::by_date_all(GenerationOrder::Date, 6, &[661, 662, 663, 664, 665])]
+    fn test_get_last_n_generations_by(
+        rolled_back: Result<GenerationSet>,
+        #[case] order: GenerationOrder,
+        #[case] n: usize,
+        #[case] ids: &[u32],
+    ) -> Result<()> {
+        let filtered: BTreeSet<u32> = rolled_back?.get_last_n_generations_by(n, order).into();
+
+        assert_eq!(filtered, ids.iter().copied().collect());
+
+        Ok(())
+    }
+
+    /// Generations 2 to 4 have been built by a script within the same second.
+    const INPUT_SAME_DATE: &str = r#"   1   2023-06-01 08:10:47
+       2   2023-06-05 21:35:55
+       3   2023-06-05 21:35:55
+       4   2023-06-05 21:35:55
+       5   2023-06-07 07:57:08   (current)"#;
+
+    #[rstest]
+    #[case::by_id(GenerationOrder::Id, 2, &[4, 5])]
+    #[case::by_date(GenerationOrder::Date, 2, &[4, 5])]
+    #[case::by_date_three(GenerationOrder::Date, 3, &[3, 4, 5])]
+    fn ties_broken_by_id(
+        #[case] order: GenerationOrder,
+        #[case] n: usize,
+        #[case] ids: &[u32],
+    ) -> Result<()> {
+        let generations: GenerationSet = Generation::parse_many(INPUT_SAME_DATE)?.into();
+
+        let filtered: BTreeSet<u32> = generations.get_last_n_generations_by(n, order).into();
+
+        assert_eq!(filtered, ids.iter().copied().collect());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::daily(Period::Day, 3, &[678, 680, 681])]
+    #[case::weekly_skips_empty_weeks(Period::Week, 3, &[672, 674, 681])]
+    #[case::monthly(Period::Month, 2, &[672, 681])]
+    #[case::more_than_there_are(Period::Month, 5, &[672, 681])]
+    #[case::none(Period::Day, 0, &[])]
+    fn keeps_last_per_period(
+        parsed: Result<GenerationSet>,
+        #[case] period: Period,
+        #[case] n: usize,
+        #[case] ids: &[u32],
+    ) -> Result<()> {
+        let kept: BTreeSet<u32> = parsed?.last_per_period(period, n).into();
+
+        assert_eq!(kept, ids.iter().copied().collect());
+
+        Ok(())
+    }
+
+    #[rstest]
+    fn thinning_combines_periods(parsed: Result<GenerationSet>) -> Result<()> {
+        let kept: BTreeSet<u32> = parsed?.thin(2, 3, 2).into();
+
+        assert_eq!(kept, BTreeSet::from([672, 674, 680, 681]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn active_ties_broken_by_id() -> Result<()> {
+        let generations: GenerationSet = Generation::parse_many(INPUT_SAME_DATE)?.into();
+
+        let active: BTreeSet<u32> = generations
+            .get_active_on_or_after(ndt!("2023-06-06 00:00:00"))
+            .into();
+
+        assert_eq!(active, [4, 5].into_iter().collect());
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn compare_is_total(
+            a in (1..20u32, 0..3i64),
+            b in (1..20u32, 0..3i64),
+            order in prop_oneof![Just(GenerationOrder::Id), Just(GenerationOrder::Date)],
+        ) {
+            let generation = |(id, day): (u32, i64)| Generation {
+                id,
+                date: ndt!("2023-06-01 00:00:00") + chrono::Duration::days(day),
+                current: false,
+            };
+            let (a, b) = (generation(a), generation(b));
+
+            prop_assert_eq!(order.compare(&a, &b), order.compare(&b, &a).reverse());
+            if a.id != b.id {
+                prop_assert_ne!(order.compare(&a, &b), Ordering::Equal);
+            }
+        }
+    }
+
+    #[rstest]
+    #[case::id("id", GenerationOrder::Id)]
+    #[case::date("date", GenerationOrder::Date)]
+    fn order_roundtrip(#[case] name: &str, #[case] order: GenerationOrder) {
+        assert_eq!(name.parse::<GenerationOrder>().unwrap(), order);
+        assert_eq!(order.to_string(), name);
+    }
+
     #[rstest]
     #[case(ndt!("2023-06-01 00:00:00"), 661..=681)]
     #[case(ndt!("2023-06-10 00:00:00"), 666..=681)]
@@ -339,13 +1135,92 @@ mod test {
     where
         R: RangeBounds<u32> + IntoIterator<Item = u32>,
     {
-        let filtered: BTreeSet<u32> = parsed?.generations_to_delete(keep, date).into();
+        let filtered: BTreeSet<u32> = parsed?
+            .generations_to_delete(keep, None, date, &BTreeSet::new())
+            .into();
+
+        assert_eq!(filtered, ids.into_iter().collect());
+
+        Ok(())
+    }
+
+    #[rstest]
+    fn keep_at_most_keeps_rolled_back_current(rolled_back: Result<GenerationSet>) -> Result<()> {
+        let filtered: BTreeSet<u32> = rolled_back?
+            .generations_to_delete(5, Some(1), ndt!("2023-06-01 00:00:00"), &BTreeSet::new())
+            .into();
+
+        assert_eq!(filtered, BTreeSet::from([661, 662, 664]));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::date_rule_capped(1, Some(3), ndt!("2023-06-01 00:00:00"), 661..=678)]
+    #[case::count_capped(10, Some(5), ndt!("2023-07-15 12:00:00"), 661..=676)]
+    #[case::cap_above_everything(1, Some(30), ndt!("2023-07-01 00:00:00"), 661..=671)]
+    #[case::only_current_kept(1, Some(0), ndt!("2023-06-01 00:00:00"), 661..=680)]
+    fn keep_at_most_caps_kept_generations<R>(
+        parsed: Result<GenerationSet>,
+        #[case] keep: usize,
+        #[case] keep_at_most: Option<usize>,
+        #[case] date: NaiveDateTime,
+        #[case] ids: R,
+    ) -> Result<()>
+    where
+        R: RangeBounds<u32> + IntoIterator<Item = u32>,
+    {
+        let filtered: BTreeSet<u32> = parsed?
+            .generations_to_delete(keep, keep_at_most, date, &BTreeSet::new())
+            .into();
 
         assert_eq!(filtered, ids.into_iter().collect());
 
         Ok(())
     }
 
+    #[rstest]
+    fn pinned_never_deleted(parsed: Result<GenerationSet>) -> Result<()> {
+        let pinned = BTreeSet::from([661, 665, 999]);
+        let filtered: BTreeSet<u32> = parsed?
+            .generations_to_delete(1, None, ndt!("2023-07-01 00:00:00"), &pinned)
+            .into();
+
+        assert_eq!(
+            filtered,
+            (662..=671).filter(|id| *id != 665).collect::<BTreeSet<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::same(661..=681, 661..=681, 0..0)]
+    #[case::deleted(661..=681, 671..=681, 0..0)]
+    #[case::appeared(661..=681, 671..=690, 682..=690)]
+    #[case::disjoint(1..=5, 10..=15, 10..=15)]
+    fn test_difference<R, S, T>(#[case] before: R, #[case] after: S, #[case] ids: T)
+    where
+        R: IntoIterator<Item = u32>,
+        S: IntoIterator<Item = u32>,
+        T: IntoIterator<Item = u32>,
+    {
+        let set = |ids: &mut dyn Iterator<Item = u32>| {
+            ids.map(|id| Generation {
+                id,
+                date: ndt!("2020-01-01 00:00:00"),
+                current: false,
+            })
+            .collect::<GenerationSet>()
+        };
+
+        let before = set(&mut before.into_iter());
+        let after = set(&mut after.into_iter());
+        let new: BTreeSet<u32> = after.difference(&before).into();
+
+        assert_eq!(new, ids.into_iter().collect());
+    }
+
     #[rstest]
     #[case(661, ndt!("2023-06-01 08:10:47"), false)]
     #[case(666, ndt!("2023-06-08 07:42:25"), false)]
@@ -398,4 +1273,245 @@ mod test {
     fn test_empty(#[case] set: GenerationSet, #[case] empty: bool) {
         assert_eq!(set.is_empty(), empty);
     }
+
+    #[rstest]
+    #[case::none(&[], None)]
+    #[case::single(&[3], Some(3))]
+    #[case::two(&[2, 4], Some(4))]
+    #[case::all(&[1, 2, 3, 4, 5], Some(5))]
+    fn test_current_is_unique(#[case] marked: &[u32], #[case] expected: Option<u32>) {
+        let set = (1..=5)
+            .map(|id| Generation {
+                id,
+                date: ndt!("2020-01-01 00:00:00"),
+                current: marked.contains(&id),
+            })
+            .collect::<GenerationSet>();
+
+        let current = set
+            .iter()
+            .filter(|g| g.current)
+            .map(|g| g.id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(current, expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(set.len(), 5);
+    }
+
+    #[rstest]
+    #[case::all_old(670, ndt!("2023-07-16 00:00:00"), 661..=669)]
+    #[case::active_kept(670, ndt!("2023-06-15 00:00:00"), 661..=667)]
+    #[case::current_kept(700, ndt!("2023-07-20 00:00:00"), 661..=680)]
+    #[case::none(661, ndt!("2023-07-20 00:00:00"), 0..0)]
+    fn test_generations_before<R>(
+        parsed: Result<GenerationSet>,
+        #[case] id: u32,
+        #[case] date: NaiveDateTime,
+        #[case] ids: R,
+    ) -> Result<()>
+    where
+        R: RangeBounds<u32> + IntoIterator<Item = u32>,
+    {
+        let before: BTreeSet<u32> = parsed?.generations_before(id, date).into();
+
+        assert_eq!(before, ids.into_iter().collect());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::linear(&[(1, 1, false), (2, 2, false), (3, 3, true)], &[])]
+    #[case::rolled_back(&[(1, 1, false), (2, 2, true), (3, 3, false)], &[2])]
+    #[case::dated_back(&[(1, 1, false), (2, 5, false), (3, 3, false), (4, 6, true)], &[3])]
+    #[case::gap(&[(1, 4, false), (5, 2, true)], &[5])]
+    fn test_rollback_targets(#[case] generations: &[(u32, u32, bool)], #[case] expected: &[u32]) {
+        let set = generations
+            .iter()
+            .map(|&(id, day, current)| Generation {
+                id,
+                date: NaiveDate::from_ymd_opt(2023, 6, day)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap(),
+                current,
+            })
+            .collect::<GenerationSet>();
+
+        let targets = set.rollback_targets();
+
+        assert_eq!(targets.iter().map(|g| g.id).collect::<Vec<_>>(), expected);
+        for generation in &set {
+            let expected = match expected.contains(&generation.id) {
+                true => Origin::RollbackTarget,
+                false => Origin::Rebuild,
+            };
+            assert_eq!(set.origin(generation.id), Some(expected));
+        }
+        assert_eq!(set.origin(100), None);
+    }
+
+    #[rstest]
+    #[case::empty(0, 3, &[])]
+    #[case::exact(6, 3, &[3, 3])]
+    #[case::remainder(7, 3, &[3, 3, 1])]
+    #[case::single(2, 5, &[2])]
+    fn test_batches(#[case] count: u32, #[case] size: usize, #[case] lens: &[usize]) {
+        let generations = (1..=count)
+            .map(|id| Generation {
+                id,
+                date: ndt!("2020-01-01 00:00:00"),
+                current: false,
+            })
+            .collect::<GenerationSet>();
+
+        let batches = generations.batches(size);
+
+        assert_eq!(batches.iter().map(|b| b.len()).collect::<Vec<_>>(), lens);
+        let ids = batches
+            .iter()
+            .flat_map(|b| b.iter().map(|g| g.id))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (1..=count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delete_args_are_sorted_and_unique() {
+        // the same id with different dates is one argument
+        let generations = [(12, "2020-01-02 00:00:00"), (3, "2020-01-01 00:00:00")]
+            .into_iter()
+            .chain([(12, "2020-01-03 00:00:00")])
+            .map(|(id, date)| Generation {
+                id,
+                date: ndt!(date),
+                current: false,
+            })
+            .collect::<GenerationSet>();
+
+        assert_eq!(generations.to_delete_args(), ["3", "12"]);
+        assert!(GenerationSet::default().to_delete_args().is_empty());
+    }
+
+    #[rstest]
+    #[case::keep_last("+5")]
+    #[case::days("30d")]
+    #[case::old("old")]
+    #[case::negative("-1")]
+    #[case::empty("")]
+    #[case::spaces(" 3")]
+    #[case::overflow("4294967296")]
+    fn delete_args_rejected(#[case] arg: &str) {
+        assert!(GenerationSet::parse_delete_args([arg]).is_err());
+    }
+
+    fn listing(generations: &[(u32, &str, bool)]) -> GenerationSet {
+        generations
+            .iter()
+            .map(|&(id, date, current)| Generation {
+                id,
+                date: ndt!(date),
+                current,
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case::agree(
+        listing(&[(1, "2023-06-01 00:00:00", false), (2, "2023-06-02 00:00:00", true)]),
+        &[]
+    )]
+    #[case::missing(listing(&[(2, "2023-06-02 00:00:00", true)]), &[1])]
+    #[case::date(
+        listing(&[(1, "2023-06-01 00:00:01", false), (2, "2023-06-02 00:00:00", true)]),
+        &[1]
+    )]
+    #[case::current(
+        listing(&[(1, "2023-06-01 00:00:00", true), (2, "2023-06-02 00:00:00", false)]),
+        &[1, 2]
+    )]
+    #[case::additional(
+        listing(&[
+            (1, "2023-06-01 00:00:00", false),
+            (2, "2023-06-02 00:00:00", true),
+            (3, "2023-06-03 00:00:00", false),
+        ]),
+        &[3]
+    )]
+    fn merge_listings(#[case] other: GenerationSet, #[case] conflicts: &[u32]) {
+        let nix_env = listing(&[
+            (1, "2023-06-01 00:00:00", false),
+            (2, "2023-06-02 00:00:00", true),
+        ]);
+
+        let (merged, found) = GenerationSet::merge_listings(&[nix_env.clone(), other.clone()]);
+
+        assert_eq!(found.iter().map(|c| c.id).collect::<Vec<_>>(), conflicts);
+        assert_eq!(merged.len(), nix_env.len().max(other.len()));
+        assert_eq!(merged.get(1), nix_env.get(1));
+    }
+
+    #[test]
+    fn listing_conflict_display() {
+        let (_, conflicts) = GenerationSet::merge_listings(&[
+            listing(&[(1, "2023-06-01 00:00:00", true)]),
+            listing(&[(1, "2023-06-01 00:00:00", false)]),
+            GenerationSet::default(),
+        ]);
+
+        assert_eq!(
+            conflicts[0].to_string(),
+            "generation 1: 2023-06-01 00:00:00 (current), 2023-06-01 00:00:00, missing"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_keeps_a_single_current() -> Result<()> {
+        let json = r#"[
+            {"id": 2, "date": "2023-06-02 08:10:47", "current": true},
+            {"id": 1, "date": "2023-06-01 08:10:47", "current": true}
+        ]"#;
+
+        let generations: GenerationSet = serde_json::from_str(json)?;
+
+        let current: Vec<_> = generations
+            .iter()
+            .filter(|g| g.current)
+            .map(|g| g.id)
+            .collect();
+        assert_eq!(current, [2]);
+        assert_eq!(
+            serde_json::to_string(&generations)?,
+            r#"[{"id":1,"date":"2023-06-01 08:10:47","current":false},{"id":2,"date":"2023-06-02 08:10:47","current":true}]"#
+        );
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn merged_listings_agree_with_themselves(ids in prop::collection::btree_set(1..100u32, 0..20)) {
+            let generations = ids
+                .iter()
+                .map(|&id| Generation { id, date: ndt!("2020-01-01 00:00:00"), current: false })
+                .collect::<GenerationSet>();
+
+            let (merged, conflicts) = GenerationSet::merge_listings(&[generations.clone(), generations.clone()]);
+            prop_assert_eq!(merged, generations);
+            prop_assert!(conflicts.is_empty());
+        }
+
+        #[test]
+        fn delete_args_roundtrip(ids in prop::collection::btree_set(any::<u32>(), 0..50)) {
+            let generations = ids
+                .iter()
+                .map(|&id| Generation {
+                    id,
+                    date: ndt!("2020-01-01 00:00:00"),
+                    current: false,
+                })
+                .collect::<GenerationSet>();
+
+            let parsed = GenerationSet::parse_delete_args(generations.to_delete_args()).unwrap();
+            prop_assert_eq!(parsed, ids);
+        }
+    }
 }
@@ -1,31 +1,236 @@
 use std::{
-    fmt::Debug,
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt::{self, Debug, Display},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
 use chrono::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::BoxError,
+    generation::Generation,
+    generation_set::GenerationSet,
+    generation_set::{GenerationOrder, Thinning},
+    planning::{self, Decision, Exceptions, Explanation, Reason, Rules},
+    policy::KeepSinceDerivation,
+    references::Reference,
+    report::ProfileReport,
+};
+
+use self::state::{Discovered, Executed, Listed, Planned, State, Verified};
+
+/// Identifies a [Job] within a single janitor run.
+///
+/// Every span, log line and report entry belonging to a job carries its id,
+/// so that the output of jobs running concurrently can be told apart.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::Job;
+///
+/// let first = Job::new("/", Default::default(), 0);
+/// let second = Job::new("/", Default::default(), 0);
+/// assert_ne!(first.id(), second.id());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+impl JobId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job-{}", self.0)
+    }
+}
+
+/// The states a [Job] passes through, in order.
+///
+/// ```text
+/// Discovered → Listed → Planned → Executed → Verified
+/// ```
+///
+/// Each state carries the data gathered up to that point, the transitions
+/// are the methods of [Job] available in the respective state.
+pub mod state {
+    use std::collections::BTreeMap;
+
+    #[cfg(feature = "serde")]
+    use serde::{Deserialize, Serialize};
+
+    use crate::{generation_set::GenerationSet, references::Reference};
+
+    /// A state of a [Job](super::Job).
+    pub trait State {
+        /// The name of the state, as used in [Timing](super::Timing)s and
+        /// errors.
+        const NAME: &'static str;
+    }
+
+    /// The profile has been found, nothing is known about its generations
+    /// yet.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Discovered;
+
+    /// The generations of the profile have been listed.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Listed {
+        /// All generations of the profile.
+        pub generations: GenerationSet,
+
+        /// The references to generations from outside of the profile, by
+        /// generation id.
+        pub referenced: BTreeMap<u32, Vec<Reference>>,
+    }
+
+    /// The generations to delete have been determined by the retention
+    /// policy.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Planned {
+        /// All generations of the profile.
+        pub listed: GenerationSet,
+
+        /// The generations to delete.
+        pub to_delete: GenerationSet,
+    }
+
+    /// Generations have been deleted.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Executed {
+        /// All generations of the profile, as listed before the deletion.
+        pub listed: GenerationSet,
+
+        /// The generations that have actually been deleted. This might be
+        /// fewer than planned, e.g. when freeing space stopped early.
+        pub deleted: GenerationSet,
+    }
+
+    /// The profile has been listed again after the deletion.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Verified {
+        /// The generations that have been deleted.
+        pub deleted: GenerationSet,
+
+        /// Generations that appeared while the janitor was running.
+        pub appeared: GenerationSet,
+    }
+
+    impl State for Discovered {
+        const NAME: &'static str = "discovered";
+    }
+
+    impl State for Listed {
+        const NAME: &'static str = "listed";
+    }
+
+    impl State for Planned {
+        const NAME: &'static str = "planned";
+    }
+
+    impl State for Executed {
+        const NAME: &'static str = "executed";
+    }
+
+    impl State for Verified {
+        const NAME: &'static str = "verified";
+    }
+}
+
+/// How long a [Job] spent in one of its states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// The name of the state, see [State::NAME].
+    pub state: &'static str,
+
+    /// The time spent in the state, up to the transition into the next one.
+    pub duration: Duration,
+}
 
 /// Represents a Janitor job.
 ///
 /// This bundles together the data needed to execute a janitor job for a
-/// particular profile path.
-#[derive(Debug, PartialEq, Eq)]
-pub struct Job<T> {
+/// particular profile path. A job moves through the states of the [state]
+/// module, which one it is in is tracked by the type parameter `S`. The
+/// time spent in each state is recorded as [Timing]s.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::NaiveDateTime;
+/// use janitor::{Generation, GenerationSet, Job};
+///
+/// let generations = Generation::parse_many("1 2023-06-01 08:10:47\n2 2023-06-02 08:10:47 (current)")?;
+/// let keep_since = NaiveDateTime::parse_from_str("2023-07-01 00:00:00", "%Y-%m-%d %H:%M:%S")?;
+///
+/// let job = Job::new("/some/profile", keep_since, 1)
+///     .listed(GenerationSet::from(generations))
+///     .plan();
+/// assert_eq!(job.state().to_delete.len(), 1);
+///
+/// let deleted = job.state().to_delete.clone();
+/// let report = job.executed(deleted).verify(None).into_report();
+/// assert_eq!(report.deleted.len(), 1);
+/// assert_eq!(report.timings.len(), 4);
+/// # Ok::<(), eyre::Report>(())
+/// ```
+///
+/// With the `serde` feature, a job is serialized with its settings and its
+/// state, e.g. to hand a plan to another process. The [JobId], the [Timing]s,
+/// the explanation of the plan and the derivation of `keep_since` belong to
+/// the run that made them and are left out, a deserialized job gets a fresh
+/// id.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Job<S> {
+    #[cfg_attr(feature = "serde", serde(skip, default = "JobId::next"))]
+    id: JobId,
     path: PathBuf,
+    #[cfg_attr(feature = "serde", serde(with = "crate::generation::date_format"))]
     keep_since: NaiveDateTime,
     keep_at_least: usize,
-    data: T,
+    keep_at_most: Option<usize>,
+    before_generation: Option<u32>,
+    protect_rollback_targets: bool,
+    order_by: GenerationOrder,
+    thinning: Thinning,
+    exceptions: Exceptions,
+    #[cfg_attr(feature = "serde", serde(skip, default = "Instant::now"))]
+    entered: Instant,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    timings: Vec<Timing>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    explanation: Option<Explanation>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    derivation: Option<KeepSinceDerivation>,
+    state: S,
 }
 
-impl<T> Job<T> {
-    /// Creates a new Job instance.
+impl Job<Discovered> {
+    /// Creates a new Job instance in the [Discovered] state.
+    ///
+    /// Each job gets a fresh [JobId] assigned.
     ///
     /// # Arguments
     ///
-    /// * `path` - The path to the profile to clean up  
+    /// * `path` - The path to the profile to clean up
     /// * `keep_since` - The cutoff date for keeping generations
-    /// * `keep_at_least` - The minimum number of generations to keep  
-    /// * `data` - The data for this job
+    /// * `keep_at_least` - The minimum number of generations to keep
     ///
     /// # Examples
     ///
@@ -38,22 +243,248 @@ impl<T> Job<T> {
     ///     PathBuf::from("/some/path"),
     ///     NaiveDateTime::from_timestamp(0, 0),
     ///     5,
-    ///     "data".to_string(),
     /// );
     /// ```
-    pub fn new<P: AsRef<Path>>(
-        path: P,
-        keep_since: NaiveDateTime,
-        keep_at_least: usize,
-        data: T,
-    ) -> Self {
+    pub fn new<P: AsRef<Path>>(path: P, keep_since: NaiveDateTime, keep_at_least: usize) -> Self {
         Self {
+            id: JobId::next(),
             path: path.as_ref().to_path_buf(),
             keep_since,
             keep_at_least,
-            data,
+            keep_at_most: None,
+            before_generation: None,
+            protect_rollback_targets: false,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+            exceptions: Exceptions::default(),
+            entered: Instant::now(),
+            timings: Vec::new(),
+            explanation: None,
+            derivation: None,
+            state: Discovered,
+        }
+    }
+
+    /// Returns a [JobBuilder], validating the settings of the job before
+    /// creating it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{Job, JobBuilderError};
+    ///
+    /// let keep_since = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+    ///
+    /// let job = Job::builder()
+    ///     .path("/nix/var/nix/profiles/system")
+    ///     .keep_since(keep_since)
+    ///     .keep_at_least(3)
+    ///     .build()?;
+    /// assert_eq!(job.keep_at_least(), 3);
+    ///
+    /// let error = Job::builder()
+    ///     .path("relative/profile")
+    ///     .keep_since(keep_since)
+    ///     .build()
+    ///     .unwrap_err();
+    /// assert!(matches!(error, JobBuilderError::RelativePath(_)));
+    /// # Ok::<(), JobBuilderError>(())
+    /// ```
+    pub fn builder() -> JobBuilder {
+        JobBuilder::default()
+    }
+
+    /// Records the `generations` listed for the profile.
+    pub fn listed(self, generations: GenerationSet) -> Job<Listed> {
+        self.advance(Listed {
+            generations,
+            referenced: BTreeMap::new(),
+        })
+    }
+}
+
+impl Job<Listed> {
+    /// Records the generations `referenced` from outside of the profile, see
+    /// [Referrers::of_generations](crate::references::Referrers::of_generations).
+    pub fn referenced(mut self, referenced: BTreeMap<u32, Vec<Reference>>) -> Self {
+        self.state.referenced = referenced;
+        self
+    }
+
+    /// Determines the generations to delete according to the retention
+    /// policy of the job, see [planning::decide].
+    ///
+    /// If `keep_at_least` covers all generations of the profile, nothing can
+    /// be deleted, which is noted in the log. Generations that look like
+    /// [rollback targets](crate::Origin::RollbackTarget), generations
+    /// [referenced](Job::referenced) from outside of the profile and
+    /// [pinned](JobBuilder::pinned) generations are noted in the log as
+    /// well.
+    pub fn plan(self) -> Job<Planned> {
+        let total = self.state.generations.len();
+        if total > 0 && self.keep_at_least >= total {
+            tracing::info!(
+                job_id = %self.id,
+                keep_at_least = self.keep_at_least,
+                generations = total,
+                "keep-at-least covers all generations, nothing will be deleted"
+            );
+        }
+
+        let decisions = self.decide();
+        for decision in &decisions {
+            let generation = decision.generation.id;
+            for reason in &decision.reasons {
+                match reason {
+                    Reason::RollbackTarget { protected } => tracing::info!(
+                        job_id = %self.id,
+                        generation,
+                        protected,
+                        "generation looks like a rollback target"
+                    ),
+                    Reason::Referenced(reference) => tracing::info!(
+                        job_id = %self.id,
+                        generation,
+                        %reference,
+                        "keeping generation referenced from outside of the profile"
+                    ),
+                    Reason::Pinned => tracing::info!(
+                        job_id = %self.id,
+                        generation,
+                        "keeping pinned generation"
+                    ),
+                    _ => {}
+                }
+            }
+        }
+
+        let listed = self.state.generations.clone();
+        let to_delete = planning::to_delete(&decisions);
+        let explanation = planning::explain(&decisions, &self.rules());
+
+        let mut job = self.advance(Planned { listed, to_delete });
+        job.explanation = explanation;
+        job
+    }
+
+    /// Explains why [Job::plan] keeps or deletes the generation with the id
+    /// `id`.
+    ///
+    /// Returns `None` if the profile has no such generation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Generation, GenerationSet, Job, Reason};
+    ///
+    /// let generations = Generation::parse_many(
+    ///     "1 2023-06-01 00:00:00\n\
+    ///      2 2023-06-02 00:00:00 (current)",
+    /// )?
+    /// .into_iter()
+    /// .collect::<GenerationSet>();
+    /// let job = Job::new("/p", Default::default(), 1).listed(generations);
+    ///
+    /// let decision = job.explain(2).unwrap();
+    /// assert!(!decision.delete);
+    /// assert_eq!(decision.reasons[0], Reason::Recent(1));
+    /// assert!(job.explain(3).is_none());
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    pub fn explain(&self, id: u32) -> Option<Decision> {
+        self.decide()
+            .into_iter()
+            .find(|decision| decision.generation.id == id)
+    }
+
+    /// Tightens the retention policy of the job, see [planning::tighten],
+    /// returning the generation it no longer keeps.
+    pub fn tighten(&mut self, floor: usize) -> Option<Generation> {
+        let tightening = planning::tighten(&self.state.generations, &self.rules(), floor)?;
+        self.keep_at_least = tightening.rules.keep_at_least;
+        self.keep_since = tightening.rules.keep_since;
+
+        tracing::info!(
+            job_id = %self.id,
+            generation = tightening.released.id,
+            keep_at_least = self.keep_at_least,
+            keep_since = %self.keep_since,
+            "tightened the retention policy"
+        );
+
+        Some(tightening.released)
+    }
+
+    fn decide(&self) -> Vec<Decision> {
+        planning::decide(
+            &self.state.generations,
+            &self.state.referenced,
+            &self.exceptions,
+            &self.rules(),
+        )
+    }
+}
+
+impl Job<Planned> {
+    /// Records the generations that have actually been `deleted`.
+    pub fn executed(self, deleted: GenerationSet) -> Job<Executed> {
+        let listed = self.state.listed.clone();
+
+        self.advance(Executed { listed, deleted })
+    }
+}
+
+impl Job<Executed> {
+    /// Compares the generations `relisted` after the deletion to those listed
+    /// before, to find generations created while the janitor was running.
+    ///
+    /// If the profile could not be listed again, pass `None`, no generation
+    /// will be considered as appeared then.
+    pub fn verify(self, relisted: Option<&GenerationSet>) -> Job<Verified> {
+        let appeared = relisted
+            .map(|relisted| relisted.difference(&self.state.listed))
+            .unwrap_or_default();
+        let deleted = self.state.deleted.clone();
+
+        self.advance(Verified { deleted, appeared })
+    }
+}
+
+impl Job<Verified> {
+    /// Turns the finished job into the report of its profile.
+    pub fn into_report(self) -> ProfileReport {
+        let keep_since = self.keep_since_derivation();
+
+        ProfileReport {
+            job_id: self.id,
+            path: self.path,
+            deleted: self.state.deleted,
+            appeared: self.state.appeared,
+            keep_since,
+            timings: self.timings,
+            explanation: self.explanation,
         }
     }
+}
+
+impl<S> Job<S> {
+    /// Returns the id of this job.
+    ///
+    /// The id is retained through all state transitions.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{GenerationSet, Job};
+    ///
+    /// let job = Job::new("/", Default::default(), 0);
+    /// let id = job.id();
+    /// assert_eq!(job.listed(GenerationSet::default()).id(), id);
+    /// ```
+    pub fn id(&self) -> JobId {
+        self.id
+    }
 
     /// Returns a reference to the path field.
     ///
@@ -63,7 +494,7 @@ impl<T> Job<T> {
     /// use std::path::PathBuf;
     /// use janitor::Job;
     ///
-    /// let job = Job::new(PathBuf::new(), Default::default(), 0, ());  
+    /// let job = Job::new(PathBuf::new(), Default::default(), 0);
     /// assert_eq!(job.path(), &PathBuf::new());
     /// ```
     pub fn path(&self) -> &PathBuf {
@@ -81,13 +512,24 @@ impl<T> Job<T> {
     /// use chrono::NaiveDateTime;
     /// use janitor::Job;
     ///
-    /// let job = Job::new("/", NaiveDateTime::from_timestamp(0, 0), 0, ());
+    /// let job = Job::new("/", NaiveDateTime::from_timestamp(0, 0), 0);
     /// assert_eq!(job.keep_since(), NaiveDateTime::from_timestamp(0, 0));
     /// ```
     pub fn keep_since(&self) -> NaiveDateTime {
         self.keep_since
     }
 
+    /// Returns how the keep_since date of this job has been derived, if the
+    /// job has been built from a derivation, see
+    /// [JobBuilder::keep_since_derived].
+    ///
+    /// The derivation is adjusted to the current keep_since date, e.g. after
+    /// [Job::tighten].
+    pub fn keep_since_derivation(&self) -> Option<KeepSinceDerivation> {
+        self.derivation
+            .map(|derivation| derivation.adjusted_to(self.keep_since))
+    }
+
     /// Returns the minimum number of generations to keep.
     ///
     /// This is the lower bound for how many recent generations should be
@@ -98,7 +540,7 @@ impl<T> Job<T> {
     /// ```
     /// use janitor::Job;
     ///
-    /// let job = Job::new("/", Default::default(), 5, ());
+    /// let job = Job::new("/", Default::default(), 5);
     /// let min = job.keep_at_least();
     /// assert_eq!(min, 5);
     /// ```
@@ -106,102 +548,904 @@ impl<T> Job<T> {
         self.keep_at_least
     }
 
-    /// Returns a reference to the data field.
-    ///
-    /// The data can be any generic type T.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use janitor::Job;
-    ///
-    /// let job = Job::new("/", Default::default(), 0, "data".to_string());
-    /// let data = job.data();
-    /// assert_eq!(data, &"data".to_string());
-    /// ```
-    pub fn data(&self) -> &T {
-        &self.data
+    /// Returns the maximum number of recent generations to keep, if any.
+    pub fn keep_at_most(&self) -> Option<usize> {
+        self.keep_at_most
     }
 
-    /// Replaces the data in the Job with new data of type `U` and returns a new Job instance.
-    ///
-    /// # Arguments
-    ///
-    /// * `data` - The new data to assign to the Job. This can be any type `U`.
-    ///
-    /// # Returns
-    ///  
-    /// A new `Job<U>` instance with the same configuration but the new `data` value.
+    /// Returns the id below which generations are deleted regardless of
+    /// `keep_at_least`, if any.
+    pub fn before_generation(&self) -> Option<u32> {
+        self.before_generation
+    }
+
+    /// Returns whether generations looking like rollback targets are kept.
+    pub fn protect_rollback_targets(&self) -> bool {
+        self.protect_rollback_targets
+    }
+
+    /// Returns the order deciding which generations are the most recent
+    /// ones.
+    pub fn order_by(&self) -> GenerationOrder {
+        self.order_by
+    }
+
+    /// Returns how many days, weeks and months the last generation is kept
+    /// of.
+    pub fn thinning(&self) -> Thinning {
+        self.thinning
+    }
+
+    /// Returns the generations that are kept or deleted no matter what the
+    /// retention policy says.
+    pub fn exceptions(&self) -> &Exceptions {
+        &self.exceptions
+    }
+
+    /// Returns the rules deciding which generations of the profile are
+    /// deleted, see [planning::decide].
+    pub fn rules(&self) -> Rules {
+        Rules {
+            keep_at_least: self.keep_at_least,
+            keep_at_most: self.keep_at_most,
+            keep_since: self.keep_since,
+            before_generation: self.before_generation,
+            protect_rollback_targets: self.protect_rollback_targets,
+            order_by: self.order_by,
+            thinning: self.thinning,
+        }
+    }
+
+    /// Returns the current state of the job, including the data gathered so
+    /// far.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Returns the time spent in each of the previous states, in order.
+    pub fn timings(&self) -> &[Timing] {
+        &self.timings
+    }
+}
+
+impl<S: State> Job<S> {
+    /// Creates the error for the transition out of the current state having
+    /// failed with `error`.
     ///
     /// # Examples
     ///
     /// ```
     /// use janitor::Job;
     ///
-    /// let original = Job::new("/", Default::default(), 0, 1);
-    /// let updated = original.set_data("new data");
-    ///
-    /// assert_eq!(updated.data(), &"new data");
+    /// let job = Job::new("/some/profile", Default::default(), 0);
+    /// let error = job.fail(std::io::Error::other("nix-env not found"));
+    /// assert_eq!(error.state, "discovered");
+    /// assert!(error.to_string().contains("nix-env not found"));
     /// ```
-    pub fn set_data<U>(&self, data: U) -> Job<U> {
-        Job {
+    pub fn fail<E: Into<BoxError>>(&self, error: E) -> JobError {
+        JobError {
+            job_id: self.id,
             path: self.path.clone(),
+            state: S::NAME,
+            elapsed: self.entered.elapsed(),
+            source: error.into(),
+        }
+    }
+
+    fn advance<U>(mut self, state: U) -> Job<U> {
+        self.timings.push(Timing {
+            state: S::NAME,
+            duration: self.entered.elapsed(),
+        });
+
+        Job {
+            id: self.id,
+            path: self.path,
             keep_since: self.keep_since,
             keep_at_least: self.keep_at_least,
-            data,
+            keep_at_most: self.keep_at_most,
+            before_generation: self.before_generation,
+            protect_rollback_targets: self.protect_rollback_targets,
+            order_by: self.order_by,
+            thinning: self.thinning,
+            exceptions: self.exceptions,
+            entered: Instant::now(),
+            timings: self.timings,
+            explanation: self.explanation,
+            derivation: self.derivation,
+            state,
+        }
+    }
+}
+
+/// Builds a [Job] in the [Discovered] state, validating its settings.
+///
+/// Created by [Job::builder].
+#[derive(Debug, Clone)]
+pub struct JobBuilder {
+    path: Option<PathBuf>,
+    keep_since: Option<NaiveDateTime>,
+    keep_at_least: usize,
+    keep_at_most: Option<usize>,
+    by_age_only: bool,
+    before_generation: Option<u32>,
+    protect_rollback_targets: bool,
+    order_by: GenerationOrder,
+    thinning: Thinning,
+    exceptions: Exceptions,
+    now: Option<NaiveDateTime>,
+    derivation: Option<KeepSinceDerivation>,
+}
+
+impl Default for JobBuilder {
+    fn default() -> Self {
+        Self {
+            path: None,
+            keep_since: None,
+            keep_at_least: 1,
+            keep_at_most: None,
+            by_age_only: false,
+            before_generation: None,
+            protect_rollback_targets: false,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+            exceptions: Exceptions::default(),
+            now: None,
+            derivation: None,
         }
     }
 }
 
+impl JobBuilder {
+    /// Sets the path of the profile to clean up. Must be absolute.
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the cutoff date for keeping generations. Must not be in the
+    /// future.
+    pub fn keep_since(mut self, keep_since: NaiveDateTime) -> Self {
+        self.keep_since = Some(keep_since);
+        self
+    }
+
+    /// Sets the cutoff date for keeping generations like
+    /// [JobBuilder::keep_since] to the one of the `derivation`, which is
+    /// reported along with the job.
+    pub fn keep_since_derived(mut self, derivation: KeepSinceDerivation) -> Self {
+        self.keep_since = Some(derivation.keep_since);
+        self.derivation = Some(derivation);
+        self
+    }
+
+    /// Sets the minimum number of generations to keep, defaults to 1.
+    pub fn keep_at_least(mut self, keep_at_least: usize) -> Self {
+        self.keep_at_least = keep_at_least;
+        self
+    }
+
+    /// Sets the maximum number of recent generations to keep, even if they
+    /// have been active since `keep_since`, defaults to no limit. Must not
+    /// be below `keep_at_least` or 1.
+    pub fn keep_at_most(mut self, keep_at_most: Option<usize>) -> Self {
+        self.keep_at_most = keep_at_most;
+        self
+    }
+
+    /// Allows retaining generations by their age only, which permits a
+    /// `keep_at_least` of 0.
+    pub fn by_age_only(mut self, by_age_only: bool) -> Self {
+        self.by_age_only = by_age_only;
+        self
+    }
+
+    /// Deletes all generations with an id below `id`, unless they are current
+    /// or have been active since `keep_since`, even if `keep_at_least` would
+    /// keep them.
+    pub fn before_generation(mut self, id: Option<u32>) -> Self {
+        self.before_generation = id;
+        self
+    }
+
+    /// Keeps the generations that look like the profile has been rolled back
+    /// to them, see [Origin::RollbackTarget](crate::Origin::RollbackTarget).
+    pub fn protect_rollback_targets(mut self, protect: bool) -> Self {
+        self.protect_rollback_targets = protect;
+        self
+    }
+
+    /// Sets the order deciding which generations are the `keep_at_least`
+    /// most recent ones, defaults to [GenerationOrder::Id].
+    pub fn order_by(mut self, order: GenerationOrder) -> Self {
+        self.order_by = order;
+        self
+    }
+
+    /// Keeps the last generation of the most recent days, weeks and months,
+    /// see [GenerationSet::thin], defaults to keeping none of them.
+    pub fn thinning(mut self, thinning: Thinning) -> Self {
+        self.thinning = thinning;
+        self
+    }
+
+    /// Keeps the generations with the ids in `pinned`, no matter what the
+    /// retention policy says about them.
+    pub fn pinned(mut self, pinned: BTreeSet<u32>) -> Self {
+        self.exceptions.pinned = pinned;
+        self
+    }
+
+    /// Deletes the generations with the ids in `always_delete`, no matter
+    /// what the retention policy says about them, unless they are kept for
+    /// their own safety, see [Exceptions::always_delete].
+    pub fn always_delete(mut self, always_delete: BTreeSet<u32>) -> Self {
+        self.exceptions.always_delete = always_delete;
+        self
+    }
+
+    /// Sets the point in time `keep_since` is checked against, defaults to
+    /// the current time in UTC.
+    pub fn now(mut self, now: NaiveDateTime) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /// Validates the settings and creates the [Job].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [JobBuilderError] describing the first invalid setting.
+    pub fn build(self) -> Result<Job<Discovered>, JobBuilderError> {
+        let path = self.path.ok_or(JobBuilderError::Missing("path"))?;
+        let keep_since = self
+            .keep_since
+            .ok_or(JobBuilderError::Missing("keep_since"))?;
+        let now = self.now.unwrap_or_else(|| Utc::now().naive_utc());
+
+        if !path.is_absolute() {
+            return Err(JobBuilderError::RelativePath(path));
+        }
+
+        if keep_since > now {
+            return Err(JobBuilderError::KeepSinceInFuture { keep_since, now });
+        }
+
+        if self.keep_at_least == 0 && !self.by_age_only {
+            return Err(JobBuilderError::NothingKept);
+        }
+
+        if let Some(keep_at_most) = self.keep_at_most {
+            if keep_at_most < self.keep_at_least.max(1) {
+                return Err(JobBuilderError::KeepAtMostBelowKeepAtLeast {
+                    keep_at_least: self.keep_at_least,
+                    keep_at_most,
+                });
+            }
+        }
+
+        Ok(Job {
+            keep_at_most: self.keep_at_most,
+            before_generation: self.before_generation,
+            protect_rollback_targets: self.protect_rollback_targets,
+            order_by: self.order_by,
+            thinning: self.thinning,
+            exceptions: self.exceptions,
+            derivation: self.derivation,
+            ..Job::new(path, keep_since, self.keep_at_least)
+        })
+    }
+}
+
+/// The settings passed to a [JobBuilder] are invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobBuilderError {
+    /// A required setting has not been given.
+    Missing(&'static str),
+
+    /// The path of the profile is not absolute.
+    RelativePath(PathBuf),
+
+    /// The cutoff date lies in the future, which would doom every generation
+    /// not protected by `keep_at_least`.
+    KeepSinceInFuture {
+        /// The cutoff date.
+        keep_since: NaiveDateTime,
+
+        /// The time it has been checked against.
+        now: NaiveDateTime,
+    },
+
+    /// `keep_at_least` is 0 without retaining by age only.
+    NothingKept,
+
+    /// `keep_at_most` is below `keep_at_least` or 0.
+    KeepAtMostBelowKeepAtLeast {
+        /// The minimum number of generations to keep.
+        keep_at_least: usize,
+
+        /// The maximum number of generations to keep.
+        keep_at_most: usize,
+    },
+}
+
+impl Display for JobBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(setting) => write!(f, "{setting} has not been set"),
+            Self::RelativePath(path) => {
+                write!(f, "profile path {} is not absolute", path.display())
+            }
+            Self::KeepSinceInFuture { keep_since, now } => {
+                write!(f, "keep_since {keep_since} lies in the future of {now}")
+            }
+            Self::NothingKept => {
+                f.write_str("keep_at_least must be at least 1 unless retaining by age only")
+            }
+            Self::KeepAtMostBelowKeepAtLeast {
+                keep_at_least,
+                keep_at_most,
+            } => write!(
+                f,
+                "keep_at_most {keep_at_most} must be at least 1 and not below \
+                 keep_at_least {keep_at_least}"
+            ),
+        }
+    }
+}
+
+impl Error for JobBuilderError {}
+
+/// A [Job] failed to transition out of one of its states.
+#[derive(Debug)]
+pub struct JobError {
+    /// The id of the failed job.
+    pub job_id: JobId,
+
+    /// The path of the profile of the failed job.
+    pub path: PathBuf,
+
+    /// The state the job has been in when failing, see [State::NAME].
+    pub state: &'static str,
+
+    /// The time spent in the state until the failure.
+    pub elapsed: Duration,
+
+    source: BoxError,
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{id} ({path}) failed after being {state} for {elapsed:?}: {source}",
+            id = self.job_id,
+            path = self.path.display(),
+            state = self.state,
+            elapsed = self.elapsed,
+            source = self.source,
+        )
+    }
+}
+
+impl Error for JobError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::Path;
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        path::Path,
+    };
 
     use chrono::prelude::*;
     use proptest::prelude::*;
 
+    use crate::{
+        generation::Generation,
+        generation_set::GenerationSet,
+        references::{Reference, Referrer},
+    };
+
+    use rstest::rstest;
+
+    use super::{Job, JobBuilderError, Reason};
+
     proptest! {
         #[test]
         fn path_remains_unchanged(path in "(/[a-z]+)+") {
-            let job = super::Job::new(&path, Default::default(), 0, ());
+            let job = Job::new(&path, Default::default(), 0);
             prop_assert_eq!(job.path().as_path(), Path::new(&path));
         }
 
         #[test]
         fn keep_since_remains_unchanged(timestamp in 0..100_000_000i64) {
             let date = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap();
-            let job = super::Job::new("/", date, 0, ());
+            let job = Job::new("/", date, 0);
             prop_assert_eq!(job.keep_since(), date);
         }
 
         #[test]
         fn keep_at_least_remains_unchanged(min in 0..100usize) {
-            let job = super::Job::new("/", Default::default(), min, ());
+            let job = Job::new("/", Default::default(), min);
             prop_assert_eq!(job.keep_at_least(), min);
         }
 
         #[test]
-        fn data_remains_unchanged(data in "[a-z]+") {
-            let job = super::Job::new("/", Default::default(), 0, data.clone());
-            prop_assert_eq!(job.data(), &data);
-        }
-
-        #[test]
-        fn after_data_update_anything_else_remains_intact_but_data_changed(
+        fn after_transitions_anything_else_remains_intact(
             path in "(/[a-z]+)+",
             timestamp in 0..100_000_000i64,
             min in 0..100usize,
-            init_data in "[a-z]+",
-            new_data in 0..100_000_000usize,
+            count in 0..20u32,
         ) {
             let date = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap();
-            let job = super::Job::new(path, date, min, init_data.clone());
-            let updated = job.set_data(new_data);
-            prop_assert_eq!(updated.path(), job.path());
-            prop_assert_eq!(updated.keep_since(), job.keep_since());
-            prop_assert_eq!(updated.keep_at_least(), job.keep_at_least());
-            prop_assert_eq!(job.data(), &init_data);
-            prop_assert_eq!(updated.data(), &new_data);
+            let generations = (1..=count)
+                .map(|id| Generation { id, date, current: id == count })
+                .collect::<GenerationSet>();
+
+            let job = Job::new(path, date, min);
+            let planned = job.listed(generations.clone()).plan();
+
+            prop_assert_eq!(&planned.state().listed, &generations);
+            prop_assert_eq!(
+                &planned.state().to_delete,
+                &generations.generations_to_delete(min, None, date, &BTreeSet::new())
+            );
+
+            let to_delete = planned.state().to_delete.clone();
+            let executed = planned.executed(to_delete.clone());
+            let verified = executed.verify(Some(&generations.difference(&to_delete)));
+
+            prop_assert_eq!(verified.keep_since(), date);
+            prop_assert_eq!(verified.keep_at_least(), min);
+            prop_assert_eq!(&verified.state().deleted, &to_delete);
+            prop_assert!(verified.state().appeared.is_empty());
+
+            let states = verified.timings().iter().map(|t| t.state).collect::<Vec<_>>();
+            prop_assert_eq!(states, ["discovered", "listed", "planned", "executed"]);
         }
     }
+
+    #[test]
+    fn tighten_keeps_referenced() {
+        let date = NaiveDateTime::default();
+        let generations: GenerationSet = (1..=3)
+            .map(|id| Generation {
+                id,
+                date: date + chrono::Duration::days(i64::from(id)),
+                current: id == 3,
+            })
+            .collect();
+        let reference = Reference {
+            referrer: Referrer::Container("web".to_string()),
+            specialisation: None,
+        };
+
+        let mut job = Job::new("/", date, 3)
+            .listed(generations)
+            .referenced(BTreeMap::from([(1, vec![reference])]));
+
+        assert_eq!(job.tighten(1).map(|g| g.id), Some(1));
+        assert_eq!(job.tighten(1).map(|g| g.id), Some(2));
+        assert_eq!(job.tighten(1), None);
+        assert_eq!(job.keep_at_least(), 1);
+
+        let to_delete = job.plan().state().to_delete.clone();
+        assert_eq!(to_delete.iter().map(|g| g.id).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn applies_exceptions() -> Result<(), JobBuilderError> {
+        let date = NaiveDateTime::default();
+        let generations: GenerationSet = (1..=4)
+            .map(|id| Generation {
+                id,
+                date: date + chrono::Duration::days(i64::from(id)),
+                current: id == 4,
+            })
+            .collect();
+
+        let job = Job::builder()
+            .path("/p")
+            .keep_since(date + chrono::Duration::days(10))
+            .now(date + chrono::Duration::days(10))
+            .keep_at_least(3)
+            .pinned(BTreeSet::from([2]))
+            .always_delete(BTreeSet::from([2, 3, 4]))
+            .build()?
+            .listed(generations);
+
+        assert_eq!(
+            job.explain(2).unwrap().reasons.last(),
+            Some(&Reason::Pinned)
+        );
+        assert_eq!(
+            job.explain(3).unwrap().reasons.last(),
+            Some(&Reason::AlwaysDelete)
+        );
+        let to_delete = job.plan().state().to_delete.clone();
+        assert_eq!(to_delete.iter().map(|g| g.id).collect::<Vec<_>>(), [1, 3]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::nothing_deleted(3, Some(2))]
+    #[case::deleted(1, None)]
+    fn reports_explanation(#[case] keep_at_least: usize, #[case] recent: Option<usize>) {
+        let date = NaiveDateTime::default();
+        let generations: GenerationSet = (1..=3)
+            .map(|id| Generation {
+                id,
+                date: date - chrono::Duration::days(i64::from(4 - id)),
+                current: id == 3,
+            })
+            .collect();
+
+        let job = Job::new("/", date + chrono::Duration::days(1), keep_at_least)
+            .listed(generations)
+            .plan();
+        let deleted = job.state().to_delete.clone();
+        let report = job.executed(deleted).verify(None).into_report();
+
+        assert_eq!(report.explanation.map(|e| e.recent), recent);
+    }
+
+    #[test]
+    fn reports_keep_since_derivation() {
+        let now = NaiveDateTime::default() + chrono::Duration::days(10);
+        let policy = crate::RetentionPolicy::default_for(crate::ProfileKind::User);
+        let derivation = policy.derive_keep_since(now);
+        let generations: GenerationSet = (1..=3)
+            .map(|id| Generation {
+                id,
+                date: now - chrono::Duration::days(i64::from(4 - id)),
+                current: id == 3,
+            })
+            .collect();
+
+        let mut job = Job::builder()
+            .path("/p/profile")
+            .keep_since_derived(derivation)
+            .now(now)
+            .build()
+            .unwrap()
+            .listed(generations);
+        assert_eq!(job.keep_since(), derivation.keep_since);
+        assert_eq!(job.keep_since_derivation(), Some(derivation));
+
+        job.tighten(1).unwrap();
+        let report = job
+            .plan()
+            .executed(GenerationSet::default())
+            .verify(None)
+            .into_report();
+
+        let reported = report.keep_since.unwrap();
+        assert_eq!(reported.keep_since, derivation.keep_since);
+        assert!(reported
+            .adjusted
+            .is_some_and(|adjusted| adjusted > derivation.keep_since));
+    }
+
+    #[test]
+    fn verify_finds_appeared() {
+        let date = NaiveDateTime::default();
+        let generation = |id| Generation {
+            id,
+            date,
+            current: false,
+        };
+        let listed = GenerationSet::from([generation(1), generation(2)]);
+        let relisted = GenerationSet::from([generation(2), generation(3)]);
+
+        let job = Job::new("/", date, 1)
+            .listed(listed)
+            .plan()
+            .executed(GenerationSet::from([generation(1)]));
+
+        let report = job.verify(Some(&relisted)).into_report();
+
+        assert_eq!(report.appeared, GenerationSet::from([generation(3)]));
+        assert_eq!(report.deleted, GenerationSet::from([generation(1)]));
+    }
+
+    #[test]
+    fn fail_records_state() {
+        let job = Job::new("/some/profile", Default::default(), 0).listed(GenerationSet::default());
+
+        let error = job.fail(eyre::eyre!("boom"));
+
+        assert_eq!(error.job_id, job.id());
+        assert_eq!(error.state, "listed");
+        assert!(error.to_string().starts_with(&format!(
+            "{} (/some/profile) failed after being listed for",
+            job.id()
+        )));
+    }
+
+    #[test]
+    fn builder_builds_valid_job() {
+        let now = NaiveDateTime::from_timestamp_opt(1_000, 0).unwrap();
+        let keep_since = NaiveDateTime::from_timestamp_opt(500, 0).unwrap();
+
+        let job = Job::builder()
+            .path("/some/profile")
+            .keep_since(keep_since)
+            .keep_at_least(2)
+            .now(now)
+            .build()
+            .unwrap();
+
+        assert_eq!(job.path(), Path::new("/some/profile"));
+        assert_eq!(job.keep_since(), keep_since);
+        assert_eq!(job.keep_at_least(), 2);
+    }
+
+    #[rstest]
+    #[case::missing_path(None, Some(0), 1, false, JobBuilderError::Missing("path"))]
+    #[case::missing_keep_since(Some("/p"), None, 1, false, JobBuilderError::Missing("keep_since"))]
+    #[case::relative(Some("p"), Some(0), 1, false, JobBuilderError::RelativePath("p".into()))]
+    #[case::future(Some("/p"), Some(2_000), 1, false, JobBuilderError::KeepSinceInFuture {
+        keep_since: NaiveDateTime::from_timestamp_opt(2_000, 0).unwrap(),
+        now: NaiveDateTime::from_timestamp_opt(1_000, 0).unwrap(),
+    })]
+    #[case::nothing_kept(Some("/p"), Some(0), 0, false, JobBuilderError::NothingKept)]
+    fn builder_rejects(
+        #[case] path: Option<&str>,
+        #[case] keep_since: Option<i64>,
+        #[case] keep_at_least: usize,
+        #[case] by_age_only: bool,
+        #[case] expected: JobBuilderError,
+    ) {
+        let mut builder = Job::builder()
+            .keep_at_least(keep_at_least)
+            .by_age_only(by_age_only)
+            .now(NaiveDateTime::from_timestamp_opt(1_000, 0).unwrap());
+        if let Some(path) = path {
+            builder = builder.path(path);
+        }
+        if let Some(keep_since) = keep_since {
+            builder = builder.keep_since(NaiveDateTime::from_timestamp_opt(keep_since, 0).unwrap());
+        }
+
+        assert_eq!(builder.build().unwrap_err(), expected);
+    }
+
+    #[rstest]
+    #[case::below_keep_at_least(3, 2)]
+    #[case::zero(1, 0)]
+    fn builder_rejects_keep_at_most(#[case] keep_at_least: usize, #[case] keep_at_most: usize) {
+        let error = Job::builder()
+            .path("/p")
+            .keep_since(NaiveDateTime::from_timestamp_opt(0, 0).unwrap())
+            .keep_at_least(keep_at_least)
+            .keep_at_most(Some(keep_at_most))
+            .now(NaiveDateTime::from_timestamp_opt(1_000, 0).unwrap())
+            .build()
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            JobBuilderError::KeepAtMostBelowKeepAtLeast {
+                keep_at_least,
+                keep_at_most,
+            }
+        );
+    }
+
+    #[test]
+    fn plan_deletes_before_generation() {
+        let date = |day| {
+            NaiveDate::from_ymd_opt(2023, 6, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let generations = (1..=6)
+            .map(|id| Generation {
+                id,
+                date: date(id),
+                current: id == 6,
+            })
+            .collect::<GenerationSet>();
+
+        let job = Job::builder()
+            .path("/p")
+            .keep_since(date(4))
+            .keep_at_least(5)
+            .before_generation(Some(5))
+            .now(date(10))
+            .build()
+            .unwrap()
+            .listed(generations)
+            .plan();
+
+        // 3 has been active until 4 has been created
+        let ids: Vec<_> = job.state().to_delete.iter().map(|g| g.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(job.before_generation(), Some(5));
+    }
+
+    #[rstest]
+    #[case::unprotected(false, vec![1, 2])]
+    #[case::protected(true, vec![1])]
+    fn plan_protects_rollback_targets(#[case] protect: bool, #[case] expected: Vec<u32>) {
+        let date = |day| {
+            NaiveDate::from_ymd_opt(2023, 6, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        // 2 is dated before 1, 4 has been rolled back from
+        let generations = [
+            (1, 3, false),
+            (2, 1, false),
+            (3, 4, false),
+            (4, 5, true),
+            (5, 6, false),
+        ]
+        .into_iter()
+        .map(|(id, day, current)| Generation {
+            id,
+            date: date(day),
+            current,
+        })
+        .collect::<GenerationSet>();
+
+        let job = Job::builder()
+            .path("/p")
+            .keep_since(date(5))
+            .keep_at_least(1)
+            .protect_rollback_targets(protect)
+            .now(date(10))
+            .build()
+            .unwrap()
+            .listed(generations)
+            .plan();
+
+        let ids: Vec<_> = job.state().to_delete.iter().map(|g| g.id).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn plan_keeps_referenced_generations() {
+        let generations = (1..=4)
+            .map(|id| Generation {
+                id,
+                date: NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
+                current: id == 4,
+            })
+            .collect::<GenerationSet>();
+        let referenced = BTreeMap::from([(
+            2,
+            vec![Reference {
+                referrer: Referrer::Container("web".to_string()),
+                specialisation: None,
+            }],
+        )]);
+
+        let job = Job::new("/p", NaiveDateTime::from_timestamp_opt(1, 0).unwrap(), 1)
+            .listed(generations)
+            .referenced(referenced)
+            .plan();
+
+        let ids: Vec<_> = job.state().to_delete.iter().map(|g| g.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[rstest]
+    #[case::unprotected(false)]
+    #[case::protected(true)]
+    fn explain_matches_plan(#[case] protect: bool) {
+        let date = |day| {
+            NaiveDate::from_ymd_opt(2023, 6, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        // 2 is dated before 1, 6 has been rolled back from
+        let generations = [
+            (1, 3, false),
+            (2, 1, false),
+            (3, 4, false),
+            (4, 5, false),
+            (5, 6, true),
+            (6, 7, false),
+            (7, 8, false),
+        ]
+        .into_iter()
+        .map(|(id, day, current)| Generation {
+            id,
+            date: date(day),
+            current,
+        })
+        .collect::<GenerationSet>();
+        let referenced = BTreeMap::from([(
+            3,
+            vec![Reference {
+                referrer: Referrer::Container("web".to_string()),
+                specialisation: None,
+            }],
+        )]);
+        let job = || {
+            Job::builder()
+                .path("/p")
+                .keep_since(date(7))
+                .keep_at_least(1)
+                .before_generation(Some(2))
+                .protect_rollback_targets(protect)
+                .now(date(10))
+                .build()
+                .unwrap()
+                .listed(generations.clone())
+                .referenced(referenced.clone())
+        };
+
+        let explained = job();
+        let planned = job().plan();
+
+        for generation in &generations {
+            let decision = explained.explain(generation.id).unwrap();
+            assert_eq!(
+                decision.delete,
+                planned.state().to_delete.contains(generation.id),
+                "generation {}: {:?}",
+                generation.id,
+                decision.reasons
+            );
+        }
+        assert!(explained
+            .explain(3)
+            .unwrap()
+            .reasons
+            .contains(&Reason::Referenced(referenced[&3][0].clone())));
+    }
+
+    #[test]
+    fn builder_allows_nothing_kept_by_age_only() {
+        let job = Job::builder()
+            .path("/p")
+            .keep_since(NaiveDateTime::default())
+            .keep_at_least(0)
+            .by_age_only(true)
+            .build();
+
+        assert!(job.is_ok());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrips_plans() -> eyre::Result<()> {
+        let date = NaiveDateTime::parse_from_str("2023-06-05 12:00:00.5", "%Y-%m-%d %H:%M:%S%.f")?;
+        let generations: GenerationSet = (1..=3)
+            .map(|id| Generation {
+                id,
+                date: date + chrono::Duration::days(i64::from(id)),
+                current: id == 3,
+            })
+            .collect();
+        let planned = Job::builder()
+            .path("/p")
+            .keep_since(date + chrono::Duration::days(5))
+            .keep_at_least(1)
+            .pinned(BTreeSet::from([1]))
+            .build()?
+            .listed(generations)
+            .plan();
+
+        let json = serde_json::to_string(&planned)?;
+        let restored: Job<super::state::Planned> = serde_json::from_str(&json)?;
+
+        assert_ne!(restored.id(), planned.id());
+        assert_eq!(restored.path(), planned.path());
+        assert_eq!(restored.keep_since(), planned.keep_since());
+        assert_eq!(restored.exceptions(), planned.exceptions());
+        assert_eq!(restored.state(), planned.state());
+        assert!(restored.timings().is_empty());
+        Ok(())
+    }
 }
@@ -1,9 +1,52 @@
+#[macro_use]
+mod error;
+
+mod backend;
+pub mod duration;
+mod executor;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filesystem;
 mod generation;
 mod generation_set;
 mod job;
+pub mod mounts;
+pub mod nix_env;
+pub mod nix_profile;
+pub mod nix_store;
+pub mod planning;
+#[cfg(feature = "system")]
+pub mod platform;
+mod policy;
+mod policy_chain;
 mod profiles;
+mod progress;
+pub mod references;
+pub mod registry;
+mod report;
+pub mod rusage;
+pub mod schedule;
+pub mod size;
+mod source;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-pub use generation::Generation;
-pub use generation_set::GenerationSet;
-pub use job::Job;
-pub use profiles::Profile;
+pub use backend::Backend;
+pub use error::{BoxError, JanitorError};
+#[cfg(feature = "tokio")]
+pub use executor::TokioExecutor;
+pub use executor::{Blocking, BlockingExecutor, CommandLine, Executor, StdExecutor};
+pub use generation::{Generation, Origin, UnrecognizedFormat};
+pub use generation_set::{GenerationOrder, GenerationSet, ListingConflict, Period, Thinning};
+pub use job::{state, Job, JobBuilder, JobBuilderError, JobError, JobId, Timing};
+pub use planning::{Decision, Exceptions, Explanation, Reason, Rules, Tightening};
+pub use policy::{KeepSinceDerivation, RetentionOverrides, RetentionPolicy};
+pub use policy_chain::{ChainRule, PolicyChain, Stage, Verdict};
+#[cfg(feature = "system")]
+pub use profiles::system_by_default;
+pub use profiles::{Profile, ProfileKind, DEFAULT_PROFILE, PER_USER_PACKAGES};
+pub use progress::Progress;
+pub use report::{ProfileFailure, ProfileReport, QuotaReport, RunReport};
+pub use source::{
+    DetectingSource, FilesystemSource, GenerationSource, NixEnvSource, NixProfileSource,
+};
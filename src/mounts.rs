@@ -0,0 +1,211 @@
+//! Reads the mount table of the janitor's process, to tell in advance whether
+//! profiles and the store can be written to.
+//!
+//! NixOS mounts the store read-only by default, which is harmless, as nix
+//! remounts it writable in a private mount namespace for itself. Deleting
+//! generations and collecting garbage only fail when the underlying file
+//! system can not be written to at all, e.g. a read-only superblock, a
+//! squashfs image or an overlay without an upper layer.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The mount table of the current process.
+pub const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// File systems that can never be written to.
+const READ_ONLY_FILESYSTEMS: [&str; 4] = ["squashfs", "iso9660", "erofs", "cramfs"];
+
+/// A single mount, as listed in [MOUNTINFO].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Where the file system is mounted.
+    pub mount_point: PathBuf,
+
+    /// The type of the file system, e.g. `ext4` or `overlay`.
+    pub fstype: String,
+
+    /// What is mounted, e.g. a device or `overlay`.
+    pub source: String,
+
+    /// The options of this mount, e.g. `ro` for a read-only bind mount.
+    pub options: Vec<String>,
+
+    /// The options of the file system, shared by all of its mounts.
+    pub super_options: Vec<String>,
+}
+
+impl Mount {
+    /// Whether writing below the mount point fails.
+    pub fn read_only(&self) -> bool {
+        self.options
+            .iter()
+            .chain(&self.super_options)
+            .any(|o| o == "ro")
+    }
+
+    /// Whether the mount is writable, or can be made writable by remounting
+    /// it, as nix does with a read-only bind mount of the store.
+    pub fn can_remount_writable(&self) -> bool {
+        let overlay_without_upper = self.fstype == "overlay"
+            && !self
+                .super_options
+                .iter()
+                .any(|o| o.starts_with("upperdir="));
+
+        !self.super_options.iter().any(|o| o == "ro")
+            && !READ_ONLY_FILESYSTEMS.contains(&self.fstype.as_str())
+            && !overlay_without_upper
+    }
+
+    /// Parses a line of [MOUNTINFO], `None` if it is malformed.
+    fn parse(line: &str) -> Option<Self> {
+        // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+        let (mount, filesystem) = line.split_once(" - ")?;
+        let mut mount = mount.split(' ');
+        let mount_point = mount.nth(4)?;
+        let options = mount.next()?;
+        let mut filesystem = filesystem.split(' ');
+
+        Some(Self {
+            mount_point: PathBuf::from(unescape(mount_point)),
+            fstype: filesystem.next()?.to_string(),
+            source: unescape(filesystem.next()?),
+            options: options.split(',').map(ToOwned::to_owned).collect(),
+            super_options: filesystem
+                .next()?
+                .split(',')
+                .map(ToOwned::to_owned)
+                .collect(),
+        })
+    }
+}
+
+/// The mounts of a mount table, in the order they have been mounted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountTable(Vec<Mount>);
+
+impl MountTable {
+    /// Reads the mount table of the current process from [MOUNTINFO].
+    ///
+    /// The table is empty if it can not be read, e.g. on systems other than
+    /// Linux.
+    pub fn system() -> Self {
+        match fs::read_to_string(MOUNTINFO) {
+            Ok(table) => Self::parse(&table),
+            Err(error) => {
+                tracing::debug!(%error, "not reading the mount table");
+                Self::default()
+            }
+        }
+    }
+
+    /// Parses a mount table in the format of [MOUNTINFO], skipping malformed
+    /// lines.
+    pub fn parse(table: &str) -> Self {
+        Self(table.lines().filter_map(Mount::parse).collect())
+    }
+
+    /// The mount `path` is on, the last mounted of those with the longest
+    /// mount point containing it.
+    pub fn mount_of<P: AsRef<Path>>(&self, path: P) -> Option<&Mount> {
+        let path = path.as_ref();
+
+        self.0
+            .iter()
+            .filter(|mount| path.starts_with(&mount.mount_point))
+            .max_by_key(|mount| mount.mount_point.components().count())
+    }
+}
+
+/// Replaces the octal escapes of the mount table, e.g. `\040` for a space.
+fn unescape(field: &str) -> String {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut bytes = field.as_bytes();
+
+    while let Some((&byte, rest)) = bytes.split_first() {
+        let escaped = rest
+            .get(..3)
+            .filter(|_| byte == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+
+        match escaped {
+            Some(escaped) => {
+                unescaped.push(escaped);
+                bytes = &rest[3..];
+            }
+            None => {
+                unescaped.push(byte);
+                bytes = rest;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    const TABLE: &str = "\
+22 1 0:21 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:21 /nix/store /nix/store ro,relatime shared:2 - ext4 /dev/sda1 rw
+24 22 0:22 / /home/alice\\040smith rw,relatime shared:3 - ext4 /dev/sda2 rw
+25 22 0:23 / /mnt/iso ro,relatime shared:4 - iso9660 /dev/sr0 ro
+26 22 0:24 / /mnt/lower rw,relatime shared:5 - overlay overlay rw,lowerdir=/a:/b
+27 22 0:25 / /mnt/upper rw,relatime shared:6 - overlay overlay rw,lowerdir=/a,upperdir=/u,workdir=/w
+28 22 0:26 / /mnt/broken rw
+";
+
+    #[rstest]
+    #[case::root("/etc/nix", "/", false, true)]
+    #[case::store_bind_mount("/nix/store/abc-hello", "/nix/store", true, true)]
+    #[case::profiles("/nix/var/nix/profiles", "/", false, true)]
+    #[case::escaped("/home/alice smith/.local", "/home/alice smith", false, true)]
+    #[case::iso("/mnt/iso/nix/store", "/mnt/iso", true, false)]
+    #[case::overlay_without_upper("/mnt/lower/x", "/mnt/lower", false, false)]
+    #[case::overlay_with_upper("/mnt/upper/x", "/mnt/upper", false, true)]
+    fn mount_of(
+        #[case] path: &str,
+        #[case] mount_point: &str,
+        #[case] read_only: bool,
+        #[case] remountable: bool,
+    ) {
+        let table = MountTable::parse(TABLE);
+        let mount = table.mount_of(path).unwrap();
+
+        assert_eq!(mount.mount_point, Path::new(mount_point));
+        assert_eq!(mount.read_only(), read_only);
+        assert_eq!(mount.can_remount_writable(), remountable);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert_eq!(MountTable::parse(TABLE).0.len(), 6);
+        assert_eq!(MountTable::parse("garbage").mount_of("/"), None);
+    }
+
+    #[test]
+    fn stacked_mounts() {
+        let table = MountTable::parse(
+            "1 0 0:1 / /nix rw - ext4 /dev/a rw\n2 1 0:2 / /nix ro - squashfs /dev/b ro\n",
+        );
+
+        assert_eq!(table.mount_of("/nix/store").unwrap().fstype, "squashfs");
+    }
+
+    #[rstest]
+    #[case::plain("/nix/store", "/nix/store")]
+    #[case::space("a\\040b", "a b")]
+    #[case::backslash("a\\134b", "a\\b")]
+    #[case::truncated("a\\04", "a\\04")]
+    fn unescapes(#[case] field: &str, #[case] expected: &str) {
+        assert_eq!(unescape(field), expected);
+    }
+}
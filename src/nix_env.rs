@@ -0,0 +1,573 @@
+//! Wrappers around the `nix-env` commands used by the janitor.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path,
+    process::Output,
+};
+
+use crate::error::{JanitorError, Result, SpawnContext};
+use tracing::Instrument;
+
+use crate::{
+    executor::{CommandLine, Executor},
+    generation::Generation,
+    generation_set::GenerationSet,
+    progress::Progress,
+};
+
+/// Lists all generations of the profile at `profile`.
+///
+/// # Errors
+///
+/// Fails if `nix-env` can not be spawned, exits unsuccessfully, or its output
+/// can not be parsed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_env, TokioExecutor};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let generations = nix_env::list_generations(&TokioExecutor, "/nix/var/nix/profiles/system").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn list_generations<E, P>(executor: &E, profile: P) -> Result<GenerationSet>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    let output = executor
+        .output(list_command(profile.as_ref()))
+        .instrument(tracing::info_span!("nix-env"))
+        .await
+        .spawning("nix-env")?;
+
+    parse_list_output(&output)
+}
+
+/// Deletes the `generations` from the profile at `profile`.
+///
+/// Nothing is run if `generations` is empty.
+///
+/// Returns the ids of the generations `nix-env` reported removing, see
+/// [parse_removed], or `None` if it did not report anything recognizable.
+///
+/// # Errors
+///
+/// Fails if `nix-env` can not be spawned or exits unsuccessfully.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_env, GenerationSet, TokioExecutor};
+///
+/// # async fn example(to_delete: GenerationSet) -> eyre::Result<()> {
+/// nix_env::delete_generations(&TokioExecutor, "/nix/var/nix/profiles/system", &to_delete).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn delete_generations<E, P>(
+    executor: &E,
+    profile: P,
+    generations: &GenerationSet,
+) -> Result<Option<BTreeSet<u32>>>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    delete_generations_with_progress(executor, profile, generations, &Progress::default()).await
+}
+
+/// Deletes the `generations` from the profile at `profile` like
+/// [delete_generations], reporting the deleted ones to the `progress`.
+///
+/// All `generations` are reported unless `nix-env` reported removing only
+/// some of them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_env, GenerationSet, Progress, TokioExecutor};
+///
+/// # async fn example(to_delete: GenerationSet) -> eyre::Result<()> {
+/// let progress = Progress::default().on_generation_deleted(|profile, generation| {
+///     println!("deleted {} from {}", generation.id, profile.display());
+/// });
+/// nix_env::delete_generations_with_progress(
+///     &TokioExecutor,
+///     "/nix/var/nix/profiles/system",
+///     &to_delete,
+///     &progress,
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn delete_generations_with_progress<E, P>(
+    executor: &E,
+    profile: P,
+    generations: &GenerationSet,
+    progress: &Progress,
+) -> Result<Option<BTreeSet<u32>>>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if generations.is_empty() {
+        tracing::debug!("nothing to delete");
+        return Ok(Some(BTreeSet::new()));
+    }
+
+    let output = executor
+        .output(delete_command(profile.as_ref(), generations))
+        .instrument(tracing::info_span!("delete_generations"))
+        .await
+        .spawning("nix-env")?;
+
+    check_output(&output)?;
+
+    let removed = parse_removed(&String::from_utf8_lossy(&output.stderr));
+    if !progress.is_empty() {
+        progress.generations_deleted(profile.as_ref(), &deleted(generations, removed.as_ref()));
+    }
+
+    Ok(removed)
+}
+
+/// The `generations` requested to be deleted that `nix-env` reported as
+/// `removed`, all of them if it did not report anything.
+fn deleted(generations: &GenerationSet, removed: Option<&BTreeSet<u32>>) -> GenerationSet {
+    match removed {
+        Some(removed) => generations
+            .iter()
+            .filter(|generation| removed.contains(&generation.id))
+            .cloned()
+            .collect(),
+        None => generations.clone(),
+    }
+}
+
+/// Parses the ids of the generations `nix-env --delete-generations` reports
+/// removing in its diagnostic output, with lines like `removing profile
+/// version 661` or `removing generation 661`.
+///
+/// Returns `None` if not a single line is recognized, as versions of
+/// `nix-env` reporting differently can not be told apart from one removing
+/// nothing.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::nix_env;
+///
+/// let removed = nix_env::parse_removed("removing profile version 661\nremoving profile version 662\n");
+/// assert_eq!(removed.unwrap().into_iter().collect::<Vec<_>>(), [661, 662]);
+///
+/// assert_eq!(nix_env::parse_removed("warning: something else\n"), None);
+/// ```
+pub fn parse_removed(stderr: &str) -> Option<BTreeSet<u32>> {
+    const PREFIXES: &[&str] = &["removing profile version ", "removing generation "];
+
+    let mut recognized = false;
+    let removed = stderr
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| PREFIXES.iter().find_map(|prefix| line.strip_prefix(prefix)))
+        .filter_map(|id| {
+            recognized = true;
+            id.parse().ok()
+        })
+        .collect();
+
+    recognized.then_some(removed)
+}
+
+/// Why a generation requested for deletion has not been removed by
+/// `nix-env`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The generation became the current one while the janitor was running,
+    /// `nix-env` never deletes the current generation.
+    BecameCurrent,
+
+    /// The generation is still there for a reason `nix-env` did not tell.
+    StillPresent,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::BecameCurrent => "became current during the run",
+            Self::StillPresent => "still present after the deletion",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// Determines which of the `requested` generations have not been deleted
+/// and why, from the ids `nix-env` reported as `removed` and the generations
+/// `relisted` after the deletion.
+///
+/// Generations neither reported as removed nor relisted are gone all the
+/// same and not considered skipped.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::NaiveDateTime;
+/// use janitor::{nix_env::{self, SkipReason}, Generation, GenerationSet};
+///
+/// let date = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+/// let requested: GenerationSet = [1, 2, 3]
+///     .into_iter()
+///     .map(|id| Generation { id, date, current: false })
+///     .collect();
+/// let relisted: GenerationSet = [Generation { id: 2, date, current: true }].into_iter().collect();
+///
+/// let skipped = nix_env::skipped(&requested, &[1].into(), &relisted);
+/// assert_eq!(skipped.into_iter().collect::<Vec<_>>(), [(2, SkipReason::BecameCurrent)]);
+/// ```
+pub fn skipped(
+    requested: &GenerationSet,
+    removed: &BTreeSet<u32>,
+    relisted: &GenerationSet,
+) -> BTreeMap<u32, SkipReason> {
+    requested
+        .iter()
+        .filter(|generation| !removed.contains(&generation.id))
+        .filter_map(|generation| {
+            let still = relisted.get(generation.id)?;
+            let reason = match still.current {
+                true => SkipReason::BecameCurrent,
+                false => SkipReason::StillPresent,
+            };
+
+            Some((generation.id, reason))
+        })
+        .collect()
+}
+
+/// Runs the deletion of `generations` from the profile at `profile` with
+/// `--dry-run`, which only reports what would be deleted.
+///
+/// Nothing is run if `generations` is empty.
+pub async fn delete_generations_dry_run<E, P>(
+    executor: &E,
+    profile: P,
+    generations: &GenerationSet,
+) -> Result<()>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if generations.is_empty() {
+        tracing::debug!("nothing to delete");
+        return Ok(());
+    }
+
+    let output = executor
+        .output(delete_command(profile.as_ref(), generations).arg("--dry-run"))
+        .instrument(tracing::info_span!("delete_generations", dry_run = true))
+        .await
+        .spawning("nix-env")?;
+
+    check_output(&output)?;
+
+    Ok(())
+}
+
+/// Synchronous variants of the `nix-env` wrappers, for use without an async
+/// runtime.
+pub mod blocking {
+    use std::{collections::BTreeSet, path::Path};
+
+    use crate::error::{Result, SpawnContext};
+
+    use super::{check_output, delete_command, list_command, parse_list_output, parse_removed};
+    use crate::{executor::BlockingExecutor, generation_set::GenerationSet};
+
+    /// Lists all generations of the profile at `profile`.
+    ///
+    /// This is the blocking counterpart of [super::list_generations].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use janitor::{nix_env, StdExecutor};
+    ///
+    /// # fn main() -> eyre::Result<()> {
+    /// let generations =
+    ///     nix_env::blocking::list_generations(&StdExecutor, "/nix/var/nix/profiles/system")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list_generations<E, P>(executor: &E, profile: P) -> Result<GenerationSet>
+    where
+        E: BlockingExecutor + ?Sized,
+        P: AsRef<Path>,
+    {
+        let _span = tracing::info_span!("nix-env").entered();
+
+        let output = executor
+            .output(list_command(profile.as_ref()))
+            .spawning("nix-env")?;
+
+        parse_list_output(&output)
+    }
+
+    /// Deletes the `generations` from the profile at `profile`.
+    ///
+    /// This is the blocking counterpart of [super::delete_generations].
+    pub fn delete_generations<E, P>(
+        executor: &E,
+        profile: P,
+        generations: &GenerationSet,
+    ) -> Result<Option<BTreeSet<u32>>>
+    where
+        E: BlockingExecutor + ?Sized,
+        P: AsRef<Path>,
+    {
+        if generations.is_empty() {
+            tracing::debug!("nothing to delete");
+            return Ok(Some(BTreeSet::new()));
+        }
+
+        let _span = tracing::info_span!("delete_generations").entered();
+
+        let output = executor
+            .output(delete_command(profile.as_ref(), generations))
+            .spawning("nix-env")?;
+
+        check_output(&output)?;
+
+        Ok(parse_removed(&String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+fn list_command(profile: &Path) -> CommandLine {
+    CommandLine::new("nix-env")
+        .arg("--list-generations")
+        .arg("--profile")
+        .arg(profile)
+}
+
+fn delete_command(profile: &Path, generations: &GenerationSet) -> CommandLine {
+    CommandLine::new("nix-env")
+        .arg("--profile")
+        .arg(profile)
+        .arg("--delete-generations")
+        .args(generations.to_delete_args())
+}
+
+fn parse_list_output(output: &Output) -> Result<GenerationSet> {
+    let stdout = check_output(output)?;
+
+    Ok(Generation::parse_many(stdout)?.into())
+}
+
+fn check_output(output: &Output) -> Result<&str> {
+    JanitorError::check_output("nix-env", output)?;
+
+    std::str::from_utf8(&output.stdout)
+        .map_err(|error| JanitorError::parse_with("nix-env printed invalid UTF-8", error))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, os::unix::process::ExitStatusExt, process::ExitStatus, sync::Mutex};
+
+    use futures::future::BoxFuture;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::executor::BlockingExecutor;
+
+    #[derive(Debug, Default)]
+    struct FakeExecutor {
+        status: i32,
+        stdout: &'static str,
+        stderr: &'static str,
+        commands: Mutex<Vec<CommandLine>>,
+    }
+
+    impl Executor for FakeExecutor {
+        fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+            self.commands.lock().unwrap().push(command);
+
+            Box::pin(async move {
+                Ok(Output {
+                    status: ExitStatus::from_raw(self.status << 8),
+                    stdout: self.stdout.as_bytes().to_vec(),
+                    stderr: self.stderr.as_bytes().to_vec(),
+                })
+            })
+        }
+    }
+
+    impl BlockingExecutor for FakeExecutor {
+        fn output(&self, command: CommandLine) -> io::Result<Output> {
+            futures::executor::block_on(Executor::output(self, command))
+        }
+    }
+
+    #[tokio::test]
+    async fn list_parses_output() -> Result<()> {
+        let executor = FakeExecutor {
+            stdout: "1 2023-06-01 08:10:47\n2 2023-06-02 08:10:47 (current)\n",
+            ..Default::default()
+        };
+
+        let generations = list_generations(&executor, "/profile").await?;
+
+        assert_eq!(generations.len(), 2);
+        assert!(generations.get(2).unwrap().current);
+        assert_eq!(
+            executor.commands.lock().unwrap()[0].args,
+            vec!["--list-generations", "--profile", "/profile"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_fails_on_error_status() {
+        let executor = FakeExecutor {
+            status: 1,
+            ..Default::default()
+        };
+
+        let result = list_generations(&executor, "/profile").await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_passes_ids() -> Result<()> {
+        let executor = FakeExecutor::default();
+        let generations = Generation::parse_many("3 2023-06-01 08:10:47\n5 2023-06-02 08:10:47")?;
+
+        delete_generations(&executor, "/profile", &generations.into()).await?;
+
+        assert_eq!(
+            executor.commands.lock().unwrap()[0].args,
+            vec!["--profile", "/profile", "--delete-generations", "3", "5"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_reports_removed() -> Result<()> {
+        let executor = FakeExecutor {
+            stderr: "removing profile version 3\n",
+            ..Default::default()
+        };
+        let generations = Generation::parse_many("3 2023-06-01 08:10:47\n5 2023-06-02 08:10:47")?;
+
+        let removed = delete_generations(&executor, "/profile", &generations.into()).await?;
+
+        assert_eq!(removed, Some(BTreeSet::from([3])));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::profile_version("removing profile version 661\n", Some(&[661][..]))]
+    #[case::generation("removing generation 661\nremoving generation 662\n", Some(&[661, 662][..]))]
+    #[case::nothing_recognized("warning: the profile is locked\n", None)]
+    #[case::empty("", None)]
+    #[case::garbled_id("removing profile version x\n", Some(&[][..]))]
+    fn parse_removed(#[case] stderr: &str, #[case] expected: Option<&[u32]>) {
+        assert_eq!(
+            super::parse_removed(stderr),
+            expected.map(|ids| ids.iter().copied().collect())
+        );
+    }
+
+    #[test]
+    fn skipped_reasons() -> Result<()> {
+        let requested: GenerationSet =
+            Generation::parse_many("1 2023-06-01 08:10:47\n2 2023-06-02 08:10:47\n3 2023-06-03 08:10:47\n4 2023-06-04 08:10:47")?.into();
+        let relisted: GenerationSet =
+            Generation::parse_many("2 2023-06-02 08:10:47 (current)\n3 2023-06-03 08:10:47")?
+                .into();
+
+        let skipped = skipped(&requested, &BTreeSet::from([1]), &relisted);
+
+        assert_eq!(
+            skipped,
+            BTreeMap::from([
+                (2, SkipReason::BecameCurrent),
+                (3, SkipReason::StillPresent)
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_list_parses_output() -> Result<()> {
+        let executor = FakeExecutor {
+            stdout: "1 2023-06-01 08:10:47 (current)\n",
+            ..Default::default()
+        };
+
+        let generations = blocking::list_generations(&executor, "/profile")?;
+
+        assert_eq!(generations.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_delete_passes_ids() -> Result<()> {
+        let executor = FakeExecutor::default();
+        let generations = Generation::parse_many("3 2023-06-01 08:10:47")?;
+
+        blocking::delete_generations(&executor, "/profile", &generations.into())?;
+
+        assert_eq!(
+            executor.commands.lock().unwrap()[0].args,
+            vec!["--profile", "/profile", "--delete-generations", "3"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_delete_passes_flag() -> Result<()> {
+        let executor = FakeExecutor::default();
+        let generations = Generation::parse_many("3 2023-06-01 08:10:47")?;
+
+        delete_generations_dry_run(&executor, "/profile", &generations.into()).await?;
+
+        assert_eq!(
+            executor.commands.lock().unwrap()[0].args,
+            vec![
+                "--profile",
+                "/profile",
+                "--delete-generations",
+                "3",
+                "--dry-run"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_nothing_does_not_run() -> Result<()> {
+        let executor = FakeExecutor::default();
+
+        delete_generations(&executor, "/profile", &GenerationSet::from(vec![])).await?;
+
+        assert!(executor.commands.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+}
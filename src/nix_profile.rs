@@ -0,0 +1,303 @@
+//! Lists generations of profiles managed by `nix profile`, from the output of
+//! `nix profile history`.
+//!
+//! The history only gives the day a generation has been created on, in UTC.
+//! A generation is taken to be created at the end of that day, so that a
+//! retention policy never deletes it earlier than it would with the exact
+//! time.
+//!
+//! Whether a profile is managed by `nix profile` at all is told by its
+//! manifest, see [manifest_version].
+
+use std::{fs, path::Path};
+
+use crate::error::{JanitorError, Result, SpawnContext};
+use chrono::{Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use tracing::Instrument;
+
+use crate::{
+    executor::{CommandLine, Executor},
+    filesystem::{parse_link_name, split_profile},
+    generation::Generation,
+    generation_set::GenerationSet,
+};
+
+/// Lists all generations of the profile at `profile` with `nix profile
+/// history`.
+///
+/// The current generation is found by resolving the profile link.
+///
+/// # Errors
+///
+/// Fails if `nix` can not be spawned, exits unsuccessfully, or its output
+/// can not be parsed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_profile, TokioExecutor};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let generations =
+///     nix_profile::list_generations(&TokioExecutor, "/home/user/.local/state/nix/profiles/profile")
+///         .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn list_generations<E, P>(executor: &E, profile: P) -> Result<GenerationSet>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    let profile = profile.as_ref();
+    let command = CommandLine::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes"])
+        .args(["profile", "history", "--profile"])
+        .arg(profile);
+
+    let output = executor
+        .output(command)
+        .instrument(tracing::info_span!("nix-profile"))
+        .await
+        .spawning("nix profile")?;
+
+    JanitorError::check_output("nix profile history", &output)?;
+    let history = std::str::from_utf8(&output.stdout).map_err(|error| {
+        JanitorError::parse_with("nix profile history printed invalid UTF-8", error)
+    })?;
+
+    parse_history(history, current(profile))
+}
+
+/// Parses the output of `nix profile history`, marking the generation with
+/// the id `current` as the current one.
+///
+/// Only the `Version` lines are looked at, the changes to the packages listed
+/// below them are skipped, as are terminal escape sequences.
+///
+/// # Errors
+///
+/// Fails if a `Version` line can not be parsed.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::nix_profile;
+///
+/// let history = "Version 1 (2023-06-01):\n  hello: ∅ -> 2.12\n\nVersion 2 (2023-06-03) <- 1:\n  hello: 2.12 -> ∅\n";
+/// let generations = nix_profile::parse_history(history, Some(2))?;
+///
+/// assert_eq!(generations.len(), 2);
+/// assert!(generations.iter().last().unwrap().current);
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn parse_history(history: &str, current: Option<u32>) -> Result<GenerationSet> {
+    let end_of_day = NaiveTime::from_hms_opt(23, 59, 59).expect("valid time");
+
+    history
+        .lines()
+        .map(strip_escapes)
+        .filter_map(|line| line.strip_prefix("Version ").map(ToOwned::to_owned))
+        .map(|version| {
+            let parse = || -> Option<Generation> {
+                let (id, rest) = version.split_once(" (")?;
+                let (date, _) = rest.split_once(')')?;
+                let id = id.parse().ok()?;
+                let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+                let date = Utc
+                    .from_utc_datetime(&date.and_time(end_of_day))
+                    .with_timezone(&Local)
+                    .naive_local();
+
+                Some(Generation {
+                    id,
+                    date,
+                    current: Some(id) == current,
+                })
+            };
+
+            parse()
+                .ok_or_else(|| parse_error!("unrecognized version in profile history: {version}"))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(GenerationSet::from)
+}
+
+/// The version of the `manifest.json` of the profile at `profile`, or `None`
+/// if it has none, e.g. because it is managed by `nix-env`, which writes a
+/// `manifest.nix` instead.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::nix_profile;
+///
+/// assert_eq!(nix_profile::manifest_version("/nonexistent"), None);
+/// ```
+pub fn manifest_version<P: AsRef<Path>>(profile: P) -> Option<u32> {
+    let manifest = fs::read_to_string(profile.as_ref().join("manifest.json")).ok()?;
+
+    parse_manifest_version(&manifest)
+}
+
+/// Whether the profile at `profile` is managed by `nix profile`, so that
+/// `nix-env --list-generations` can not be trusted with it.
+pub fn is_new_style<P: AsRef<Path>>(profile: P) -> bool {
+    manifest_version(profile).is_some()
+}
+
+/// Finds the top level `"version"` of a `manifest.json`.
+///
+/// The version is the only field of the manifest whose name is a plain
+/// `"version"` outside of its elements, which are looked past.
+fn parse_manifest_version(manifest: &str) -> Option<u32> {
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (index, c) in manifest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' if in_string => in_string = false,
+            '"' => {
+                in_string = true;
+                if depth != 1 {
+                    continue;
+                }
+                let Some(rest) = manifest[index..].strip_prefix("\"version\"") else {
+                    continue;
+                };
+                let Some(value) = rest.trim_start().strip_prefix(':') else {
+                    continue;
+                };
+                let value = value.trim_start();
+                let end = value
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(value.len());
+                return value[..end].parse().ok();
+            }
+            _ if in_string => {}
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// The id of the generation the profile at `profile` points to, if it can be
+/// resolved.
+fn current(profile: &Path) -> Option<u32> {
+    let (_, name) = split_profile(profile).ok()?;
+    let target = fs::read_link(profile).ok()?;
+
+    parse_link_name(name, target.file_name()?.to_str()?)
+}
+
+/// Removes the terminal escape sequences, like `\e[1m`, from `line`.
+fn strip_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip the `[`, the parameters, and the final letter.
+            chars.by_ref().find(char::is_ascii_alphabetic);
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use eyre::Result;
+
+    use rstest::rstest;
+
+    const HISTORY: &str = "\
+\u{1b}[1mVersion 661\u{1b}[0m (2023-06-01):
+  flake:nixpkgs#hello: \u{2205} -> 2.12
+
+\u{1b}[1mVersion 662\u{1b}[0m (2023-06-03) <- 661:
+  flake:nixpkgs#cowsay: \u{2205} -> 3.7.0
+";
+
+    #[test]
+    fn parses_history() -> Result<()> {
+        let generations = parse_history(HISTORY, Some(662))?;
+        let parsed: Vec<_> = generations.iter().map(|g| (g.id, g.current)).collect();
+
+        assert_eq!(parsed, [(661, false), (662, true)]);
+
+        let expected = Utc
+            .with_ymd_and_hms(2023, 6, 1, 23, 59, 59)
+            .unwrap()
+            .with_timezone(&Local)
+            .naive_local();
+        assert_eq!(generations.iter().next().unwrap().date, expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::no_versions("  hello: 2.12 -> \u{2205}\n")]
+    fn parses_empty_history(#[case] history: &str) -> Result<()> {
+        assert!(parse_history(history, None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::no_date("Version 3:")]
+    #[case::invalid_id("Version three (2023-06-01):")]
+    #[case::invalid_date("Version 3 (yesterday):")]
+    fn rejects_invalid_versions(#[case] history: &str) {
+        assert!(parse_history(history, None).is_err());
+    }
+
+    #[rstest]
+    #[case::v2(r#"{"version": 2, "elements": []}"#, Some(2))]
+    #[case::v3_last(r#"{"elements": {"hello": {"version": 1}}, "version":3}"#, Some(3))]
+    #[case::string_value(r#"{"name": "version", "version": 1}"#, Some(1))]
+    #[case::nested_only(r#"{"elements": [{"version": 1}]}"#, None)]
+    #[case::not_a_number(r#"{"version": "2"}"#, None)]
+    #[case::empty("", None)]
+    fn parses_manifest_version(#[case] manifest: &str, #[case] expected: Option<u32>) {
+        assert_eq!(parse_manifest_version(manifest), expected);
+    }
+
+    #[test]
+    fn detects_new_style_profiles() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("janitor-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        fs::write(dir.join("manifest.nix"), "[ ]")?;
+        let old_style = is_new_style(&dir);
+
+        fs::write(dir.join("manifest.json"), r#"{"version":2,"elements":[]}"#)?;
+        let version = manifest_version(&dir);
+        fs::remove_dir_all(&dir)?;
+
+        assert!(!old_style);
+        assert_eq!(version, Some(2));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::plain("Version 1", "Version 1")]
+    #[case::bold("\u{1b}[1mVersion\u{1b}[0m 1", "Version 1")]
+    #[case::colored("\u{1b}[32;1mhello\u{1b}[0m", "hello")]
+    fn strips_escapes(#[case] line: &str, #[case] expected: &str) {
+        assert_eq!(strip_escapes(line), expected);
+    }
+}
@@ -0,0 +1,1035 @@
+//! Wrappers around the `nix-store` commands used by the janitor.
+
+use std::{
+    collections::BTreeSet,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufWriter, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    process::Output,
+};
+
+use crate::error::{JanitorError, Result, SpawnContext};
+use tracing::Instrument;
+
+use crate::{
+    executor::{CommandLine, Executor},
+    progress::Progress,
+};
+
+/// The start of the lines of the diagnostic output of `nix-store --gc`
+/// announcing the deletion of a store path.
+const DELETING: &[u8] = b"deleting '";
+
+/// The end of the summary printed by `nix-store --gc`.
+const FREED: &[u8] = b" freed";
+
+/// The starts of the progress messages of `nix-store --gc`, besides
+/// [DELETING].
+const GC_PROGRESS: &[&[u8]] = &[
+    b"finding garbage collector roots",
+    b"removing stale",
+    b"deleting garbage",
+    b"deleting unused links",
+    b"note: ",
+];
+
+/// The initial capacity of the buffer lines of `nix-store` output are read
+/// into, enough for a store path with a long name.
+const LINE_CAPACITY: usize = 256;
+
+/// The outcome of a garbage collection run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The number of store paths that have been deleted.
+    pub paths_deleted: u64,
+
+    /// The number of bytes that have been freed.
+    pub bytes_freed: u64,
+
+    /// The number of lines of output that have not been recognized, neither
+    /// as the summary nor as one of the progress messages.
+    pub unrecognized: u64,
+}
+
+impl GcReport {
+    /// Parses the summary printed by `nix-store --gc`.
+    ///
+    /// The summary has the form `N store paths deleted, X.YZ MiB freed`. If
+    /// no summary can be found, an empty report is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::nix_store::GcReport;
+    ///
+    /// let report = GcReport::parse("3 store paths deleted, 1.50 KiB freed\n");
+    /// assert_eq!(report.paths_deleted, 3);
+    /// assert_eq!(report.bytes_freed, 1536);
+    /// ```
+    pub fn parse(output: &str) -> Self {
+        Self::scan(output.as_bytes(), io::empty()).unwrap_or_default()
+    }
+
+    /// Reads the report from the `stdout` and `stderr` of `nix-store --gc`.
+    ///
+    /// The garbage collector announces every path it deletes on `stderr`,
+    /// which can be millions of lines. They are read one after another into
+    /// a single buffer and only matched byte-wise, no line is allocated or
+    /// decoded. If `stdout` lacks the summary, e.g. because the collection
+    /// has been interrupted, the announced deletions are counted instead.
+    ///
+    /// # Errors
+    ///
+    /// Fails if reading from `stdout` or `stderr` fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::nix_store::GcReport;
+    ///
+    /// let stderr = b"deleting '/nix/store/abc-foo'\ndeleting '/nix/store/def-bar'\n";
+    ///
+    /// let report = GcReport::scan(&b"2 store paths deleted, 1.00 KiB freed\n"[..], &stderr[..])?;
+    /// assert_eq!(report.bytes_freed, 1024);
+    ///
+    /// let report = GcReport::scan(&b""[..], &stderr[..])?;
+    /// assert_eq!(report.paths_deleted, 2);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn scan<O: BufRead, E: BufRead>(stdout: O, stderr: E) -> io::Result<Self> {
+        Self::scan_with(stdout, stderr, |_| {})
+    }
+
+    /// Like [GcReport::scan], calling `on_deleted` with every store path
+    /// announced as deleted.
+    fn scan_with<O, E, F>(stdout: O, stderr: E, on_deleted: F) -> io::Result<Self>
+    where
+        O: BufRead,
+        E: BufRead,
+        F: FnMut(&[u8]),
+    {
+        let mut buffer = Vec::with_capacity(LINE_CAPACITY);
+        let mut scanner = GcScanner::new(on_deleted);
+
+        for_each_line(stdout, &mut buffer, |line| scanner.stdout_line(line))?;
+        for_each_line(stderr, &mut buffer, |line| {
+            scanner.stderr_line(line);
+        })?;
+
+        Ok(scanner.finish())
+    }
+}
+
+/// Scans the output of `nix-store --gc` line by line, as it is read.
+struct GcScanner<F> {
+    summary: Option<GcReport>,
+    deleting: u64,
+    unrecognized: u64,
+    on_deleted: F,
+}
+
+impl<F: FnMut(&[u8])> GcScanner<F> {
+    /// Calls `on_deleted` with every store path announced as deleted.
+    fn new(on_deleted: F) -> Self {
+        Self {
+            summary: None,
+            deleting: 0,
+            unrecognized: 0,
+            on_deleted,
+        }
+    }
+
+    fn stdout_line(&mut self, line: &[u8]) {
+        let line = line.trim_ascii();
+        match parse_gc_summary(line) {
+            Some(report) => self.summary = Some(report),
+            None if !line.is_empty() => self.unrecognized += 1,
+            None => {}
+        }
+    }
+
+    /// Returns whether the `line` announces a deleted store path, all other
+    /// lines are worth keeping to explain a failure.
+    fn stderr_line(&mut self, line: &[u8]) -> bool {
+        let line = line.trim_ascii();
+        if let Some(path) = line.strip_prefix(DELETING) {
+            self.deleting += 1;
+            (self.on_deleted)(path.strip_suffix(b"'").unwrap_or(path));
+            return true;
+        }
+
+        if !line.is_empty() && !GC_PROGRESS.iter().any(|p| line.starts_with(p)) {
+            self.unrecognized += 1;
+        }
+
+        false
+    }
+
+    /// Scans the collected `stdout` of a garbage collection whose stderr
+    /// has been streamed already.
+    fn stdout(mut self, stdout: &[u8]) -> GcReport {
+        for line in stdout.split(|&byte| byte == b'\n') {
+            self.stdout_line(line);
+        }
+
+        self.finish()
+    }
+
+    fn finish(self) -> GcReport {
+        let report = self.summary.unwrap_or(GcReport {
+            paths_deleted: self.deleting,
+            ..GcReport::default()
+        });
+
+        GcReport {
+            unrecognized: self.unrecognized,
+            ..report
+        }
+    }
+}
+
+/// How the store paths deleted by the garbage collector are logged.
+///
+/// A garbage collection can delete millions of paths, logging each of them
+/// would drown everything else. Only every [DeletionLog::every]th path is
+/// logged, together with the number of paths deleted so far. The full list
+/// can be written to a [file](DeletionLog::file) instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletionLog {
+    /// Log every this many deleted paths.
+    pub every: NonZeroU64,
+
+    /// Append every deleted path to this file, one per line.
+    pub file: Option<PathBuf>,
+}
+
+impl DeletionLog {
+    /// The number of deleted paths logged once by default.
+    pub const DEFAULT_EVERY: NonZeroU64 = match NonZeroU64::new(1000) {
+        Some(every) => every,
+        None => unreachable!(),
+    };
+
+    fn start<'p>(&self, progress: &'p Progress) -> DeletionLogger<'p> {
+        let file = self.file.as_ref().and_then(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(BufWriter::new)
+                .map_err(|error| {
+                    tracing::warn!(%error, ?path, "not writing the deleted paths to the gc log");
+                })
+                .ok()
+        });
+
+        DeletionLogger {
+            every: self.every.get(),
+            deleted: 0,
+            file,
+            progress: progress.wants_gc_progress().then_some(progress),
+        }
+    }
+}
+
+impl Default for DeletionLog {
+    fn default() -> Self {
+        Self {
+            every: Self::DEFAULT_EVERY,
+            file: None,
+        }
+    }
+}
+
+/// Logs the deletions of a single garbage collection according to a
+/// [DeletionLog], reporting them to the [Progress] if it asks for them.
+struct DeletionLogger<'p> {
+    every: u64,
+    deleted: u64,
+    file: Option<BufWriter<File>>,
+    progress: Option<&'p Progress>,
+}
+
+impl DeletionLogger<'_> {
+    fn deleted(&mut self, path: &[u8]) {
+        self.deleted += 1;
+
+        if let Some(progress) = self.progress {
+            progress.gc_progress(self.deleted, &String::from_utf8_lossy(path));
+        }
+
+        if self.deleted.is_multiple_of(self.every) {
+            tracing::debug!(
+                deleted = self.deleted,
+                path = %String::from_utf8_lossy(path),
+                "deleting store paths"
+            );
+        }
+
+        if let Some(file) = &mut self.file {
+            if let Err(error) = file.write_all(path).and_then(|()| file.write_all(b"\n")) {
+                tracing::warn!(%error, "failed to write to the gc log, not writing any further");
+                self.file = None;
+            }
+        }
+    }
+
+    fn finish(self) {
+        tracing::debug!(deleted = self.deleted, "deleted store paths");
+
+        if let Some(mut file) = self.file {
+            if let Err(error) = file.flush() {
+                tracing::warn!(%error, "failed to write to the gc log");
+            }
+        }
+    }
+}
+
+/// Calls `f` with every line read from `reader`, reusing `buffer` for all
+/// of them.
+fn for_each_line<R, F>(mut reader: R, buffer: &mut Vec<u8>, mut f: F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]),
+{
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', buffer)? == 0 {
+            return Ok(());
+        }
+
+        f(buffer);
+    }
+}
+
+fn parse_gc_summary(line: &[u8]) -> Option<GcReport> {
+    if !line.ends_with(FREED) {
+        return None;
+    }
+    let line = std::str::from_utf8(line).ok()?;
+
+    let (paths, freed) = line.trim().split_once(" store paths deleted, ")?;
+    let (amount, unit) = freed.strip_suffix(" freed")?.split_once(' ')?;
+
+    let factor = match unit {
+        "bytes" | "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+
+    Some(GcReport {
+        paths_deleted: paths.parse().ok()?,
+        bytes_freed: (amount.parse::<f64>().ok()? * factor).round() as u64,
+        unrecognized: 0,
+    })
+}
+
+/// The outcome of verifying the nix store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The inconsistencies reported by `nix-store --verify`, one per line.
+    pub issues: Vec<String>,
+
+    /// The store paths that have been repaired, only ever filled when
+    /// verifying with repair.
+    pub repaired: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Collects the inconsistencies and repaired paths from the diagnostic
+    /// output of `nix-store --verify`, skipping its progress messages.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::nix_store::VerifyReport;
+    ///
+    /// let report = VerifyReport::parse("reading the Nix store...\nchecking path existence...\n");
+    /// assert!(report.is_consistent());
+    ///
+    /// let report = VerifyReport::parse("repairing path '/nix/store/abc-foo'...\n");
+    /// assert_eq!(report.repaired, vec!["/nix/store/abc-foo"]);
+    /// ```
+    pub fn parse(stderr: &str) -> Self {
+        const PROGRESS: &[&str] = &[
+            "reading the Nix store...",
+            "checking path existence...",
+            "checking link count...",
+            "checking hashes...",
+        ];
+
+        let mut report = Self::default();
+
+        for line in stderr
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !PROGRESS.contains(line))
+        {
+            match parse_repaired_path(line) {
+                Some(path) => report.repaired.push(path.to_string()),
+                None => report.issues.push(line.to_string()),
+            }
+        }
+
+        report
+    }
+
+    /// Whether the store has been found to be consistent.
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+fn parse_repaired_path(line: &str) -> Option<&str> {
+    line.strip_prefix("repairing path '")?
+        .split_once('\'')
+        .map(|(path, _)| path)
+}
+
+/// Runs the garbage collector, deleting all unreachable store paths.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_store, TokioExecutor};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let report = nix_store::collect_garbage(&TokioExecutor).await?;
+/// println!("freed {} bytes", report.bytes_freed);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn collect_garbage<E>(executor: &E) -> Result<GcReport>
+where
+    E: Executor + ?Sized,
+{
+    collect_garbage_logged(executor, None, &DeletionLog::default()).await
+}
+
+/// Returns the store paths the garbage collector would delete, without
+/// deleting anything.
+pub async fn dead_paths<E>(executor: &E) -> Result<BTreeSet<String>>
+where
+    E: Executor + ?Sized,
+{
+    let output = executor
+        .output(gc_command().arg("--print-dead"))
+        .instrument(tracing::info_span!("nix-store-print-dead"))
+        .await
+        .spawning("nix-store")?;
+
+    check_output(&output)?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('/'))
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Runs the garbage collector, stopping once at least `max_freed` bytes
+/// have been freed.
+///
+/// This keeps the garbage collection short when only a certain amount of
+/// space is needed.
+pub async fn collect_garbage_bounded<E>(executor: &E, max_freed: u64) -> Result<GcReport>
+where
+    E: Executor + ?Sized,
+{
+    collect_garbage_logged(executor, Some(max_freed), &DeletionLog::default()).await
+}
+
+/// Runs the garbage collector, stopping once at least `max_freed` bytes have
+/// been freed if given, and logs the deleted paths according to `log`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_store::{self, DeletionLog}, TokioExecutor};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let log = DeletionLog { file: Some("/var/log/gc.log".into()), ..DeletionLog::default() };
+/// let report = nix_store::collect_garbage_logged(&TokioExecutor, None, &log).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn collect_garbage_logged<E>(
+    executor: &E,
+    max_freed: Option<u64>,
+    log: &DeletionLog,
+) -> Result<GcReport>
+where
+    E: Executor + ?Sized,
+{
+    collect_garbage_with_progress(executor, max_freed, log, &Progress::default()).await
+}
+
+/// Runs the garbage collector like [collect_garbage_logged], reporting every
+/// deleted store path to the `progress`.
+///
+/// The deleted store paths are scanned from stderr with
+/// [Executor::output_streaming] while the garbage collector runs, so the
+/// memory used does not grow with the number of deleted paths.
+///
+/// # Examples
+///
+/// ```no_run
+/// use janitor::{nix_store::{self, DeletionLog}, Progress, TokioExecutor};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let progress = Progress::default().on_gc_progress(|deleted, path| {
+///     println!("{deleted}: {path}");
+/// });
+/// let report =
+///     nix_store::collect_garbage_with_progress(&TokioExecutor, None, &DeletionLog::default(), &progress)
+///         .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn collect_garbage_with_progress<E>(
+    executor: &E,
+    max_freed: Option<u64>,
+    log: &DeletionLog,
+    progress: &Progress,
+) -> Result<GcReport>
+where
+    E: Executor + ?Sized,
+{
+    let mut logger = log.start(progress);
+    let mut scanner = GcScanner::new(|path: &[u8]| logger.deleted(path));
+
+    let output = executor
+        .output_streaming(gc_command_bounded_by(max_freed), &mut |line| {
+            scanner.stderr_line(line)
+        })
+        .instrument(tracing::info_span!("nix-store-gc", max_freed))
+        .await
+        .spawning("nix-store")?;
+
+    check_output(&output)?;
+    let report = scanner.stdout(&output.stdout);
+    logger.finish();
+
+    Ok(report)
+}
+
+/// Verifies the consistency of the nix store without checking the contents
+/// of the store paths.
+///
+/// Inconsistencies are not treated as errors but reported through the
+/// returned [VerifyReport].
+pub async fn verify_store<E>(executor: &E) -> Result<VerifyReport>
+where
+    E: Executor + ?Sized,
+{
+    let output = executor
+        .output(verify_command())
+        .instrument(tracing::info_span!("nix-store-verify"))
+        .await
+        .spawning("nix-store")?;
+
+    Ok(VerifyReport::parse(&String::from_utf8_lossy(
+        &output.stderr,
+    )))
+}
+
+/// Verifies the nix store including the contents of the store paths, and
+/// attempts to repair any path found to be corrupted or missing.
+///
+/// This requires root privileges. The repaired paths are reported through
+/// [VerifyReport::repaired], anything that could not be repaired through
+/// [VerifyReport::issues].
+pub async fn repair_store<E>(executor: &E) -> Result<VerifyReport>
+where
+    E: Executor + ?Sized,
+{
+    let output = executor
+        .output(repair_command())
+        .instrument(tracing::info_span!("nix-store-repair"))
+        .await
+        .spawning("nix-store")?;
+
+    Ok(VerifyReport::parse(&String::from_utf8_lossy(
+        &output.stderr,
+    )))
+}
+
+/// Returns the closure of `paths`, i.e. all store paths they reference,
+/// directly or indirectly, including the store paths of `paths` themselves.
+///
+/// `paths` may be store paths or symlinks into the store, like the links of
+/// generations.
+pub async fn requisites<E, P>(executor: &E, paths: &[P]) -> Result<BTreeSet<String>>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if paths.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+
+    let command = CommandLine::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .args(paths.iter().map(|p| p.as_ref().as_os_str()));
+
+    let output = executor
+        .output(command)
+        .instrument(tracing::debug_span!("nix-store-requisites"))
+        .await
+        .spawning("nix-store")?;
+
+    check_output(&output)?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Returns the garbage collector roots keeping the store path `path` alive,
+/// directly or through other store paths.
+///
+/// A path without roots is deleted by the next garbage collection.
+pub async fn roots<E, P>(executor: &E, path: P) -> Result<BTreeSet<String>>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    let command = CommandLine::new("nix-store")
+        .arg("--query")
+        .arg("--roots")
+        .arg(path.as_ref().as_os_str());
+
+    let output = executor
+        .output(command)
+        .instrument(tracing::debug_span!("nix-store-roots"))
+        .await
+        .spawning("nix-store")?;
+
+    check_output(&output)?;
+
+    Ok(parse_roots(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Returns the sum of the NAR sizes of the given store `paths` in bytes.
+pub async fn total_size<E, P>(executor: &E, paths: &[P]) -> Result<u64>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    const CHUNK_SIZE: usize = 1000;
+
+    let mut total = 0;
+
+    for chunk in paths.chunks(CHUNK_SIZE) {
+        let command = CommandLine::new("nix-store")
+            .arg("--query")
+            .arg("--size")
+            .args(chunk.iter().map(|p| p.as_ref().as_os_str()));
+
+        let output = executor
+            .output(command)
+            .instrument(tracing::debug_span!("nix-store-size"))
+            .await
+            .spawning("nix-store")?;
+
+        check_output(&output)?;
+
+        total += String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse::<u64>().ok())
+            .sum::<u64>();
+    }
+
+    Ok(total)
+}
+
+/// Synchronous variants of the `nix-store` wrappers, for use without an
+/// async runtime.
+pub mod blocking {
+    use crate::error::{Result, SpawnContext};
+
+    use super::{
+        check_output, gc_command_bounded_by, repair_command, verify_command, DeletionLog, GcReport,
+        GcScanner, VerifyReport,
+    };
+    use crate::{executor::BlockingExecutor, progress::Progress};
+
+    /// Runs the garbage collector.
+    ///
+    /// This is the blocking counterpart of [super::collect_garbage].
+    pub fn collect_garbage<E>(executor: &E) -> Result<GcReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        collect_garbage_logged(executor, None, &DeletionLog::default())
+    }
+
+    /// Runs the garbage collector until at least `max_freed` bytes have been
+    /// freed.
+    ///
+    /// This is the blocking counterpart of [super::collect_garbage_bounded].
+    pub fn collect_garbage_bounded<E>(executor: &E, max_freed: u64) -> Result<GcReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        collect_garbage_logged(executor, Some(max_freed), &DeletionLog::default())
+    }
+
+    /// Runs the garbage collector, logging the deleted paths according to
+    /// `log`.
+    ///
+    /// This is the blocking counterpart of [super::collect_garbage_logged].
+    pub fn collect_garbage_logged<E>(
+        executor: &E,
+        max_freed: Option<u64>,
+        log: &DeletionLog,
+    ) -> Result<GcReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        collect_garbage_with_progress(executor, max_freed, log, &Progress::default())
+    }
+
+    /// Runs the garbage collector, reporting every deleted store path to the
+    /// `progress`.
+    ///
+    /// This is the blocking counterpart of
+    /// [super::collect_garbage_with_progress].
+    pub fn collect_garbage_with_progress<E>(
+        executor: &E,
+        max_freed: Option<u64>,
+        log: &DeletionLog,
+        progress: &Progress,
+    ) -> Result<GcReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        let _span = tracing::info_span!("nix-store-gc", max_freed).entered();
+
+        let mut logger = log.start(progress);
+        let mut scanner = GcScanner::new(|path: &[u8]| logger.deleted(path));
+
+        let output = executor
+            .output_streaming(gc_command_bounded_by(max_freed), &mut |line| {
+                scanner.stderr_line(line)
+            })
+            .spawning("nix-store")?;
+
+        check_output(&output)?;
+        let report = scanner.stdout(&output.stdout);
+        logger.finish();
+
+        Ok(report)
+    }
+
+    /// Verifies the consistency of the nix store.
+    ///
+    /// This is the blocking counterpart of [super::verify_store].
+    pub fn verify_store<E>(executor: &E) -> Result<VerifyReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        let _span = tracing::info_span!("nix-store-verify").entered();
+
+        let output = executor.output(verify_command()).spawning("nix-store")?;
+
+        Ok(VerifyReport::parse(&String::from_utf8_lossy(
+            &output.stderr,
+        )))
+    }
+
+    /// Verifies and repairs the nix store.
+    ///
+    /// This is the blocking counterpart of [super::repair_store].
+    pub fn repair_store<E>(executor: &E) -> Result<VerifyReport>
+    where
+        E: BlockingExecutor + ?Sized,
+    {
+        let _span = tracing::info_span!("nix-store-repair").entered();
+
+        let output = executor.output(repair_command()).spawning("nix-store")?;
+
+        Ok(VerifyReport::parse(&String::from_utf8_lossy(
+            &output.stderr,
+        )))
+    }
+}
+
+fn gc_command() -> CommandLine {
+    CommandLine::new("nix-store").arg("--gc")
+}
+
+fn bounded_gc_command(max_freed: u64) -> CommandLine {
+    gc_command().arg("--max-freed").arg(max_freed.to_string())
+}
+
+fn gc_command_bounded_by(max_freed: Option<u64>) -> CommandLine {
+    match max_freed {
+        Some(max_freed) => bounded_gc_command(max_freed),
+        None => gc_command(),
+    }
+}
+
+fn verify_command() -> CommandLine {
+    CommandLine::new("nix-store").arg("--verify")
+}
+
+fn repair_command() -> CommandLine {
+    CommandLine::new("nix-store")
+        .arg("--verify")
+        .arg("--check-contents")
+        .arg("--repair")
+}
+
+/// Parses the output of `nix-store --query --roots`, with lines like
+/// `/home/user/result -> /nix/store/…`, into the roots.
+fn parse_roots(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once(" -> ").map(|(root, _)| root.trim()))
+        .filter(|root| !root.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn check_output(output: &Output) -> Result<()> {
+    JanitorError::check_output("nix-store", output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use eyre::Result;
+
+    use rstest::rstest;
+
+    use crate::{executor::BlockingExecutor, CommandLine};
+
+    /// Replays the output of a garbage collection exiting with `status`.
+    #[derive(Debug)]
+    struct Transcript {
+        status: i32,
+        stdout: &'static [u8],
+        stderr: &'static [u8],
+    }
+
+    impl BlockingExecutor for Transcript {
+        fn output(&self, _command: CommandLine) -> io::Result<Output> {
+            Ok(Output {
+                status: ExitStatus::from_raw(self.status << 8),
+                stdout: self.stdout.to_vec(),
+                stderr: self.stderr.to_vec(),
+            })
+        }
+    }
+
+    #[rstest]
+    #[case::bytes("0 store paths deleted, 0.00 bytes freed", 0, 0)]
+    #[case::mib("12 store paths deleted, 2.00 MiB freed", 12, 2 * 1024 * 1024)]
+    #[case::gib("1 store paths deleted, 1.50 GiB freed", 1, 3 * 512 * 1024 * 1024)]
+    #[case::surrounded(
+        "deleting '/nix/store/abc'\n2 store paths deleted, 1.00 KiB freed\n",
+        2,
+        1024
+    )]
+    #[case::missing("deleting unused links...", 0, 0)]
+    #[case::unknown_unit("2 store paths deleted, 1.00 XiB freed", 0, 0)]
+    fn parse_gc(#[case] output: &str, #[case] paths: u64, #[case] bytes: u64) {
+        let report = GcReport::parse(output);
+
+        assert_eq!(report.paths_deleted, paths);
+        assert_eq!(report.bytes_freed, bytes);
+    }
+
+    #[test]
+    fn scan_counts_deletions_without_summary() -> Result<()> {
+        let stderr = "finding garbage collector roots...\n\
+                      deleting garbage...\n\
+                      deleting '/nix/store/abc-foo'\n\
+                      deleting '/nix/store/def-bar'\n\
+                      deleting unused links...\n";
+
+        let report = GcReport::scan(io::empty(), stderr.as_bytes())?;
+
+        assert_eq!(
+            report,
+            GcReport {
+                paths_deleted: 2,
+                bytes_freed: 0,
+                unrecognized: 0,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_counts_unrecognized_lines() -> Result<()> {
+        let stdout = "2 store paths deleted, 1.00 KiB freed\n";
+        let stderr = "finding garbage collector roots...\n\
+                      removing stale link from '/nix/var/nix/gcroots/auto/x' to '/tmp/result'\n\
+                      warning: something unexpected\n\
+                      deleting '/nix/store/abc-foo'\n\
+                      \n\
+                      note: currently hard linking saves 0.00 MiB\n\
+                      garbled\n";
+
+        let report = GcReport::scan(stdout.as_bytes(), stderr.as_bytes())?;
+
+        assert_eq!(report.paths_deleted, 2);
+        assert_eq!(report.unrecognized, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn deletion_log_writes_every_path() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("janitor-gc-log-{}", std::process::id()));
+        let log = DeletionLog {
+            every: NonZeroU64::new(2).unwrap(),
+            file: Some(path.clone()),
+        };
+        let transcript = Transcript {
+            status: 0,
+            stdout: b"2 store paths deleted, 1.00 KiB freed\n",
+            stderr: b"deleting '/nix/store/abc-foo'\ndeleting '/nix/store/def-bar'\n",
+        };
+
+        let report = blocking::collect_garbage_logged(&transcript, None, &log);
+        let written = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(report?.paths_deleted, 2);
+        assert_eq!(written?, "/nix/store/abc-foo\n/nix/store/def-bar\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_gc_progress() -> Result<()> {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress = Progress::default().on_gc_progress({
+            let seen = seen.clone();
+            move |deleted, path| seen.lock().unwrap().push((deleted, path.to_string()))
+        });
+        let transcript = Transcript {
+            status: 0,
+            stdout: b"",
+            stderr: b"deleting '/nix/store/abc-foo'\ndeleting '/nix/store/def-bar'\n",
+        };
+
+        blocking::collect_garbage_with_progress(
+            &transcript,
+            None,
+            &DeletionLog::default(),
+            &progress,
+        )?;
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (1, "/nix/store/abc-foo".to_string()),
+                (2, "/nix/store/def-bar".to_string())
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn failure_keeps_all_but_deletions() {
+        let transcript = Transcript {
+            status: 1,
+            stdout: b"",
+            stderr: b"deleting garbage...\n\
+                      deleting '/nix/store/abc-foo'\n\
+                      error: cannot delete path '/nix/store/def-bar'\n",
+        };
+
+        let error = blocking::collect_garbage(&transcript).unwrap_err();
+
+        assert!(
+            matches!(&error, JanitorError::Command { stderr, .. } if stderr == "deleting garbage...\n\
+                error: cannot delete path '/nix/store/def-bar'\n"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn scan_prefers_summary() -> Result<()> {
+        let report = GcReport::scan(
+            &b"5 store paths deleted, 2.00 KiB freed\n"[..],
+            &b"deleting '/nix/store/abc-foo'\n"[..],
+        )?;
+
+        assert_eq!(report.paths_deleted, 5);
+        assert_eq!(report.bytes_freed, 2048);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_tolerates_invalid_utf8() -> Result<()> {
+        let report = GcReport::scan(
+            &b"\xff\xfe freed\n1 store paths deleted, 1.00 KiB freed"[..],
+            &b"deleting '/nix/store/\xff'\n"[..],
+        )?;
+
+        assert_eq!(report.paths_deleted, 1);
+        assert_eq!(report.bytes_freed, 1024);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::clean("reading the Nix store...\nchecking path existence...\n", &[])]
+    #[case::broken(
+        "reading the Nix store...\npath '/nix/store/abc' disappeared, but it still has valid referrers!\n",
+        &["path '/nix/store/abc' disappeared, but it still has valid referrers!"]
+    )]
+    #[case::repaired(
+        "checking hashes...\nrepairing path '/nix/store/abc-foo'...\n",
+        &[]
+    )]
+    fn parse_verify(#[case] stderr: &str, #[case] issues: &[&str]) {
+        let report = VerifyReport::parse(stderr);
+
+        assert_eq!(report.issues, issues);
+        assert_eq!(report.is_consistent(), issues.is_empty());
+    }
+
+    #[rstest]
+    #[case::none("", &[])]
+    #[case::some(
+        "/home/user/result -> /nix/store/abc-foo\n{censored} -> /nix/store/abc-foo\n",
+        &["/home/user/result", "{censored}"]
+    )]
+    fn parse_roots(#[case] output: &str, #[case] expected: &[&str]) {
+        let roots = super::parse_roots(output);
+
+        assert_eq!(
+            roots.iter().map(String::as_str).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn bounded_gc_passes_limit() {
+        let command = bounded_gc_command(1024);
+
+        assert_eq!(command.program, "nix-store");
+        assert_eq!(command.args, ["--gc", "--max-freed", "1024"]);
+    }
+}
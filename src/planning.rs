@@ -0,0 +1,1005 @@
+//! Which generations of a profile to delete, and why.
+//!
+//! The decisions are a pure function of the generations of a profile, the
+//! references to them from outside of the profile, the [Exceptions] and the
+//! [Rules]: no IO,
+//! no logging and no clock. The janitor, the wasm module and the FFI all
+//! plan through [decide], so they agree with each other, and the rules can
+//! be tested exhaustively.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+};
+
+use chrono::{Duration, NaiveDateTime};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generation::Generation,
+    generation_set::{GenerationOrder, GenerationSet, Period, Thinning},
+    references::Reference,
+};
+
+/// The rules deciding which generations of a profile are deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rules {
+    /// Keep this many of the most recent generations.
+    pub keep_at_least: usize,
+
+    /// Delete the generations that are not among this many most recent
+    /// ones, even if active on or after `keep_since` or kept by `thinning`.
+    pub keep_at_most: Option<usize>,
+
+    /// Keep the generations that have been active on or after this date.
+    pub keep_since: NaiveDateTime,
+
+    /// Delete the generations with an id below this one, unless current or
+    /// active on or after `keep_since`, even beyond `keep_at_least`.
+    pub before_generation: Option<u32>,
+
+    /// Keep the generations that look like
+    /// [rollback targets](crate::Origin::RollbackTarget).
+    pub protect_rollback_targets: bool,
+
+    /// The order deciding which generations are the `keep_at_least` most
+    /// recent ones.
+    pub order_by: GenerationOrder,
+
+    /// Keep the last generation of the most recent days, weeks and months,
+    /// see [GenerationSet::thin].
+    pub thinning: Thinning,
+}
+
+impl Rules {
+    /// The rules keeping the `keep_at_least` most recent generations and
+    /// those active on or after `keep_since`, without a cutoff and without
+    /// protecting rollback targets, thinning or a limit, the most recent ones
+    /// by id.
+    pub fn new(keep_at_least: usize, keep_since: NaiveDateTime) -> Self {
+        Self {
+            keep_at_least,
+            keep_at_most: None,
+            keep_since,
+            before_generation: None,
+            protect_rollback_targets: false,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+        }
+    }
+}
+
+/// Generations kept or deleted no matter what the [Rules] say about them,
+/// see [decide].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Exceptions {
+    /// The ids of the generations that are always kept.
+    pub pinned: BTreeSet<u32>,
+
+    /// The ids of the generations that are deleted even if the rules keep
+    /// them, unless they are kept for their own safety: as protected
+    /// rollback targets, the current generation, referenced from outside of
+    /// the profile or pinned.
+    pub always_delete: BTreeSet<u32>,
+}
+
+/// Whether a generation is kept or deleted, and why, see [decide].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// The generation decided on.
+    pub generation: Generation,
+
+    /// Whether the generation is deleted.
+    pub delete: bool,
+
+    /// The rules that applied to the generation, in the order they have been
+    /// applied, the later ones taking precedence.
+    pub reasons: Vec<Reason>,
+}
+
+/// A rule of the retention policy that applied to a generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// Kept as one of this many most recent generations.
+    Recent(usize),
+
+    /// Kept as it has been active on or after this date.
+    Active(NaiveDateTime),
+
+    /// Kept as the last generation of its period, one of the `n` most
+    /// recent periods with generations.
+    Thinned { period: Period, n: usize },
+
+    /// Deleted as neither recent nor active on or after the date.
+    Expired {
+        keep_at_least: usize,
+        keep_since: NaiveDateTime,
+    },
+
+    /// Deleted as its id is below this cutoff.
+    Cutoff(u32),
+
+    /// Deleted as it is not one of this many most recent generations kept
+    /// at most.
+    AboveLimit(usize),
+
+    /// Deleted as it is to be deleted always.
+    AlwaysDelete,
+
+    /// Looks like the profile has been rolled back to it, kept if protected.
+    RollbackTarget { protected: bool },
+
+    /// Kept as it is the current generation, which is never deleted.
+    Current,
+
+    /// Kept as it is referenced from outside of the profile.
+    Referenced(Reference),
+
+    /// Kept as it has been pinned, which is never deleted.
+    Pinned,
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recent(n) => write!(f, "keep: one of the {n} most recent generations"),
+            Self::Active(since) => write!(f, "keep: active on or after {since}"),
+            Self::Thinned { period, n } => write!(
+                f,
+                "keep: the last generation of its {period}, one of the {n} most recent"
+            ),
+            Self::Expired {
+                keep_at_least,
+                keep_since,
+            } => write!(
+                f,
+                "delete: neither one of the {keep_at_least} most recent generations \
+                 nor active on or after {keep_since}"
+            ),
+            Self::Cutoff(id) => write!(f, "delete: below the cutoff generation {id}"),
+            Self::AboveLimit(n) => write!(
+                f,
+                "delete: not one of the {n} most recent generations kept at most"
+            ),
+            Self::AlwaysDelete => write!(f, "delete: always deleted"),
+            Self::RollbackTarget { protected: true } => {
+                write!(f, "keep: looks like a rollback target, which are protected")
+            }
+            Self::RollbackTarget { protected: false } => {
+                write!(f, "looks like a rollback target, which are not protected")
+            }
+            Self::Current => write!(f, "keep: the current generation"),
+            Self::Referenced(reference) => write!(f, "keep: referenced by {reference}"),
+            Self::Pinned => write!(f, "keep: pinned"),
+        }
+    }
+}
+
+/// Decides for each of the `generations` whether it is deleted according to
+/// the `rules`, in the order of their ids.
+///
+/// The rules apply in this order, the later ones taking precedence:
+///
+/// 1. Generations that are neither among the `keep_at_least` most recent
+///    ones, by `order_by`, nor active on or after `keep_since`, nor kept by
+///    [thinning](Rules::thinning) are deleted.
+/// 2. Generations below the cutoff are deleted, unless current or active.
+/// 3. Generations that are not among the `keep_at_most` most recent ones, by
+///    `order_by`, are deleted, except for the newest one.
+/// 4. Generations [always deleted](Exceptions::always_delete) are deleted.
+/// 5. Rollback targets are kept, if protected.
+/// 6. The current generation is kept.
+/// 7. Generations [referenced](Reference) from outside of the profile are
+///    kept.
+/// 8. [Pinned](Exceptions::pinned) generations are kept.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::{BTreeMap, BTreeSet};
+///
+/// use janitor::{
+///     planning::{self, Exceptions, Rules},
+///     Generation, GenerationSet, Reason,
+/// };
+///
+/// let generations = Generation::parse_many(
+///     "1 2023-06-01 00:00:00\n\
+///      2 2023-06-02 00:00:00 (current)",
+/// )?
+/// .into_iter()
+/// .collect::<GenerationSet>();
+///
+/// let rules = Rules::new(1, "2023-06-05T00:00:00".parse()?);
+/// let decisions = planning::decide(&generations, &BTreeMap::new(), &Exceptions::default(), &rules);
+///
+/// assert!(decisions[0].delete);
+/// assert!(!decisions[1].delete);
+/// assert_eq!(decisions[1].reasons[0], Reason::Recent(1));
+///
+/// let exceptions = Exceptions {
+///     pinned: BTreeSet::from([1]),
+///     always_delete: BTreeSet::from([1, 2]),
+/// };
+/// let decisions = planning::decide(&generations, &BTreeMap::new(), &exceptions, &rules);
+/// assert!(!decisions[0].delete);
+/// assert_eq!(decisions[0].reasons.last(), Some(&Reason::Pinned));
+/// assert!(!decisions[1].delete);
+/// assert_eq!(decisions[1].reasons.last(), Some(&Reason::Current));
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn decide(
+    generations: &GenerationSet,
+    referenced: &BTreeMap<u32, Vec<Reference>>,
+    exceptions: &Exceptions,
+    rules: &Rules,
+) -> Vec<Decision> {
+    let recent = generations.get_last_n_generations_by(rules.keep_at_least, rules.order_by);
+    let active = generations.get_active_on_or_after(rules.keep_since);
+    let thinned = rules
+        .thinning
+        .periods()
+        .map(|(period, n)| (period, n, generations.last_per_period(period, n)));
+    let cutoff = rules.before_generation.map(|id| {
+        let before = generations.generations_before(id, rules.keep_since);
+        (id, before)
+    });
+    // Like the cutoff, the limit never deletes the newest generation, which
+    // might not be among the most recent ones when ordering by date.
+    let newest = generations.iter().last().map(|generation| generation.id);
+    let limit = rules
+        .keep_at_most
+        .map(|n| (n, generations.get_last_n_generations_by(n, rules.order_by)));
+    let rollback_targets = generations.rollback_targets();
+
+    generations
+        .iter()
+        .map(|generation| {
+            let id = generation.id;
+            let mut reasons = Vec::new();
+
+            if recent.contains(id) {
+                reasons.push(Reason::Recent(rules.keep_at_least));
+            }
+            if active.contains(id) {
+                reasons.push(Reason::Active(rules.keep_since));
+            }
+            for (period, n, last) in &thinned {
+                if last.contains(id) {
+                    reasons.push(Reason::Thinned {
+                        period: *period,
+                        n: *n,
+                    });
+                }
+            }
+
+            let mut delete = reasons.is_empty();
+            if delete {
+                reasons.push(Reason::Expired {
+                    keep_at_least: rules.keep_at_least,
+                    keep_since: rules.keep_since,
+                });
+            }
+
+            if let Some((cutoff, before)) = &cutoff {
+                if before.contains(id) {
+                    delete = true;
+                    reasons.push(Reason::Cutoff(*cutoff));
+                }
+            }
+
+            if let Some((n, most_recent)) = &limit {
+                if !most_recent.contains(id) && Some(id) != newest && !delete {
+                    delete = true;
+                    reasons.push(Reason::AboveLimit(*n));
+                }
+            }
+
+            if exceptions.always_delete.contains(&id) {
+                delete = true;
+                reasons.push(Reason::AlwaysDelete);
+            }
+
+            if rollback_targets.contains(id) {
+                delete &= !rules.protect_rollback_targets;
+                reasons.push(Reason::RollbackTarget {
+                    protected: rules.protect_rollback_targets,
+                });
+            }
+
+            if generation.current && delete {
+                delete = false;
+                reasons.push(Reason::Current);
+            }
+
+            for reference in referenced.get(&id).into_iter().flatten() {
+                delete = false;
+                reasons.push(Reason::Referenced(reference.clone()));
+            }
+
+            if exceptions.pinned.contains(&id) {
+                delete = false;
+                reasons.push(Reason::Pinned);
+            }
+
+            Decision {
+                generation: *generation,
+                delete,
+                reasons,
+            }
+        })
+        .collect()
+}
+
+/// The generations the `decisions` delete.
+pub fn to_delete(decisions: &[Decision]) -> GenerationSet {
+    decisions
+        .iter()
+        .filter(|decision| decision.delete)
+        .map(|decision| decision.generation)
+        .collect()
+}
+
+/// The generations of `generations` to delete according to the `rules`,
+/// without any references from outside of the profile or [Exceptions].
+///
+/// # Examples
+///
+/// ```
+/// use janitor::{planning::{self, Rules}, Generation, GenerationSet};
+///
+/// let generations = Generation::parse_many(
+///     "1 2023-06-01 00:00:00\n\
+///      2 2023-06-02 00:00:00 (current)",
+/// )?
+/// .into_iter()
+/// .collect::<GenerationSet>();
+///
+/// let rules = Rules::new(1, "2023-06-05T00:00:00".parse()?);
+/// let to_delete = planning::plan(&generations, &rules);
+///
+/// assert_eq!(to_delete.iter().map(|g| g.id).collect::<Vec<_>>(), [1]);
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn plan(generations: &GenerationSet, rules: &Rules) -> GenerationSet {
+    to_delete(&decide(
+        generations,
+        &BTreeMap::new(),
+        &Exceptions::default(),
+        rules,
+    ))
+}
+
+/// Why the retention policy deletes none of the generations of a profile,
+/// see [explain].
+///
+/// Each generation is counted once, for the first of these rules keeping
+/// it: being active, being recent, being kept by thinning, being a protected
+/// rollback target, being current, being referenced and being pinned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The number of generations of the profile.
+    pub generations: usize,
+
+    /// The generations kept as active on or after `keep_since`.
+    pub active: usize,
+
+    /// The date generations have to be active on or after to be kept.
+    pub keep_since: NaiveDateTime,
+
+    /// The generations kept as one of the `keep_at_least` most recent ones.
+    pub recent: usize,
+
+    /// The number of most recent generations kept.
+    pub keep_at_least: usize,
+
+    /// The generations kept as the last one of a day, week or month.
+    pub thinned: usize,
+
+    /// The generations kept as protected rollback targets.
+    pub protected: usize,
+
+    /// Whether the current generation is kept only for being current.
+    pub current: bool,
+
+    /// The generations kept only for being referenced from outside of the
+    /// profile.
+    pub referenced: usize,
+
+    /// The generations kept only for being pinned.
+    pub pinned: usize,
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.generations == 0 {
+            return write!(f, "the profile has no generations");
+        }
+
+        let mut parts = Vec::new();
+        if self.active > 0 {
+            parts.push(format!(
+                "{} active on or after {}",
+                self.active, self.keep_since
+            ));
+        }
+        if self.recent > 0 {
+            parts.push(format!(
+                "{} retained by keep-at-least={}",
+                self.recent, self.keep_at_least
+            ));
+        }
+        if self.thinned > 0 {
+            parts.push(format!("{} kept by thinning", self.thinned));
+        }
+        if self.protected > 0 {
+            parts.push(format!("{} protected as rollback targets", self.protected));
+        }
+        if self.current {
+            parts.push("the current one".to_string());
+        }
+        if self.referenced > 0 {
+            parts.push(format!(
+                "{} referenced from outside of the profile",
+                self.referenced
+            ));
+        }
+        if self.pinned > 0 {
+            parts.push(format!("{} pinned", self.pinned));
+        }
+
+        write!(
+            f,
+            "all {} generations are kept: {}",
+            self.generations,
+            parts.join("; ")
+        )
+    }
+}
+
+/// Explains why the `decisions` taken according to the `rules` delete no
+/// generation, see [Explanation].
+///
+/// Returns `None` if they delete any generation.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// use janitor::{planning::{self, Exceptions, Rules}, Generation, GenerationSet};
+///
+/// let generations = Generation::parse_many(
+///     "1 2023-06-01 00:00:00\n\
+///      2 2023-06-02 00:00:00\n\
+///      3 2023-06-03 00:00:00 (current)",
+/// )?
+/// .into_iter()
+/// .collect::<GenerationSet>();
+///
+/// let rules = Rules::new(3, "2023-06-05T00:00:00".parse()?);
+/// let decisions = planning::decide(&generations, &BTreeMap::new(), &Exceptions::default(), &rules);
+///
+/// assert_eq!(
+///     planning::explain(&decisions, &rules).unwrap().to_string(),
+///     "all 3 generations are kept: 1 active on or after 2023-06-05 00:00:00; \
+///      2 retained by keep-at-least=3"
+/// );
+///
+/// let rules = Rules::new(1, rules.keep_since);
+/// let decisions = planning::decide(&generations, &BTreeMap::new(), &Exceptions::default(), &rules);
+/// assert!(planning::explain(&decisions, &rules).is_none());
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn explain(decisions: &[Decision], rules: &Rules) -> Option<Explanation> {
+    if decisions.iter().any(|decision| decision.delete) {
+        return None;
+    }
+
+    let mut explanation = Explanation {
+        generations: decisions.len(),
+        active: 0,
+        keep_since: rules.keep_since,
+        recent: 0,
+        keep_at_least: rules.keep_at_least,
+        thinned: 0,
+        protected: 0,
+        current: false,
+        referenced: 0,
+        pinned: 0,
+    };
+    for decision in decisions {
+        let has = |wanted: fn(&Reason) -> bool| decision.reasons.iter().any(wanted);
+        if has(|r| matches!(r, Reason::Active(_))) {
+            explanation.active += 1;
+        } else if has(|r| matches!(r, Reason::Recent(_))) {
+            explanation.recent += 1;
+        } else if has(|r| matches!(r, Reason::Thinned { .. })) {
+            explanation.thinned += 1;
+        } else if has(|r| matches!(r, Reason::RollbackTarget { protected: true })) {
+            explanation.protected += 1;
+        } else if has(|r| matches!(r, Reason::Current)) {
+            explanation.current = true;
+        } else if has(|r| matches!(r, Reason::Referenced(_))) {
+            explanation.referenced += 1;
+        } else {
+            explanation.pinned += 1;
+        }
+    }
+
+    Some(explanation)
+}
+
+/// Rules deleting at least one generation more than the ones they tighten,
+/// see [tighten].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tightening {
+    /// The tightened rules.
+    pub rules: Rules,
+
+    /// The generation kept by the retention policy of the original rules but
+    /// not by the tightened ones.
+    pub released: Generation,
+}
+
+/// Tightens the `rules` just enough for the retention policy to no longer
+/// keep the oldest generation it keeps now, by `order_by`, without keeping
+/// fewer than the `floor` most recent generations.
+///
+/// Generations deleted anyway, e.g. below the cutoff, and the ones kept by
+/// [thinning](Rules::thinning) are not released. The
+/// tightened rules never keep more generations than the original ones, but
+/// the released generation might still be kept, e.g. if it is current or
+/// a protected rollback target. Returns `None` if the rules can not be
+/// tightened any further.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::{planning::{self, Rules}, Generation, GenerationSet};
+///
+/// let generations = Generation::parse_many(
+///     "1 2023-06-01 00:00:00\n\
+///      2 2023-06-02 00:00:00\n\
+///      3 2023-06-03 00:00:00 (current)",
+/// )?
+/// .into_iter()
+/// .collect::<GenerationSet>();
+///
+/// let rules = Rules::new(3, "2023-06-05T00:00:00".parse()?);
+/// let tightening = planning::tighten(&generations, &rules, 1).unwrap();
+///
+/// assert_eq!(tightening.released.id, 1);
+/// assert_eq!(planning::plan(&generations, &tightening.rules).len(), 1);
+/// assert!(planning::tighten(&generations, &Rules::new(1, rules.keep_since), 1).is_none());
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub fn tighten(generations: &GenerationSet, rules: &Rules, floor: usize) -> Option<Tightening> {
+    let recent = generations.get_last_n_generations_by(rules.keep_at_least, rules.order_by);
+    let active = generations.get_active_on_or_after(rules.keep_since);
+    let thinned = generations.thin(
+        rules.thinning.keep_daily,
+        rules.thinning.keep_weekly,
+        rules.thinning.keep_monthly,
+    );
+    let to_delete = plan(generations, rules);
+    let ordered = generations.sorted_by(rules.order_by);
+
+    ordered.iter().enumerate().find_map(|(index, generation)| {
+        let newer = ordered.len() - index - 1;
+        let kept = !to_delete.contains(generation.id)
+            && (recent.contains(generation.id) || active.contains(generation.id));
+        if generation.current || newer < floor.max(1) || !kept || thinned.contains(generation.id) {
+            return None;
+        }
+
+        // The last generation older than `keep_since` by id counts as
+        // active, so the one after the released generation by id has to be
+        // older too.
+        let next = generations.iter().find(|g| g.id > generation.id)?;
+        let keep_since = rules
+            .keep_since
+            .max(generation.date.max(next.date) + Duration::seconds(1));
+
+        Some(Tightening {
+            rules: Rules {
+                keep_at_least: rules.keep_at_least.min(newer),
+                keep_since,
+                ..*rules
+            },
+            released: *generation,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Duration;
+    use proptest::prelude::*;
+
+    use crate::references::Referrer;
+
+    fn base() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap()
+    }
+
+    /// Up to 20 generations dated within 60 days, not necessarily in the
+    /// order of their ids, with any of them current.
+    fn generations() -> impl Strategy<Value = GenerationSet> {
+        prop::collection::vec(0..60i64, 0..20).prop_flat_map(|days| {
+            let count = days.len();
+            (Just(days), 0..count.max(1)).prop_map(|(days, current)| {
+                days.iter()
+                    .enumerate()
+                    .map(|(i, day)| Generation {
+                        id: i as u32 + 1,
+                        date: base() + Duration::days(*day),
+                        current: i == current,
+                    })
+                    .collect()
+            })
+        })
+    }
+
+    fn rules() -> impl Strategy<Value = Rules> {
+        (
+            0..25usize,
+            0..70i64,
+            prop::option::of(0..25u32),
+            any::<bool>(),
+            prop_oneof![Just(GenerationOrder::Id), Just(GenerationOrder::Date)],
+            prop_oneof![Just(Thinning::default()), thinning()],
+            prop::option::of(0..10usize),
+        )
+            .prop_map(
+                |(
+                    keep_at_least,
+                    day,
+                    before_generation,
+                    protect_rollback_targets,
+                    order_by,
+                    thinning,
+                    above_keep_at_least,
+                )| Rules {
+                    keep_at_least,
+                    // Like jobs, the rules never keep fewer at most than at
+                    // least.
+                    keep_at_most: above_keep_at_least.map(|n| keep_at_least.max(1) + n),
+                    keep_since: base() + Duration::days(day),
+                    before_generation,
+                    protect_rollback_targets,
+                    order_by,
+                    thinning,
+                },
+            )
+    }
+
+    fn thinning() -> impl Strategy<Value = Thinning> {
+        (0..10usize, 0..5usize, 0..3usize).prop_map(|(keep_daily, keep_weekly, keep_monthly)| {
+            Thinning {
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+            }
+        })
+    }
+
+    fn ids(generations: &GenerationSet) -> Vec<u32> {
+        generations.iter().map(|g| g.id).collect()
+    }
+
+    fn is_subset(smaller: &GenerationSet, larger: &GenerationSet) -> bool {
+        smaller.difference(larger).is_empty()
+    }
+
+    fn reference() -> Reference {
+        Reference {
+            referrer: Referrer::Container("web".to_string()),
+            specialisation: None,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decides_every_generation_once(generations in generations(), rules in rules()) {
+            let decisions = decide(&generations, &BTreeMap::new(), &Exceptions::default(), &rules);
+
+            let decided: Vec<_> = decisions.iter().map(|d| d.generation.id).collect();
+            prop_assert_eq!(decided, ids(&generations));
+            prop_assert!(decisions.iter().all(|d| !d.reasons.is_empty()));
+        }
+
+        #[test]
+        fn deterministic(generations in generations(), rules in rules()) {
+            prop_assert_eq!(plan(&generations, &rules), plan(&generations, &rules));
+        }
+
+        #[test]
+        fn explains_when_nothing_is_deleted(generations in generations(), rules in rules()) {
+            let decisions = decide(&generations, &BTreeMap::new(), &Exceptions::default(), &rules);
+            let explanation = explain(&decisions, &rules);
+
+            prop_assert_eq!(explanation.is_some(), plan(&generations, &rules).is_empty());
+            if let Some(explanation) = explanation {
+                let counted = explanation.active
+                    + explanation.recent
+                    + explanation.thinned
+                    + explanation.protected
+                    + usize::from(explanation.current)
+                    + explanation.referenced
+                    + explanation.pinned;
+                prop_assert_eq!(counted, generations.len());
+                prop_assert_eq!(explanation.referenced, 0);
+                prop_assert_eq!(explanation.pinned, 0);
+            }
+        }
+
+        #[test]
+        fn never_deletes_current_or_newest(generations in generations(), rules in rules()) {
+            let to_delete = plan(&generations, &rules);
+
+            prop_assert!(to_delete.iter().all(|g| !g.current));
+            if let Some(newest) = generations.iter().last() {
+                prop_assert!(!to_delete.contains(newest.id));
+            }
+        }
+
+        #[test]
+        fn keeps_at_most(generations in generations(), rules in rules(), n in 1..10usize) {
+            let rules = Rules {
+                keep_at_most: Some(n),
+                protect_rollback_targets: false,
+                order_by: GenerationOrder::Id,
+                ..rules
+            };
+            let to_delete = plan(&generations, &rules);
+
+            let kept = generations.difference(&to_delete);
+            prop_assert!(kept.iter().filter(|g| !g.current).count() <= n);
+        }
+
+        #[test]
+        fn never_deletes_more_when_keeping_more(
+            generations in generations(),
+            rules in rules(),
+            more in 0..10usize,
+        ) {
+            let more = Rules { keep_at_least: rules.keep_at_least + more, ..rules };
+
+            prop_assert!(is_subset(&plan(&generations, &more), &plan(&generations, &rules)));
+        }
+
+        #[test]
+        fn never_deletes_more_when_keeping_longer(
+            generations in generations(),
+            rules in rules(),
+            days in 0..30i64,
+        ) {
+            let longer = Rules { keep_since: rules.keep_since - Duration::days(days), ..rules };
+
+            prop_assert!(is_subset(&plan(&generations, &longer), &plan(&generations, &rules)));
+        }
+
+        #[test]
+        fn never_deletes_more_when_thinning(
+            generations in generations(),
+            rules in rules(),
+            thinning in thinning(),
+        ) {
+            let unthinned = Rules { thinning: Thinning::default(), ..rules };
+            let thinned = Rules { thinning, ..rules };
+
+            prop_assert!(is_subset(&plan(&generations, &thinned), &plan(&generations, &unthinned)));
+        }
+
+        #[test]
+        fn never_deletes_more_when_protecting(generations in generations(), rules in rules()) {
+            let protected = Rules { protect_rollback_targets: true, ..rules };
+            let unprotected = Rules { protect_rollback_targets: false, ..rules };
+
+            prop_assert!(is_subset(&plan(&generations, &protected), &plan(&generations, &unprotected)));
+        }
+
+        #[test]
+        fn cutoff_only_adds_deletions(generations in generations(), rules in rules()) {
+            let without = Rules { before_generation: None, ..rules };
+
+            prop_assert!(is_subset(&plan(&generations, &without), &plan(&generations, &rules)));
+        }
+
+        #[test]
+        fn keeps_referenced(generations in generations(), rules in rules(), pick in any::<prop::sample::Index>()) {
+            prop_assume!(!generations.is_empty());
+            let id = ids(&generations)[pick.index(generations.len())];
+            let referenced = BTreeMap::from([(id, vec![reference()])]);
+
+            let decisions = decide(&generations, &referenced, &Exceptions::default(), &rules);
+
+            prop_assert!(!to_delete(&decisions).contains(id));
+            prop_assert!(is_subset(&to_delete(&decisions), &plan(&generations, &rules)));
+        }
+
+        #[test]
+        fn always_deletes_unless_safe(generations in generations(), rules in rules(), pick in any::<prop::sample::Index>()) {
+            prop_assume!(!generations.is_empty());
+            let id = ids(&generations)[pick.index(generations.len())];
+            let exceptions = Exceptions { always_delete: BTreeSet::from([id]), ..Exceptions::default() };
+
+            let decisions = decide(&generations, &BTreeMap::new(), &exceptions, &rules);
+
+            let generation = generations.get(id).unwrap();
+            let protected = rules.protect_rollback_targets && generations.rollback_targets().contains(id);
+            prop_assert_eq!(to_delete(&decisions).contains(id), !generation.current && !protected);
+            prop_assert!(is_subset(&plan(&generations, &rules), &to_delete(&decisions)));
+        }
+
+        #[test]
+        fn keeps_pinned(generations in generations(), rules in rules(), pick in any::<prop::sample::Index>()) {
+            prop_assume!(!generations.is_empty());
+            let id = ids(&generations)[pick.index(generations.len())];
+
+            let exceptions = Exceptions { pinned: BTreeSet::from([id]), ..Exceptions::default() };
+            let decisions = decide(&generations, &BTreeMap::new(), &exceptions, &rules);
+
+            prop_assert!(!to_delete(&decisions).contains(id));
+            prop_assert_eq!(
+                to_delete(&decisions),
+                plan(&generations, &rules).iter().filter(|g| g.id != id).copied().collect()
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn tightening_deletes_more(generations in generations(), rules in rules(), floor in 0..5usize) {
+            let Some(tightening) = tighten(&generations, &rules, floor) else {
+                return Ok(());
+            };
+            let before = plan(&generations, &rules);
+            let after = plan(&generations, &tightening.rules);
+
+            prop_assert!(is_subset(&before, &after));
+            prop_assert!(!before.contains(tightening.released.id));
+            let rollback_target = generations.rollback_targets().contains(tightening.released.id);
+            if !(rules.protect_rollback_targets && rollback_target) {
+                prop_assert!(after.contains(tightening.released.id));
+            }
+        }
+
+        #[test]
+        fn tightening_keeps_the_floor(generations in generations(), rules in rules(), floor in 0..5usize) {
+            let mut rules = Rules {
+                keep_at_least: rules.keep_at_least.max(floor),
+                keep_at_most: rules.keep_at_most.map(|n| n.max(floor)),
+                before_generation: None,
+                ..rules
+            };
+            let mut steps = 0;
+            while let Some(tightening) = tighten(&generations, &rules, floor) {
+                rules = tightening.rules;
+                steps += 1;
+                prop_assert!(steps <= generations.len());
+            }
+
+            let to_delete = plan(&generations, &rules);
+            let floor = generations.get_last_n_generations_by(floor, rules.order_by);
+            prop_assert!(floor.iter().all(|g| !to_delete.contains(g.id)));
+        }
+    }
+
+    #[test]
+    fn keeps_rolled_back_current() {
+        let generations: GenerationSet = (1..=3)
+            .map(|id| Generation {
+                id,
+                date: base() + Duration::days(i64::from(id)),
+                current: id == 1,
+            })
+            .collect();
+
+        let decisions = decide(
+            &generations,
+            &BTreeMap::new(),
+            &Exceptions::default(),
+            &Rules::new(1, base() + Duration::days(10)),
+        );
+
+        assert!(!decisions[0].delete);
+        assert_eq!(decisions[0].reasons.last(), Some(&Reason::Current));
+        assert_eq!(ids(&to_delete(&decisions)), [2]);
+    }
+
+    #[test]
+    fn keeps_last_generation_per_period() {
+        // A generation every third day over two months, the last one current.
+        let generations: GenerationSet = (1..=20)
+            .map(|id| Generation {
+                id,
+                date: base() + Duration::days(3 * i64::from(id)),
+                current: id == 20,
+            })
+            .collect();
+        let rules = Rules {
+            thinning: Thinning {
+                keep_daily: 2,
+                keep_weekly: 0,
+                keep_monthly: 3,
+            },
+            ..Rules::new(1, base() + Duration::days(100))
+        };
+
+        let decisions = decide(
+            &generations,
+            &BTreeMap::new(),
+            &Exceptions::default(),
+            &rules,
+        );
+        let kept: Vec<_> = decisions
+            .iter()
+            .filter(|d| !d.delete)
+            .map(|d| d.generation.id)
+            .collect();
+
+        assert_eq!(kept, ids(&generations.thin(2, 0, 3)));
+        assert!(decisions[18].reasons.contains(&Reason::Thinned {
+            period: Period::Day,
+            n: 2,
+        }));
+    }
+
+    #[rstest::rstest]
+    #[case::by_id(GenerationOrder::Id, &[1, 2])]
+    #[case::by_date(GenerationOrder::Date, &[1, 3])]
+    fn orders_recent_generations(#[case] order_by: GenerationOrder, #[case] expected: &[u32]) {
+        // Generation 2 has been re-activated and its link re-created after
+        // generation 4 has been built.
+        let generations: GenerationSet = [(1, 1), (2, 5), (3, 2), (4, 3)]
+            .into_iter()
+            .map(|(id, day)| Generation {
+                id,
+                date: base() + Duration::days(day),
+                current: id == 4,
+            })
+            .collect();
+        let rules = Rules {
+            order_by,
+            ..Rules::new(2, base() + Duration::days(10))
+        };
+
+        assert_eq!(ids(&plan(&generations, &rules)), expected);
+    }
+
+    #[rstest::rstest]
+    #[case::by_id(GenerationOrder::Id)]
+    #[case::by_date(GenerationOrder::Date)]
+    fn breaks_date_ties_by_id(#[case] order_by: GenerationOrder) {
+        // Generations 1 to 4 have been built by a script within the same
+        // second.
+        let date = base() + Duration::days(1);
+        let generations: GenerationSet = (1..=5)
+            .map(|id| Generation {
+                id,
+                date: if id < 5 {
+                    date
+                } else {
+                    date + Duration::days(1)
+                },
+                current: id == 5,
+            })
+            .collect();
+        let rules = Rules {
+            order_by,
+            ..Rules::new(2, base() + Duration::days(3))
+        };
+
+        assert_eq!(ids(&plan(&generations, &rules)), [1, 2, 3]);
+        assert_eq!(
+            tighten(&generations, &rules, 1).map(|tightening| tightening.released.id),
+            Some(4)
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Detects the platform the janitor runs on at runtime rather than at
+//! compile time, so that a single static binary finds the profiles and uses
+//! the integrations available on NixOS, other Linux distributions and macOS
+//! alike.
+
+use std::{
+    env,
+    ffi::OsStr,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// Present on NixOS, relative to the root.
+const NIXOS_MARKER: &str = "etc/NIXOS";
+
+/// The mount table of the janitor's process, relative to the root, see
+/// [MOUNTINFO](crate::mounts::MOUNTINFO).
+const MOUNTINFO: &str = "proc/self/mountinfo";
+
+/// Where the kernel lists the power supplies, relative to the root.
+const POWER_SUPPLIES: &str = "sys/class/power_supply";
+
+/// Present while systemd is the service manager, relative to the root.
+const SYSTEMD: &str = "run/systemd/system";
+
+/// Set by systemd when stderr or stdout is connected to the journal.
+const JOURNAL_STREAM: &str = "JOURNAL_STREAM";
+
+/// The operating system, as far as the janitor cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Os {
+    /// NixOS, where units and the packages of users are declared in the
+    /// system configuration.
+    NixOs,
+
+    /// Any other Linux distribution with nix installed.
+    Linux,
+
+    /// macOS, with or without nix-darwin.
+    MacOs,
+
+    /// Anything else, treated like Linux.
+    Other,
+}
+
+impl Os {
+    /// Detects the operating system named `name`, like
+    /// [std::env::consts::OS], in the system mounted at `root`.
+    pub fn detect(name: &str, root: &Path) -> Self {
+        match name {
+            "linux" if root.join(NIXOS_MARKER).exists() => Self::NixOs,
+            "linux" => Self::Linux,
+            "macos" => Self::MacOs,
+            _ => Self::Other,
+        }
+    }
+
+    /// The operating system the janitor runs on.
+    pub fn current() -> Self {
+        Self::detect(env::consts::OS, Path::new("/"))
+    }
+
+    /// The directory holding the home of each user.
+    pub fn homes(self) -> &'static Path {
+        Path::new(match self {
+            Self::MacOs => "/Users",
+            _ => "/home",
+        })
+    }
+}
+
+impl fmt::Display for Os {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::NixOs => "NixOS",
+            Self::Linux => "Linux",
+            Self::MacOs => "macOS",
+            Self::Other => env::consts::OS,
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The operating system and the integrations available on it.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::platform::Platform;
+///
+/// let platform = Platform::current();
+/// println!("{platform}");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    /// The operating system.
+    pub os: Os,
+
+    /// The directory holding the home of each user.
+    pub homes: PathBuf,
+
+    /// Whether the mount table can be read from procfs, to check in advance
+    /// whether profiles and the store can be written to.
+    pub procfs: bool,
+
+    /// Whether the power supplies can be read from sysfs, to skip the
+    /// garbage collection on a low battery.
+    pub power_supplies: bool,
+
+    /// Whether systemd is the service manager, to install timers with.
+    pub systemd: bool,
+
+    /// Whether the log is written to the journal, which adds timestamps of
+    /// its own and does not render colors.
+    pub journald: bool,
+}
+
+impl Platform {
+    /// Detects the platform of the operating system named `name`, mounted
+    /// at `root`, with the value of the `JOURNAL_STREAM` variable of the
+    /// environment.
+    pub fn detect(name: &str, root: &Path, journal_stream: Option<&OsStr>) -> Self {
+        let os = Os::detect(name, root);
+        let linux = matches!(os, Os::NixOs | Os::Linux);
+
+        Self {
+            os,
+            homes: os.homes().to_path_buf(),
+            procfs: linux && root.join(MOUNTINFO).exists(),
+            power_supplies: linux && root.join(POWER_SUPPLIES).is_dir(),
+            systemd: linux && root.join(SYSTEMD).is_dir(),
+            journald: journal_stream.is_some_and(|stream| !stream.is_empty()),
+        }
+    }
+
+    /// The platform the janitor runs on.
+    pub fn current() -> Self {
+        Self::detect(
+            env::consts::OS,
+            Path::new("/"),
+            env::var_os(JOURNAL_STREAM).as_deref(),
+        )
+    }
+
+    /// The integrations, and whether each of them is active.
+    pub fn integrations(&self) -> [(&'static str, bool); 4] {
+        [
+            ("procfs mount table", self.procfs),
+            ("sysfs power supplies", self.power_supplies),
+            ("systemd", self.systemd),
+            ("journald", self.journald),
+        ]
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "platform: {}", self.os)?;
+        writeln!(f, "  homes: {}", self.homes.display())?;
+        for (integration, active) in self.integrations() {
+            let state = match active {
+                true => "active",
+                false => "inactive",
+            };
+            writeln!(f, "  {integration}: {state}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use eyre::Result;
+    use rstest::rstest;
+
+    /// An empty root for the test `name`.
+    fn root(name: &str) -> Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("janitor-platform-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
+    #[rstest]
+    #[case::nixos("linux", true, Os::NixOs)]
+    #[case::linux("linux", false, Os::Linux)]
+    #[case::macos("macos", false, Os::MacOs)]
+    #[case::marker_ignored_elsewhere("macos", true, Os::MacOs)]
+    #[case::other("freebsd", false, Os::Other)]
+    fn detects_the_os(
+        #[case] name: &str,
+        #[case] marker: bool,
+        #[case] expected: Os,
+    ) -> Result<()> {
+        let root = root(&format!("{name}-{marker}"))?;
+        if marker {
+            fs::create_dir_all(root.join("etc"))?;
+            fs::write(root.join(NIXOS_MARKER), "")?;
+        }
+
+        let detected = Os::detect(name, &root);
+        fs::remove_dir_all(&root)?;
+
+        assert_eq!(detected, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_the_integrations() -> Result<()> {
+        let root = root("integrations")?;
+        fs::create_dir_all(root.join("proc/self"))?;
+        fs::write(root.join(MOUNTINFO), "")?;
+        fs::create_dir_all(root.join(SYSTEMD))?;
+
+        let linux = Platform::detect("linux", &root, Some(OsStr::new("8:1234")));
+        // Files that happen to exist at the same paths on macOS are ignored.
+        let macos = Platform::detect("macos", &root, None);
+        fs::remove_dir_all(&root)?;
+
+        assert_eq!(
+            linux.to_string(),
+            "platform: Linux\n  homes: /home\n  procfs mount table: active\n  \
+             sysfs power supplies: inactive\n  systemd: active\n  journald: active\n"
+        );
+        assert_eq!(macos.homes, Path::new("/Users"));
+        assert_eq!(macos.integrations().map(|(_, active)| active), [false; 4]);
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+use chrono::{prelude::*, Duration};
+
+use crate::{
+    duration::format_duration,
+    generation_set::{GenerationOrder, Thinning},
+    profiles::ProfileKind,
+};
+
+/// Describes how many generations of a profile to retain.
+///
+/// # Fields
+///
+/// * `keep` - Generations active within this duration are kept.
+/// * `since` - Generations active on or after this date are kept instead.
+/// * `keep_at_least` - The minimum number of recent generations to keep.
+/// * `keep_at_most` - The maximum number of recent generations to keep.
+/// * `order_by` - The order deciding which generations are the recent ones.
+/// * `thinning` - How many days, weeks and months to keep a generation of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Generations that have been active within this duration are kept.
+    pub keep: Duration,
+
+    /// Generations that have been active on or after this date are kept,
+    /// regardless of `keep`.
+    pub since: Option<NaiveDateTime>,
+
+    /// The minimum number of recent generations to keep.
+    pub keep_at_least: usize,
+
+    /// The maximum number of recent generations to keep, even if active
+    /// within `keep`, no limit for every kind unless overridden.
+    pub keep_at_most: Option<usize>,
+
+    /// The order deciding which generations are the `keep_at_least` most
+    /// recent ones, by id for every kind unless overridden. Generations
+    /// sharing a date are ordered by id, see [GenerationOrder::compare].
+    pub order_by: GenerationOrder,
+
+    /// How many of the most recent days, weeks and months the last
+    /// generation is kept of, none for every kind unless overridden.
+    pub thinning: Thinning,
+}
+
+impl RetentionPolicy {
+    /// Returns the default policy for a [ProfileKind].
+    ///
+    /// | kind         | days | generations |
+    /// |--------------|-----:|------------:|
+    /// | system       |   14 |          10 |
+    /// | user         |    7 |           5 |
+    /// | home-manager |    7 |           5 |
+    /// | channels     |   30 |           2 |
+    /// | container    |   14 |           5 |
+    /// | default      |   90 |           5 |
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{ProfileKind, RetentionPolicy};
+    ///
+    /// let policy = RetentionPolicy::default_for(ProfileKind::System);
+    /// assert_eq!(policy.keep, chrono::Duration::days(14));
+    /// assert_eq!(policy.keep_at_least, 10);
+    /// ```
+    pub fn default_for(kind: ProfileKind) -> Self {
+        let (days, keep_at_least) = match kind {
+            ProfileKind::System => (14, 10),
+            ProfileKind::User => (7, 5),
+            ProfileKind::HomeManager => (7, 5),
+            ProfileKind::Channels => (30, 2),
+            ProfileKind::Container => (14, 5),
+            ProfileKind::Default => (90, 5),
+        };
+
+        Self {
+            keep: Duration::days(days),
+            since: None,
+            keep_at_least,
+            keep_at_most: None,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+        }
+    }
+
+    /// Returns the policy for a [ProfileKind] with the given `overrides`
+    /// applied on top of its defaults.
+    ///
+    /// A negative `keep` would move the cutoff into the future and
+    /// doom every generation not protected by `keep_at_least`, it is clamped
+    /// to 0 with a warning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::Duration;
+    /// use janitor::{ProfileKind, RetentionOverrides, RetentionPolicy};
+    ///
+    /// let overrides = RetentionOverrides { keep: Some(Duration::hours(36)), ..Default::default() };
+    /// let policy = RetentionPolicy::resolve(ProfileKind::Channels, overrides);
+    /// assert_eq!(policy.keep, Duration::hours(36));
+    /// assert_eq!(policy.keep_at_least, 2);
+    /// ```
+    pub fn resolve(kind: ProfileKind, overrides: RetentionOverrides) -> Self {
+        let defaults = Self::default_for(kind);
+
+        let keep = match overrides.keep {
+            Some(keep) if keep < Duration::zero() => {
+                tracing::warn!(
+                    keep = %format_duration(keep),
+                    %kind,
+                    "negative retention duration, using 0 instead"
+                );
+                Duration::zero()
+            }
+            keep => keep.unwrap_or(defaults.keep),
+        };
+
+        Self {
+            keep,
+            since: overrides.keep_since,
+            keep_at_least: overrides.keep_at_least.unwrap_or(defaults.keep_at_least),
+            keep_at_most: overrides.keep_at_most.or(defaults.keep_at_most),
+            order_by: overrides.order_by.unwrap_or(defaults.order_by),
+            thinning: overrides.thinning.unwrap_or(defaults.thinning),
+        }
+    }
+
+    /// Returns the cutoff date for this policy relative to `now`, or the
+    /// absolute one if `since` is set.
+    ///
+    /// A `keep` reaching back before the earliest representable date keeps
+    /// everything.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{ProfileKind, RetentionPolicy};
+    ///
+    /// let now = NaiveDateTime::parse_from_str("2020-01-15 00:00", "%Y-%m-%d %H:%M").unwrap();
+    /// let policy = RetentionPolicy::default_for(ProfileKind::User);
+    /// assert_eq!(
+    ///     policy.keep_since(now),
+    ///     NaiveDateTime::parse_from_str("2020-01-08 00:00", "%Y-%m-%d %H:%M").unwrap(),
+    /// );
+    /// ```
+    pub fn keep_since(&self, now: NaiveDateTime) -> NaiveDateTime {
+        self.since.unwrap_or_else(|| {
+            now.checked_sub_signed(self.keep)
+                .unwrap_or(NaiveDateTime::MIN)
+        })
+    }
+
+    /// Returns the cutoff date for this policy relative to `now` like
+    /// [RetentionPolicy::keep_since], together with how it has been derived.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDateTime;
+    /// use janitor::{ProfileKind, RetentionPolicy};
+    ///
+    /// let now = NaiveDateTime::parse_from_str("2020-01-15 00:00", "%Y-%m-%d %H:%M").unwrap();
+    /// let derivation = RetentionPolicy::default_for(ProfileKind::User).derive_keep_since(now);
+    /// assert_eq!(
+    ///     derivation.to_string(),
+    ///     "2020-01-08 00:00:00 UTC = now 2020-01-15 00:00:00 UTC - keep 7d",
+    /// );
+    /// ```
+    pub fn derive_keep_since(&self, now: NaiveDateTime) -> KeepSinceDerivation {
+        KeepSinceDerivation {
+            now,
+            keep: self.keep,
+            since: self.since,
+            keep_since: self.keep_since(now),
+            adjusted: None,
+        }
+    }
+}
+
+/// How the cutoff date of a job has been derived from its
+/// [RetentionPolicy], to tell from a report alone why a generation has been
+/// deleted.
+///
+/// All dates are in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepSinceDerivation {
+    /// The point in time the cutoff is relative to.
+    pub now: NaiveDateTime,
+
+    /// The duration subtracted from `now`, after clamping a negative one to
+    /// 0.
+    pub keep: Duration,
+
+    /// The date given instead of a duration, if any.
+    pub since: Option<NaiveDateTime>,
+
+    /// The cutoff derived by the policy.
+    pub keep_since: NaiveDateTime,
+
+    /// The cutoff the job ended up with, if it has been moved after the
+    /// policy derived it, e.g. to meet a per-user budget.
+    pub adjusted: Option<NaiveDateTime>,
+}
+
+impl KeepSinceDerivation {
+    /// The derivation with the cutoff a job ended up with, recorded as an
+    /// adjustment if it differs from the derived one.
+    pub(crate) fn adjusted_to(self, keep_since: NaiveDateTime) -> Self {
+        Self {
+            adjusted: (keep_since != self.keep_since).then_some(keep_since),
+            ..self
+        }
+    }
+
+    /// The cutoff in effect, the adjusted one if any.
+    pub fn effective(&self) -> NaiveDateTime {
+        self.adjusted.unwrap_or(self.keep_since)
+    }
+}
+
+impl std::fmt::Display for KeepSinceDerivation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.since {
+            Some(since) => write!(f, "{since} UTC, given as a date")?,
+            None => write!(
+                f,
+                "{keep_since} UTC = now {now} UTC - keep {keep}",
+                keep_since = self.keep_since,
+                now = self.now,
+                keep = format_duration(self.keep),
+            )?,
+        }
+        if let Some(adjusted) = self.adjusted {
+            write!(f, ", adjusted to {adjusted} UTC")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Explicitly configured retention settings, taking precedence over the
+/// defaults of a [ProfileKind].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionOverrides {
+    /// Overrides [RetentionPolicy::keep].
+    pub keep: Option<Duration>,
+
+    /// Overrides [RetentionPolicy::since].
+    pub keep_since: Option<NaiveDateTime>,
+
+    /// Overrides [RetentionPolicy::keep_at_least].
+    pub keep_at_least: Option<usize>,
+
+    /// Overrides [RetentionPolicy::keep_at_most].
+    pub keep_at_most: Option<usize>,
+
+    /// Overrides [RetentionPolicy::order_by].
+    pub order_by: Option<GenerationOrder>,
+
+    /// Overrides [RetentionPolicy::thinning], all periods at once.
+    pub thinning: Option<Thinning>,
+}
+
+impl RetentionOverrides {
+    /// Combines two sets of overrides, values set in `self` take precedence
+    /// over those in `other`.
+    ///
+    /// `keep` and `keep_since` are the same setting, if either is set in
+    /// `self`, both are taken from it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::Duration;
+    /// use janitor::RetentionOverrides;
+    ///
+    /// let cli = RetentionOverrides { keep: Some(Duration::days(1)), ..Default::default() };
+    /// let file = RetentionOverrides {
+    ///     keep: Some(Duration::days(2)),
+    ///     keep_at_least: Some(3),
+    ///     ..Default::default()
+    /// };
+    ///
+    /// let merged = cli.or(file);
+    /// assert_eq!(merged.keep, Some(Duration::days(1)));
+    /// assert_eq!(merged.keep_at_least, Some(3));
+    /// ```
+    pub fn or(self, other: Self) -> Self {
+        let (keep, keep_since) = match (self.keep, self.keep_since) {
+            (None, None) => (other.keep, other.keep_since),
+            own => own,
+        };
+
+        Self {
+            keep,
+            keep_since,
+            keep_at_least: self.keep_at_least.or(other.keep_at_least),
+            keep_at_most: self.keep_at_most.or(other.keep_at_most),
+            order_by: self.order_by.or(other.order_by),
+            thinning: self.thinning.or(other.thinning),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::system(ProfileKind::System, 14, 10)]
+    #[case::user(ProfileKind::User, 7, 5)]
+    #[case::home_manager(ProfileKind::HomeManager, 7, 5)]
+    #[case::channels(ProfileKind::Channels, 30, 2)]
+    #[case::container(ProfileKind::Container, 14, 5)]
+    fn defaults(#[case] kind: ProfileKind, #[case] days: i64, #[case] at_least: usize) {
+        let policy = RetentionPolicy::default_for(kind);
+
+        assert_eq!(policy.keep, Duration::days(days));
+        assert_eq!(policy.keep_at_least, at_least);
+    }
+
+    #[rstest]
+    #[case::nothing(None, None, 14, 10)]
+    #[case::days(Some(1), None, 1, 10)]
+    #[case::at_least(None, Some(1), 14, 1)]
+    #[case::both(Some(2), Some(3), 2, 3)]
+    #[case::negative_days(Some(-3), None, 0, 10)]
+    fn resolve(
+        #[case] keep_days: Option<i64>,
+        #[case] keep_at_least: Option<usize>,
+        #[case] days: i64,
+        #[case] at_least: usize,
+    ) {
+        let overrides = RetentionOverrides {
+            keep: keep_days.map(Duration::days),
+            keep_at_least,
+            ..Default::default()
+        };
+        let policy = RetentionPolicy::resolve(ProfileKind::System, overrides);
+
+        assert_eq!(policy.keep, Duration::days(days));
+        assert_eq!(policy.keep_at_least, at_least);
+    }
+
+    #[test]
+    fn keep_since_with_hours() {
+        let now = NaiveDateTime::parse_from_str("2020-01-15 12:00", "%Y-%m-%d %H:%M").unwrap();
+        let policy = RetentionPolicy {
+            keep: Duration::hours(36),
+            since: None,
+            keep_at_least: 1,
+            keep_at_most: None,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+        };
+
+        assert_eq!(
+            policy.keep_since(now),
+            NaiveDateTime::parse_from_str("2020-01-14 00:00", "%Y-%m-%d %H:%M").unwrap()
+        );
+    }
+
+    #[rstest]
+    #[case::century(Duration::days(crate::duration::MAX_DAYS), Some(1919))]
+    #[case::beyond_dates(Duration::days(100_000_000), None)]
+    fn keep_since_clamps(#[case] keep: Duration, #[case] year: Option<i32>) {
+        let now = NaiveDateTime::parse_from_str("2020-01-15 12:00", "%Y-%m-%d %H:%M").unwrap();
+        let policy = RetentionPolicy {
+            keep,
+            ..RetentionPolicy::default_for(ProfileKind::User)
+        };
+
+        let keep_since = policy.keep_since(now);
+        match year {
+            Some(year) => assert_eq!(keep_since.year(), year),
+            None => assert_eq!(keep_since, NaiveDateTime::MIN),
+        }
+    }
+
+    #[test]
+    fn keep_since_absolute() {
+        let now = NaiveDateTime::parse_from_str("2020-01-15 12:00", "%Y-%m-%d %H:%M").unwrap();
+        let since = NaiveDateTime::parse_from_str("2019-12-01 00:00", "%Y-%m-%d %H:%M").unwrap();
+        let overrides = RetentionOverrides {
+            keep_since: Some(since),
+            ..Default::default()
+        };
+
+        let policy = RetentionPolicy::resolve(ProfileKind::System, overrides);
+
+        assert_eq!(policy.keep_since(now), since);
+    }
+
+    #[rstest]
+    #[case::own_duration(Some(1), None, Some(1), None)]
+    #[case::own_date(None, Some(1), None, Some(1))]
+    #[case::other(None, None, Some(2), Some(2))]
+    fn or_keep_since(
+        #[case] keep_days: Option<i64>,
+        #[case] since_day: Option<u32>,
+        #[case] expected_days: Option<i64>,
+        #[case] expected_day: Option<u32>,
+    ) {
+        let date = |day| {
+            NaiveDate::from_ymd_opt(2020, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let own = RetentionOverrides {
+            keep: keep_days.map(Duration::days),
+            keep_since: since_day.map(date),
+            ..Default::default()
+        };
+        let other = RetentionOverrides {
+            keep: Some(Duration::days(2)),
+            keep_since: Some(date(2)),
+            ..Default::default()
+        };
+
+        let merged = own.or(other);
+
+        assert_eq!(merged.keep, expected_days.map(Duration::days));
+        assert_eq!(merged.keep_since, expected_day.map(date));
+    }
+
+    #[rstest]
+    #[case::relative(
+        None,
+        None,
+        "2020-01-14 00:00:00 UTC = now 2020-01-15 12:00:00 UTC - keep 1d12h"
+    )]
+    #[case::absolute(
+        Some("2019-12-01 00:00"),
+        None,
+        "2019-12-01 00:00:00 UTC, given as a date"
+    )]
+    #[case::adjusted(
+        None,
+        Some("2020-01-14 06:00"),
+        "2020-01-14 00:00:00 UTC = now 2020-01-15 12:00:00 UTC - keep 1d12h, \
+         adjusted to 2020-01-14 06:00:00 UTC"
+    )]
+    fn derivation(
+        #[case] since: Option<&str>,
+        #[case] adjusted: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let date = |date| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+        let now = date("2020-01-15 12:00");
+        let policy = RetentionPolicy {
+            keep: Duration::hours(36),
+            since: since.map(date),
+            keep_at_least: 1,
+            keep_at_most: None,
+            order_by: GenerationOrder::Id,
+            thinning: Thinning::default(),
+        };
+
+        let derivation = policy.derive_keep_since(now);
+        let derivation = derivation.adjusted_to(adjusted.map_or(derivation.keep_since, date));
+
+        assert_eq!(derivation.to_string(), expected);
+        assert_eq!(
+            derivation.effective(),
+            adjusted.map_or(policy.keep_since(now), date)
+        );
+    }
+}
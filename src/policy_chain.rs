@@ -0,0 +1,397 @@
+//! Retention rules composed as an ordered chain, see [PolicyChain].
+//!
+//! Every generation is judged by the rules in the order of their precedence,
+//! the first rule keeping or deleting it decides, a generation no rule
+//! decides on is kept. The built-in rules are ordered by [Stage]:
+//!
+//! ```text
+//! Pinned > Current > MinAge > Count > Age
+//! ```
+//!
+//! Custom rules are inserted right before or after the built-in rule of a
+//! stage, see [PolicyChain::before] and [PolicyChain::after].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use chrono::NaiveDateTime;
+
+use crate::generation_set::GenerationSet;
+
+/// What a [ChainRule] decides about a generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    /// The generation is kept.
+    Keep,
+
+    /// The generation is deleted.
+    Delete,
+}
+
+/// A rule of a [PolicyChain].
+///
+/// Closures taking the [GenerationSet] and returning the verdicts by
+/// generation id are rules as well.
+pub trait ChainRule {
+    /// The verdicts on the generations of `generations` this rule decides
+    /// on, by generation id. Generations left out are passed on to the next
+    /// rule of the chain.
+    fn judge(&self, generations: &GenerationSet) -> BTreeMap<u32, Verdict>;
+}
+
+impl<F> ChainRule for F
+where
+    F: Fn(&GenerationSet) -> BTreeMap<u32, Verdict>,
+{
+    fn judge(&self, generations: &GenerationSet) -> BTreeMap<u32, Verdict> {
+        self(generations)
+    }
+}
+
+/// The stages of a [PolicyChain], in the order of their precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Keeps the pinned generations, see [PolicyChain::pinned].
+    Pinned,
+
+    /// Keeps the current generation.
+    Current,
+
+    /// Keeps the generations active on or after a date, see
+    /// [PolicyChain::min_age].
+    MinAge,
+
+    /// Keeps the most recent generations, see [PolicyChain::count].
+    Count,
+
+    /// Deletes the generations not active on or after a date, see
+    /// [PolicyChain::age].
+    Age,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Pinned => "pinned",
+            Self::Current => "current",
+            Self::MinAge => "min-age",
+            Self::Count => "count",
+            Self::Age => "age",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Where a rule is placed relative to the built-in rule of its [Stage].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Slot {
+    Before,
+    BuiltIn,
+    After,
+}
+
+/// The built-in rules of the [Stage]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BuiltIn {
+    Pinned(BTreeSet<u32>),
+    Current,
+    MinAge(NaiveDateTime),
+    Count(usize),
+    Age(NaiveDateTime),
+}
+
+impl BuiltIn {
+    fn stage(&self) -> Stage {
+        match self {
+            Self::Pinned(_) => Stage::Pinned,
+            Self::Current => Stage::Current,
+            Self::MinAge(_) => Stage::MinAge,
+            Self::Count(_) => Stage::Count,
+            Self::Age(_) => Stage::Age,
+        }
+    }
+}
+
+impl ChainRule for BuiltIn {
+    fn judge(&self, generations: &GenerationSet) -> BTreeMap<u32, Verdict> {
+        let keep = |kept: &GenerationSet| kept.iter().map(|g| (g.id, Verdict::Keep)).collect();
+
+        match self {
+            Self::Pinned(pinned) => pinned
+                .iter()
+                .filter(|id| generations.contains(**id))
+                .map(|id| (*id, Verdict::Keep))
+                .collect(),
+            Self::Current => generations
+                .iter()
+                .filter(|g| g.current)
+                .map(|g| (g.id, Verdict::Keep))
+                .collect(),
+            Self::MinAge(since) => keep(&generations.get_active_on_or_after(*since)),
+            Self::Count(n) => keep(&generations.get_last_n_generations(*n)),
+            Self::Age(since) => generations
+                .difference(&generations.get_active_on_or_after(*since))
+                .iter()
+                .map(|g| (g.id, Verdict::Delete))
+                .collect(),
+        }
+    }
+}
+
+struct Link {
+    stage: Stage,
+    slot: Slot,
+    rule: Box<dyn ChainRule + Send + Sync>,
+}
+
+/// Retention rules judging the generations of a profile in the order of
+/// their precedence, see [GenerationSet::retain_policy_chain].
+///
+/// A new chain only keeps the current generation. The other built-in rules
+/// are set with [PolicyChain::pinned], [PolicyChain::min_age],
+/// [PolicyChain::count] and [PolicyChain::age], custom rules are added with
+/// [PolicyChain::before] and [PolicyChain::after].
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// use chrono::NaiveDateTime;
+/// use janitor::{Generation, GenerationSet, PolicyChain, Stage, Verdict};
+///
+/// let generations = Generation::parse_many(
+///     "1 2023-06-01 00:00:00\n\
+///      2 2023-06-10 00:00:00\n\
+///      3 2023-06-20 00:00:00\n\
+///      4 2023-06-30 00:00:00 (current)",
+/// )?
+/// .into_iter()
+/// .collect::<GenerationSet>();
+/// let cutoff = NaiveDateTime::parse_from_str("2023-06-25 00:00:00", "%Y-%m-%d %H:%M:%S")?;
+///
+/// // Keeps generation 2 in spite of its age, and deletes generation 3 in
+/// // spite of being one of the two most recent ones.
+/// let chain = PolicyChain::new()
+///     .count(2)
+///     .age(cutoff)
+///     .before(Stage::Count, |_: &GenerationSet| {
+///         BTreeMap::from([(2, Verdict::Keep), (3, Verdict::Delete)])
+///     });
+///
+/// let kept = generations.retain_policy_chain(&chain);
+/// assert_eq!(kept.iter().map(|g| g.id).collect::<Vec<_>>(), [2, 4]);
+/// # Ok::<(), eyre::Report>(())
+/// ```
+pub struct PolicyChain {
+    links: Vec<Link>,
+}
+
+impl Default for PolicyChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PolicyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.ordered().map(|link| (link.stage, link.slot)))
+            .finish()
+    }
+}
+
+impl PolicyChain {
+    /// A chain only keeping the current generation.
+    pub fn new() -> Self {
+        Self { links: Vec::new() }.built_in(BuiltIn::Current)
+    }
+
+    /// Keeps the generations with the ids in `pinned`, taking precedence
+    /// over all other built-in rules.
+    pub fn pinned(self, pinned: BTreeSet<u32>) -> Self {
+        self.built_in(BuiltIn::Pinned(pinned))
+    }
+
+    /// Keeps the generations that have been active on or after `since`.
+    pub fn min_age(self, since: NaiveDateTime) -> Self {
+        self.built_in(BuiltIn::MinAge(since))
+    }
+
+    /// Keeps the `n` most recent generations.
+    pub fn count(self, n: usize) -> Self {
+        self.built_in(BuiltIn::Count(n))
+    }
+
+    /// Deletes the generations that have not been active on or after
+    /// `since`, unless kept by a rule of higher precedence.
+    pub fn age(self, since: NaiveDateTime) -> Self {
+        self.built_in(BuiltIn::Age(since))
+    }
+
+    /// Adds the `rule` right before the built-in rule of the `stage`, after
+    /// the rules added before it there earlier.
+    pub fn before<R>(self, stage: Stage, rule: R) -> Self
+    where
+        R: ChainRule + Send + Sync + 'static,
+    {
+        self.link(stage, Slot::Before, Box::new(rule))
+    }
+
+    /// Adds the `rule` right after the built-in rule of the `stage`, after
+    /// the rules added after it earlier.
+    pub fn after<R>(self, stage: Stage, rule: R) -> Self
+    where
+        R: ChainRule + Send + Sync + 'static,
+    {
+        self.link(stage, Slot::After, Box::new(rule))
+    }
+
+    /// Decides on each of the `generations`, by generation id.
+    pub fn judge(&self, generations: &GenerationSet) -> BTreeMap<u32, Verdict> {
+        let mut verdicts = BTreeMap::new();
+
+        for link in self.ordered() {
+            if verdicts.len() == generations.len() {
+                break;
+            }
+            for (id, verdict) in link.rule.judge(generations) {
+                if generations.contains(id) {
+                    verdicts.entry(id).or_insert(verdict);
+                }
+            }
+        }
+
+        for generation in generations {
+            verdicts.entry(generation.id).or_insert(Verdict::Keep);
+        }
+
+        verdicts
+    }
+
+    /// Replaces the built-in rule of its stage with `rule`.
+    fn built_in(mut self, rule: BuiltIn) -> Self {
+        let stage = rule.stage();
+        self.links
+            .retain(|link| link.stage != stage || link.slot != Slot::BuiltIn);
+
+        self.link(stage, Slot::BuiltIn, Box::new(rule))
+    }
+
+    fn link(mut self, stage: Stage, slot: Slot, rule: Box<dyn ChainRule + Send + Sync>) -> Self {
+        self.links.push(Link { stage, slot, rule });
+        self
+    }
+
+    /// The links in the order of their precedence, those of the same stage
+    /// and slot in the order they have been added.
+    fn ordered(&self) -> impl Iterator<Item = &Link> {
+        let mut links: Vec<_> = self.links.iter().collect();
+        links.sort_by_key(|link| (link.stage, link.slot));
+
+        links.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Duration;
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use crate::generation::Generation;
+
+    fn generations(count: u32, current: u32) -> GenerationSet {
+        let start = NaiveDateTime::default();
+
+        (1..=count)
+            .map(|id| Generation {
+                id,
+                date: start + Duration::days(i64::from(id)),
+                current: id == current,
+            })
+            .collect()
+    }
+
+    fn day(n: i64) -> NaiveDateTime {
+        NaiveDateTime::default() + Duration::days(n)
+    }
+
+    fn ids(generations: &GenerationSet) -> Vec<u32> {
+        generations.iter().map(|g| g.id).collect()
+    }
+
+    #[rstest]
+    #[case::nothing_decides(PolicyChain::new(), &[1, 2, 3, 4, 5, 6])]
+    #[case::age(PolicyChain::new().age(day(5)), &[3, 4, 5, 6])]
+    #[case::count(PolicyChain::new().count(2).age(day(7)), &[3, 5, 6])]
+    #[case::min_age(PolicyChain::new().min_age(day(4)).age(day(7)), &[3, 4, 5, 6])]
+    #[case::pinned(PolicyChain::new().pinned(BTreeSet::from([1, 9])).age(day(7)), &[1, 3, 6])]
+    #[case::replaced(PolicyChain::new().count(5).count(1).age(day(7)), &[3, 6])]
+    fn built_in_rules(#[case] chain: PolicyChain, #[case] expected: &[u32]) {
+        let kept = generations(6, 3).retain_policy_chain(&chain);
+
+        assert_eq!(ids(&kept), expected);
+    }
+
+    #[test]
+    fn custom_rules_take_their_position() {
+        let delete_all =
+            |set: &GenerationSet| set.iter().map(|g| (g.id, Verdict::Delete)).collect();
+
+        let before_current = PolicyChain::new()
+            .pinned(BTreeSet::from([2]))
+            .before(Stage::Current, delete_all);
+        let after_current = PolicyChain::new()
+            .pinned(BTreeSet::from([2]))
+            .after(Stage::Current, delete_all);
+
+        assert_eq!(
+            ids(&generations(3, 3).retain_policy_chain(&before_current)),
+            [2]
+        );
+        assert_eq!(
+            ids(&generations(3, 3).retain_policy_chain(&after_current)),
+            [2, 3]
+        );
+    }
+
+    #[test]
+    fn earlier_custom_rules_win() {
+        let keep = |set: &GenerationSet| set.iter().map(|g| (g.id, Verdict::Keep)).collect();
+        let delete = |set: &GenerationSet| set.iter().map(|g| (g.id, Verdict::Delete)).collect();
+
+        let chain = PolicyChain::new()
+            .after(Stage::Age, keep)
+            .before(Stage::Age, delete);
+
+        assert_eq!(ids(&generations(3, 3).retain_policy_chain(&chain)), [3]);
+    }
+
+    proptest! {
+        #[test]
+        fn matches_generations_to_delete(
+            count in 1..20u32,
+            keep in 0..10usize,
+            since in 0..25i64,
+            pinned in prop::collection::btree_set(1..25u32, 0..4),
+        ) {
+            let generations = generations(count, count);
+            let chain = PolicyChain::new()
+                .pinned(pinned.clone())
+                .min_age(day(since))
+                .count(keep)
+                .age(day(since));
+
+            let deleted = generations.generations_to_delete(keep, None, day(since), &pinned);
+            let kept = generations.retain_policy_chain(&chain);
+
+            prop_assert_eq!(generations.difference(&deleted), kept);
+        }
+    }
+}
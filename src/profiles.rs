@@ -1,9 +1,89 @@
 use std::{
-    env,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use eyre::Result;
+#[cfg(feature = "system")]
+use std::{env, fs};
+
+#[cfg(feature = "system")]
+use glob::MatchOptions;
+
+#[cfg(feature = "system")]
+use crate::platform::Os;
+
+use crate::{
+    error::{JanitorError, Result},
+    references::PER_CONTAINER,
+};
+
+/// How profile patterns are matched, wildcards never match a `/` or a
+/// leading `.`.
+#[cfg(feature = "system")]
+const PATTERN_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// The kind of a Nix profile.
+///
+/// The kind determines which retention defaults apply to a profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProfileKind {
+    /// The NixOS system profile.
+    System,
+    /// A regular user profile, as managed by `nix-env` or `nix profile`.
+    User,
+    /// A profile managed by home-manager.
+    HomeManager,
+    /// The channels profile managed by `nix-channel`.
+    Channels,
+    /// The system profile of a nixos-container,
+    /// `/nix/var/nix/profiles/per-container/<name>/system`.
+    Container,
+    /// The default profile of root, [DEFAULT_PROFILE], which holds nix itself
+    /// on installations by the multi-user installer.
+    Default,
+}
+
+impl std::fmt::Display for ProfileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::HomeManager => "home-manager",
+            Self::Channels => "channels",
+            Self::Container => "container",
+            Self::Default => "default",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl ProfileKind {
+    /// All kinds of profiles.
+    pub const ALL: [Self; 6] = [
+        Self::System,
+        Self::User,
+        Self::HomeManager,
+        Self::Channels,
+        Self::Container,
+        Self::Default,
+    ];
+}
+
+impl FromStr for ProfileKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.to_string() == s)
+            .ok_or_else(|| parse_error!("unknown profile kind: {s}"))
+    }
+}
 
 /// Represents a Nix profile path.
 ///
@@ -29,35 +109,255 @@ impl Profile {
         Self(path.into())
     }
 
+    /// Returns the kind of this profile.
+    ///
+    /// The kind is derived from the name of the profile, any profile that is
+    /// not recognized is considered a [ProfileKind::User] profile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::{Profile, ProfileKind};
+    ///
+    /// assert_eq!(Profile::new("/nix/var/nix/profiles/system").kind(), ProfileKind::System);
+    /// assert_eq!(
+    ///     Profile::new("/nix/var/nix/profiles/per-container/web/system").kind(),
+    ///     ProfileKind::Container
+    /// );
+    /// assert_eq!(Profile::new("/nix/var/nix/profiles/default").kind(), ProfileKind::Default);
+    /// assert_eq!(Profile::new("/foo/bar").kind(), ProfileKind::User);
+    /// ```
+    pub fn kind(&self) -> ProfileKind {
+        if self.container().is_some() {
+            return ProfileKind::Container;
+        }
+        if self.0 == Path::new(DEFAULT_PROFILE) {
+            return ProfileKind::Default;
+        }
+
+        match self.0.file_name().and_then(|name| name.to_str()) {
+            Some("system") => ProfileKind::System,
+            Some("home-manager") => ProfileKind::HomeManager,
+            Some("channels") => ProfileKind::Channels,
+            _ => ProfileKind::User,
+        }
+    }
+
+    /// Returns the name of the nixos-container this is the system profile
+    /// of, if it is one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::Profile;
+    ///
+    /// let profile = Profile::new("/nix/var/nix/profiles/per-container/web/system");
+    /// assert_eq!(profile.container(), Some("web"));
+    /// assert_eq!(Profile::new("/nix/var/nix/profiles/system").container(), None);
+    /// ```
+    pub fn container(&self) -> Option<&str> {
+        let container = self.0.strip_prefix(PER_CONTAINER).ok()?;
+        match container.iter().map(|c| c.to_str()).collect::<Vec<_>>()[..] {
+            [Some(name), Some("system")] => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Whether the profile is one of the profiles NixOS builds for the
+    /// packages of each user in [PER_USER_PACKAGES].
+    ///
+    /// These are part of the system generation, they have no generations of
+    /// their own to delete.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::Profile;
+    ///
+    /// assert!(Profile::new("/etc/profiles/per-user/alice").managed_by_system());
+    /// assert!(!Profile::new("/nix/var/nix/profiles/per-user/alice/profile").managed_by_system());
+    /// ```
+    pub fn managed_by_system(&self) -> bool {
+        self.0.parent() == Some(Path::new(PER_USER_PACKAGES))
+    }
+
+    /// Returns the id of the user owning the link of the profile, `None` if
+    /// it does not exist.
+    #[cfg(feature = "system")]
+    pub fn owner(&self) -> Option<u32> {
+        use std::os::unix::fs::MetadataExt;
+
+        fs::symlink_metadata(&self.0).ok().map(|meta| meta.uid())
+    }
+
+    /// Expands the glob `pattern` into the profiles it matches, sorted by
+    /// path.
+    ///
+    /// Besides `*`, `?` and `[…]`, which never match a `/` or a leading
+    /// `.`, the pattern is taken literally, so this also works where no
+    /// shell expands it, e.g. in a unit file. Generation links like
+    /// `system-661-link` are never matched, only profiles.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pattern is not absolute, not a valid glob, or matches
+    /// more than [MAX_PATTERN_MATCHES] profiles, which most likely means it
+    /// is broader than intended.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use janitor::Profile;
+    ///
+    /// let profiles = Profile::expand("/nix/var/nix/profiles/per-user/*/profile")?;
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    #[cfg(feature = "system")]
+    pub fn expand(pattern: &str) -> Result<Vec<Self>> {
+        if !Path::new(pattern).is_absolute() {
+            return Err(parse_error!("profile pattern {pattern:?} is not absolute"));
+        }
+
+        let paths = glob::glob_with(pattern, PATTERN_OPTIONS).map_err(|error| {
+            JanitorError::parse_with(format!("invalid profile pattern {pattern:?}"), error)
+        })?;
+
+        let mut profiles = Vec::new();
+        for path in paths {
+            let path = match path {
+                Ok(path) => path,
+                Err(error) => {
+                    tracing::debug!(%error, pattern, "skipping unreadable path");
+                    continue;
+                }
+            };
+            if is_generation_link(&path) {
+                continue;
+            }
+
+            profiles.push(Self::new(path));
+            if profiles.len() > MAX_PATTERN_MATCHES {
+                return Err(JanitorError::TooManyProfiles {
+                    pattern: pattern.to_string(),
+                    limit: MAX_PATTERN_MATCHES,
+                });
+            }
+        }
+
+        tracing::info!(pattern, profiles = ?profiles, "expanded profile pattern");
+
+        Ok(profiles)
+    }
+
+    /// Whether the path of the profile matches the glob `pattern`, which is
+    /// matched like by [Profile::expand].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pattern is not a valid glob.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::Profile;
+    ///
+    /// let profile = Profile::new("/home/alice/.local/state/nix/profiles/home-manager");
+    /// assert!(profile.matches("/home/*/.local/state/nix/profiles/home-manager*")?);
+    /// assert!(!profile.matches("/home/*/profile")?);
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
+    #[cfg(feature = "system")]
+    pub fn matches(&self, pattern: &str) -> Result<bool> {
+        let pattern = glob::Pattern::new(pattern).map_err(|error| {
+            JanitorError::parse_with(format!("invalid profile pattern {pattern:?}"), error)
+        })?;
+
+        Ok(pattern.matches_path_with(&self.0, PATTERN_OPTIONS))
+    }
+
     /// Returns all default profile paths for the current user.
     ///
     /// This discovers the Nix profile paths by detecting if running as root/sudo,
-    /// and expanding environment variables.
+    /// and finding the home of the user in `/home`, or `/Users` on macOS.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_system` - Whether to include the system profile, the
+    ///   system profiles of all nixos-containers and the [DEFAULT_PROFILE].
+    ///   They are only ever included when running as root.
     ///
     /// # Examples
     ///
     /// ```
     /// use janitor::Profile;
-    /// let profiles = Profile::all();
+    /// let profiles = Profile::all(false);
     /// ```
-    pub fn all() -> Vec<Self> {
-        let mut paths = vec![
-            "/nix/var/nix/profiles/per-user/$USER/profile",
-            "/home/$USER/.local/state/nix/profiles/home-manager",
-        ];
+    #[cfg(feature = "system")]
+    pub fn all(include_system: bool) -> Vec<Self> {
+        let users = get_username()
+            .map(|user| user_paths(Os::current().homes(), &user))
+            .unwrap_or_default();
 
-        if is_root::is_root() {
-            paths.push("/nix/var/nix/profiles/system");
+        Self::with_system(users, include_system)
+    }
+
+    /// Returns the profiles of all users, and those of the system if
+    /// `include_system`, like [Profile::all].
+    ///
+    /// The profiles of a user are those in their directory in
+    /// `/nix/var/nix/profiles/per-user` and in
+    /// `~/.local/state/nix/profiles` of their home in `/home`, or `/Users` on
+    /// macOS. Only profiles owned by the owner of that directory and pointing
+    /// to a generation within it are included, so that a user can not have
+    /// another profile cleaned up with their retention by linking it into
+    /// their directory.
+    /// The profiles of all users in [PER_USER_PACKAGES] are included as
+    /// well, see [Profile::managed_by_system].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::Profile;
+    /// let profiles = Profile::all_users(false);
+    /// ```
+    #[cfg(feature = "system")]
+    pub fn all_users(include_system: bool) -> Vec<Self> {
+        let users = user_dirs(Path::new(PER_USER), "")
+            .into_iter()
+            .chain(user_dirs(
+                Os::current().homes(),
+                ".local/state/nix/profiles",
+            ))
+            .flat_map(|dir| user_profiles(&dir))
+            .chain(packages_profiles(Path::new(PER_USER_PACKAGES)))
+            .collect();
+
+        Self::with_system(users, include_system)
+    }
+
+    /// The profiles at the existing paths of `users`, followed by the ones
+    /// of the system if `include_system` and running as root.
+    #[cfg(feature = "system")]
+    fn with_system(mut paths: Vec<PathBuf>, include_system: bool) -> Vec<Self> {
+        if include_system && is_root::is_root() {
+            paths.push(PathBuf::from("/nix/var/nix/profiles/system"));
+            paths.push(PathBuf::from(DEFAULT_PROFILE));
+        } else if is_root::is_root() && Path::new(DEFAULT_PROFILE).exists() {
+            tracing::info!(
+                profile = DEFAULT_PROFILE,
+                "not cleaning up the default profile of root, pass --system to include it"
+            );
+        }
+
+        if include_system && is_root::is_root() {
+            paths.extend(containers());
         }
 
         paths
-            .iter()
-            .map(|p| -> Result<_> { Ok(shellexpand::env_with_context(p, context).unwrap()) })
-            .map(|p| -> Result<_> { Ok(PathBuf::from(p?.to_string())) })
-            .filter_map(|pr| pr.ok())
+            .into_iter()
             .filter(|p| p.exists())
             .map(Self::new)
-            .collect::<Vec<_>>()
+            .collect()
     }
 }
 
@@ -67,13 +367,168 @@ impl AsRef<Path> for Profile {
     }
 }
 
-fn context(s: &str) -> Result<Option<String>> {
-    match s {
-        "USER" => Ok(get_username()),
-        v => Err(eyre::eyre!("unknown variable: {v}")),
+/// The directory NixOS links the profile of the packages of each user to,
+/// `users.users.<name>.packages`, see [Profile::managed_by_system].
+pub const PER_USER_PACKAGES: &str = "/etc/profiles/per-user";
+
+/// The default profile of root, where the multi-user installer installs nix
+/// and every `nix upgrade-nix` adds a generation.
+pub const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
+/// Whether the system profile should be cleaned up when not told otherwise.
+///
+/// This is only the case when running in a root shell. When running via
+/// `sudo`, e.g. from a user timer, the system profile is left alone unless
+/// explicitly requested, as its rollbacks are usually meant to be kept longer.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::system_by_default;
+///
+/// let include_system = system_by_default();
+/// ```
+#[cfg(feature = "system")]
+pub fn system_by_default() -> bool {
+    is_root::is_root() && env::var_os("SUDO_USER").is_none()
+}
+
+/// The most profiles a single pattern given to [Profile::expand] may match.
+#[cfg(feature = "system")]
+pub const MAX_PATTERN_MATCHES: usize = 64;
+
+/// Whether `path` is the link of a generation, `<profile>-<id>-link`, rather
+/// than a profile.
+#[cfg(feature = "system")]
+fn is_generation_link(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix("-link"))
+        .and_then(|name| name.rsplit_once('-'))
+        .is_some_and(|(_, id)| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The directory holding a directory of profiles for each user.
+#[cfg(feature = "system")]
+const PER_USER: &str = "/nix/var/nix/profiles/per-user";
+
+/// The directories `sub` within each directory in `base`, that exist, sorted.
+#[cfg(feature = "system")]
+fn user_dirs(base: &Path, sub: &str) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(base) {
+        Ok(entries) => entries,
+        Err(error) => {
+            tracing::debug!(%error, base = %base.display(), "no user directories");
+            return Vec::new();
+        }
+    };
+
+    let mut dirs: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path().join(sub)))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    dirs.sort();
+
+    dirs
+}
+
+/// The profiles in the directory `dir` of a user, sorted by name.
+///
+/// Profiles not owned by the owner of `dir`, or not pointing to a generation
+/// link within `dir`, are skipped with a warning.
+#[cfg(feature = "system")]
+fn user_profiles(dir: &Path) -> Vec<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (owner, entries) = match (fs::metadata(dir), fs::read_dir(dir)) {
+        (Ok(meta), Ok(entries)) => (meta.uid(), entries),
+        (Err(error), _) | (_, Err(error)) => {
+            tracing::debug!(%error, dir = %dir.display(), "can not read profiles");
+            return Vec::new();
+        }
+    };
+
+    let mut profiles = Vec::new();
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        let Ok(target) = fs::read_link(&path) else {
+            continue;
+        };
+        if is_generation_link(&path) {
+            continue;
+        }
+
+        let owned = fs::symlink_metadata(&path).is_ok_and(|meta| meta.uid() == owner);
+        let local = target.parent() == Some(Path::new("")) && is_generation_link(&target);
+        if !owned || !local {
+            tracing::warn!(
+                profile = %path.display(),
+                target = %target.display(),
+                "not owned by the user or pointing outside of their profiles, skipping it"
+            );
+            continue;
+        }
+
+        profiles.push(path);
     }
+    profiles.sort();
+
+    profiles
+}
+
+/// The profiles of the packages of all users in `dir`, sorted by name.
+#[cfg(feature = "system")]
+fn packages_profiles(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            tracing::debug!(%error, dir = %dir.display(), "no profiles of user packages");
+            return Vec::new();
+        }
+    };
+
+    let mut profiles: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect();
+    profiles.sort();
+
+    profiles
+}
+
+/// Lists the system profiles of all nixos-containers, sorted by name.
+#[cfg(feature = "system")]
+fn containers() -> Vec<PathBuf> {
+    let entries = match fs::read_dir(PER_CONTAINER) {
+        Ok(entries) => entries,
+        Err(error) => {
+            tracing::debug!(%error, "no container profiles");
+            return Vec::new();
+        }
+    };
+
+    let mut profiles: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path().join("system")))
+        .collect();
+    profiles.sort();
+
+    profiles
+}
+
+/// The default profile paths of the `user`, whose home is in `homes`.
+#[cfg(feature = "system")]
+fn user_paths(homes: &Path, user: &str) -> Vec<PathBuf> {
+    let per_user = Path::new(PER_USER).join(user);
+    let home = homes.join(user).join(".local/state/nix/profiles");
+
+    vec![
+        per_user.join("profile"),
+        per_user.join("channels"),
+        home.join("home-manager"),
+        home.join("channels"),
+        Path::new(PER_USER_PACKAGES).join(user),
+    ]
 }
 
+#[cfg(feature = "system")]
 fn get_username() -> Option<String> {
     if is_root::is_root() {
         tracing::debug!("running as root, using SUDO_USER");
@@ -88,7 +543,20 @@ fn get_username() -> Option<String> {
 mod test {
     use super::*;
 
+    use eyre::Result;
+
     use proptest::prelude::*;
+    use rstest::rstest;
+
+    #[test]
+    fn kind_display_roundtrips() -> Result<()> {
+        for kind in ProfileKind::ALL {
+            assert_eq!(kind.to_string().parse::<ProfileKind>()?, kind);
+        }
+        assert!("nixos".parse::<ProfileKind>().is_err());
+
+        Ok(())
+    }
 
     proptest! {
         #[test]
@@ -99,5 +567,170 @@ mod test {
         }
     }
 
+    #[rstest]
+    #[case::system("/nix/var/nix/profiles/system", ProfileKind::System)]
+    #[case::user("/nix/var/nix/profiles/per-user/alice/profile", ProfileKind::User)]
+    #[case::channels("/nix/var/nix/profiles/per-user/alice/channels", ProfileKind::Channels)]
+    #[case::home_manager(
+        "/home/alice/.local/state/nix/profiles/home-manager",
+        ProfileKind::HomeManager
+    )]
+    #[case::container(
+        "/nix/var/nix/profiles/per-container/web/system",
+        ProfileKind::Container
+    )]
+    #[case::container_other_profile(
+        "/nix/var/nix/profiles/per-container/web/profile",
+        ProfileKind::User
+    )]
+    #[case::default("/nix/var/nix/profiles/default", ProfileKind::Default)]
+    #[case::default_elsewhere("/home/alice/.local/state/nix/profiles/default", ProfileKind::User)]
+    #[case::unknown("/foo/bar", ProfileKind::User)]
+    fn kind(#[case] path: &str, #[case] expected: ProfileKind) {
+        assert_eq!(Profile::new(path).kind(), expected);
+    }
+
+    #[rstest]
+    #[case::generation("/nix/var/nix/profiles/system-661-link", true)]
+    #[case::profile("/nix/var/nix/profiles/system", false)]
+    #[case::named_link("/home/alice/result-link", false)]
+    #[case::no_id("/nix/var/nix/profiles/system--link", false)]
+    #[cfg(feature = "system")]
+    fn generation_links(#[case] path: &str, #[case] expected: bool) {
+        assert_eq!(is_generation_link(Path::new(path)), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn expand_patterns() -> Result<()> {
+        use std::{fs, os::unix::fs::symlink};
+
+        let dir = env::temp_dir().join(format!("janitor-profiles-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for user in ["alice", "bob", ".hidden"] {
+            let user = dir.join(user);
+            fs::create_dir_all(&user)?;
+            symlink("profile-1-link", user.join("profile"))?;
+            symlink(&dir, user.join("profile-1-link"))?;
+        }
+
+        let expanded = |pattern: &str| -> Result<Vec<PathBuf>> {
+            let pattern = format!("{}/{pattern}", dir.display());
+            Ok(Profile::expand(&pattern)?
+                .into_iter()
+                .map(|p| p.0)
+                .collect())
+        };
+        let all = expanded("*/profile*");
+        let single = expanded("alice/profile");
+        let none = expanded("carol/profile");
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(all?, [dir.join("alice/profile"), dir.join("bob/profile")]);
+        assert_eq!(single?, [dir.join("alice/profile")]);
+        assert!(none?.is_empty());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::relative("per-user/*/profile")]
+    #[case::invalid("/nix/var/nix/profiles/[")]
+    #[cfg(feature = "system")]
+    fn expand_rejects(#[case] pattern: &str) {
+        assert!(Profile::expand(pattern).is_err());
+    }
+
+    #[rstest]
+    #[case::literal("/p/alice/profile", true)]
+    #[case::wildcard("/p/*/profile", true)]
+    #[case::not_across_separators("/p/*", false)]
+    #[case::other("/p/bob/profile", false)]
+    #[cfg(feature = "system")]
+    fn matches_patterns(#[case] pattern: &str, #[case] expected: bool) {
+        let profile = Profile::new("/p/alice/profile");
+
+        assert_eq!(profile.matches(pattern).unwrap(), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn matches_rejects_invalid() {
+        assert!(Profile::new("/p/profile").matches("/p/[").is_err());
+    }
+
+    #[rstest]
+    #[case::linux(Os::Linux, "/home/alice/.local/state/nix/profiles")]
+    #[case::macos(Os::MacOs, "/Users/alice/.local/state/nix/profiles")]
+    #[cfg(feature = "system")]
+    fn user_paths_in_the_home(#[case] os: Os, #[case] home: &str) {
+        let home = Path::new(home);
+
+        assert_eq!(
+            user_paths(os.homes(), "alice"),
+            [
+                PathBuf::from("/nix/var/nix/profiles/per-user/alice/profile"),
+                PathBuf::from("/nix/var/nix/profiles/per-user/alice/channels"),
+                home.join("home-manager"),
+                home.join("channels"),
+                PathBuf::from("/etc/profiles/per-user/alice"),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn finds_user_profiles() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = env::temp_dir().join(format!("janitor-users-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let alice = dir.join("alice");
+        fs::create_dir_all(&alice)?;
+        fs::create_dir_all(dir.join("bob"))?;
+        fs::write(dir.join("stray"), "")?;
+        for name in ["profile", "channels"] {
+            symlink(format!("{name}-1-link"), alice.join(name))?;
+            symlink(&dir, alice.join(format!("{name}-1-link")))?;
+        }
+        symlink("/nix/var/nix/profiles/system", alice.join("escape"))?;
+        fs::write(alice.join("profile.lock"), "")?;
+
+        let dirs = user_dirs(&dir, "");
+        let profiles = user_profiles(&alice);
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(dirs, [dir.join("alice/"), dir.join("bob/")]);
+        assert_eq!(profiles, [alice.join("channels"), alice.join("profile")]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn finds_packages_profiles() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = env::temp_dir().join(format!("janitor-packages-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store"))?;
+        fs::create_dir_all(dir.join("per-user"))?;
+        for user in ["bob", "alice"] {
+            symlink(dir.join("store"), dir.join("per-user").join(user))?;
+        }
+
+        let profiles = packages_profiles(&dir.join("per-user"));
+        let missing = packages_profiles(&dir.join("missing"));
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(
+            profiles,
+            [dir.join("per-user/alice"), dir.join("per-user/bob")]
+        );
+        assert!(missing.is_empty());
+
+        Ok(())
+    }
+
     // TODO: provide some tests for Profile::all()
 }
@@ -0,0 +1,173 @@
+//! Callbacks reporting the progress of a cleanup as it happens, for
+//! integrations that only want to show what is going on.
+
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::{Generation, GenerationSet};
+
+/// Called with the number of store paths deleted so far and the path just
+/// deleted.
+type OnGcProgress = Arc<dyn Fn(u64, &str) + Send + Sync>;
+
+/// Called with the profile and the generation deleted from it.
+type OnGenerationDeleted = Arc<dyn Fn(&Path, &Generation) + Send + Sync>;
+
+/// The callbacks notified while generations are deleted and garbage is
+/// collected, none by default.
+///
+/// Callbacks are called on the task doing the work, so they should return
+/// quickly.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::{
+///     atomic::{AtomicU64, Ordering},
+///     Arc,
+/// };
+///
+/// use janitor::Progress;
+///
+/// let deleted = Arc::new(AtomicU64::new(0));
+/// let progress = Progress::default()
+///     .on_gc_progress({
+///         let deleted = deleted.clone();
+///         move |count, _path| deleted.store(count, Ordering::Relaxed)
+///     })
+///     .on_generation_deleted(|profile, generation| {
+///         println!("deleted {} from {}", generation.id, profile.display())
+///     });
+///
+/// progress.gc_progress(3, "/nix/store/abc-foo");
+/// assert_eq!(deleted.load(Ordering::Relaxed), 3);
+/// ```
+#[derive(Clone, Default)]
+pub struct Progress {
+    on_gc_progress: Option<OnGcProgress>,
+    on_generation_deleted: Option<OnGenerationDeleted>,
+}
+
+impl Progress {
+    /// Calls `f` with the number of store paths deleted so far and the store
+    /// path the garbage collector deletes.
+    pub fn on_gc_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(u64, &str) + Send + Sync + 'static,
+    {
+        self.on_gc_progress = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` with the profile and every generation deleted from it.
+    pub fn on_generation_deleted<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Path, &Generation) + Send + Sync + 'static,
+    {
+        self.on_generation_deleted = Some(Arc::new(f));
+        self
+    }
+
+    /// Whether any callback is set.
+    pub fn is_empty(&self) -> bool {
+        self.on_gc_progress.is_none() && self.on_generation_deleted.is_none()
+    }
+
+    /// Reports the store `path` as the `deleted`th one deleted by the
+    /// garbage collector.
+    pub fn gc_progress(&self, deleted: u64, path: &str) {
+        if let Some(f) = &self.on_gc_progress {
+            f(deleted, path);
+        }
+    }
+
+    /// Reports the `generations` as deleted from the `profile`.
+    pub fn generations_deleted(&self, profile: &Path, generations: &GenerationSet) {
+        if let Some(f) = &self.on_generation_deleted {
+            for generation in generations.iter() {
+                f(profile, generation);
+            }
+        }
+    }
+
+    /// Whether the garbage collector reports progress, to skip decoding the
+    /// store paths otherwise.
+    pub(crate) fn wants_gc_progress(&self) -> bool {
+        self.on_gc_progress.is_some()
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("on_gc_progress", &self.on_gc_progress.is_some())
+            .field(
+                "on_generation_deleted",
+                &self.on_generation_deleted.is_some(),
+            )
+            .finish()
+    }
+}
+
+/// Two sets of callbacks are equal if they share the very same callbacks.
+impl PartialEq for Progress {
+    fn eq(&self, other: &Self) -> bool {
+        fn same<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+        }
+
+        same(&self.on_gc_progress, &other.on_gc_progress)
+            && same(&self.on_generation_deleted, &other.on_generation_deleted)
+    }
+}
+
+impl Eq for Progress {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn reports_every_deleted_generation() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let progress = Progress::default().on_generation_deleted({
+            let seen = seen.clone();
+            move |profile, generation| {
+                seen.lock()
+                    .unwrap()
+                    .push((profile.to_path_buf(), generation.id))
+            }
+        });
+        let generations: GenerationSet = [1, 2]
+            .into_iter()
+            .map(|id| Generation {
+                id,
+                date: Default::default(),
+                current: false,
+            })
+            .collect();
+
+        progress.generations_deleted(Path::new("/p"), &generations);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (Path::new("/p").to_path_buf(), 1),
+                (Path::new("/p").to_path_buf(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn equal_only_with_the_same_callbacks() {
+        let progress = Progress::default().on_gc_progress(|_, _| {});
+
+        assert_eq!(progress, progress.clone());
+        assert_ne!(progress, Progress::default().on_gc_progress(|_, _| {}));
+        assert_eq!(Progress::default(), Progress::default());
+    }
+}
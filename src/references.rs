@@ -0,0 +1,381 @@
+//! Finds references to generations from outside of their profile, which keep
+//! their store paths alive no matter whether the generation is deleted.
+//!
+//! A system generation is referenced when a garbage collector root or the
+//! profile of a nixos-container resolves to its store path, or to the store
+//! path of one of its specialisations, `<generation>/specialisation/<name>`.
+//! Deleting such a generation frees nothing but makes it impossible to boot
+//! or switch back to it, so the janitor keeps it.
+//!
+//! The system generation that has been booted, [BOOTED_SYSTEM], is kept as
+//! well. It differs from the running one after switching to a newer
+//! generation without rebooting, and deleting it would break booting back
+//! into it after a crash.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{filesystem::split_profile, generation_set::GenerationSet};
+
+/// The directory holding the garbage collector roots.
+pub const GCROOTS: &str = "/nix/var/nix/gcroots";
+
+/// The directory holding the profiles of nixos-containers, one directory per
+/// container with its `system` profile in it.
+pub const PER_CONTAINER: &str = "/nix/var/nix/profiles/per-container";
+
+/// The link NixOS points to the system that has been booted.
+pub const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+/// What refers to a store path.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Referrer {
+    /// A garbage collector root, the link in [GCROOTS] or below it.
+    GcRoot(PathBuf),
+
+    /// The system profile of the nixos-container with the given name.
+    Container(String),
+
+    /// The system that has been booted, see [BOOTED_SYSTEM].
+    BootedSystem,
+}
+
+impl fmt::Display for Referrer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GcRoot(link) => write!(f, "gc root {}", link.display()),
+            Self::Container(name) => write!(f, "container {name}"),
+            Self::BootedSystem => write!(f, "the booted system"),
+        }
+    }
+}
+
+/// A reference to a generation, see [Referrers::of_generations].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Reference {
+    /// What refers to the generation.
+    pub referrer: Referrer,
+
+    /// The specialisation of the generation being referred to, if it is not
+    /// the generation itself.
+    pub specialisation: Option<String>,
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.specialisation {
+            Some(name) => write!(f, "{} (specialisation {name})", self.referrer),
+            None => write!(f, "{}", self.referrer),
+        }
+    }
+}
+
+/// The paths referred to by garbage collector roots, container profiles and
+/// the booted system, with what refers to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Referrers {
+    targets: BTreeMap<PathBuf, Vec<Referrer>>,
+}
+
+impl Referrers {
+    /// Scans the garbage collector roots in [GCROOTS] and the container
+    /// profiles in [PER_CONTAINER], and resolves the [BOOTED_SYSTEM].
+    pub fn system() -> Self {
+        Self::scan(GCROOTS, PER_CONTAINER).booted(BOOTED_SYSTEM)
+    }
+
+    /// Scans the garbage collector roots below `gcroots` and the container
+    /// profiles in `containers`.
+    ///
+    /// Directories below `gcroots` are scanned recursively, links to
+    /// directories are not followed but taken as roots, so that the link to
+    /// the profiles directory does not refer to every generation. Entries
+    /// that can not be read or resolved are skipped.
+    pub fn scan<P: AsRef<Path>, Q: AsRef<Path>>(gcroots: P, containers: Q) -> Self {
+        let mut referrers = Self::default();
+
+        referrers.scan_roots(gcroots.as_ref());
+
+        for entry in read_dir(containers.as_ref()) {
+            let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+                continue;
+            };
+            if let Some(target) = resolve(&entry.path().join("system")) {
+                referrers.insert(target, Referrer::Container(name));
+            }
+        }
+
+        referrers
+    }
+
+    /// Adds the system the link `booted` resolves to as the booted system.
+    ///
+    /// Nothing is added if the link does not exist, e.g. when not running
+    /// NixOS.
+    pub fn booted<P: AsRef<Path>>(mut self, booted: P) -> Self {
+        if let Some(target) = resolve(booted.as_ref()) {
+            self.insert(target, Referrer::BootedSystem);
+        }
+
+        self
+    }
+
+    fn scan_roots(&mut self, dir: &Path) {
+        for entry in read_dir(dir) {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            if file_type.is_dir() {
+                self.scan_roots(&path);
+            } else if let Some(target) = resolve(&path) {
+                self.insert(target, Referrer::GcRoot(path));
+            }
+        }
+    }
+
+    fn insert(&mut self, target: PathBuf, referrer: Referrer) {
+        self.targets.entry(target).or_default().push(referrer);
+    }
+
+    /// Finds the references to the `generations` of the profile at
+    /// `profile`, by resolving their generation links.
+    ///
+    /// Returns the references by generation id, generations without any are
+    /// left out.
+    pub fn of_generations<P: AsRef<Path>>(
+        &self,
+        profile: P,
+        generations: &GenerationSet,
+    ) -> BTreeMap<u32, Vec<Reference>> {
+        let Ok((dir, name)) = split_profile(profile.as_ref()) else {
+            return BTreeMap::new();
+        };
+
+        generations
+            .iter()
+            .filter_map(|generation| {
+                let link = dir.join(format!("{name}-{id}-link", id = generation.id));
+                let references = self.of_generation(&link);
+
+                (!references.is_empty()).then_some((generation.id, references))
+            })
+            .collect()
+    }
+
+    fn of_generation(&self, link: &Path) -> Vec<Reference> {
+        let Some(path) = resolve(link) else {
+            return Vec::new();
+        };
+
+        let specialisations = read_dir(&path.join("specialisation")).filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_owned();
+
+            Some((Some(name), resolve(&entry.path())?))
+        });
+
+        let mut references: Vec<_> = [(None, path.clone())]
+            .into_iter()
+            .chain(specialisations)
+            .flat_map(|(specialisation, target)| {
+                self.targets
+                    .get(&target)
+                    .into_iter()
+                    .flatten()
+                    .map(move |referrer| Reference {
+                        referrer: referrer.clone(),
+                        specialisation: specialisation.clone(),
+                    })
+            })
+            .collect();
+        references.sort();
+
+        references
+    }
+}
+
+fn read_dir(dir: &Path) -> impl Iterator<Item = fs::DirEntry> {
+    let entries = fs::read_dir(dir)
+        .map_err(|error| tracing::debug!(%error, dir = %dir.display(), "not scanning"))
+        .ok();
+
+    entries.into_iter().flatten().filter_map(Result::ok)
+}
+
+fn resolve(link: &Path) -> Option<PathBuf> {
+    fs::canonicalize(link)
+        .map_err(|error| tracing::debug!(%error, link = %link.display(), "not resolving"))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, os::unix::fs::symlink};
+
+    use super::*;
+
+    use eyre::Result;
+
+    use crate::generation::Generation;
+
+    /// Builds a fake store with three system generations, the second having
+    /// a specialisation, and a container.
+    fn setup(dir: &Path) -> Result<()> {
+        let store = dir.join("store");
+        for toplevel in [
+            "system-1",
+            "system-2",
+            "system-2-special",
+            "system-3",
+            "container",
+        ] {
+            fs::create_dir_all(store.join(toplevel))?;
+        }
+        fs::create_dir_all(store.join("system-2/specialisation"))?;
+        symlink(
+            store.join("system-2-special"),
+            store.join("system-2/specialisation/special"),
+        )?;
+
+        let profiles = dir.join("profiles");
+        fs::create_dir_all(&profiles)?;
+        for id in [1, 2, 3] {
+            symlink(
+                store.join(format!("system-{id}")),
+                profiles.join(format!("system-{id}-link")),
+            )?;
+        }
+        symlink("system-3-link", profiles.join("system"))?;
+
+        let gcroots = dir.join("gcroots");
+        fs::create_dir_all(gcroots.join("auto"))?;
+        symlink(&profiles, gcroots.join("profiles"))?;
+        symlink(profiles.join("system"), gcroots.join("current-system"))?;
+        symlink(
+            store.join("system-2-special"),
+            gcroots.join("auto/special-result"),
+        )?;
+
+        let container = dir.join("per-container/web");
+        fs::create_dir_all(&container)?;
+        symlink(store.join("system-1"), container.join("system"))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_references() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-references-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let found = setup(&dir).map(|()| {
+            let generations: GenerationSet = [1, 2, 3, 4]
+                .into_iter()
+                .map(|id| Generation {
+                    id,
+                    date: Default::default(),
+                    current: id == 3,
+                })
+                .collect();
+
+            Referrers::scan(dir.join("gcroots"), dir.join("per-container"))
+                .of_generations(dir.join("profiles/system"), &generations)
+        });
+        fs::remove_dir_all(&dir)?;
+        let found = found?;
+
+        let reasons: Vec<_> = found
+            .iter()
+            .map(|(id, references)| {
+                let references: Vec<_> = references
+                    .iter()
+                    .map(|reference| {
+                        reference
+                            .to_string()
+                            .replace(&dir.display().to_string(), "")
+                    })
+                    .collect();
+
+                (*id, references)
+            })
+            .collect();
+
+        assert_eq!(
+            reasons,
+            vec![
+                (1, vec!["container web".to_string()]),
+                (
+                    2,
+                    vec![
+                        "gc root /gcroots/auto/special-result (specialisation special)".to_string()
+                    ]
+                ),
+                (3, vec!["gc root /gcroots/current-system".to_string()]),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_booted_system() -> Result<()> {
+        let dir = env::temp_dir().join(format!("janitor-booted-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let found = setup(&dir).and_then(|()| {
+            symlink(
+                dir.join("store/system-2-special"),
+                dir.join("booted-system"),
+            )?;
+            let generations: GenerationSet = [1, 2, 3]
+                .into_iter()
+                .map(|id| Generation {
+                    id,
+                    date: Default::default(),
+                    current: id == 3,
+                })
+                .collect();
+
+            Ok(Referrers::default()
+                .booted(dir.join("booted-system"))
+                .booted(dir.join("missing"))
+                .of_generations(dir.join("profiles/system"), &generations))
+        });
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(
+            found?,
+            BTreeMap::from([(
+                2,
+                vec![Reference {
+                    referrer: Referrer::BootedSystem,
+                    specialisation: Some("special".to_string()),
+                }]
+            )])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_directories() {
+        let dir =
+            env::temp_dir().join(format!("janitor-references-missing-{}", std::process::id()));
+
+        assert_eq!(
+            Referrers::scan(dir.join("gcroots"), dir.join("per-container")),
+            Referrers::default()
+        );
+    }
+}
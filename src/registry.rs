@@ -0,0 +1,279 @@
+//! Wrappers around the `nix registry` commands, to find and fix flake
+//! registry entries pinned to store paths.
+//!
+//! `nix registry pin` pins an entry to the store path the flake has been
+//! fetched to. Nothing keeps that path alive, so once the garbage collector
+//! has deleted it, the entry dangles.
+
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::error::{JanitorError, Result, SpawnContext};
+use tracing::Instrument;
+
+use crate::{
+    executor::{CommandLine, Executor},
+    size::NIX_STORE,
+};
+
+/// The registries `nix registry list` shows entries of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// The registry of the user, `~/.config/nix/registry.json`.
+    User,
+
+    /// The registry of the machine, `/etc/nix/registry.json`.
+    System,
+
+    /// The registry downloaded from the `flake-registry` setting.
+    Global,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::User => "user",
+            Self::System => "system",
+            Self::Global => "global",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Scope {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(Self::User),
+            "system" => Ok(Self::System),
+            "global" => Ok(Self::Global),
+            _ => Err(parse_error!("unknown registry {s:?}")),
+        }
+    }
+}
+
+/// A single entry of a flake registry, mapping one flake reference to
+/// another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryEntry {
+    /// The registry the entry is in.
+    pub scope: Scope,
+
+    /// The reference being mapped, e.g. `flake:nixpkgs`.
+    pub from: String,
+
+    /// The reference it is mapped to, e.g. `github:NixOS/nixpkgs` or
+    /// `path:/nix/store/…-source?narHash=…`.
+    pub to: String,
+}
+
+impl RegistryEntry {
+    /// The store path the entry is pinned to, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use janitor::registry::{RegistryEntry, Scope};
+    ///
+    /// let entry = RegistryEntry {
+    ///     scope: Scope::User,
+    ///     from: "flake:nixpkgs".to_string(),
+    ///     to: "path:/nix/store/q0v8g8pkx7z2j2mkxrdk6v7z3yksbplh-source?narHash=sha256-x".to_string(),
+    /// };
+    ///
+    /// assert_eq!(
+    ///     entry.store_path().as_deref(),
+    ///     Some(Path::new("/nix/store/q0v8g8pkx7z2j2mkxrdk6v7z3yksbplh-source"))
+    /// );
+    /// ```
+    pub fn store_path(&self) -> Option<PathBuf> {
+        let path = self.to.strip_prefix("path:").unwrap_or(&self.to);
+        let path = Path::new(path.split(['?', '#']).next()?);
+
+        let name = path.strip_prefix(NIX_STORE).ok()?.components().next()?;
+        match name {
+            Component::Normal(name) => Some(Path::new(NIX_STORE).join(name)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RegistryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.scope, self.from, self.to)
+    }
+}
+
+impl FromStr for RegistryEntry {
+    type Err = JanitorError;
+
+    /// Parses a line of the output of `nix registry list`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split_whitespace();
+
+        let (Some(scope), Some(from), Some(to), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(parse_error!("unrecognized registry entry {s:?}"));
+        };
+
+        Ok(Self {
+            scope: scope.parse()?,
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+/// Parses the output of `nix registry list`, skipping lines that are not
+/// recognized.
+pub fn parse_list(output: &str) -> Vec<RegistryEntry> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match line.parse() {
+            Ok(entry) => Some(entry),
+            Err(error) => {
+                tracing::debug!(%error, "skipping registry entry");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Lists the entries of all registries.
+pub async fn list<E>(executor: &E) -> Result<Vec<RegistryEntry>>
+where
+    E: Executor + ?Sized,
+{
+    let output = run(executor, nix_registry("list")).await?;
+
+    Ok(parse_list(&output))
+}
+
+/// Removes the entries for `from` from the registry in the file `registry`,
+/// or from the user registry if not given.
+pub async fn remove<E>(executor: &E, from: &str, registry: Option<&Path>) -> Result<()>
+where
+    E: Executor + ?Sized,
+{
+    run(
+        executor,
+        with_registry(nix_registry("remove"), registry).arg(from),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Pins `from` to the reference it currently resolves to, in the registry in
+/// the file `registry` or in the user registry if not given.
+pub async fn pin<E>(executor: &E, from: &str, registry: Option<&Path>) -> Result<()>
+where
+    E: Executor + ?Sized,
+{
+    run(
+        executor,
+        with_registry(nix_registry("pin"), registry).arg(from),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn nix_registry(subcommand: &str) -> CommandLine {
+    CommandLine::new("nix")
+        .arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("registry")
+        .arg(subcommand)
+}
+
+fn with_registry(command: CommandLine, registry: Option<&Path>) -> CommandLine {
+    match registry {
+        Some(registry) => command.arg("--registry").arg(registry),
+        None => command,
+    }
+}
+
+async fn run<E>(executor: &E, command: CommandLine) -> Result<String>
+where
+    E: Executor + ?Sized,
+{
+    let output = executor
+        .output(command)
+        .instrument(tracing::debug_span!("nix-registry"))
+        .await
+        .spawning("nix registry")?;
+    JanitorError::check_output("nix registry", &output)?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    const LIST: &str = "\
+user   flake:nixpkgs path:/nix/store/q0v8g8pkx7z2j2mkxrdk6v7z3yksbplh-source?lastModified=1686592866&narHash=sha256-x
+system flake:home-manager github:nix-community/home-manager
+global flake:agda github:agda/agda
+this is not an entry at all
+";
+
+    fn entry(to: &str) -> RegistryEntry {
+        RegistryEntry {
+            scope: Scope::User,
+            from: "flake:nixpkgs".to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let entries = parse_list(LIST);
+
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.scope, entry.from.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Scope::User, "flake:nixpkgs"),
+                (Scope::System, "flake:home-manager"),
+                (Scope::Global, "flake:agda"),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::path(
+        "path:/nix/store/abc-source?narHash=sha256-x",
+        Some("/nix/store/abc-source")
+    )]
+    #[case::bare("/nix/store/abc-source", Some("/nix/store/abc-source"))]
+    #[case::subdir("path:/nix/store/abc-source/sub?dir=x", Some("/nix/store/abc-source"))]
+    #[case::outside_store("path:/home/user/flake", None)]
+    #[case::github("github:NixOS/nixpkgs", None)]
+    #[case::store_itself("path:/nix/store", None)]
+    fn store_path(#[case] to: &str, #[case] expected: Option<&str>) {
+        assert_eq!(entry(to).store_path(), expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn display_roundtrips() -> Result<()> {
+        for entry in parse_list(LIST) {
+            assert_eq!(entry.to_string().parse::<RegistryEntry>()?, entry);
+        }
+
+        Ok(())
+    }
+}
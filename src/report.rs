@@ -0,0 +1,364 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    generation_set::GenerationSet,
+    job::{JobId, Timing},
+    nix_store::{GcReport, VerifyReport},
+    planning::Explanation,
+    policy::KeepSinceDerivation,
+    rusage::ResourceUsage,
+};
+
+/// The outcome of cleaning up a single profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileReport {
+    /// The id of the job that processed the profile.
+    pub job_id: JobId,
+
+    /// The path of the profile.
+    pub path: PathBuf,
+
+    /// The generations that have been deleted.
+    pub deleted: GenerationSet,
+
+    /// Generations that appeared while the janitor was running, e.g. because
+    /// of a concurrent rebuild. They have not been considered by the plan and
+    /// are left untouched.
+    pub appeared: GenerationSet,
+
+    /// The time the job spent in each of its states.
+    pub timings: Vec<Timing>,
+
+    /// Why the retention policy deletes no generation of the profile, if it
+    /// deletes none.
+    pub explanation: Option<Explanation>,
+
+    /// How the cutoff date of the job has been derived, if it has been
+    /// created from a retention policy.
+    pub keep_since: Option<KeepSinceDerivation>,
+}
+
+impl ProfileReport {
+    /// Creates a new report for the profile at `path`.
+    pub fn new<P: AsRef<Path>>(job_id: JobId, path: P, deleted: GenerationSet) -> Self {
+        Self {
+            job_id,
+            path: path.as_ref().to_path_buf(),
+            deleted,
+            appeared: GenerationSet::default(),
+            timings: Vec::new(),
+            explanation: None,
+            keep_since: None,
+        }
+    }
+
+    /// Records the generations that appeared during the run.
+    pub fn with_appeared(self, appeared: GenerationSet) -> Self {
+        Self { appeared, ..self }
+    }
+}
+
+/// A profile whose cleanup failed, while the others have been cleaned up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileFailure {
+    /// The path of the profile.
+    pub path: PathBuf,
+
+    /// Why the cleanup failed, with the chain of its causes.
+    pub error: String,
+}
+
+/// How the generations retained for a user compared to their budget, for a
+/// user whose retained generations exceeded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaReport {
+    /// The name of the user, or their id if it can not be resolved.
+    pub user: String,
+
+    /// The budget of the user in bytes.
+    pub budget: u64,
+
+    /// The size of the generations retained by the retention policies, in
+    /// bytes.
+    pub retained: u64,
+
+    /// The size of the generations retained after tightening the retention
+    /// policies, in bytes.
+    pub tightened: u64,
+
+    /// The number of generations no longer retained after tightening.
+    pub released: usize,
+}
+
+impl QuotaReport {
+    /// Whether tightening the retention policies brought the user within
+    /// their budget.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::QuotaReport;
+    ///
+    /// let quota = QuotaReport {
+    ///     user: "alice".to_string(),
+    ///     budget: 100,
+    ///     retained: 150,
+    ///     tightened: 90,
+    ///     released: 2,
+    /// };
+    /// assert!(quota.met());
+    /// ```
+    pub fn met(&self) -> bool {
+        self.tightened <= self.budget
+    }
+}
+
+/// Summarizes a janitor run over all profiles.
+///
+/// The report is built incrementally, profile by profile, as the jobs finish.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::{GenerationSet, Job, RunReport};
+///
+/// let job = Job::new("/some/profile", Default::default(), 0)
+///     .listed(GenerationSet::default())
+///     .plan()
+///     .executed(GenerationSet::default())
+///     .verify(None);
+///
+/// let mut report = RunReport::default();
+/// report.record(job.into_report());
+///
+/// assert_eq!(report.profiles().len(), 1);
+/// assert_eq!(report.deleted_count(), 0);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunReport {
+    profiles: Vec<ProfileReport>,
+    gc: Option<GcReport>,
+    verification: Option<VerifyReport>,
+    skipped: Vec<PathBuf>,
+    resources: Option<ResourceUsage>,
+    quotas: Vec<QuotaReport>,
+    failures: Vec<ProfileFailure>,
+    cut_short: Vec<String>,
+}
+
+impl RunReport {
+    /// Adds the outcome of a single profile to the report.
+    pub fn record(&mut self, profile: ProfileReport) {
+        self.profiles.push(profile);
+    }
+
+    /// Returns the reports of all profiles recorded so far, in the order they
+    /// have been recorded.
+    pub fn profiles(&self) -> &[ProfileReport] {
+        &self.profiles
+    }
+
+    /// Returns the total number of generations deleted over all profiles.
+    pub fn deleted_count(&self) -> usize {
+        self.profiles.iter().map(|p| p.deleted.len()).sum()
+    }
+
+    /// Records the outcome of a garbage collection.
+    ///
+    /// The garbage collector might run several times during a run, their
+    /// outcomes are summed up.
+    pub fn record_gc(&mut self, gc: GcReport) {
+        let total = self.gc.get_or_insert_with(GcReport::default);
+        total.paths_deleted += gc.paths_deleted;
+        total.bytes_freed += gc.bytes_freed;
+        total.unrecognized += gc.unrecognized;
+    }
+
+    /// Returns the outcome of the garbage collection, if it has been run.
+    pub fn gc(&self) -> Option<&GcReport> {
+        self.gc.as_ref()
+    }
+
+    /// Records the outcome of the store verification.
+    pub fn record_verification(&mut self, verification: VerifyReport) {
+        self.verification = Some(verification);
+    }
+
+    /// Returns the outcome of the store verification, if it has been run.
+    pub fn verification(&self) -> Option<&VerifyReport> {
+        self.verification.as_ref()
+    }
+
+    /// Records that the profile at `path` has not been cleaned up, because
+    /// the space to free up has already been reclaimed.
+    pub fn record_skipped<P: AsRef<Path>>(&mut self, path: P) {
+        self.skipped.push(path.as_ref().to_path_buf());
+    }
+
+    /// Returns the profiles that have been skipped, in the order they have
+    /// been recorded.
+    pub fn skipped(&self) -> &[PathBuf] {
+        &self.skipped
+    }
+
+    /// Records that cleaning up the profile at `path` failed with `error`,
+    /// formatted in its alternate form to include its causes, if it does.
+    pub fn record_failure<P: AsRef<Path>, E: fmt::Display>(&mut self, path: P, error: &E) {
+        self.failures.push(ProfileFailure {
+            path: path.as_ref().to_path_buf(),
+            error: format!("{error:#}"),
+        });
+    }
+
+    /// Returns the profiles whose cleanup failed, in the order they have been
+    /// recorded.
+    pub fn failures(&self) -> &[ProfileFailure] {
+        &self.failures
+    }
+
+    /// Records that the `step` of the run has been skipped, as the run
+    /// exceeded its maximum runtime.
+    pub fn record_cut_short<S: Into<String>>(&mut self, step: S) {
+        self.cut_short.push(step.into());
+    }
+
+    /// Returns the steps skipped as the run exceeded its maximum runtime, in
+    /// the order they have been recorded.
+    pub fn cut_short(&self) -> &[String] {
+        &self.cut_short
+    }
+
+    /// Whether the run has only partially been performed, as it exceeded its
+    /// maximum runtime.
+    pub fn is_partial(&self) -> bool {
+        !self.cut_short.is_empty()
+    }
+
+    /// Records a user whose retained generations exceeded their budget.
+    pub fn record_quota(&mut self, quota: QuotaReport) {
+        self.quotas.push(quota);
+    }
+
+    /// Returns the users whose retained generations exceeded their budget,
+    /// in the order they have been recorded.
+    pub fn quotas(&self) -> &[QuotaReport] {
+        &self.quotas
+    }
+
+    /// Records the resources used by the commands spawned during the run.
+    pub fn record_resources(&mut self, resources: ResourceUsage) {
+        self.resources = Some(resources);
+    }
+
+    /// Returns the resources used by the commands spawned during the run, if
+    /// they have been accounted.
+    pub fn resources(&self) -> Option<&ResourceUsage> {
+        self.resources.as_ref()
+    }
+
+    /// Returns the total number of generations that appeared during the run
+    /// over all profiles.
+    pub fn appeared_count(&self) -> usize {
+        self.profiles.iter().map(|p| p.appeared.len()).sum()
+    }
+
+    /// Sorts the profiles, failed ones included, and the store paths of the
+    /// verification by path and the users exceeding their budget by name,
+    /// so that the report does not depend on the order the jobs finished in.
+    pub fn sort(&mut self) {
+        self.profiles.sort_by(|a, b| a.path.cmp(&b.path));
+        self.skipped.sort();
+        self.failures.sort_by(|a, b| a.path.cmp(&b.path));
+        self.cut_short.sort();
+        self.quotas.sort_by(|a, b| a.user.cmp(&b.user));
+        if let Some(verification) = &mut self.verification {
+            verification.issues.sort();
+            verification.repaired.sort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{generation::Generation, job::Job};
+
+    #[test]
+    fn deleted_count_sums_profiles() {
+        let generations =
+            Generation::parse_many("1 2023-06-01 08:10:47\n2 2023-06-02 08:10:47").unwrap();
+
+        let id = Job::new("/", Default::default(), 0).id();
+
+        let mut report = RunReport::default();
+        report.record(ProfileReport::new(id, "/a", generations.clone().into()));
+        report.record(ProfileReport::new(id, "/b", generations[..1].into()));
+
+        assert_eq!(report.deleted_count(), 3);
+        assert_eq!(report.profiles()[1].path, PathBuf::from("/b"));
+    }
+
+    #[test]
+    fn sort_orders_by_path() {
+        let id = Job::new("/", Default::default(), 0).id();
+
+        let mut report = RunReport::default();
+        for path in ["/c", "/a", "/b"] {
+            report.record(ProfileReport::new(id, path, GenerationSet::default()));
+            report.record_skipped(path);
+            report.record_failure(path, &eyre::eyre!("failed"));
+        }
+        report.record_verification(VerifyReport {
+            issues: vec!["z".to_string(), "y".to_string()],
+            repaired: Vec::new(),
+        });
+        report.sort();
+
+        let paths: Vec<_> = report.profiles().iter().map(|p| p.path.clone()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c"].map(PathBuf::from));
+        assert_eq!(report.skipped(), ["/a", "/b", "/c"].map(PathBuf::from));
+        let failed: Vec<_> = report.failures().iter().map(|f| f.path.clone()).collect();
+        assert_eq!(failed, ["/a", "/b", "/c"].map(PathBuf::from));
+        assert_eq!(report.verification().unwrap().issues, ["y", "z"]);
+    }
+
+    #[test]
+    fn partial_when_cut_short() {
+        let mut report = RunReport::default();
+        assert!(!report.is_partial());
+
+        report.record_cut_short("collecting garbage");
+        assert!(report.is_partial());
+        assert_eq!(report.cut_short(), ["collecting garbage"]);
+    }
+
+    #[test]
+    fn gc_accumulates() {
+        let mut report = RunReport::default();
+        assert_eq!(report.gc(), None);
+
+        report.record_gc(GcReport {
+            paths_deleted: 2,
+            bytes_freed: 100,
+            unrecognized: 1,
+        });
+        report.record_gc(GcReport {
+            paths_deleted: 3,
+            bytes_freed: 50,
+            unrecognized: 0,
+        });
+
+        assert_eq!(
+            report.gc(),
+            Some(&GcReport {
+                paths_deleted: 5,
+                bytes_freed: 150,
+                unrecognized: 1,
+            })
+        );
+    }
+}
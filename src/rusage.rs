@@ -0,0 +1,221 @@
+//! Accounts for the resources used by the commands the janitor spawns.
+//!
+//! The kernel accounts the usage of a child process to its parent once the
+//! child has been waited for. The usage of all children of a run is the
+//! difference of [ResourceUsage::children] before and after it, the usage of
+//! a single command is returned when waiting for it with [wait].
+
+use std::{fmt, time::Duration};
+
+use crate::size::format_size;
+
+/// The size of the blocks the kernel counts file system input and output in.
+#[cfg(feature = "system")]
+const BLOCK_SIZE: u64 = 512;
+
+/// The resources used by one or several processes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The CPU time spent in user mode.
+    pub user_time: Duration,
+
+    /// The CPU time spent in the kernel on behalf of the processes.
+    pub system_time: Duration,
+
+    /// The largest resident set size of any of the processes, in bytes.
+    pub max_rss: u64,
+
+    /// The bytes read from the file systems, not counting reads served from
+    /// the page cache.
+    pub bytes_read: u64,
+
+    /// The bytes written to the file systems.
+    pub bytes_written: u64,
+}
+
+impl ResourceUsage {
+    /// The total CPU time, in user mode and in the kernel.
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+
+    /// Returns the resources used by all children of the current process that
+    /// have terminated and been waited for.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the usage can not be queried from the kernel.
+    #[cfg(feature = "system")]
+    pub fn children() -> std::io::Result<Self> {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+
+        // SAFETY: `usage` points to enough memory for a `rusage` struct,
+        // which is initialized on success.
+        let usage = unsafe {
+            if libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            usage.assume_init()
+        };
+
+        Ok(Self::from_rusage(&usage))
+    }
+
+    /// Returns the resources used between the `earlier` usage and this one,
+    /// both taken with [ResourceUsage::children].
+    ///
+    /// The kernel only keeps the largest resident set size of all children
+    /// ever waited for, so that is kept as is.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            user_time: self.user_time.saturating_sub(earlier.user_time),
+            system_time: self.system_time.saturating_sub(earlier.system_time),
+            max_rss: self.max_rss,
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(earlier.bytes_written),
+        }
+    }
+
+    #[cfg(feature = "system")]
+    #[allow(clippy::unnecessary_cast)]
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let time = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+
+        // Linux reports the resident set size in KiB, macOS in bytes.
+        #[cfg(target_os = "macos")]
+        let max_rss = usage.ru_maxrss as u64;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss = usage.ru_maxrss as u64 * 1024;
+
+        Self {
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+            max_rss,
+            bytes_read: usage.ru_inblock as u64 * BLOCK_SIZE,
+            bytes_written: usage.ru_oublock as u64 * BLOCK_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cpu {:.2}s ({:.2}s user, {:.2}s system), peak rss {}, read {}, written {}",
+            self.cpu_time().as_secs_f64(),
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64(),
+            format_size(self.max_rss),
+            format_size(self.bytes_read),
+            format_size(self.bytes_written),
+        )
+    }
+}
+
+/// Waits for the child process `pid` to terminate, returning its exit status
+/// and the resources it used.
+///
+/// # Errors
+///
+/// Fails if `pid` is not a child of the current process or has already been
+/// waited for.
+#[cfg(feature = "system")]
+pub fn wait(pid: u32) -> std::io::Result<(std::process::ExitStatus, ResourceUsage)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = libc::pid_t::try_from(pid)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid pid"))?;
+    let mut status = 0;
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+
+    // SAFETY: `status` and `usage` point to enough memory for an int and a
+    // `rusage` struct, which are initialized on success.
+    let usage = unsafe {
+        loop {
+            if libc::wait4(pid, &mut status, 0, usage.as_mut_ptr()) != -1 {
+                break;
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        usage.assume_init()
+    };
+
+    Ok((
+        std::process::ExitStatus::from_raw(status),
+        ResourceUsage::from_rusage(&usage),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn since() {
+        let earlier = ResourceUsage {
+            user_time: Duration::from_secs(1),
+            system_time: Duration::from_millis(500),
+            max_rss: 1024,
+            bytes_read: 512,
+            bytes_written: 0,
+        };
+        let later = ResourceUsage {
+            user_time: Duration::from_secs(3),
+            system_time: Duration::from_secs(1),
+            max_rss: 4096,
+            bytes_read: 2048,
+            bytes_written: 1024,
+        };
+
+        assert_eq!(
+            later.since(&earlier),
+            ResourceUsage {
+                user_time: Duration::from_secs(2),
+                system_time: Duration::from_millis(500),
+                max_rss: 4096,
+                bytes_read: 1536,
+                bytes_written: 1024,
+            }
+        );
+        assert_eq!(
+            later.since(&earlier).cpu_time(),
+            Duration::from_millis(2500)
+        );
+    }
+
+    #[test]
+    fn display() {
+        let usage = ResourceUsage {
+            user_time: Duration::from_millis(1250),
+            system_time: Duration::from_millis(250),
+            max_rss: 64 * 1024 * 1024,
+            bytes_read: 0,
+            bytes_written: 2048,
+        };
+
+        assert_eq!(
+            usage.to_string(),
+            "cpu 1.50s (1.25s user, 0.25s system), peak rss 64.00 MiB, read 0 B, written 2.00 KiB"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn accounts_waited_children() -> std::io::Result<()> {
+        let before = ResourceUsage::children()?;
+        let child = std::process::Command::new("true").spawn()?;
+        let (status, _) = wait(child.id())?;
+        let after = ResourceUsage::children()?;
+
+        assert!(status.success());
+        assert!(after.cpu_time() >= before.cpu_time());
+        assert!(wait(child.id()).is_err());
+
+        Ok(())
+    }
+}
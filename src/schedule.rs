@@ -0,0 +1,541 @@
+//! Calendar windows like `Sat..Sun 03:00..05:00`, during which the daemon
+//! starts its cleanups.
+//!
+//! Each cleanup starts at a random time within its window, so that machines
+//! sharing a schedule do not all collect garbage at the same moment. The
+//! functions here take the current time and the source of randomness as
+//! arguments, so that they do not depend on the real clock.
+
+use std::{fmt, str::FromStr};
+
+use crate::error::{JanitorError, Result};
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Timelike, Weekday};
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// Recurring windows on some days of the week, all starting at the same time
+/// of day and lasting equally long.
+///
+/// Written as an optional list of days followed by an optional time range,
+/// e.g. `Sat..Sun 03:00..05:00`, `Mon,Wed,Fri 22:00..02:00` or `04:30`. Days
+/// may be given as ranges, which wrap around the end of the week like
+/// `Fri..Mon`. A time range ending before it starts ends on the next day, a
+/// single time is a window without length. Without days, the window recurs
+/// daily, without time, it lasts all day.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::NaiveDate;
+/// use janitor::schedule::Schedule;
+///
+/// let schedule: Schedule = "Sat..Sun 03:00..05:00".parse().unwrap();
+/// let friday = NaiveDate::from_ymd_opt(2023, 6, 2)
+///     .unwrap()
+///     .and_hms_opt(12, 0, 0)
+///     .unwrap();
+///
+/// let window = schedule.window_after(friday);
+/// assert_eq!(window.start.to_string(), "2023-06-03 03:00:00");
+/// assert_eq!(window.end.to_string(), "2023-06-03 05:00:00");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// The days the windows start on, indexed from Monday.
+    days: [bool; 7],
+    start: NaiveTime,
+    length: Duration,
+}
+
+/// A single occurrence of a [Schedule].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+/// When the next cleanup of a [Schedule] starts, and the window it belongs
+/// to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub at: NaiveDateTime,
+    pub window: Window,
+}
+
+impl Schedule {
+    /// The first window that has not ended at `after`, which may be one
+    /// that has already started.
+    ///
+    /// A window without length counts as ended at its start.
+    pub fn window_after(&self, after: NaiveDateTime) -> Window {
+        // a window may have started the day before, and the week after
+        // contains every day
+        (-1..=7)
+            .map(|offset| after.date() + Duration::days(offset))
+            .filter(|date| self.days[date.weekday().num_days_from_monday() as usize])
+            .map(|date| {
+                let start = date.and_time(self.start);
+
+                Window {
+                    start,
+                    end: start + self.length,
+                }
+            })
+            .find(|window| window.end > after || window.start > after)
+            .expect("a schedule has at least one day")
+    }
+
+    /// Picks when the next cleanup starts after `after`.
+    ///
+    /// The cleanup starts up to `jitter` after the start of the next
+    /// [window](Self::window_after), defaulting to the length of the
+    /// window. Given a maximum, `random` returns a random duration between
+    /// zero and that maximum. A window that has already started only leaves
+    /// the remaining time to choose from.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::{Duration, NaiveDate};
+    /// use janitor::schedule::Schedule;
+    ///
+    /// let schedule: Schedule = "03:00".parse().unwrap();
+    /// let midnight = NaiveDate::from_ymd_opt(2023, 6, 2)
+    ///     .unwrap()
+    ///     .and_hms_opt(0, 0, 0)
+    ///     .unwrap();
+    ///
+    /// let run = schedule.next_run(midnight, Some(Duration::minutes(30)), |max| max / 2);
+    /// assert_eq!(run.at.to_string(), "2023-06-02 03:15:00");
+    /// ```
+    pub fn next_run(
+        &self,
+        after: NaiveDateTime,
+        jitter: Option<Duration>,
+        random: impl FnOnce(Duration) -> Duration,
+    ) -> Run {
+        let window = self.window_after(after);
+        let latest = window.start + jitter.unwrap_or(self.length);
+        let earliest = window.start.max(after);
+
+        let spread = (latest - earliest).max(Duration::zero());
+        let delay = random(spread).clamp(Duration::zero(), spread);
+
+        Run {
+            at: earliest + delay,
+            window,
+        }
+    }
+
+    /// The last window that has started at `at`, which may still be open.
+    pub fn window_before(&self, at: NaiveDateTime) -> Window {
+        (-7..=0)
+            .rev()
+            .map(|offset| at.date() + Duration::days(offset))
+            .filter(|date| self.days[date.weekday().num_days_from_monday() as usize])
+            .map(|date| {
+                let start = date.and_time(self.start);
+
+                Window {
+                    start,
+                    end: start + self.length,
+                }
+            })
+            .find(|window| window.start <= at)
+            .expect("a schedule has at least one day")
+    }
+
+    /// Whether a window has started since the `last` cleanup, as of `now`.
+    ///
+    /// Without an earlier cleanup, a window has always been missed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::NaiveDate;
+    /// use janitor::schedule::Schedule;
+    ///
+    /// let schedule: Schedule = "Sat 03:00..05:00".parse().unwrap();
+    /// let date = |day| NaiveDate::from_ymd_opt(2023, 6, day).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    ///
+    /// // the machine was off on Saturday, 2023-06-03
+    /// assert!(schedule.missed(Some(date(1)), date(5)));
+    /// assert!(!schedule.missed(Some(date(3)), date(5)));
+    /// ```
+    pub fn missed(&self, last: Option<NaiveDateTime>, now: NaiveDateTime) -> bool {
+        last.is_none_or(|last| self.window_before(now).start > last)
+    }
+
+    fn lasts_all_day(&self) -> bool {
+        self.start == NaiveTime::MIN && self.length == Duration::days(1)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split_whitespace().peekable();
+
+        let days = match parts.peek() {
+            None => return Err(parse_error!("empty schedule")),
+            Some(part) if part.contains(':') => [true; 7],
+            Some(_) => parse_days(parts.next().expect("peeked"))?,
+        };
+        let (start, length) = match parts.next() {
+            Some(part) => parse_times(part)?,
+            None => (NaiveTime::MIN, Duration::days(1)),
+        };
+
+        if let Some(part) = parts.next() {
+            return Err(parse_error!("unexpected {part:?} in schedule {s:?}"));
+        }
+
+        Ok(Self {
+            days,
+            start,
+            length,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let all_days = self.days.iter().all(|day| *day);
+
+        if !all_days || self.lasts_all_day() {
+            let mut ranges = Vec::new();
+            let mut day = 0;
+            while day < 7 {
+                if !self.days[day] {
+                    day += 1;
+                    continue;
+                }
+
+                let first = day;
+                while day + 1 < 7 && self.days[day + 1] {
+                    day += 1;
+                }
+                ranges.push(match first == day {
+                    true => weekday(first).to_string(),
+                    false => format!("{}..{}", weekday(first), weekday(day)),
+                });
+                day += 1;
+            }
+
+            f.write_str(&ranges.join(","))?;
+        }
+
+        if self.lasts_all_day() {
+            return Ok(());
+        }
+        if !all_days {
+            f.write_str(" ")?;
+        }
+
+        write!(f, "{}", self.start.format(TIME_FORMAT))?;
+        if self.length > Duration::zero() {
+            write!(f, "..{}", (self.start + self.length).format(TIME_FORMAT))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn weekday(index: usize) -> Weekday {
+    Weekday::try_from(index as u8).expect("indices are below 7")
+}
+
+fn parse_day(input: &str) -> Result<Weekday> {
+    input
+        .parse()
+        .map_err(|_| parse_error!("unknown day {input:?} in schedule"))
+}
+
+fn parse_days(input: &str) -> Result<[bool; 7]> {
+    let mut days = [false; 7];
+
+    for part in input.split(',') {
+        let (first, last) = match part.split_once("..") {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(part)?, parse_day(part)?),
+        };
+
+        let mut day = first;
+        loop {
+            days[day.num_days_from_monday() as usize] = true;
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+
+    Ok(days)
+}
+
+fn parse_time(input: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(input, TIME_FORMAT)
+        .map_err(|_| parse_error!("invalid time {input:?} in schedule, expected HH:MM"))
+}
+
+fn parse_times(input: &str) -> Result<(NaiveTime, Duration)> {
+    let Some((start, end)) = input.split_once("..") else {
+        return Ok((parse_time(input)?, Duration::zero()));
+    };
+
+    let start = parse_time(start)?;
+    let length = match end {
+        "24:00" => Duration::days(1) - Duration::seconds(start.num_seconds_from_midnight().into()),
+        end => {
+            let end = parse_time(end)?;
+            if end == start {
+                return Err(parse_error!(
+                    "empty time range {input:?} in schedule, use a single time instead"
+                ));
+            }
+
+            let length = end - start;
+            if length < Duration::zero() {
+                length + Duration::days(1)
+            } else {
+                length
+            }
+        }
+    };
+
+    Ok((start, length))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::NaiveDate;
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn window(start: &str, end: &str) -> Window {
+        Window {
+            start: at(start),
+            end: at(end),
+        }
+    }
+
+    // 2023-06-03 is a Saturday
+    #[rstest]
+    #[case::before(
+        "Sat..Sun 03:00..05:00",
+        "2023-06-02 12:00",
+        window("2023-06-03 03:00", "2023-06-03 05:00")
+    )]
+    #[case::within(
+        "Sat..Sun 03:00..05:00",
+        "2023-06-03 04:00",
+        window("2023-06-03 03:00", "2023-06-03 05:00")
+    )]
+    #[case::at_end(
+        "Sat..Sun 03:00..05:00",
+        "2023-06-03 05:00",
+        window("2023-06-04 03:00", "2023-06-04 05:00")
+    )]
+    #[case::next_week(
+        "Sat..Sun 03:00..05:00",
+        "2023-06-04 06:00",
+        window("2023-06-10 03:00", "2023-06-10 05:00")
+    )]
+    #[case::over_midnight(
+        "Fri 23:00..01:00",
+        "2023-06-03 00:30",
+        window("2023-06-02 23:00", "2023-06-03 01:00")
+    )]
+    #[case::wrapping_days(
+        "Sun..Mon",
+        "2023-06-03 12:00",
+        window("2023-06-04 00:00", "2023-06-05 00:00")
+    )]
+    #[case::daily(
+        "04:30",
+        "2023-06-03 04:30",
+        window("2023-06-04 04:30", "2023-06-04 04:30")
+    )]
+    #[case::whole_day(
+        "Sat",
+        "2023-06-03 12:00",
+        window("2023-06-03 00:00", "2023-06-04 00:00")
+    )]
+    fn window_after(
+        #[case] schedule: &str,
+        #[case] now: &str,
+        #[case] expected: Window,
+    ) -> Result<()> {
+        let schedule: Schedule = schedule.parse()?;
+
+        assert_eq!(schedule.window_after(at(now)), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::within("2023-06-03 04:00", window("2023-06-03 03:00", "2023-06-03 05:00"))]
+    #[case::at_start("2023-06-03 03:00", window("2023-06-03 03:00", "2023-06-03 05:00"))]
+    #[case::before_start("2023-06-03 02:00", window("2023-05-28 03:00", "2023-05-28 05:00"))]
+    #[case::after("2023-06-06 12:00", window("2023-06-04 03:00", "2023-06-04 05:00"))]
+    fn window_before(#[case] now: &str, #[case] expected: Window) -> Result<()> {
+        let schedule: Schedule = "Sat..Sun 03:00..05:00".parse()?;
+
+        assert_eq!(schedule.window_before(at(now)), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::never(None, "2023-06-05 12:00", true)]
+    #[case::switched_off(Some("2023-06-02 12:00"), "2023-06-05 12:00", true)]
+    #[case::ran_in_window(Some("2023-06-04 04:00"), "2023-06-05 12:00", false)]
+    #[case::ran_manually(Some("2023-06-04 12:00"), "2023-06-05 12:00", false)]
+    #[case::window_open(Some("2023-06-02 12:00"), "2023-06-03 03:30", true)]
+    #[case::before_window(Some("2023-06-02 12:00"), "2023-06-03 02:00", false)]
+    fn missed(#[case] last: Option<&str>, #[case] now: &str, #[case] expected: bool) -> Result<()> {
+        let schedule: Schedule = "Sat..Sun 03:00..05:00".parse()?;
+
+        assert_eq!(schedule.missed(last.map(at), at(now)), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::start_of_window("2023-06-02 12:00", None, "2023-06-03 03:00")]
+    #[case::rest_of_window("2023-06-03 04:00", None, "2023-06-03 04:00")]
+    #[case::jitter_passed("2023-06-03 04:00", Some(Duration::minutes(30)), "2023-06-03 04:00")]
+    fn next_run_earliest(
+        #[case] now: &str,
+        #[case] jitter: Option<Duration>,
+        #[case] expected: &str,
+    ) -> Result<()> {
+        let schedule: Schedule = "Sat..Sun 03:00..05:00".parse()?;
+
+        let run = schedule.next_run(at(now), jitter, |_| Duration::zero());
+
+        assert_eq!(run.at, at(expected));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::window(None, "2023-06-03 05:00")]
+    #[case::jitter(Some(Duration::minutes(30)), "2023-06-03 03:30")]
+    #[case::beyond_window(Some(Duration::hours(3)), "2023-06-03 06:00")]
+    fn next_run_latest(#[case] jitter: Option<Duration>, #[case] expected: &str) -> Result<()> {
+        let schedule: Schedule = "Sat..Sun 03:00..05:00".parse()?;
+
+        let run = schedule.next_run(at("2023-06-02 12:00"), jitter, |max| max * 2);
+
+        assert_eq!(run.at, at(expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn simulated_month() -> Result<()> {
+        let schedule: Schedule = "Sat..Sun 03:00..05:00".parse()?;
+        let end = at("2023-07-01 00:00");
+
+        // a simple linear congruential generator
+        let mut seed: i64 = 42;
+        let mut random = |max: Duration| {
+            seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+            Duration::seconds(seed % (max.num_seconds() + 1))
+        };
+
+        let mut now = at("2023-06-01 00:00");
+        let mut runs = Vec::new();
+        loop {
+            let run = schedule.next_run(now, None, &mut random);
+            if run.at >= end {
+                break;
+            }
+
+            assert!(run.window.start <= run.at && run.at <= run.window.end);
+            runs.push(run);
+
+            // the cleanup takes ten minutes
+            now = run.window.end.max(run.at + Duration::minutes(10));
+        }
+
+        let days: Vec<_> = runs.iter().map(|run| run.at.day()).collect();
+        assert_eq!(days, vec![3, 4, 10, 11, 17, 18, 24, 25]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::days_and_times("Sat..Sun 03:00..05:00", "Sat..Sun 03:00..05:00")]
+    #[case::list("mon,wed,friday 22:00..02:00", "Mon,Wed,Fri 22:00..02:00")]
+    #[case::wrapping("Fri..Mon", "Mon,Fri..Sun")]
+    #[case::time_only("04:30", "04:30")]
+    #[case::until_midnight("Sun 22:00..24:00", "Sun 22:00..00:00")]
+    #[case::all_week("Mon..Sun", "Mon..Sun")]
+    #[case::all_week_time("Mon..Sun 01:00..02:00", "01:00..02:00")]
+    fn display(#[case] input: &str, #[case] expected: &str) -> Result<()> {
+        assert_eq!(input.parse::<Schedule>()?.to_string(), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::unknown_day("Someday 03:00")]
+    #[case::invalid_time("Sat 25:00")]
+    #[case::empty_range("Sat 03:00..03:00")]
+    #[case::trailing("Sat 03:00 04:00")]
+    #[case::times_first("03:00 Sat")]
+    fn parse_errors(#[case] input: &str) {
+        assert!(input.parse::<Schedule>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn next_run_within_bounds(
+            days in 1u8..128,
+            start in 0u32..1440,
+            minutes in 0i64..1440,
+            after in 0i64..10_000_000,
+            jitter in proptest::option::of(0i64..600),
+            fraction in 0.0..=1.0f64,
+        ) {
+            let schedule = Schedule {
+                days: std::array::from_fn(|day| days & (1 << day) != 0),
+                start: NaiveTime::from_num_seconds_from_midnight_opt(start * 60, 0).unwrap(),
+                length: Duration::minutes(minutes),
+            };
+            let after = NaiveDate::from_ymd_opt(2023, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()
+                + Duration::seconds(after);
+            let jitter = jitter.map(Duration::minutes);
+
+            let run = schedule.next_run(after, jitter, |max| {
+                Duration::seconds((max.num_seconds() as f64 * fraction) as i64)
+            });
+
+            prop_assert!(run.at >= after);
+            prop_assert!(run.at >= run.window.start);
+            prop_assert!(run.at <= run.window.start.max(after) + jitter.unwrap_or(schedule.length));
+            prop_assert!(run.window.start - after < Duration::days(8));
+        }
+
+        #[test]
+        fn display_roundtrips(days in 1u8..128, start in 0u32..1440, minutes in 0i64..1440) {
+            let schedule = Schedule {
+                days: std::array::from_fn(|day| days & (1 << day) != 0),
+                start: NaiveTime::from_num_seconds_from_midnight_opt(start * 60, 0).unwrap(),
+                length: Duration::minutes(minutes),
+            };
+
+            prop_assert_eq!(schedule.to_string().parse::<Schedule>().unwrap(), schedule);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! Handling of sizes, free space, and estimations of reclaimable space.
+
+use std::{fmt, path::Path};
+
+use crate::error::{JanitorError, Result, SpawnContext};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+    executor::{CommandLine, Executor},
+    generation_set::GenerationSet,
+    nix_store,
+};
+
+/// The location of the nix store.
+pub const NIX_STORE: &str = "/nix/store";
+
+const UNITS: &[(&str, u64)] = &[
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("TiB", 1 << 40),
+    ("K", 1 << 10),
+    ("M", 1 << 20),
+    ("G", 1 << 30),
+    ("T", 1 << 40),
+    ("KB", 1_000),
+    ("MB", 1_000_000),
+    ("GB", 1_000_000_000),
+    ("TB", 1_000_000_000_000),
+    ("B", 1),
+];
+
+/// Parses a human readable size into bytes.
+///
+/// Accepts a plain number of bytes, or a number followed by a binary
+/// (`K`, `KiB`, `M`, `MiB`, …) or decimal (`KB`, `MB`, …) unit.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::size::parse_size;
+///
+/// assert_eq!(parse_size("1024").unwrap(), 1024);
+/// assert_eq!(parse_size("20GiB").unwrap(), 20 * 1024 * 1024 * 1024);
+/// assert_eq!(parse_size("1.5 M").unwrap(), 1024 * 1024 * 3 / 2);
+/// assert!(parse_size("many").is_err());
+/// ```
+pub fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    let factor = match unit.trim() {
+        "" => 1,
+        unit => match UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        {
+            Some((_, factor)) => *factor,
+            None => return Err(parse_error!("unknown size unit: {unit}")),
+        },
+    };
+
+    let amount = amount
+        .parse::<f64>()
+        .map_err(|_| parse_error!("invalid size: {input}"))?;
+
+    Ok((amount * factor as f64).round() as u64)
+}
+
+/// Formats `bytes` in a human readable way, using binary units.
+///
+/// # Examples
+///
+/// ```
+/// use janitor::size::format_size;
+///
+/// assert_eq!(format_size(512), "512 B");
+/// assert_eq!(format_size(1536), "1.50 KiB");
+/// ```
+pub fn format_size(bytes: u64) -> String {
+    const BINARY: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in BINARY {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+
+    format!("{value:.2} {unit}")
+}
+
+/// Returns the space available to unprivileged users on the file system
+/// containing `path`, in bytes.
+#[cfg(feature = "system")]
+pub fn free_space<P: AsRef<Path>>(path: P) -> std::io::Result<u64> {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is a valid NUL terminated string and `stat` points to
+    // enough memory for a `statvfs` struct, which is initialized on success.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// How the sizes of generations are determined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum SizeEstimation {
+    /// Query the sizes of the closures from the store, which is exact but can
+    /// be slow, falling back to [SizeEstimation::Fast] if that fails.
+    #[default]
+    Exact,
+
+    /// Run `du` on the store paths of the generations themselves, which is
+    /// fast but approximate, as their dependencies are left out.
+    Fast,
+}
+
+impl fmt::Display for SizeEstimation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Exact => "exact",
+            Self::Fast => "fast",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// A size in bytes, and whether it is only an approximation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// The size in bytes.
+    pub bytes: u64,
+
+    /// Whether the size has been approximated with `du`, see
+    /// [SizeEstimation::Fast].
+    pub approximate: bool,
+}
+
+impl fmt::Display for Estimate {
+    /// Formats the size like [format_size], marking approximations.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use janitor::size::Estimate;
+    ///
+    /// assert_eq!(Estimate { bytes: 2048, approximate: false }.to_string(), "2.00 KiB");
+    /// assert_eq!(Estimate { bytes: 2048, approximate: true }.to_string(), "~2.00 KiB (approximate)");
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.approximate {
+            true => write!(f, "~{} (approximate)", format_size(self.bytes)),
+            false => f.write_str(&format_size(self.bytes)),
+        }
+    }
+}
+
+/// Returns the disk usage of the `paths` in bytes, measured with `du`.
+///
+/// Symlinks given as `paths`, like the links of generations, are followed,
+/// but the paths referenced from within the store paths are not counted.
+///
+/// # Errors
+///
+/// Fails if `du` can not be spawned, exits unsuccessfully, or its output can
+/// not be parsed.
+pub async fn disk_usage<E, P>(executor: &E, paths: &[P]) -> Result<u64>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if paths.is_empty() {
+        return Ok(0);
+    }
+
+    let command = CommandLine::new("du")
+        .args(["-s", "-k", "-H"])
+        .args(paths.iter().map(|p| p.as_ref().as_os_str()));
+
+    let output = executor
+        .output(command)
+        .instrument(tracing::debug_span!("du"))
+        .await
+        .spawning("du")?;
+    JanitorError::check_output("du", &output)?;
+
+    parse_disk_usage(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Sums up the sizes in KiB listed by `du -s -k`, one path per line.
+fn parse_disk_usage(output: &str) -> Result<u64> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (size, _) = line.split_once('\t').unwrap_or((line, ""));
+            size.trim()
+                .parse::<u64>()
+                .map(|kib| kib * 1024)
+                .map_err(|_| parse_error!("unrecognized output of du: {line}"))
+        })
+        .sum()
+}
+
+/// Returns the size of the generation linked to by `link`, the size of its
+/// closure with [SizeEstimation::Exact], or only of its store path with
+/// [SizeEstimation::Fast].
+///
+/// # Errors
+///
+/// Fails if neither the store nor `du` can tell the size.
+pub async fn generation_size<E, P>(
+    executor: &E,
+    link: P,
+    estimation: SizeEstimation,
+) -> Result<Estimate>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    closure_size(executor, &[link], estimation).await
+}
+
+/// Returns the size of the generations linked to by `links` together, like
+/// [generation_size], counting store paths shared between them once with
+/// [SizeEstimation::Exact].
+///
+/// # Errors
+///
+/// Fails if neither the store nor `du` can tell the size.
+pub async fn closure_size<E, P>(
+    executor: &E,
+    links: &[P],
+    estimation: SizeEstimation,
+) -> Result<Estimate>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if estimation == SizeEstimation::Exact {
+        let exact = async {
+            let closure = nix_store::requisites(executor, links).await?;
+            let closure: Vec<_> = closure.into_iter().collect();
+
+            nix_store::total_size(executor, &closure).await
+        };
+        match exact.await {
+            Ok(bytes) => {
+                return Ok(Estimate {
+                    bytes,
+                    approximate: false,
+                })
+            }
+            Err(error) => tracing::warn!(%error, "failed to query the closure size, using du"),
+        }
+    }
+
+    Ok(Estimate {
+        bytes: disk_usage(executor, links).await?,
+        approximate: true,
+    })
+}
+
+/// Estimates how much space deleting the generations `to_delete` of the
+/// profile at `profile` would free up, like [estimate_reclaimable] with
+/// [SizeEstimation::Exact], or by adding up the disk usage of the store paths
+/// of the generations alone with [SizeEstimation::Fast].
+///
+/// # Errors
+///
+/// Fails if neither the store nor `du` can tell the sizes.
+pub async fn estimate_reclaimable_with<E, P>(
+    executor: &E,
+    profile: P,
+    to_delete: &GenerationSet,
+    kept: &GenerationSet,
+    estimation: SizeEstimation,
+) -> Result<Estimate>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    let profile = profile.as_ref();
+
+    if estimation == SizeEstimation::Exact {
+        match estimate_reclaimable(executor, profile, to_delete, kept).await {
+            Ok(bytes) => {
+                return Ok(Estimate {
+                    bytes,
+                    approximate: false,
+                })
+            }
+            Err(error) => tracing::warn!(%error, "failed to query the closure sizes, using du"),
+        }
+    }
+
+    let links: Vec<_> = to_delete.iter().map(|g| g.link(profile)).collect();
+
+    Ok(Estimate {
+        bytes: disk_usage(executor, &links).await?,
+        approximate: true,
+    })
+}
+
+/// Estimates how much space deleting the generations `to_delete` of the
+/// profile at `profile` would free up, given the generations `kept` will
+/// remain.
+///
+/// The estimate is the size of all store paths in the closures of the
+/// deleted generations that are not part of the closures of the kept ones.
+/// It is an upper bound, as paths might still be referenced by other GC
+/// roots.
+pub async fn estimate_reclaimable<E, P>(
+    executor: &E,
+    profile: P,
+    to_delete: &GenerationSet,
+    kept: &GenerationSet,
+) -> Result<u64>
+where
+    E: Executor + ?Sized,
+    P: AsRef<Path>,
+{
+    if to_delete.is_empty() {
+        return Ok(0);
+    }
+
+    let profile = profile.as_ref();
+    let links = |set: &GenerationSet| set.iter().map(|g| g.link(profile)).collect::<Vec<_>>();
+
+    let deleted = nix_store::requisites(executor, &links(to_delete)).await?;
+    let retained = nix_store::requisites(executor, &links(kept)).await?;
+
+    let unique = deleted.difference(&retained).collect::<Vec<_>>();
+
+    nix_store::total_size(executor, &unique).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::bytes("123", 123)]
+    #[case::bytes_unit("123B", 123)]
+    #[case::kib("2KiB", 2048)]
+    #[case::k("2k", 2048)]
+    #[case::kb("2KB", 2000)]
+    #[case::gib("20GiB", 20 << 30)]
+    #[case::spaced(" 1 G ", 1 << 30)]
+    #[case::fraction("0.5MiB", 512 * 1024)]
+    fn test_parse_size(#[case] input: &str, #[case] expected: u64) {
+        assert_eq!(parse_size(input).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::unit_only("GiB")]
+    #[case::unknown_unit("12 apples")]
+    #[case::negative("-1G")]
+    fn test_parse_size_errors(#[case] input: &str) {
+        assert!(parse_size(input).is_err());
+    }
+
+    #[rstest]
+    #[case::zero(0, "0 B")]
+    #[case::kib(2048, "2.00 KiB")]
+    #[case::gib(3 << 30, "3.00 GiB")]
+    fn test_format_size(#[case] bytes: u64, #[case] expected: &str) {
+        assert_eq!(format_size(bytes), expected);
+    }
+
+    #[rstest]
+    #[case::empty("", 0)]
+    #[case::single("12\t/nix/store/abc-hello\n", 12 * 1024)]
+    #[case::several("12\t/nix/store/abc-hello\n3\t/nix/store/def-world\n", 15 * 1024)]
+    fn parses_disk_usage(#[case] output: &str, #[case] expected: u64) {
+        assert_eq!(parse_disk_usage(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_unknown_disk_usage() {
+        assert!(parse_disk_usage("du: cannot access 'x'\n").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn disk_usage_follows_links() -> eyre::Result<()> {
+        use std::{env, fs, os::unix::fs::symlink};
+
+        use crate::{Blocking, StdExecutor};
+
+        let dir = env::temp_dir().join(format!("janitor-du-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store"))?;
+        fs::write(dir.join("store/data"), vec![1; 64 * 1024])?;
+        symlink(dir.join("store"), dir.join("profile-1-link"))?;
+
+        let usage = futures::executor::block_on(disk_usage(
+            &Blocking(StdExecutor),
+            &[dir.join("profile-1-link")],
+        ));
+        fs::remove_dir_all(&dir)?;
+
+        assert!(usage? >= 64 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "system")]
+    fn free_space_of_root() {
+        assert!(free_space("/").is_ok());
+    }
+}
@@ -0,0 +1,328 @@
+//! Where the generations of a profile come from and how they are deleted.
+//!
+//! The janitor itself uses [Backend::source], but the retention policies and
+//! reports work with any [GenerationSource], so setups the janitor does not
+//! know about, e.g. profiles of custom deployment tools, can plug in their
+//! own.
+
+use std::{collections::BTreeSet, fmt::Debug, path::Path};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    backend::Backend, error::Result, executor::Executor, filesystem, generation_set::GenerationSet,
+    nix_env, nix_profile,
+};
+
+/// Lists and deletes the generations of profiles.
+///
+/// # Examples
+///
+/// A source for profiles whose generations are never deleted:
+///
+/// ```
+/// use std::{collections::BTreeSet, path::Path};
+///
+/// use futures::future::BoxFuture;
+/// use janitor::{filesystem, GenerationSet, GenerationSource, JanitorError};
+///
+/// #[derive(Debug)]
+/// struct Archive;
+///
+/// impl GenerationSource for Archive {
+///     fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet, JanitorError>> {
+///         Box::pin(async move { filesystem::list_generations(profile) })
+///     }
+///
+///     fn delete<'a>(
+///         &'a self,
+///         _profile: &'a Path,
+///         _generations: &'a GenerationSet,
+///     ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>, JanitorError>> {
+///         Box::pin(async { Ok(Some(BTreeSet::new())) })
+///     }
+/// }
+/// ```
+pub trait GenerationSource: Debug + Send + Sync {
+    /// Lists all generations of the profile at `profile`.
+    fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet>>;
+
+    /// Deletes the `generations` from the profile at `profile`.
+    ///
+    /// Returns the ids of the generations actually removed, or `None` if the
+    /// source can not tell, in which case all of them are taken as removed.
+    fn delete<'a>(
+        &'a self,
+        profile: &'a Path,
+        generations: &'a GenerationSet,
+    ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>>>;
+}
+
+/// Lists generations with `nix-env --list-generations`, see [nix_env].
+#[derive(Debug, Clone, Copy)]
+pub struct NixEnvSource<'e> {
+    executor: &'e dyn Executor,
+}
+
+/// Lists generations by reading their links, see [filesystem].
+#[derive(Debug, Clone, Copy)]
+pub struct FilesystemSource<'e> {
+    executor: &'e dyn Executor,
+}
+
+/// Lists generations with `nix profile history`, see [nix_profile].
+#[derive(Debug, Clone, Copy)]
+pub struct NixProfileSource<'e> {
+    executor: &'e dyn Executor,
+}
+
+/// Lists profiles managed by `nix profile` with a [NixProfileSource], and
+/// all others with the `fallback`, see [nix_profile::is_new_style].
+#[derive(Debug)]
+pub struct DetectingSource<'e> {
+    fallback: Box<dyn GenerationSource + 'e>,
+    nix_profile: NixProfileSource<'e>,
+}
+
+impl<'e> NixEnvSource<'e> {
+    /// Creates a source running `nix-env` on the `executor`.
+    pub fn new(executor: &'e dyn Executor) -> Self {
+        Self { executor }
+    }
+}
+
+impl<'e> FilesystemSource<'e> {
+    /// Creates a source deleting generations by running `nix-env` on the
+    /// `executor`.
+    pub fn new(executor: &'e dyn Executor) -> Self {
+        Self { executor }
+    }
+}
+
+impl<'e> NixProfileSource<'e> {
+    /// Creates a source running `nix` and `nix-env` on the `executor`.
+    pub fn new(executor: &'e dyn Executor) -> Self {
+        Self { executor }
+    }
+}
+
+impl<'e> DetectingSource<'e> {
+    /// Creates a source falling back to `fallback` for profiles not managed
+    /// by `nix profile`, running `nix` on the `executor` for the others.
+    pub fn new(fallback: Box<dyn GenerationSource + 'e>, executor: &'e dyn Executor) -> Self {
+        Self {
+            fallback,
+            nix_profile: NixProfileSource::new(executor),
+        }
+    }
+
+    fn pick(&self, profile: &Path) -> &dyn GenerationSource {
+        match nix_profile::is_new_style(profile) {
+            true => &self.nix_profile,
+            false => self.fallback.as_ref(),
+        }
+    }
+}
+
+impl GenerationSource for NixEnvSource<'_> {
+    fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet>> {
+        Box::pin(nix_env::list_generations(self.executor, profile))
+    }
+
+    fn delete<'a>(
+        &'a self,
+        profile: &'a Path,
+        generations: &'a GenerationSet,
+    ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>>> {
+        Box::pin(nix_env::delete_generations(
+            self.executor,
+            profile,
+            generations,
+        ))
+    }
+}
+
+impl GenerationSource for FilesystemSource<'_> {
+    fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet>> {
+        Box::pin(async move { filesystem::list_generations(profile) })
+    }
+
+    /// Deletes through `nix-env`, so that nix itself keeps track of the
+    /// profile.
+    fn delete<'a>(
+        &'a self,
+        profile: &'a Path,
+        generations: &'a GenerationSet,
+    ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>>> {
+        Box::pin(nix_env::delete_generations(
+            self.executor,
+            profile,
+            generations,
+        ))
+    }
+}
+
+impl GenerationSource for NixProfileSource<'_> {
+    fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet>> {
+        Box::pin(nix_profile::list_generations(self.executor, profile))
+    }
+
+    /// Deletes through `nix-env`, as `nix profile` can only wipe the history
+    /// by age.
+    fn delete<'a>(
+        &'a self,
+        profile: &'a Path,
+        generations: &'a GenerationSet,
+    ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>>> {
+        Box::pin(nix_env::delete_generations(
+            self.executor,
+            profile,
+            generations,
+        ))
+    }
+}
+
+impl GenerationSource for DetectingSource<'_> {
+    fn list<'a>(&'a self, profile: &'a Path) -> BoxFuture<'a, Result<GenerationSet>> {
+        self.pick(profile).list(profile)
+    }
+
+    fn delete<'a>(
+        &'a self,
+        profile: &'a Path,
+        generations: &'a GenerationSet,
+    ) -> BoxFuture<'a, Result<Option<BTreeSet<u32>>>> {
+        self.pick(profile).delete(profile, generations)
+    }
+}
+
+impl Backend {
+    /// The source listing generations with this backend, running commands on
+    /// the `executor`.
+    ///
+    /// `nix-env` does not understand profiles managed by `nix profile`, so
+    /// these are listed with `nix profile history` instead. The links read by
+    /// the [filesystem] backend are the same for both kinds of profiles.
+    pub fn source<'e>(&self, executor: &'e dyn Executor) -> Box<dyn GenerationSource + 'e> {
+        match self {
+            Self::NixEnv => Box::new(DetectingSource::new(
+                Box::new(NixEnvSource::new(executor)),
+                executor,
+            )),
+            Self::Filesystem => Box::new(FilesystemSource::new(executor)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        os::unix::process::ExitStatusExt,
+        process::{ExitStatus, Output},
+        sync::Mutex,
+    };
+
+    use super::*;
+
+    use eyre::Result;
+
+    use crate::executor::CommandLine;
+
+    /// Answers every command with the same output, remembering the programs
+    /// run.
+    #[derive(Debug, Default)]
+    struct Recorder {
+        stdout: &'static str,
+        programs: Mutex<Vec<String>>,
+    }
+
+    impl Executor for Recorder {
+        fn output(&self, command: CommandLine) -> BoxFuture<'_, io::Result<Output>> {
+            self.programs.lock().unwrap().push(command.program);
+
+            Box::pin(async move {
+                Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: self.stdout.as_bytes().to_vec(),
+                    stderr: b"removing profile version 1\n".to_vec(),
+                })
+            })
+        }
+    }
+
+    fn exercise(source: &dyn GenerationSource) -> Result<(GenerationSet, Option<BTreeSet<u32>>)> {
+        futures::executor::block_on(async {
+            let listed = source.list(Path::new("/nix/var/nix/profiles/p")).await?;
+            let to_delete: GenerationSet = listed.iter().filter(|g| g.id == 1).cloned().collect();
+            let removed = source
+                .delete(Path::new("/nix/var/nix/profiles/p"), &to_delete)
+                .await?;
+
+            Ok((listed, removed))
+        })
+    }
+
+    #[test]
+    fn nix_env_source() -> Result<()> {
+        let executor = Recorder {
+            stdout: "   1   2023-06-01 10:00:00   \n   2   2023-06-02 10:00:00   (current)\n",
+            ..Default::default()
+        };
+        let (listed, removed) = exercise(&NixEnvSource::new(&executor))?;
+
+        assert_eq!(listed.len(), 2);
+        assert_eq!(removed, Some(BTreeSet::from([1])));
+        assert_eq!(*executor.programs.lock().unwrap(), ["nix-env", "nix-env"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nix_profile_source() -> Result<()> {
+        let executor = Recorder {
+            stdout: "Version 1 (2023-06-01):\n\nVersion 2 (2023-06-02) <- 1:\n",
+            ..Default::default()
+        };
+        let (listed, removed) = exercise(&NixProfileSource::new(&executor))?;
+
+        assert_eq!(listed.len(), 2);
+        assert_eq!(removed, Some(BTreeSet::from([1])));
+        assert_eq!(*executor.programs.lock().unwrap(), ["nix", "nix-env"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn detecting_source() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("janitor-detect-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+
+        let executor = Recorder {
+            stdout: "",
+            ..Default::default()
+        };
+        let source = Backend::NixEnv.source(&executor);
+        let old_style = futures::executor::block_on(source.list(&dir));
+        std::fs::write(dir.join("manifest.json"), r#"{"version": 2}"#)?;
+        let new_style = futures::executor::block_on(source.list(&dir));
+        std::fs::remove_dir_all(&dir)?;
+
+        old_style?;
+        new_style?;
+        assert_eq!(*executor.programs.lock().unwrap(), ["nix-env", "nix"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn backend_sources() {
+        let executor = Recorder::default();
+
+        assert!(format!("{:?}", Backend::NixEnv.source(&executor)).starts_with("DetectingSource"));
+        assert!(
+            format!("{:?}", Backend::Filesystem.source(&executor)).starts_with("FilesystemSource")
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Bindings of the listing parser and the retention policies for
+//! JavaScript, so that retention settings can be explored in the browser.
+//!
+//! Only available with the `wasm` feature, which is meant to be used without
+//! the default features:
+//!
+//! ```sh
+//! cargo build --lib --release --target wasm32-unknown-unknown --no-default-features --features wasm
+//! wasm-bindgen --target web target/wasm32-unknown-unknown/release/janitor.wasm --out-dir pkg
+//! ```
+//!
+//! Dates are given in the format `nix-env --list-generations` uses, e.g.
+//! `2023-06-01 08:10:47`.
+
+use chrono::NaiveDateTime;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    duration::{format_duration, parse_duration},
+    error::{JanitorError, Result},
+    planning::{self, Rules},
+    Generation, GenerationSet, Profile, RetentionOverrides, RetentionPolicy,
+};
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Parses the output of `nix-env --list-generations` and returns the ids of
+/// the generations found.
+#[wasm_bindgen(js_name = parseListing)]
+pub fn parse_listing(listing: &str) -> Result<Vec<u32>, JsError> {
+    let generations = Generation::parse_many(listing).map_err(into_js)?;
+
+    Ok(generations.iter().map(|g| g.id).collect())
+}
+
+/// Returns the ids of the generations in `listing` the janitor would delete
+/// from the profile at `profile` at the time `now`.
+///
+/// The retention policy is the default of the kind of the profile, `keep`
+/// (a duration like `2d12h`) and `keep_at_least` override it if given.
+#[wasm_bindgen]
+pub fn plan(
+    listing: &str,
+    profile: &str,
+    keep: Option<String>,
+    keep_at_least: Option<usize>,
+    now: &str,
+) -> Result<Vec<u32>, JsError> {
+    plan_ids(listing, profile, keep.as_deref(), keep_at_least, now).map_err(into_js)
+}
+
+/// Returns the default `keep` duration of the profile at `profile`, e.g.
+/// `14d` for the system profile.
+#[wasm_bindgen(js_name = defaultKeep)]
+pub fn default_keep(profile: &str) -> String {
+    format_duration(RetentionPolicy::default_for(Profile::new(profile).kind()).keep)
+}
+
+/// Returns the default `keep_at_least` of the profile at `profile`.
+#[wasm_bindgen(js_name = defaultKeepAtLeast)]
+pub fn default_keep_at_least(profile: &str) -> usize {
+    RetentionPolicy::default_for(Profile::new(profile).kind()).keep_at_least
+}
+
+fn plan_ids(
+    listing: &str,
+    profile: &str,
+    keep: Option<&str>,
+    keep_at_least: Option<usize>,
+    now: &str,
+) -> Result<Vec<u32>> {
+    let generations: GenerationSet = Generation::parse_many(listing)?.into();
+    let now = NaiveDateTime::parse_from_str(now.trim(), DATE_FORMAT)
+        .map_err(|error| JanitorError::parse_with(format!("invalid date {now:?}"), error))?;
+    let overrides = RetentionOverrides {
+        keep: keep.map(parse_duration).transpose()?,
+        keep_since: None,
+        keep_at_least,
+        keep_at_most: None,
+        order_by: None,
+        thinning: None,
+    };
+
+    let policy = RetentionPolicy::resolve(Profile::new(profile).kind(), overrides);
+    let rules = Rules::new(policy.keep_at_least, policy.keep_since(now));
+    let to_delete = planning::plan(&generations, &rules);
+
+    Ok(to_delete.iter().map(|g| g.id).collect())
+}
+
+fn into_js(error: JanitorError) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LISTING: &str = "
+        1 2023-06-01 08:00:00
+        2 2023-06-10 08:00:00
+        3 2023-06-20 08:00:00 (current)
+    ";
+
+    #[test]
+    fn plan_with_overrides() -> Result<()> {
+        let ids = plan_ids(
+            LISTING,
+            "/home/user/.nix-profile",
+            Some("5d"),
+            Some(1),
+            "2023-06-21 00:00:00",
+        )?;
+
+        assert_eq!(ids, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_with_defaults() -> Result<()> {
+        let ids = plan_ids(
+            LISTING,
+            "/nix/var/nix/profiles/system",
+            None,
+            None,
+            "2023-06-21 00:00:00",
+        )?;
+
+        assert!(ids.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_rejects_invalid_keep() {
+        assert!(plan_ids(LISTING, "profile", Some("5"), None, "2023-06-21 00:00:00").is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(default_keep("/nix/var/nix/profiles/system"), "14d");
+        assert_eq!(default_keep_at_least("/nix/var/nix/profiles/system"), 10);
+    }
+}